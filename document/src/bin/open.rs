@@ -1,41 +1,632 @@
+//! Command line tools for XML documents
+//!
+//! ```text
+//! open <command> [--in-place] [file...]
+//! ```
+//!
+//! Each file is processed in turn. With no files, or with `-`, the
+//! document is read from standard input.
+//!
+//! - `fmt` indents element-only content, leaving the rest of the
+//!   markup as it was written. `--in-place` rewrites the files instead
+//!   of writing to standard output.
+//! - `check` only parses, reporting errors as `file:line:column`.
+//! - `c14n` writes Canonical XML, without comments. Attributes are
+//!   sorted by namespace and local name, and namespace declarations
+//!   already in scope are left out. Attributes with an undeclared
+//!   prefix cannot be canonicalized.
+//! - `strip-comments` removes all comments.
+//! - `stats` counts the elements by name and measures the depth.
+//! - `to-json` converts to BadgerFish JSON.
+//!
+//! The exit status is 1 if any file could not be read, parsed or
+//! written, and 2 if the arguments were not understood.
+
 extern crate document;
 
-use std::cmp::min;
-use std::io::File;
-use std::io::BufferedWriter;
-use std::io::stdio::stdout_raw;
+use std::cmp::{min,max};
+use std::collections::HashMap;
+use std::collections::hash_map::{Occupied,Vacant};
+use std::io;
+use std::io::{File,IoError,IoResult,MemWriter,InvalidInput};
+use std::os;
+
+use document::dom4;
+use document::dom4::{ElementCOE,TextCOE,CommentCOE,ProcessingInstructionCOE};
+use document::dom4::{ElementCOR,CommentCOR,ProcessingInstructionCOR};
+use document::parser::{Parser,ParseError,SyntaxError,LimitExceeded};
+use document::writer;
+use document::json;
+
+static USAGE: &'static str =
+    "usage: open <fmt|check|c14n|strip-comments|stats|to-json> [--in-place] [file...]";
 
-use document::parser::Parser;
+static INDENT: &'static str = "  ";
+
+#[deriving(PartialEq)]
+enum Command {
+    Fmt,
+    Check,
+    C14n,
+    StripComments,
+    Stats,
+    ToJson,
+}
+
+impl Command {
+    fn from_name(name: &str) -> Option<Command> {
+        match name {
+            "fmt"            => Some(Fmt),
+            "check"          => Some(Check),
+            "c14n"           => Some(C14n),
+            "strip-comments" => Some(StripComments),
+            "stats"          => Some(Stats),
+            "to-json"        => Some(ToJson),
+            _                => None,
+        }
+    }
+}
+
+struct Input {
+    name: String,
+    path: Option<Path>,
+}
+
+impl Input {
+    fn stdin() -> Input {
+        Input { name: "<stdin>".to_string(), path: None }
+    }
+
+    fn file(name: &str) -> Input {
+        Input { name: name.to_string(), path: Some(Path::new(name)) }
+    }
+
+    fn read(&self) -> IoResult<String> {
+        match self.path {
+            Some(ref path) => File::open(path).read_to_string(),
+            None => io::stdin().read_to_string(),
+        }
+    }
+}
+
+enum Failure {
+    Unreadable(IoError),
+    /// The error, with the line and column it occurred at and the
+    /// text found there
+    Malformed(ParseError, uint, uint, String),
+    Unwritable(IoError),
+}
 
 fn pretty_error(xml: &str, position: uint) -> &str {
-    let s = xml.slice_from(position);
+    let s = xml.slice_from(min(position, xml.len()));
     let l = s.char_len();
     s.slice_chars(0, min(l, 15))
 }
 
-fn main() {
-    let mut args = std::os::args();
+fn line_and_column(xml: &str, position: uint) -> (uint, uint) {
+    let before = xml.slice_to(min(position, xml.len()));
+    let line = before.chars().filter(|&c| c == '\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (line, before.slice_from(line_start).char_len() + 1)
+}
 
-    let filename = args.remove(1).expect("File required");
-    let path = Path::new(filename);
-    let mut file = File::open(&path);
+fn malformed(xml: &str, error: ParseError) -> Failure {
+    let offset = error.offset();
+    let (line, column) = line_and_column(xml, offset);
+    Malformed(error, line, column, pretty_error(xml, offset).to_string())
+}
+
+fn describe(input: &Input, failure: Failure) -> String {
+    match failure {
+        Unreadable(e) => format!("{}: can't read: {}", input.name, e),
+        Malformed(SyntaxError(_), line, column, near) => {
+            format!("{}:{}:{}: not well-formed at '{}'", input.name, line, column, near)
+        },
+        Malformed(LimitExceeded(limit, _), line, column, _) => {
+            format!("{}:{}:{}: exceeded the {} limit", input.name, line, column, limit)
+        },
+        Unwritable(e) => format!("{}: can't write: {}", input.name, e),
+    }
+}
+
+fn parse_arguments(args: &[String]) -> Option<(Command, bool, Vec<Input>)> {
+    let command = match args.get(0).and_then(|name| Command::from_name(name.as_slice())) {
+        Some(command) => command,
+        None => return None,
+    };
+
+    let mut in_place = false;
+    let mut inputs = Vec::new();
+
+    for arg in args.slice_from(1).iter() {
+        match arg.as_slice() {
+            "-i" | "--in-place" if command == Fmt => in_place = true,
+            "-" => inputs.push(Input::stdin()),
+            option if option.starts_with("-") => return None,
+            name => inputs.push(Input::file(name)),
+        }
+    }
+
+    if inputs.is_empty() {
+        inputs.push(Input::stdin());
+    }
+
+    Some((command, in_place, inputs))
+}
+
+fn main() {
+    let args = os::args();
+    let mut stderr = io::stderr();
 
-    let data = match file.read_to_string() {
-        Ok(x) => x,
-        Err(x) => panic!("Can't read: {}", x),
+    let (command, in_place, inputs) = match parse_arguments(args.slice_from(1)) {
+        Some(arguments) => arguments,
+        None => {
+            let _ = writeln!(stderr, "{}", USAGE);
+            os::set_exit_status(2);
+            return;
+        },
     };
 
-    let p = Parser::new();
+    let mut out = io::stdout();
+    let mut failed = false;
+
+    for input in inputs.iter() {
+        if let Err(failure) = run(command, in_place, input, &mut out) {
+            let _ = writeln!(stderr, "{}", describe(input, failure));
+            failed = true;
+        }
+    }
+
+    if failed {
+        os::set_exit_status(1);
+    }
+}
+
+fn run<W : Writer>(command: Command, in_place: bool, input: &Input, out: &mut W) -> Result<(), Failure> {
+    let xml = try!(input.read().map_err(Unreadable));
 
-    let package = match p.parse(data.as_slice()) {
-        Ok(d) => d,
-        Err(point) => panic!("Unable to parse: {}", pretty_error(data.as_slice(), point)),
+    // Keeping the original markup means that only what the command
+    // changes is written differently
+    let parser = Parser::new();
+    let parsed = match command {
+        Fmt | StripComments => parser.parse_lossless(xml.as_slice()),
+        _                   => parser.parse(xml.as_slice()),
     };
 
-    let mut out = BufferedWriter::new(stdout_raw());
+    let package = try!(parsed.map_err(|e| malformed(xml.as_slice(), e)));
+    let doc = package.as_document();
+
+    match input.path {
+        Some(ref path) if in_place => {
+            let mut buffer = MemWriter::new();
+            try!(execute(command, &doc, &mut buffer).map_err(Unwritable));
+            File::create(path).write(buffer.get_ref()).map_err(Unwritable)
+        },
+        _ => execute(command, &doc, out).map_err(Unwritable),
+    }
+}
+
+fn execute<'d, W : Writer>(command: Command, doc: &'d dom4::Document<'d>, out: &mut W) -> IoResult<()> {
+    match command {
+        Fmt => {
+            indent(doc);
+            writer::format_document(doc, out)
+        },
+        Check => Ok(()),
+        C14n => canonicalize(doc, out),
+        StripComments => {
+            strip_comments(doc);
+            writer::format_document(doc, out)
+        },
+        Stats => stats(doc, out),
+        ToJson => {
+            try!(json::format_document(doc, out));
+            out.write_str("\n")
+        },
+    }
+}
+
+fn is_space(c: char) -> bool {
+    c == ' ' || c == '\t' || c == '\r' || c == '\n'
+}
+
+fn on_new_line<'d>(child: dom4::ChildOfRoot<'d>) {
+    let before = Some("\n".to_string());
+    match child {
+        ElementCOR(e)               => e.set_source(dom4::Source { before: before, ..e.source() }),
+        CommentCOR(c)               => c.set_source(dom4::Source { before: before, ..c.source() }),
+        ProcessingInstructionCOR(p) => p.set_source(dom4::Source { before: before, ..p.source() }),
+    }
+}
+
+/// Puts each top-level node on its own line and indents element-only
+/// content. Elements with text of their own, and those marked with
+/// `xml:space='preserve'`, are left as they are.
+fn indent<'d>(doc: &'d dom4::Document<'d>) {
+    let root = doc.root();
+
+    // A document without a declaration gets the default one, so that
+    // every top-level node can start on a new line
+    let mut source = root.source();
+    if source.markup.as_ref().map_or(false, |m| m.is_empty()) {
+        source.markup = None;
+    }
+    source.after = Some("\n".to_string());
+    root.set_source(source);
+
+    if let Some(doctype) = root.doctype() {
+        doctype.set_source(dom4::Source { before: Some("\n".to_string()), ..doctype.source() });
+    }
+
+    let mut todo = Vec::new();
+
+    for child in root.children().into_iter() {
+        on_new_line(child);
+        if let Some(element) = child.element() {
+            todo.push((element, 0u));
+        }
+    }
+
+    while ! todo.is_empty() {
+        let (element, depth) = todo.pop().unwrap();
+
+        if element.attribute_value("xml:space") == Some("preserve") { continue }
+
+        // Whitespace written as a reference or in a CDATA section is
+        // significant; plain whitespace is not
+        let children = element.children();
+        let mixed = children.iter().filter_map(|c| c.text()).any(|t| {
+            let written_as_is = t.source().markup.map_or(true, |m| m.as_slice() == t.text());
+            ! written_as_is || ! t.text().chars().all(is_space)
+        });
+        if mixed { continue }
+
+        for child in children.iter() {
+            element.remove_child(*child);
+        }
+
+        let kept: Vec<_> = children.into_iter().filter(|c| c.text().is_none()).collect();
+        if kept.is_empty() { continue }
+
+        let inner = format!("\n{}", INDENT.repeat(depth + 1));
+        for child in kept.into_iter() {
+            element.append_child(doc.create_text(inner.as_slice()));
+            element.append_child(child);
+            if let Some(e) = child.element() {
+                todo.push((e, depth + 1));
+            }
+        }
+
+        let outer = format!("\n{}", INDENT.repeat(depth));
+        element.append_child(doc.create_text(outer.as_slice()));
+    }
+}
+
+fn strip_comments<'d>(doc: &'d dom4::Document<'d>) {
+    let root = doc.root();
+    let mut todo = Vec::new();
+
+    for child in root.children().into_iter() {
+        match child {
+            ElementCOR(e)               => todo.push(e),
+            CommentCOR(c)               => root.remove_child(c),
+            ProcessingInstructionCOR(_) => {},
+        }
+    }
+
+    while ! todo.is_empty() {
+        let element = todo.pop().unwrap();
+
+        for child in element.children().into_iter() {
+            match child {
+                ElementCOE(e) => todo.push(e),
+                CommentCOE(c) => element.remove_child(c),
+                _             => {},
+            }
+        }
+    }
+}
+
+fn stats<'d, W : Writer>(doc: &'d dom4::Document<'d>, out: &mut W) -> IoResult<()> {
+    let mut counts = HashMap::new();
+    let mut total = 0u;
+    let mut deepest = 0u;
+
+    let mut todo: Vec<_> = doc.root().children().into_iter()
+        .filter_map(|c| c.element())
+        .map(|e| (e, 1u))
+        .collect();
+
+    while ! todo.is_empty() {
+        let (element, depth) = todo.pop().unwrap();
+
+        total += 1;
+        deepest = max(deepest, depth);
+
+        match counts.entry(element.name().to_string()) {
+            Occupied(entry) => *entry.into_mut() += 1,
+            Vacant(entry) => { entry.set(1u); },
+        }
+
+        todo.extend(element.children().into_iter()
+                    .filter_map(|c| c.element())
+                    .map(|e| (e, depth + 1)));
+    }
+
+    try!(writeln!(out, "elements: {}", total));
+    try!(writeln!(out, "depth: {}", deepest));
+
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort();
+
+    for &(ref name, count) in counts.iter() {
+        try!(writeln!(out, "{:>8} {}", count, name));
+    }
+
+    Ok(())
+}
+
+enum Canonical<'d> {
+    Node(dom4::ChildOfElement<'d>),
+    EndTag(dom4::Element<'d>),
+}
+
+fn escape_canonical<W : Writer>(text: &str, in_attribute: bool, out: &mut W) -> IoResult<()> {
+    for c in text.chars() {
+        try!(match c {
+            '&'                   => out.write_str("&amp;"),
+            '<'                   => out.write_str("&lt;"),
+            '>' if ! in_attribute => out.write_str("&gt;"),
+            '"' if in_attribute   => out.write_str("&quot;"),
+            '\t' if in_attribute  => out.write_str("&#x9;"),
+            '\n' if in_attribute  => out.write_str("&#xA;"),
+            '\r'                  => out.write_str("&#xD;"),
+            c                     => out.write_char(c),
+        });
+    }
+
+    Ok(())
+}
+
+static XML_NAMESPACE: &'static str = "http://www.w3.org/XML/1998/namespace";
+
+/// The namespace URI bound to each prefix, with the default namespace
+/// under the empty prefix. No default namespace is the empty URI.
+type Scope = HashMap<String, String>;
+
+fn initial_scope() -> Scope {
+    let mut scope = HashMap::new();
+    scope.insert("".to_string(), "".to_string());
+    scope.insert("xml".to_string(), XML_NAMESPACE.to_string());
+    scope
+}
+
+fn split_name(name: &str) -> (Option<&str>, &str) {
+    match name.find(':') {
+        Some(i) => (Some(name.slice_to(i)), name.slice_from(i + 1)),
+        None    => (None, name),
+    }
+}
+
+/// The declared prefix, or the empty prefix for the default namespace
+fn declared_prefix(name: &str) -> Option<&str> {
+    match split_name(name) {
+        (None, "xmlns")         => Some(""),
+        (Some("xmlns"), prefix) => Some(prefix),
+        _                       => None,
+    }
+}
+
+fn undeclared_prefix(name: &str) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: "namespace prefix is not declared",
+        detail: Some(name.to_string()),
+    }
+}
+
+/// Writes the start tag, returning the namespaces in scope within the
+/// element. Only declarations that change the namespaces in scope are
+/// written, sorted by prefix, followed by the other attributes sorted
+/// by namespace URI and local name.
+fn canonicalize_start_tag<'d, W : Writer>(element: dom4::Element<'d>, parent: &Scope, out: &mut W)
+                                          -> IoResult<Scope>
+{
+    let all_attributes = element.attributes();
+    let mut scope = parent.clone();
+    let mut declarations = Vec::new();
+    let mut attributes = Vec::new();
+
+    for attr in all_attributes.iter() {
+        match declared_prefix(attr.name()) {
+            Some(prefix) => {
+                scope.insert(prefix.to_string(), attr.value().to_string());
+                if parent.get(prefix).map(|u| u.as_slice()) != Some(attr.value()) {
+                    declarations.push((prefix, attr.value()));
+                }
+            },
+            None => attributes.push(attr),
+        }
+    }
 
     {
-        let d = package.as_document();
-        document::writer::format_document(&d, &mut out).ok().expect("I can't output");
+        let mut qualified = Vec::new();
+        for attr in attributes.into_iter() {
+            let (uri, local) = match split_name(attr.name()) {
+                (None, local)         => ("", local),
+                (Some(prefix), local) => match scope.get(prefix) {
+                    Some(uri) => (uri.as_slice(), local),
+                    None      => return Err(undeclared_prefix(attr.name())),
+                },
+            };
+            qualified.push((uri, local, attr));
+        }
+
+        declarations.sort();
+        qualified.sort_by(|&(a_uri, a_local, _), &(b_uri, b_local, _)| (a_uri, a_local).cmp(&(b_uri, b_local)));
+
+        try!(write!(out, "<{}", element.name()));
+
+        for &(prefix, uri) in declarations.iter() {
+            match prefix {
+                ""     => try!(out.write_str(" xmlns=\"")),
+                prefix => try!(write!(out, " xmlns:{}=\"", prefix)),
+            }
+            try!(escape_canonical(uri, true, out));
+            try!(out.write_str("\""));
+        }
+
+        for &(_, _, attr) in qualified.iter() {
+            try!(write!(out, " {}=\"", attr.name()));
+            try!(escape_canonical(attr.value(), true, out));
+            try!(out.write_str("\""));
+        }
+
+        try!(out.write_str(">"));
+    }
+
+    Ok(scope)
+}
+
+fn canonicalize_pi<'d, W : Writer>(pi: dom4::ProcessingInstruction<'d>, out: &mut W) -> IoResult<()> {
+    match pi.value() {
+        Some(value) => write!(out, "<?{} {}?>", pi.target(), value),
+        None        => write!(out, "<?{}?>", pi.target()),
+    }
+}
+
+fn canonicalize_element<'d, W : Writer>(element: dom4::Element<'d>, out: &mut W) -> IoResult<()> {
+    let mut todo = vec![Node(ElementCOE(element))];
+    let mut scopes = vec![initial_scope()];
+
+    while ! todo.is_empty() {
+        match todo.pop().unwrap() {
+            Node(ElementCOE(e)) => {
+                let scope = try!(canonicalize_start_tag(e, scopes.last().unwrap(), out));
+                scopes.push(scope);
+
+                todo.push(EndTag(e));
+                let mut children = e.children();
+                children.reverse();
+                todo.extend(children.into_iter().map(Node));
+            },
+            Node(TextCOE(t))                  => try!(escape_canonical(t.text(), false, out)),
+            Node(CommentCOE(_))               => {},
+            Node(ProcessingInstructionCOE(p)) => try!(canonicalize_pi(p, out)),
+            EndTag(e)                         => {
+                scopes.pop();
+                try!(write!(out, "</{}>", e.name()));
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the document as Canonical XML without comments. There is
+/// no declaration or document type, and top-level processing
+/// instructions are separated from the document element by newlines.
+fn canonicalize<'d, W : Writer>(doc: &'d dom4::Document<'d>, out: &mut W) -> IoResult<()> {
+    let mut seen_element = false;
+
+    for child in doc.root().children().into_iter() {
+        match child {
+            ElementCOR(e) => {
+                try!(canonicalize_element(e, out));
+                seen_element = true;
+            },
+            ProcessingInstructionCOR(p) => {
+                if seen_element { try!(out.write_str("\n")) }
+                try!(canonicalize_pi(p, out));
+                if ! seen_element { try!(out.write_str("\n")) }
+            },
+            CommentCOR(_) => {},
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::MemWriter;
+    use document::parser::Parser;
+    use super::{execute,Fmt,C14n};
+
+    fn fmt(xml: &str) -> String {
+        let package = Parser::new().parse_lossless(xml).ok().expect("Failed to parse");
+        let doc = package.as_document();
+        let mut out = MemWriter::new();
+        execute(Fmt, &doc, &mut out).ok().expect("Failed to format");
+        String::from_utf8(out.unwrap()).ok().expect("Not UTF-8")
+    }
+
+    fn c14n(xml: &str) -> Result<String, ()> {
+        let package = Parser::new().parse(xml).ok().expect("Failed to parse");
+        let doc = package.as_document();
+        let mut out = MemWriter::new();
+        try!(execute(C14n, &doc, &mut out).map_err(|_| ()));
+        Ok(String::from_utf8(out.unwrap()).ok().expect("Not UTF-8"))
+    }
+
+    #[test]
+    fn fmt_indents_element_only_content() {
+        let formatted = fmt("<a>\n<b>x</b><c/>\n</a>");
+
+        assert_eq!(formatted.as_slice(), "<?xml version='1.0'?>\n<a>\n  <b>x</b>\n  <c/>\n</a>\n");
+    }
+
+    #[test]
+    fn fmt_indents_nested_elements() {
+        let formatted = fmt("<a> <b> <c/> </b> </a>");
+
+        assert_eq!(formatted.as_slice(), "<?xml version='1.0'?>\n<a>\n  <b>\n    <c/>\n  </b>\n</a>\n");
+    }
+
+    #[test]
+    fn fmt_leaves_mixed_content_alone() {
+        let formatted = fmt("<a>x <b/> y</a>");
+
+        assert_eq!(formatted.as_slice(), "<?xml version='1.0'?>\n<a>x <b/> y</a>\n");
+    }
+
+    #[test]
+    fn fmt_keeps_whitespace_written_as_a_reference() {
+        let formatted = fmt("<a>&#32;<b/></a>");
+
+        assert_eq!(formatted.as_slice(), "<?xml version='1.0'?>\n<a>&#32;<b/></a>\n");
+    }
+
+    #[test]
+    fn fmt_leaves_preserved_space_alone() {
+        let formatted = fmt("<a xml:space='preserve'> <b/> </a>");
+
+        assert_eq!(formatted.as_slice(), "<?xml version='1.0'?>\n<a xml:space='preserve'> <b/> </a>\n");
+    }
+
+    #[test]
+    fn c14n_sorts_attributes_by_namespace_and_local_name() {
+        let canonical = c14n("<a xmlns:z='urn:a' xmlns:b='urn:b' b:x='1' z:y='2' c='3'/>");
+
+        assert_eq!(canonical, Ok("<a xmlns:b=\"urn:b\" xmlns:z=\"urn:a\" c=\"3\" z:y=\"2\" b:x=\"1\"></a>".to_string()));
+    }
+
+    #[test]
+    fn c14n_leaves_out_declarations_already_in_scope() {
+        let canonical = c14n("<a xmlns='urn:a' xmlns:p='urn:p'><b xmlns='urn:a' xmlns:p='urn:q' xmlns:xml='http://www.w3.org/XML/1998/namespace'/></a>");
+
+        assert_eq!(canonical, Ok("<a xmlns=\"urn:a\" xmlns:p=\"urn:p\"><b xmlns:p=\"urn:q\"></b></a>".to_string()));
+    }
+
+    #[test]
+    fn c14n_undeclares_the_default_namespace_only_when_needed() {
+        let canonical = c14n("<a xmlns=''><b xmlns='urn:b'><c xmlns=''/></b></a>");
+
+        assert_eq!(canonical, Ok("<a><b xmlns=\"urn:b\"><c xmlns=\"\"></c></b></a>".to_string()));
+    }
+
+    #[test]
+    fn c14n_rejects_undeclared_prefixes() {
+        assert_eq!(c14n("<a p:x='1'/>"), Err(()));
     }
 }
@@ -0,0 +1,406 @@
+//! Resolves external identifiers through OASIS XML Catalogs
+//!
+//! A catalog maps the public and system identifiers of DTDs and
+//! external entities to local files, so that documents which refer to
+//! well-known DTDs can be processed without the network. Identifiers
+//! that are not in the catalog are never looked up anywhere else.
+//!
+//! ### Example
+//!
+//! ```no_run
+//! use document::catalog::Catalog;
+//! use document::parser::Parser;
+//!
+//! let catalog = Catalog::from_file(&Path::new("dtd/catalog.xml")).ok().expect("Invalid catalog");
+//!
+//! let mut parser = Parser::new();
+//! parser.set_resolver(catalog);
+//! ```
+//!
+//! ### Supported entries
+//!
+//! `public`, `system`, `rewriteSystem`, `systemSuffix`, `group` and
+//! `nextCatalog`, along with the `prefer` and `xml:base` attributes.
+//! As the XML Catalogs 1.1 specification requires, system entries are
+//! consulted before public entries, and both before the next catalogs.
+//! Relative targets are found from the directory of the catalog file.
+//!
+//! ### Known issues
+//!
+//! - `delegatePublic`, `delegateSystem` and the `uri` entries are
+//!   ignored.
+//! - `urn:publicid:` identifiers are not unwrapped.
+//! - Only plain paths and `file://` URIs can be used as targets.
+
+use std::collections::HashSet;
+use std::io::{File,IoError};
+
+use super::dom4;
+use super::parser::{Parser,ParseError,EntityResolver,ResolveError,UnknownIdentifier,UnreadableResource};
+use super::xmlstr::local_name;
+
+/// Why a catalog could not be loaded. The location is the path of the
+/// catalog file.
+#[deriving(Show,Clone,PartialEq)]
+pub enum CatalogError {
+    ReadFailure(String, IoError),
+    ParseFailure(String, ParseError),
+    /// The document element is not `catalog`
+    NotACatalog(String),
+    /// An entry is missing a required attribute
+    InvalidEntry(String),
+}
+
+#[deriving(Clone)]
+enum Entry {
+    SystemEntry(String, Path),
+    /// The start of matching system identifiers, and the directory and
+    /// prefix that replace it
+    RewriteSystemEntry(String, Path, String),
+    SystemSuffixEntry(String, Path),
+    /// Public entries made while `prefer` is `system` only apply when
+    /// there is no system identifier
+    PublicEntry(String, Path, bool),
+}
+
+#[deriving(Clone)]
+pub struct Catalog {
+    entries: Vec<Entry>,
+    next: Vec<Catalog>,
+}
+
+impl Catalog {
+    /// Loads the catalog and every catalog it names with `nextCatalog`
+    pub fn from_file(path: &Path) -> Result<Catalog, CatalogError> {
+        Catalog::load(path, &mut HashSet::new())
+    }
+
+    fn load(path: &Path, loaded: &mut HashSet<String>) -> Result<Catalog, CatalogError> {
+        let location = path.display().to_string();
+        loaded.insert(location.clone());
+
+        let xml = match File::open(path).read_to_string() {
+            Ok(xml) => xml,
+            Err(e) => return Err(ReadFailure(location, e)),
+        };
+
+        let package = match Parser::new().parse(xml.as_slice()) {
+            Ok(package) => package,
+            Err(e) => return Err(ParseFailure(location, e)),
+        };
+        let doc = package.as_document();
+
+        let root = match doc.root().children().into_iter().filter_map(|c| c.element()).next() {
+            Some(root) if local_name(root.name()) == "catalog" => root,
+            _ => return Err(NotACatalog(location)),
+        };
+
+        let mut catalog = Catalog { entries: Vec::new(), next: Vec::new() };
+        let mut next_catalogs = Vec::new();
+        try!(catalog.read_entries(root, &path.dir_path(), true, &mut next_catalogs));
+
+        for next in next_catalogs.iter() {
+            if loaded.contains(&next.display().to_string()) { continue }
+            catalog.next.push(try!(Catalog::load(next, loaded)));
+        }
+
+        Ok(catalog)
+    }
+
+    fn read_entries(&mut self, element: dom4::Element, base: &Path, prefer_public: bool,
+                    next_catalogs: &mut Vec<Path>)
+                    -> Result<(), CatalogError>
+    {
+        let base = with_base(element, base);
+        let prefer_public = match element.attribute_value("prefer") {
+            Some("public") => true,
+            Some("system") => false,
+            _ => prefer_public,
+        };
+
+        for child in element.children().into_iter() {
+            let child = match child.element() {
+                Some(child) => child,
+                None => continue,
+            };
+            let child_base = with_base(child, &base);
+
+            match local_name(child.name()) {
+                "group" => try!(self.read_entries(child, &base, prefer_public, next_catalogs)),
+                "public" => {
+                    let id = try!(required(child, "publicId"));
+                    let uri = try!(required(child, "uri"));
+                    self.entries.push(PublicEntry(normalize_public_id(id), to_path(&child_base, uri), prefer_public));
+                },
+                "system" => {
+                    let id = try!(required(child, "systemId"));
+                    let uri = try!(required(child, "uri"));
+                    self.entries.push(SystemEntry(id.to_string(), to_path(&child_base, uri)));
+                },
+                "rewriteSystem" => {
+                    let start = try!(required(child, "systemIdStartString"));
+                    let prefix = try!(required(child, "rewritePrefix"));
+                    let prefix = prefix.slice_from(if prefix.starts_with("file://") { "file://".len() } else { 0 });
+                    self.entries.push(RewriteSystemEntry(start.to_string(), child_base.clone(), prefix.to_string()));
+                },
+                "systemSuffix" => {
+                    let suffix = try!(required(child, "systemIdSuffix"));
+                    let uri = try!(required(child, "uri"));
+                    self.entries.push(SystemSuffixEntry(suffix.to_string(), to_path(&child_base, uri)));
+                },
+                "nextCatalog" => {
+                    let uri = try!(required(child, "catalog"));
+                    next_catalogs.push(to_path(&child_base, uri));
+                },
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The file that the identifiers are mapped to, if any
+    pub fn lookup(&self, public_id: Option<&str>, system_id: Option<&str>) -> Option<Path> {
+        if let Some(system_id) = system_id {
+            if let Some(path) = self.lookup_system(system_id) {
+                return Some(path);
+            }
+        }
+
+        if let Some(public_id) = public_id {
+            let public_id = normalize_public_id(public_id);
+            for entry in self.entries.iter() {
+                if let &PublicEntry(ref id, ref path, prefer_public) = entry {
+                    if *id == public_id && (prefer_public || system_id.is_none()) {
+                        return Some(path.clone());
+                    }
+                }
+            }
+        }
+
+        for next in self.next.iter() {
+            if let Some(path) = next.lookup(public_id, system_id) {
+                return Some(path);
+            }
+        }
+
+        None
+    }
+
+    /// An exact match wins, then the longest rewritten prefix, then
+    /// the longest suffix
+    fn lookup_system(&self, system_id: &str) -> Option<Path> {
+        let mut rewrite: Option<(uint, Path)> = None;
+        let mut suffix: Option<(uint, Path)> = None;
+
+        for entry in self.entries.iter() {
+            match entry {
+                &SystemEntry(ref id, ref path) if id.as_slice() == system_id => {
+                    return Some(path.clone());
+                },
+                &RewriteSystemEntry(ref start, ref base, ref prefix) if system_id.starts_with(start.as_slice()) => {
+                    if rewrite.as_ref().map_or(true, |&(len, _)| start.len() > len) {
+                        let rewritten = format!("{}{}", prefix, system_id.slice_from(start.len()));
+                        rewrite = Some((start.len(), base.join(rewritten)));
+                    }
+                },
+                &SystemSuffixEntry(ref end, ref path) if system_id.ends_with(end.as_slice()) => {
+                    if suffix.as_ref().map_or(true, |&(len, _)| end.len() > len) {
+                        suffix = Some((end.len(), path.clone()));
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        rewrite.or(suffix).map(|(_, path)| path)
+    }
+}
+
+impl EntityResolver for Catalog {
+    fn resolve(&self, public_id: Option<&str>, system_id: Option<&str>) -> Result<String, ResolveError> {
+        match self.lookup(public_id, system_id) {
+            Some(path) => File::open(&path).read_to_string().map_err(|e| UnreadableResource(e)),
+            None => Err(UnknownIdentifier),
+        }
+    }
+}
+
+fn required<'d>(element: dom4::Element<'d>, attribute: &str) -> Result<&'d str, CatalogError> {
+    match element.attribute_value(attribute) {
+        Some(value) => Ok(value),
+        None => Err(InvalidEntry(format!("{} has no {}", element.name(), attribute))),
+    }
+}
+
+/// Public identifiers are compared with their whitespace collapsed
+fn normalize_public_id(id: &str) -> String {
+    let words: Vec<&str> = id.words().collect();
+    words.connect(" ")
+}
+
+fn to_path(base: &Path, uri: &str) -> Path {
+    let uri = uri.slice_from(if uri.starts_with("file://") { "file://".len() } else { 0 });
+    base.join(uri)
+}
+
+/// The directory that relative targets of the element are found from
+fn with_base(element: dom4::Element, base: &Path) -> Path {
+    match element.attribute_value("xml:base") {
+        Some(uri) if uri.ends_with("/") => to_path(base, uri),
+        Some(uri) => to_path(base, uri).dir_path(),
+        None => base.clone(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{File,TempDir,USER_RWX};
+    use std::io::fs::mkdir;
+
+    use super::super::parser::{Parser,EntityResolver,UnknownIdentifier};
+    use super::{Catalog,NotACatalog,InvalidEntry};
+
+    fn write_files(dir: &TempDir, files: &[(&str, &str)]) {
+        for &(name, contents) in files.iter() {
+            File::create(&dir.path().join(name)).write_str(contents).unwrap();
+        }
+    }
+
+    static XHTML_PUBLIC: &'static str = "-//W3C//DTD XHTML 1.0 Strict//EN";
+    static XHTML_SYSTEM: &'static str = "http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd";
+
+    #[test]
+    fn public_and_system_entries() {
+        let dir = TempDir::new("catalog").unwrap();
+        write_files(&dir, [("catalog.xml", r#"
+            <catalog xmlns="urn:oasis:names:tc:entity:xmlns:xml:catalog">
+              <public publicId="-//W3C//DTD XHTML 1.0 Strict//EN" uri="public.dtd"/>
+              <system systemId="http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd" uri="system.dtd"/>
+            </catalog>"#)]);
+
+        let catalog = Catalog::from_file(&dir.path().join("catalog.xml")).ok().expect("Invalid catalog");
+
+        assert_eq!(catalog.lookup(None, Some(XHTML_SYSTEM)), Some(dir.path().join("system.dtd")));
+        assert_eq!(catalog.lookup(Some(XHTML_PUBLIC), Some(XHTML_SYSTEM)), Some(dir.path().join("system.dtd")));
+        assert_eq!(catalog.lookup(Some("-//W3C//DTD  XHTML 1.0\nStrict//EN"), Some("other.dtd")),
+                   Some(dir.path().join("public.dtd")));
+        assert_eq!(catalog.lookup(None, Some("other.dtd")), None);
+    }
+
+    #[test]
+    fn public_entries_can_be_ignored_when_a_system_identifier_is_given() {
+        let dir = TempDir::new("catalog").unwrap();
+        write_files(&dir, [("catalog.xml", r#"
+            <catalog xmlns="urn:oasis:names:tc:entity:xmlns:xml:catalog">
+              <group prefer="system">
+                <public publicId="-//W3C//DTD XHTML 1.0 Strict//EN" uri="public.dtd"/>
+              </group>
+            </catalog>"#)]);
+
+        let catalog = Catalog::from_file(&dir.path().join("catalog.xml")).ok().expect("Invalid catalog");
+
+        assert_eq!(catalog.lookup(Some(XHTML_PUBLIC), Some(XHTML_SYSTEM)), None);
+        assert_eq!(catalog.lookup(Some(XHTML_PUBLIC), None), Some(dir.path().join("public.dtd")));
+    }
+
+    #[test]
+    fn longest_rewrite_and_suffix_win() {
+        let dir = TempDir::new("catalog").unwrap();
+        write_files(&dir, [("catalog.xml", r#"
+            <catalog xmlns="urn:oasis:names:tc:entity:xmlns:xml:catalog">
+              <rewriteSystem systemIdStartString="http://www.w3.org/" rewritePrefix="w3c/"/>
+              <rewriteSystem systemIdStartString="http://www.w3.org/TR/xhtml1/DTD/" rewritePrefix="xhtml/"/>
+              <systemSuffix systemIdSuffix=".ent" uri="any.ent"/>
+              <systemSuffix systemIdSuffix="-lat1.ent" uri="lat1.ent"/>
+            </catalog>"#)]);
+
+        let catalog = Catalog::from_file(&dir.path().join("catalog.xml")).ok().expect("Invalid catalog");
+
+        assert_eq!(catalog.lookup(None, Some(XHTML_SYSTEM)), Some(dir.path().join("xhtml/xhtml1-strict.dtd")));
+        assert_eq!(catalog.lookup(None, Some("http://www.w3.org/other.dtd")), Some(dir.path().join("w3c/other.dtd")));
+        assert_eq!(catalog.lookup(None, Some("xhtml-lat1.ent")), Some(dir.path().join("lat1.ent")));
+        assert_eq!(catalog.lookup(None, Some("xhtml-symbol.ent")), Some(dir.path().join("any.ent")));
+    }
+
+    #[test]
+    fn next_catalogs_are_consulted_last() {
+        let dir = TempDir::new("catalog").unwrap();
+        mkdir(&dir.path().join("more"), USER_RWX).unwrap();
+        write_files(&dir, [
+            ("catalog.xml", r#"
+                <catalog xmlns="urn:oasis:names:tc:entity:xmlns:xml:catalog">
+                  <nextCatalog catalog="more/catalog.xml"/>
+                  <public publicId="-//W3C//DTD XHTML 1.0 Strict//EN" uri="first.dtd"/>
+                </catalog>"#),
+            ("more/catalog.xml", r#"
+                <catalog xmlns="urn:oasis:names:tc:entity:xmlns:xml:catalog">
+                  <system systemId="http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd" uri="second.dtd"/>
+                  <nextCatalog catalog="../catalog.xml"/>
+                </catalog>"#),
+        ]);
+
+        let catalog = Catalog::from_file(&dir.path().join("catalog.xml")).ok().expect("Invalid catalog");
+
+        assert_eq!(catalog.lookup(Some(XHTML_PUBLIC), Some(XHTML_SYSTEM)), Some(dir.path().join("first.dtd")));
+        assert_eq!(catalog.lookup(None, Some(XHTML_SYSTEM)), Some(dir.path().join("more/second.dtd")));
+    }
+
+    #[test]
+    fn xml_base_changes_the_directory_of_targets() {
+        let dir = TempDir::new("catalog").unwrap();
+        write_files(&dir, [("catalog.xml", r#"
+            <catalog xmlns="urn:oasis:names:tc:entity:xmlns:xml:catalog">
+              <group xml:base="dtd/">
+                <system systemId="a.dtd" uri="a.dtd"/>
+              </group>
+            </catalog>"#)]);
+
+        let catalog = Catalog::from_file(&dir.path().join("catalog.xml")).ok().expect("Invalid catalog");
+
+        assert_eq!(catalog.lookup(None, Some("a.dtd")), Some(dir.path().join("dtd/a.dtd")));
+    }
+
+    #[test]
+    fn invalid_catalogs() {
+        let dir = TempDir::new("catalog").unwrap();
+        write_files(&dir, [
+            ("not.xml", "<catalogue/>"),
+            ("missing.xml", "<catalog><system uri='a.dtd'/></catalog>"),
+        ]);
+
+        match Catalog::from_file(&dir.path().join("not.xml")) {
+            Err(NotACatalog(..)) => {},
+            _ => panic!("Expected the catalog to be rejected"),
+        }
+        match Catalog::from_file(&dir.path().join("missing.xml")) {
+            Err(InvalidEntry(..)) => {},
+            _ => panic!("Expected the entry to be rejected"),
+        }
+    }
+
+    #[test]
+    fn parser_resolves_external_subsets_from_the_catalog() {
+        let dir = TempDir::new("catalog").unwrap();
+        write_files(&dir, [
+            ("catalog.xml", r#"
+                <catalog xmlns="urn:oasis:names:tc:entity:xmlns:xml:catalog">
+                  <public publicId="-//Example//DTD Greeting//EN" uri="greeting.dtd"/>
+                </catalog>"#),
+            ("greeting.dtd", "<!ELEMENT greeting (#PCDATA)>"),
+        ]);
+
+        let catalog = Catalog::from_file(&dir.path().join("catalog.xml")).ok().expect("Invalid catalog");
+        assert_eq!(catalog.resolve(None, Some("http://example.com/greeting.dtd")), Err(UnknownIdentifier));
+
+        let mut parser = Parser::new();
+        parser.set_resolver(catalog);
+
+        let xml = r#"<!DOCTYPE greeting PUBLIC "-//Example//DTD Greeting//EN" "http://example.com/greeting.dtd"><greeting/>"#;
+        let package = parser.parse(xml).ok().expect("Failed to parse");
+        let doc = package.as_document();
+        let doctype = doc.root().doctype().unwrap();
+
+        assert_eq!(doctype.external_subset(), Some("<!ELEMENT greeting (#PCDATA)>"));
+    }
+}
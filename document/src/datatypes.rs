@@ -0,0 +1,320 @@
+//! The built-in datatypes of XML Schema and their facets, shared by
+//! the schema languages that use them
+
+use std::cmp::{Ordering,Less,Equal,Greater};
+use std::{i32,i64};
+
+use regex::Regex;
+
+use super::xmlstr::{is_name,is_nmtoken};
+use super::xsd::{Facet,LengthFacet,MinLengthFacet,MaxLengthFacet,PatternFacet,EnumerationFacet};
+use super::xsd::{MinInclusiveFacet,MaxInclusiveFacet,MinExclusiveFacet,MaxExclusiveFacet};
+use super::xsd::{TotalDigitsFacet,FractionDigitsFacet};
+
+#[deriving(Show,Clone,PartialEq)]
+pub enum WhiteSpace {
+    Preserve,
+    Replace,
+    Collapse,
+}
+
+/// Why a facet could not be added
+#[deriving(Show,Clone,PartialEq)]
+pub enum FacetError {
+    UnknownFacet,
+    InvalidFacetValue,
+    /// The `pattern` is not a regular expression
+    InvalidFacetPattern,
+}
+
+pub struct Facets {
+    pub enumeration: Vec<String>,
+    pub patterns: Vec<(String, Regex)>,
+    pub length: Option<uint>,
+    pub min_length: Option<uint>,
+    pub max_length: Option<uint>,
+    pub min_inclusive: Option<String>,
+    pub max_inclusive: Option<String>,
+    pub min_exclusive: Option<String>,
+    pub max_exclusive: Option<String>,
+    pub total_digits: Option<uint>,
+    pub fraction_digits: Option<uint>,
+    pub white_space: Option<WhiteSpace>,
+}
+
+static BUILTIN_TYPES: &'static [&'static str] = &[
+    "anyType", "anySimpleType",
+    "string", "normalizedString", "token", "language", "anyURI",
+    "Name", "NCName", "QName", "NOTATION",
+    "ID", "IDREF", "IDREFS", "ENTITY", "ENTITIES", "NMTOKEN", "NMTOKENS",
+    "boolean", "decimal", "float", "double",
+    "integer", "nonPositiveInteger", "negativeInteger", "nonNegativeInteger", "positiveInteger",
+    "long", "int", "short", "byte",
+    "unsignedLong", "unsignedInt", "unsignedShort", "unsignedByte",
+    "duration", "dateTime", "date", "time",
+    "gYear", "gYearMonth", "gMonth", "gMonthDay", "gDay",
+    "hexBinary", "base64Binary",
+];
+
+static TIMEZONE: &'static str = r"(Z|[+-]\d{2}:\d{2})?";
+
+/// Patterns for the lexical spaces of the built-in types that are not
+/// names or integers. The timezone pattern is appended to the date
+/// and time types.
+static LEXICAL_FORMS: &'static [(&'static str, &'static str, bool)] = &[
+    ("boolean",      r"true|false|1|0",                                          false),
+    ("decimal",      r"[+-]?(\d+(\.\d*)?|\.\d+)",                                false),
+    ("float",        r"[+-]?(\d+(\.\d*)?|\.\d+)([eE][+-]?\d+)?|-?INF|NaN",       false),
+    ("double",       r"[+-]?(\d+(\.\d*)?|\.\d+)([eE][+-]?\d+)?|-?INF|NaN",       false),
+    ("integer",      r"[+-]?\d+",                                                false),
+    ("duration",     r"-?P(\d+Y)?(\d+M)?(\d+D)?(T(\d+H)?(\d+M)?(\d+(\.\d+)?S)?)?", false),
+    ("dateTime",     r"-?\d{4,}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(\.\d+)?",          true),
+    ("date",         r"-?\d{4,}-\d{2}-\d{2}",                                    true),
+    ("time",         r"\d{2}:\d{2}:\d{2}(\.\d+)?",                               true),
+    ("gYear",        r"-?\d{4,}",                                                true),
+    ("gYearMonth",   r"-?\d{4,}-\d{2}",                                          true),
+    ("gMonth",       r"--\d{2}",                                                 true),
+    ("gMonthDay",    r"--\d{2}-\d{2}",                                           true),
+    ("gDay",         r"---\d{2}",                                                true),
+    ("hexBinary",    r"([0-9a-fA-F]{2})*",                                       false),
+    ("base64Binary", r"[A-Za-z0-9+/ ]*=? ?=?",                                   false),
+    ("language",     r"[a-zA-Z]{1,8}(-[a-zA-Z0-9]{1,8})*",                       false),
+];
+
+pub fn is_builtin(name: &str) -> bool {
+    BUILTIN_TYPES.contains(&name)
+}
+
+/// The smallest and largest values of the built-in integer types
+fn integer_bounds(name: &str) -> Option<(Option<i64>, Option<i64>)> {
+    let bounds = match name {
+        "integer"            => (None, None),
+        "nonPositiveInteger" => (None, Some(0)),
+        "negativeInteger"    => (None, Some(-1)),
+        "nonNegativeInteger" => (Some(0), None),
+        "positiveInteger"    => (Some(1), None),
+        "long"               => (Some(i64::MIN), Some(i64::MAX)),
+        "int"                => (Some(i32::MIN as i64), Some(i32::MAX as i64)),
+        "short"              => (Some(-32768), Some(32767)),
+        "byte"               => (Some(-128), Some(127)),
+        "unsignedLong"       => (Some(0), None),
+        "unsignedInt"        => (Some(0), Some(4294967295)),
+        "unsignedShort"      => (Some(0), Some(65535)),
+        "unsignedByte"       => (Some(0), Some(255)),
+        _                    => return None,
+    };
+    Some(bounds)
+}
+
+fn in_bounds(value: &str, min: Option<i64>, max: Option<i64>) -> bool {
+    match from_str::<i64>(value.trim_left_chars('+')) {
+        Some(n) => min.map_or(true, |min| n >= min) && max.map_or(true, |max| n <= max),
+        // Too large for an i64, so only an unbounded side can allow it
+        None => if value.starts_with("-") { min.is_none() } else { max.is_none() },
+    }
+}
+
+pub fn is_ncname(s: &str) -> bool {
+    is_name(s) && ! s.contains_char(':')
+}
+
+pub fn is_qname(s: &str) -> bool {
+    match s.find(':') {
+        Some(i) => is_ncname(s.slice_to(i)) && is_ncname(s.slice_from(i + 1)),
+        None => is_ncname(s),
+    }
+}
+
+/// How the built-in type treats whitespace before checking a value
+pub fn builtin_white_space(name: &str) -> WhiteSpace {
+    match name {
+        "string" | "anySimpleType" | "anyType" => Preserve,
+        "normalizedString"                     => Replace,
+        _                                      => Collapse,
+    }
+}
+
+/// Whether the length facets of the built-in type count list items
+/// rather than characters
+pub fn is_builtin_list(name: &str) -> bool {
+    name == "IDREFS" || name == "ENTITIES" || name == "NMTOKENS"
+}
+
+pub fn normalize(value: &str, white_space: WhiteSpace) -> String {
+    match white_space {
+        Preserve => value.to_string(),
+        Replace => value.chars().map(|c| if c == '\t' || c == '\n' || c == '\r' { ' ' } else { c }).collect(),
+        Collapse => {
+            let tokens: Vec<&str> = value.words().collect();
+            tokens.connect(" ")
+        },
+    }
+}
+
+/// Orders two values as numbers when both are numbers, and as
+/// strings otherwise
+fn compare(a: &str, b: &str) -> Ordering {
+    match (from_str::<f64>(a), from_str::<f64>(b)) {
+        (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Equal),
+        _ => a.cmp(&b),
+    }
+}
+
+/// The total and fraction digits of a decimal, not counting leading
+/// or trailing zeros
+fn digits(value: &str) -> (uint, uint) {
+    let value = value.trim_left_chars(|c: char| c == '+' || c == '-');
+    let (integer, fraction) = match value.find('.') {
+        Some(i) => (value.slice_to(i), value.slice_from(i + 1)),
+        None => (value, ""),
+    };
+    let integer = integer.trim_left_chars('0');
+    let fraction = fraction.trim_right_chars('0');
+    (integer.len() + fraction.len(), fraction.len())
+}
+
+fn facet_number(value: &str) -> Result<uint, FacetError> {
+    from_str::<uint>(value.trim()).ok_or(InvalidFacetValue)
+}
+
+impl Facets {
+    pub fn new() -> Facets {
+        Facets {
+            enumeration: Vec::new(),
+            patterns: Vec::new(),
+            length: None,
+            min_length: None,
+            max_length: None,
+            min_inclusive: None,
+            max_inclusive: None,
+            min_exclusive: None,
+            max_exclusive: None,
+            total_digits: None,
+            fraction_digits: None,
+            white_space: None,
+        }
+    }
+
+    /// Adds the facet with the given name, such as `maxLength`
+    pub fn add(&mut self, name: &str, value: &str) -> Result<(), FacetError> {
+        match name {
+            "enumeration" => self.enumeration.push(value.to_string()),
+            "pattern" => {
+                match Regex::new(format!("^(?:{})$", value).as_slice()) {
+                    Ok(regex) => self.patterns.push((value.to_string(), regex)),
+                    Err(..) => return Err(InvalidFacetPattern),
+                }
+            },
+            "length"         => self.length = Some(try!(facet_number(value))),
+            "minLength"      => self.min_length = Some(try!(facet_number(value))),
+            "maxLength"      => self.max_length = Some(try!(facet_number(value))),
+            "minInclusive"   => self.min_inclusive = Some(value.to_string()),
+            "maxInclusive"   => self.max_inclusive = Some(value.to_string()),
+            "minExclusive"   => self.min_exclusive = Some(value.to_string()),
+            "maxExclusive"   => self.max_exclusive = Some(value.to_string()),
+            "totalDigits"    => self.total_digits = Some(try!(facet_number(value))),
+            "fractionDigits" => self.fraction_digits = Some(try!(facet_number(value))),
+            "whiteSpace"     => {
+                self.white_space = Some(match value {
+                    "preserve" => Preserve,
+                    "replace"  => Replace,
+                    "collapse" => Collapse,
+                    _          => return Err(InvalidFacetValue),
+                });
+            },
+            _ => return Err(UnknownFacet),
+        }
+
+        Ok(())
+    }
+
+    /// Checks an already normalized value. The length is in characters
+    /// or, for list types, in items.
+    pub fn check(&self, value: &str, length: uint) -> Result<(), Facet> {
+        if ! self.enumeration.is_empty() && ! self.enumeration.iter().any(|e| e.as_slice() == value) {
+            return Err(EnumerationFacet);
+        }
+
+        if ! self.patterns.is_empty() && ! self.patterns.iter().any(|&(_, ref regex)| regex.is_match(value)) {
+            let patterns: Vec<&str> = self.patterns.iter().map(|&(ref pattern, _)| pattern.as_slice()).collect();
+            return Err(PatternFacet(patterns.connect("|")));
+        }
+
+        if let Some(n) = self.length {
+            if length != n { return Err(LengthFacet(n)) }
+        }
+        if let Some(n) = self.min_length {
+            if length < n { return Err(MinLengthFacet(n)) }
+        }
+        if let Some(n) = self.max_length {
+            if length > n { return Err(MaxLengthFacet(n)) }
+        }
+
+        if let Some(ref bound) = self.min_inclusive {
+            if compare(value, bound.as_slice()) == Less { return Err(MinInclusiveFacet(bound.clone())) }
+        }
+        if let Some(ref bound) = self.max_inclusive {
+            if compare(value, bound.as_slice()) == Greater { return Err(MaxInclusiveFacet(bound.clone())) }
+        }
+        if let Some(ref bound) = self.min_exclusive {
+            if compare(value, bound.as_slice()) != Greater { return Err(MinExclusiveFacet(bound.clone())) }
+        }
+        if let Some(ref bound) = self.max_exclusive {
+            if compare(value, bound.as_slice()) != Less { return Err(MaxExclusiveFacet(bound.clone())) }
+        }
+
+        let (total, fraction) = digits(value);
+        if let Some(n) = self.total_digits {
+            if total > n { return Err(TotalDigitsFacet(n)) }
+        }
+        if let Some(n) = self.fraction_digits {
+            if fraction > n { return Err(FractionDigitsFacet(n)) }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks values against the lexical spaces of the built-in types
+pub struct Builtins {
+    lexical_forms: Vec<(&'static str, Regex)>,
+}
+
+impl Builtins {
+    pub fn new() -> Builtins {
+        let lexical_forms = LEXICAL_FORMS.iter().map(|&(name, pattern, timezone)| {
+            let pattern = format!("^(?:{}){}$", pattern, if timezone { TIMEZONE } else { "" });
+            let regex = Regex::new(pattern.as_slice()).ok().expect("Invalid built-in pattern");
+            (name, regex)
+        }).collect();
+
+        Builtins { lexical_forms: lexical_forms }
+    }
+
+    /// Checks an already normalized value
+    pub fn check(&self, name: &str, value: &str) -> bool {
+        if let Some((min, max)) = integer_bounds(name) {
+            return self.matches_lexical_form("integer", value) && in_bounds(value, min, max);
+        }
+
+        let tokens: Vec<&str> = value.words().collect();
+
+        match name {
+            "Name"                                  => is_name(value),
+            "NCName" | "ID" | "IDREF" | "ENTITY"    => is_ncname(value),
+            "QName" | "NOTATION"                    => is_qname(value),
+            "NMTOKEN"                               => is_nmtoken(value),
+            "IDREFS" | "ENTITIES"                   => ! tokens.is_empty() && tokens.iter().all(|t| is_ncname(*t)),
+            "NMTOKENS"                              => ! tokens.is_empty() && tokens.iter().all(|t| is_nmtoken(*t)),
+            _                                       => self.matches_lexical_form(name, value),
+        }
+    }
+
+    /// Types without a lexical form pattern accept any value
+    fn matches_lexical_form(&self, name: &str, value: &str) -> bool {
+        match self.lexical_forms.iter().find(|&&(n, _)| n == name) {
+            Some(&(_, ref regex)) => regex.is_match(value),
+            None => true,
+        }
+    }
+}
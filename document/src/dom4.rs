@@ -1,10 +1,128 @@
 use super::raw;
+use super::navigator;
+use super::navigator::{Navigator,NodeKind};
+use super::string_pool::InternedString;
+use std::cmp;
+use std::cmp::Ordering;
 use std::fmt;
-use std::cell::RefCell;
+use std::hash;
+use std::mem;
+use std::kinds::marker::ContravariantLifetime;
+use std::cell::{Cell,RefCell};
+
+pub use super::raw::{Source,NodeId};
 
 pub struct Document<'d> {
     storage: &'d raw::Storage,
     connections: RefCell<&'d raw::Connections>,
+    observers: &'d Observers,
+}
+
+/// The observers of every document made from one package, so that a
+/// change made through any of them is reported to all observers
+pub struct Observers {
+    /// Each observer with the document it was added through. Their
+    /// lifetimes are erased; a document removes its observers when it
+    /// is dropped, so none outlives the nodes it is given.
+    registered: RefCell<Vec<(ObserverId, Box<Registered<'static>>)>>,
+    next_observer: Cell<uint>,
+    /// The observer being told about a change, and whether it removed
+    /// itself meanwhile
+    notifying: Cell<Option<(ObserverId, bool)>>,
+}
+
+struct Registered<'d> {
+    document: &'d Document<'d>,
+    observer: Box<MutationObserver<'d> + 'd>,
+}
+
+impl Observers {
+    pub fn new() -> Observers {
+        Observers {
+            registered: RefCell::new(Vec::new()),
+            next_observer: Cell::new(0),
+            notifying: Cell::new(None),
+        }
+    }
+}
+
+/// A change made to a document, reported after it has been made.
+/// Old values are given as they were before the change.
+#[deriving(Show,Clone,PartialEq)]
+pub enum Mutation<'d> {
+    ChildAdded(ParentOfChild<'d>, ChildOfElement<'d>),
+    /// The position the child was removed from
+    ChildRemoved(ParentOfChild<'d>, ChildOfElement<'d>, uint),
+    /// The attribute name and its previous value, if it had one. The
+    /// attribute may since have been removed. The last field is the
+    /// attribute node that was replaced or removed, if the change was
+    /// not made in place.
+    AttributeChanged(Element<'d>, String, Option<String>, Option<Attribute<'d>>),
+    /// The previous name
+    ElementRenamed(Element<'d>, String),
+    TextChanged(Text<'d>, String),
+    CommentChanged(Comment<'d>, String),
+    /// The previous target and value
+    ProcessingInstructionChanged(ProcessingInstruction<'d>, String, Option<String>),
+}
+
+/// Receives the changes made to a package, through any of its
+/// documents. Changes that observers make while being notified are
+/// not reported.
+pub trait MutationObserver<'d> {
+    fn mutated(&mut self, mutation: &Mutation<'d>);
+}
+
+/// The user data of some nodes, saved by `Document::save_user_data`
+/// so that it can be given back to the same nodes once removing them
+/// has cleared it
+pub struct SavedUserData<'d> {
+    values: Vec<(uint, raw::UserDataValues)>,
+    lifetime: ContravariantLifetime<'d>,
+}
+
+/// Identifies a registered observer so that it can be removed
+#[deriving(Show,Clone,PartialEq)]
+pub struct ObserverId(uint);
+
+/// An element or attribute name interned by a package, created by
+/// `Document::intern_name`. Comparing two names, or a name with an
+/// element's, compares pointers instead of text. Names from different
+/// packages are never equal.
+#[deriving(Clone)]
+pub struct Name<'d> {
+    name: InternedString,
+    lifetime: ContravariantLifetime<'d>,
+}
+
+impl<'d> Name<'d> {
+    fn wrap(name: InternedString) -> Name<'d> {
+        Name { name: name, lifetime: ContravariantLifetime }
+    }
+
+    pub fn as_slice(&self) -> &'d str { self.name.as_slice() }
+}
+
+impl<'d> PartialEq for Name<'d> {
+    fn eq(&self, other: &Name<'d>) -> bool {
+        self.name.is(&other.name)
+    }
+}
+
+impl<'d> Eq for Name<'d> {}
+
+impl<'d, S : hash::Writer> hash::Hash<S> for Name<'d> {
+    fn hash(&self, state: &mut S) {
+        let name = self.as_slice();
+        (name.as_ptr() as uint).hash(state);
+        name.len().hash(state);
+    }
+}
+
+impl<'d> fmt::Show for Name<'d> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_slice().fmt(f)
+    }
 }
 
 macro_rules! wrapper(
@@ -20,17 +138,201 @@ macro_rules! wrapper(
 
 impl<'d> Document<'d> {
     wrapper!(wrap_root, Root, raw::Root)
+    wrapper!(wrap_document_type, DocumentType, raw::DocumentType)
     wrapper!(wrap_element, Element, raw::Element)
     wrapper!(wrap_attribute, Attribute, raw::Attribute)
     wrapper!(wrap_text, Text, raw::Text)
     wrapper!(wrap_comment, Comment, raw::Comment)
     wrapper!(wrap_pi, ProcessingInstruction, raw::ProcessingInstruction)
 
-    pub fn new(storage: &'d raw::Storage, connections: &'d raw::Connections) -> Document<'d> {
+    pub fn new(storage: &'d raw::Storage,
+               connections: &'d raw::Connections,
+               observers: &'d Observers) -> Document<'d> {
         Document {
             storage: storage,
             connections: RefCell::new(connections),
+            observers: observers,
+        }
+    }
+
+    /// Reports every later change made to the package, through this
+    /// document or any other made from it, to the observer. Nodes in
+    /// the changes belong to this document. The observer is removed
+    /// when this document is dropped.
+    pub fn add_observer(&'d self, observer: Box<MutationObserver<'d> + 'd>) -> ObserverId {
+        let observers = self.observers;
+        let id = ObserverId(observers.next_observer.get());
+        observers.next_observer.set(observers.next_observer.get() + 1);
+
+        let registered = box Registered { document: self, observer: observer };
+        let registered: Box<Registered<'static>> = unsafe { mem::transmute(registered) };
+        observers.registered.borrow_mut().push((id.clone(), registered));
+        id
+    }
+
+    /// Stops reporting changes to an observer added through this
+    /// document and gives it back. Observers may be removed while
+    /// being told about a change; one that removes itself is not given
+    /// back, as it is still running, and is dropped once it returns.
+    pub fn remove_observer(&self, id: ObserverId) -> Option<Box<MutationObserver<'d> + 'd>> {
+        let observers = self.observers;
+        if let Some((running, _)) = observers.notifying.get() {
+            if running == id {
+                observers.notifying.set(Some((running, true)));
+                return None;
+            }
+        }
+
+        let mut registered = observers.registered.borrow_mut();
+        let index = registered.iter().position(|&(ref i, ref r)| *i == id && self.registered(&**r));
+        match index.and_then(|index| registered.remove(index)) {
+            Some((_, r)) => {
+                let r: Box<Registered<'d>> = unsafe { mem::transmute(r) };
+                Some(r.observer)
+            },
+            None => None,
+        }
+    }
+
+    /// Whether the observer was added through this document
+    fn registered(&self, registered: &Registered<'static>) -> bool {
+        registered.document as *const Document as uint == self as *const Document as uint
+    }
+
+    fn is_observed(&self) -> bool {
+        ! self.observers.registered.borrow().is_empty()
+    }
+
+    fn notify(&self, mutation: Mutation<'d>) {
+        let observers = self.observers;
+
+        // Changes made by observers while they run are not reported
+        if ! self.is_observed() || observers.notifying.get().is_some() { return }
+
+        // Each observer is taken out while it runs, so that it can add
+        // and remove the others. Those added are told about the next
+        // change.
+        let ids: Vec<ObserverId> = observers.registered.borrow().iter().map(|&(ref id, _)| id.clone()).collect();
+        for id in ids.into_iter() {
+            let taken = {
+                let mut registered = observers.registered.borrow_mut();
+                match registered.iter().position(|&(ref i, _)| *i == id) {
+                    Some(index) => registered.remove(index).map(|(_, r)| (index, r)),
+                    None => None,
+                }
+            };
+
+            let (index, mut r) = match taken {
+                Some(taken) => taken,
+                None => continue,
+            };
+
+            observers.notifying.set(Some((id.clone(), false)));
+            {
+                let mutation = r.document.wrap_mutation(&mutation);
+                r.observer.mutated(&mutation);
+            }
+            let removed_itself = observers.notifying.get().map_or(false, |(_, removed)| removed);
+            observers.notifying.set(None);
+
+            if ! removed_itself {
+                let mut registered = observers.registered.borrow_mut();
+                let index = cmp::min(index, registered.len());
+                registered.insert(index, (id, r));
+            }
+        }
+    }
+
+    /// The same change, with its nodes belonging to this document
+    fn wrap_mutation(&'d self, mutation: &Mutation) -> Mutation<'d> {
+        match *mutation {
+            ChildAdded(parent, child) => {
+                ChildAdded(self.wrap_parent_of_child(parent.as_raw()), self.wrap_child_of_element(child.as_raw()))
+            },
+            ChildRemoved(parent, child, index) => {
+                ChildRemoved(self.wrap_parent_of_child(parent.as_raw()), self.wrap_child_of_element(child.as_raw()), index)
+            },
+            AttributeChanged(element, ref name, ref old, old_attr) => {
+                AttributeChanged(self.wrap_element(element.node), name.clone(), old.clone(),
+                                 old_attr.map(|a| self.wrap_attribute(a.node)))
+            },
+            ElementRenamed(element, ref name) => ElementRenamed(self.wrap_element(element.node), name.clone()),
+            TextChanged(text, ref old) => TextChanged(self.wrap_text(text.node), old.clone()),
+            CommentChanged(comment, ref old) => CommentChanged(self.wrap_comment(comment.node), old.clone()),
+            ProcessingInstructionChanged(pi, ref target, ref value) => {
+                ProcessingInstructionChanged(self.wrap_pi(pi.node), target.clone(), value.clone())
+            },
+        }
+    }
+
+    fn notify_all(&self, mutations: Vec<Mutation<'d>>) {
+        for mutation in mutations.into_iter() {
+            self.notify(mutation);
+        }
+    }
+
+    /// Copies the user data of the node, its attributes and everything
+    /// beneath it
+    pub fn save_user_data(&'d self, node: Node<'d>) -> SavedUserData<'d> {
+        let values = self.subtree(node).into_iter().map(|address| {
+            (address, self.storage.user_data_values(address))
+        }).filter(|&(_, ref values)| ! values.is_empty()).collect();
+
+        SavedUserData { values: values, lifetime: ContravariantLifetime }
+    }
+
+    /// Gives saved user data back to the nodes it was saved from,
+    /// replacing whatever they have now
+    pub fn restore_user_data(&self, saved: SavedUserData<'d>) {
+        for (address, values) in saved.values.into_iter() {
+            self.storage.set_user_data_values(address, values);
+        }
+    }
+
+    /// Drops the user data of a removed node and everything beneath it
+    fn forget_user_data(&'d self, node: Node<'d>) {
+        for address in self.subtree(node).into_iter() {
+            self.storage.clear_user_data(address);
+        }
+    }
+
+    /// The addresses of the node, its attributes and its descendants
+    fn subtree(&'d self, node: Node<'d>) -> Vec<uint> {
+        let mut addresses = Vec::new();
+        let mut todo = vec![node];
+
+        while ! todo.is_empty() {
+            let node = todo.pop().unwrap();
+            addresses.push(node.address());
+            todo.extend(self.attributes(&node).into_iter());
+            todo.extend(self.children(&node).into_iter());
+        }
+
+        addresses
+    }
+
+    /// The removals caused by moving the child to a new parent. A new
+    /// element child of the root also replaces the old one.
+    fn detachments(&'d self, child: ChildOfElement<'d>, to_root: bool) -> Vec<Mutation<'d>> {
+        let mut mutations = Vec::new();
+        if ! self.is_observed() { return mutations }
+
+        if let Some(parent) = child.parent() {
+            mutations.push(ChildRemoved(parent, child, parent.position_of(child)));
+        }
+
+        if to_root && child.element().is_some() {
+            let root = RootPOC(self.root());
+            for c in root.children().into_iter() {
+                if let ElementCOE(_) = c {
+                    if c != child {
+                        mutations.push(ChildRemoved(root, c, root.position_of(c)));
+                    }
+                }
+            }
         }
+
+        mutations
     }
 
     fn wrap_parent_of_child(&'d self, node: raw::ParentOfChild) -> ParentOfChild<'d> {
@@ -61,10 +363,89 @@ impl<'d> Document<'d> {
         self.wrap_root(self.connections.borrow().root())
     }
 
+    /// The node with this ID. The document type declaration is found
+    /// through the root instead.
+    pub fn node_by_id(&'d self, id: NodeId) -> Option<Node<'d>> {
+        self.storage.node(id).and_then(|n| match n {
+            raw::RootNode(n)                  => Some(RootNode(self.wrap_root(n))),
+            raw::DocumentTypeNode(..)         => None,
+            raw::ElementNode(n)               => Some(ElementNode(self.wrap_element(n))),
+            raw::AttributeNode(n)             => Some(AttributeNode(self.wrap_attribute(n))),
+            raw::TextNode(n)                  => Some(TextNode(self.wrap_text(n))),
+            raw::CommentNode(n)               => Some(CommentNode(self.wrap_comment(n))),
+            raw::ProcessingInstructionNode(n) => Some(ProcessingInstructionNode(self.wrap_pi(n))),
+        })
+    }
+
+    /// Compares the nodes with these IDs by document order, when both
+    /// can be found
+    pub fn compare_ids(&'d self, a: NodeId, b: NodeId) -> Option<Ordering> {
+        match (self.node_by_id(a), self.node_by_id(b)) {
+            (Some(a), Some(b)) => Some(self.document_order(&a, &b)),
+            _ => None,
+        }
+    }
+
+    /// Finds the element in the document with this `xml:id` or
+    /// declared ID attribute. When several elements share the ID, the
+    /// first in document order is found.
+    pub fn element_by_id(&'d self, id: &str) -> Option<Element<'d>> {
+        let connections = self.connections.borrow();
+        connections.element_by_id(id).map(|n| self.wrap_element(n))
+    }
+
+    /// Every element in the document whose ID was already given to
+    /// an element before it, along with that ID
+    pub fn duplicate_ids(&'d self) -> Vec<(Element<'d>, String)> {
+        let connections = self.connections.borrow();
+        connections.duplicate_ids().into_iter().map(|(n, id)| (self.wrap_element(n), id)).collect()
+    }
+
+    /// Keeps an index of the elements in the document by name, so that
+    /// `elements_named` and `Element::descendants_named` do not visit
+    /// every element. The index is updated as the document changes.
+    pub fn enable_name_index(&self) {
+        let connections = self.connections.borrow_mut();
+        connections.enable_name_index()
+    }
+
+    /// The elements in the document with this name, in document order
+    pub fn elements_named(&'d self, name: &str) -> Vec<Element<'d>> {
+        let connections = self.connections.borrow();
+        connections.elements_named(None, name).into_iter().map(|n| self.wrap_element(n)).collect()
+    }
+
+    /// Treats the attribute as an ID on elements with this name, in
+    /// addition to `xml:id`
+    pub fn declare_id_attribute(&self, element: &str, attribute: &str) {
+        let connections = self.connections.borrow_mut();
+        connections.declare_id_attribute(element, attribute)
+    }
+
+    pub fn create_document_type(&'d self,
+                                name: &str,
+                                public_id: Option<&str>,
+                                system_id: Option<&str>,
+                                internal_subset: Option<&str>)
+                                -> DocumentType<'d>
+    {
+        self.wrap_document_type(self.storage.create_document_type(name, public_id, system_id, internal_subset))
+    }
+
+    /// Interns the name once, so that it can be compared with element
+    /// and attribute names without comparing text
+    pub fn intern_name(&'d self, name: &str) -> Name<'d> {
+        Name::wrap(self.storage.intern(name))
+    }
+
     pub fn create_element(&'d self, name: &str) -> Element<'d> {
         self.wrap_element(self.storage.create_element(name))
     }
 
+    pub fn create_element_named(&'d self, name: Name<'d>) -> Element<'d> {
+        self.wrap_element(self.storage.create_element_from(name.name))
+    }
+
     pub fn create_text(&'d self, text: &str) -> Text<'d> {
         self.wrap_text(self.storage.create_text(text))
     }
@@ -78,6 +459,16 @@ impl<'d> Document<'d> {
     }
 }
 
+/// Removes the observers added through the document, which may hold
+/// its nodes
+#[unsafe_destructor]
+impl<'d> Drop for Document<'d> {
+    fn drop(&mut self) {
+        let mut registered = self.observers.registered.borrow_mut();
+        registered.retain(|&(_, ref r)| ! self.registered(&**r));
+    }
+}
+
 impl<'d> PartialEq for Document<'d> {
     fn eq(&self, other: &Document<'d>) -> bool {
         self as *const Document == other as *const Document
@@ -102,6 +493,47 @@ macro_rules! node(
             fn node(&self) -> &$raw { unsafe { &*self.node } }
 
             pub fn document(&self) -> &'d Document<'d> { self.document }
+
+            pub fn id(&self) -> NodeId { self.node().id() }
+
+            /// How the node was written in the text it was parsed
+            /// from, when recorded by `Parser::parse_lossless`. The
+            /// position of the document type is recorded by every
+            /// parse.
+            pub fn source(&self) -> Source {
+                self.document.storage.source(self.node as uint)
+            }
+
+            pub fn set_source(&self, source: Source) {
+                self.document.storage.set_source(self.node as uint, source)
+            }
+
+            /// The value of type `T` that the application attached to
+            /// the node. Values are cleared when the node, or an
+            /// ancestor, is removed from its parent, but not when it
+            /// is moved to a new one.
+            pub fn user_data<T : Clone + 'static>(&self) -> Option<T> {
+                self.document.storage.user_data::<T>(self.node as uint)
+            }
+
+            /// Attaches the value to the node, replacing any other
+            /// value of the same type
+            pub fn set_user_data<T : Clone + 'static>(&self, value: T) {
+                self.document.storage.set_user_data(self.node as uint, value)
+            }
+
+            pub fn remove_user_data<T : Clone + 'static>(&self) -> Option<T> {
+                self.document.storage.remove_user_data::<T>(self.node as uint)
+            }
+
+            /// The recorded markup no longer matches the node
+            #[allow(dead_code)]
+            fn forget_markup(&self) {
+                let mut source = self.source();
+                if source.markup.is_none() { return }
+                source.markup = None;
+                self.set_source(source);
+            }
         }
 
         impl<'d> PartialEq for $name<'d> {
@@ -109,6 +541,10 @@ macro_rules! node(
                 self.node == other.node
             }
         }
+
+        impl<'d> Clone for $name<'d> {
+            fn clone(&self) -> $name<'d> { *self }
+        }
     )
 )
 
@@ -117,8 +553,57 @@ node!(Root, raw::Root)
 impl<'d> Root<'d> {
     pub fn append_child<C : ToChildOfRoot<'d>>(&self, child: C) {
         let child = child.to_child_of_root();
-        let connections = self.document.connections.borrow_mut();
-        connections.append_root_child(child.as_raw())
+        let removed = self.document.detachments(child.to_child_of_element(), true);
+        let replaced = self.replaced_by(child);
+        {
+            let connections = self.document.connections.borrow_mut();
+            connections.append_root_child(child.as_raw());
+        }
+        self.document.notify_all(removed);
+        self.document.notify(ChildAdded(RootPOC(*self), child.to_child_of_element()));
+        if let Some(replaced) = replaced {
+            self.document.forget_user_data(ElementNode(replaced));
+        }
+    }
+
+    /// Inserts the child before the one at `index`, or at the end when
+    /// there are fewer children
+    pub fn insert_child<C : ToChildOfRoot<'d>>(&self, index: uint, child: C) {
+        let child = child.to_child_of_root();
+        let removed = self.document.detachments(child.to_child_of_element(), true);
+        let replaced = self.replaced_by(child);
+        {
+            let connections = self.document.connections.borrow_mut();
+            connections.insert_root_child(index, child.as_raw());
+        }
+        self.document.notify_all(removed);
+        self.document.notify(ChildAdded(RootPOC(*self), child.to_child_of_element()));
+        if let Some(replaced) = replaced {
+            self.document.forget_user_data(ElementNode(replaced));
+        }
+    }
+
+    /// The document element that a new child would replace
+    fn replaced_by(&self, child: ChildOfRoot<'d>) -> Option<Element<'d>> {
+        match child {
+            ElementCOR(new) => self.children().into_iter().filter_map(|c| c.element()).find(|e| *e != new),
+            _ => None,
+        }
+    }
+
+    /// Detaches the child. Nothing happens if it is not a child of
+    /// the root.
+    pub fn remove_child<C : ToChildOfRoot<'d>>(&self, child: C) {
+        let child = child.to_child_of_root();
+        let removed = child.to_child_of_element();
+        if removed.parent() != Some(RootPOC(*self)) { return }
+        let index = RootPOC(*self).position_of(removed);
+        {
+            let connections = self.document.connections.borrow_mut();
+            connections.remove_root_child(child.as_raw());
+        }
+        self.document.notify(ChildRemoved(RootPOC(*self), removed, index));
+        self.document.forget_user_data(removed.to_node());
     }
 
     pub fn children(&self) -> Vec<ChildOfRoot<'d>> {
@@ -132,6 +617,29 @@ impl<'d> Root<'d> {
             }).collect()
         }
     }
+
+    /// The document type declaration, if the document has one
+    pub fn doctype(&self) -> Option<DocumentType<'d>> {
+        let connections = self.document.connections.borrow();
+        connections.root_doctype().map(|n| self.document.wrap_document_type(n))
+    }
+
+    pub fn set_doctype(&self, doctype: Option<DocumentType<'d>>) {
+        let connections = self.document.connections.borrow_mut();
+        connections.set_root_doctype(doctype.map(|d| d.node))
+    }
+
+    /// The text content of the document element, or an empty string
+    /// when there is none. Comments and processing instructions
+    /// outside of it have no text content. There is no setter, as the
+    /// root cannot have text children.
+    pub fn text_content(&self) -> String {
+        self.children().iter()
+            .filter_map(|c| c.element())
+            .next()
+            .map(|e| e.text_content())
+            .unwrap_or(String::new())
+    }
 }
 
 impl<'d> fmt::Show for Root<'d> {
@@ -140,13 +648,57 @@ impl<'d> fmt::Show for Root<'d> {
     }
 }
 
+node!(DocumentType, raw::DocumentType)
+
+impl<'d> DocumentType<'d> {
+    /// The name the document element is declared to have
+    pub fn name(&self) -> &str { self.node().name() }
+    pub fn public_id(&self) -> Option<&str> { self.node().public_id() }
+    pub fn system_id(&self) -> Option<&str> { self.node().system_id() }
+    /// The unparsed text between the square brackets
+    pub fn internal_subset(&self) -> Option<&str> { self.node().internal_subset() }
+    /// The text of the external subset, when the parser's resolver
+    /// was able to retrieve it
+    pub fn external_subset(&self) -> Option<&str> { self.node().external_subset() }
+
+    pub fn set_external_subset(&self, subset: Option<&str>) {
+        self.document.storage.document_type_set_external_subset(self.node, subset)
+    }
+}
+
+impl<'d> fmt::Show for DocumentType<'d> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DocumentType {{ name: {} }}", self.name())
+    }
+}
+
 node!(Element, raw::Element)
 
 impl<'d> Element<'d> {
     pub fn name(&self) -> &str { self.node().name() }
 
+    /// The name, for comparing with names from `Document::intern_name`
+    pub fn interned_name(&self) -> Name<'d> { Name::wrap(self.node().interned_name()) }
+
+    pub fn is_named(&self, name: Name<'d>) -> bool {
+        self.node().interned_name().is(&name.name)
+    }
+
     pub fn set_name(&self, name: &str) {
-        self.document.storage.element_set_name(self.node, name)
+        let old_name = self.name().to_string();
+        self.document.storage.element_set_name(self.node, name);
+
+        let mut source = self.source();
+        if source.after.as_ref().map_or(false, |a| ! a.is_empty()) {
+            source.after = Some(format!("</{}>", name));
+            self.set_source(source);
+        }
+
+        {
+            let connections = self.document.connections.borrow_mut();
+            connections.element_renamed(self.node, old_name.as_slice());
+        }
+        self.document.notify(ElementRenamed(*self, old_name));
     }
 
     pub fn parent(&self) -> Option<ParentOfChild<'d>> {
@@ -159,8 +711,40 @@ impl<'d> Element<'d> {
 
     pub fn append_child<C : ToChildOfElement<'d>>(&self, child: C) {
         let child = child.to_child_of_element();
-        let connections = self.document.connections.borrow_mut();
-        connections.append_element_child(self.node, child.as_raw())
+        let removed = self.document.detachments(child, false);
+        {
+            let connections = self.document.connections.borrow_mut();
+            connections.append_element_child(self.node, child.as_raw());
+        }
+        self.document.notify_all(removed);
+        self.document.notify(ChildAdded(ElementPOC(*self), child));
+    }
+
+    /// Inserts the child before the one at `index`, or at the end when
+    /// there are fewer children
+    pub fn insert_child<C : ToChildOfElement<'d>>(&self, index: uint, child: C) {
+        let child = child.to_child_of_element();
+        let removed = self.document.detachments(child, false);
+        {
+            let connections = self.document.connections.borrow_mut();
+            connections.insert_element_child(self.node, index, child.as_raw());
+        }
+        self.document.notify_all(removed);
+        self.document.notify(ChildAdded(ElementPOC(*self), child));
+    }
+
+    /// Detaches the child. Nothing happens if it is not a child of
+    /// this element.
+    pub fn remove_child<C : ToChildOfElement<'d>>(&self, child: C) {
+        let child = child.to_child_of_element();
+        if child.parent() != Some(ElementPOC(*self)) { return }
+        let index = ElementPOC(*self).position_of(child);
+        {
+            let connections = self.document.connections.borrow_mut();
+            connections.remove_element_child(self.node, child.as_raw());
+        }
+        self.document.notify(ChildRemoved(ElementPOC(*self), child, index));
+        self.document.forget_user_data(child.to_node());
     }
 
     pub fn children(&self) -> Vec<ChildOfElement<'d>> {
@@ -175,6 +759,96 @@ impl<'d> Element<'d> {
         }
     }
 
+    /// All the text beneath this element concatenated in document
+    /// order, which is its XPath string-value
+    pub fn text_content(&self) -> String {
+        let mut content = String::new();
+        let mut todo = self.children();
+        todo.reverse();
+
+        while ! todo.is_empty() {
+            match todo.pop().unwrap() {
+                TextCOE(t) => content.push_str(t.text()),
+                ElementCOE(e) => {
+                    let mut children = e.children();
+                    children.reverse();
+                    todo.extend(children.into_iter());
+                },
+                _ => {},
+            }
+        }
+
+        content
+    }
+
+    /// Replaces all the children with a single text node, or with
+    /// nothing when the text is empty
+    pub fn set_text_content(&self, text: &str) {
+        for child in self.children().into_iter() {
+            self.remove_child(child);
+        }
+
+        if ! text.is_empty() {
+            self.append_child(self.document.create_text(text));
+        }
+    }
+
+    /// A copy of the element and everything beneath it, without a
+    /// parent. The user data of every node is copied along with it.
+    pub fn deep_clone(&self) -> Element<'d> {
+        let document = self.document;
+        let storage = document.storage;
+        let copy = document.create_element(self.name());
+        let mut todo = vec![(*self, copy)];
+
+        while ! todo.is_empty() {
+            let (original, copy) = todo.pop().unwrap();
+            storage.copy_user_data(original.node as uint, copy.node as uint);
+
+            for attr in original.attributes().iter() {
+                let attr_copy = copy.set_attribute_value(attr.name(), attr.value());
+                storage.copy_user_data(attr.node as uint, attr_copy.node as uint);
+            }
+
+            for child in original.children().into_iter() {
+                let child_copy = match child {
+                    ElementCOE(n) => {
+                        let c = document.create_element(n.name());
+                        todo.push((n, c));
+                        ElementCOE(c)
+                    },
+                    TextCOE(n) => {
+                        let c = document.create_text(n.text());
+                        storage.copy_user_data(n.node as uint, c.node as uint);
+                        TextCOE(c)
+                    },
+                    CommentCOE(n) => {
+                        let c = document.create_comment(n.text());
+                        storage.copy_user_data(n.node as uint, c.node as uint);
+                        CommentCOE(c)
+                    },
+                    ProcessingInstructionCOE(n) => {
+                        let c = document.create_processing_instruction(n.target(), n.value());
+                        storage.copy_user_data(n.node as uint, c.node as uint);
+                        ProcessingInstructionCOE(c)
+                    },
+                };
+                copy.append_child(child_copy);
+            }
+        }
+
+        copy
+    }
+
+    /// The descendants of this element with this name, in document
+    /// order
+    pub fn descendants_named(&self, name: &str) -> Vec<Element<'d>> {
+        let connections = self.document.connections.borrow();
+        connections.elements_named(Some(self.node), name).into_iter().map(|n| {
+            self.document.wrap_element(n)
+        }).collect()
+    }
+
     pub fn attributes(&self) -> Vec<Attribute<'d>> {
         let connections = self.document.connections.borrow();
         // This is safe because we copy of the children, and the
@@ -187,10 +861,62 @@ impl<'d> Element<'d> {
     }
 
     pub fn set_attribute_value(&self, name: &str, value: &str) -> Attribute<'d> {
+        let old_markup = self.attribute(name).and_then(|a| a.source().markup);
         let attr = self.document.storage.create_attribute(name, value);
-        let connections = self.document.connections.borrow_mut();
-        connections.set_attribute(self.node, attr);
-        self.document.wrap_attribute(attr)
+        if let Some(markup) = old_markup.and_then(|m| requoted(m.as_slice(), value)) {
+            self.document.storage.set_source(attr as uint, Source { markup: Some(markup), ..Default::default() });
+        }
+        let attr = self.document.wrap_attribute(attr);
+        self.set_attribute(attr);
+        attr
+    }
+
+    /// Attaches the attribute node, replacing any attribute with the
+    /// same name. An attribute of another element is moved.
+    pub fn set_attribute(&self, attribute: Attribute<'d>) {
+        let name = attribute.name().to_string();
+        let old_attr = self.attribute(name.as_slice());
+        if old_attr == Some(attribute) { return }
+
+        // Moving the attribute keeps its user data
+        if let Some(parent) = attribute.parent() {
+            let value = attribute.value().to_string();
+            {
+                let connections = self.document.connections.borrow_mut();
+                connections.remove_attribute(parent.node, name.as_slice());
+            }
+            self.document.notify(AttributeChanged(parent, name.clone(), Some(value), Some(attribute)));
+        }
+
+        let old_value = old_attr.map(|a| a.value().to_string());
+        {
+            let connections = self.document.connections.borrow_mut();
+            connections.set_attribute(self.node, attribute.node);
+        }
+        self.document.notify(AttributeChanged(*self, name, old_value, old_attr));
+        if let Some(old_attr) = old_attr {
+            self.document.forget_user_data(AttributeNode(old_attr));
+        }
+    }
+
+    /// Removes the attribute with this name, if there is one
+    pub fn remove_attribute(&self, name: &str) {
+        let old_attr = match self.attribute(name) {
+            Some(a) => a,
+            None => return,
+        };
+        let old_value = old_attr.value().to_string();
+        {
+            let connections = self.document.connections.borrow_mut();
+            connections.remove_attribute(self.node, name);
+        }
+        self.document.notify(AttributeChanged(*self, name.to_string(), Some(old_value), Some(old_attr)));
+        self.document.forget_user_data(AttributeNode(old_attr));
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<Attribute<'d>> {
+        let connections = self.document.connections.borrow();
+        connections.attribute(self.node, name).map(|a| self.document.wrap_attribute(a))
     }
 
     pub fn attribute_value(&self, name: &str) -> Option<&'d str> {
@@ -200,6 +926,21 @@ impl<'d> Element<'d> {
             a_r.value()
         })
     }
+
+    /// Like `attribute`, without comparing text
+    pub fn attribute_named(&self, name: Name<'d>) -> Option<Attribute<'d>> {
+        let connections = self.document.connections.borrow();
+        connections.attribute_interned(self.node, name.name).map(|a| self.document.wrap_attribute(a))
+    }
+
+    /// Like `attribute_value`, without comparing text
+    pub fn attribute_value_named(&self, name: Name<'d>) -> Option<&'d str> {
+        let connections = self.document.connections.borrow();
+        connections.attribute_interned(self.node, name.name).map(|a| {
+            let a_r = unsafe { &*a };
+            a_r.value()
+        })
+    }
 }
 
 impl<'d> fmt::Show for Element<'d> {
@@ -212,8 +953,34 @@ node!(Attribute, raw::Attribute)
 
 impl<'d> Attribute<'d> {
     pub fn name(&self)  -> &str { self.node().name() }
+    pub fn interned_name(&self) -> Name<'d> { Name::wrap(self.node().interned_name()) }
     pub fn value(&self) -> &str { self.node().value() }
 
+    /// The value, as with `Element::text_content`
+    pub fn text_content(&self) -> String {
+        self.value().to_string()
+    }
+
+    /// Changes the value in place
+    pub fn set_text_content(&self, value: &str) {
+        let old_value = self.value().to_string();
+        self.document.storage.attribute_set_value(self.node, value);
+
+        let mut source = self.source();
+        if let Some(markup) = source.markup.take() {
+            source.markup = requoted(markup.as_slice(), value);
+            self.set_source(source);
+        }
+
+        {
+            let connections = self.document.connections.borrow_mut();
+            connections.attribute_value_changed(self.node, old_value.as_slice());
+        }
+        if let Some(parent) = self.parent() {
+            self.document.notify(AttributeChanged(parent, self.name().to_string(), Some(old_value), None));
+        }
+    }
+
     pub fn parent(&self) -> Option<Element<'d>> {
         let connections = self.document.connections.borrow();
         connections.attribute_parent(self.node).map(|n| {
@@ -222,13 +989,46 @@ impl<'d> Attribute<'d> {
     }
 }
 
+impl<'d> fmt::Show for Attribute<'d> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Attribute {{ name: {}, value: {} }}", self.name(), self.value())
+    }
+}
+
+/// The markup of an attribute with a new value, keeping the spacing
+/// and quotes of the old markup
+fn requoted(markup: &str, value: &str) -> Option<String> {
+    let quote_at = match markup.find(|c: char| c == '\'' || c == '"') {
+        Some(i) => i,
+        None => return None,
+    };
+    let quote = markup.char_at(quote_at);
+
+    let mut requoted = markup.slice_to(quote_at + 1).to_string();
+    for c in value.chars() {
+        match c {
+            '&' => requoted.push_str("&amp;"),
+            '<' => requoted.push_str("&lt;"),
+            '"' if quote == '"' => requoted.push_str("&quot;"),
+            '\'' if quote == '\'' => requoted.push_str("&apos;"),
+            c => requoted.push(c),
+        }
+    }
+    requoted.push(quote);
+
+    Some(requoted)
+}
+
 node!(Text, raw::Text)
 
 impl<'d> Text<'d> {
     pub fn text(&self) -> &str { self.node().text() }
 
     pub fn set_text(&self, text: &str) {
-        self.document.storage.text_set_text(self.node, text)
+        let old_text = self.text().to_string();
+        self.document.storage.text_set_text(self.node, text);
+        self.forget_markup();
+        self.document.notify(TextChanged(*self, old_text));
     }
 
     pub fn parent(&self) -> Option<Element<'d>> {
@@ -251,7 +1051,10 @@ impl<'d> Comment<'d> {
     pub fn text(&self) -> &str { self.node().text() }
 
     pub fn set_text(&self, new_text: &str) {
-        self.document.storage.comment_set_text(self.node, new_text)
+        let old_text = self.text().to_string();
+        self.document.storage.comment_set_text(self.node, new_text);
+        self.forget_markup();
+        self.document.notify(CommentChanged(*self, old_text));
     }
 
     pub fn parent(&self) -> Option<ParentOfChild<'d>> {
@@ -275,11 +1078,21 @@ impl<'d> ProcessingInstruction<'d> {
     pub fn value(&self) -> Option<&str> { self.node().value() }
 
     pub fn set_target(&self, new_target: &str) {
+        let (old_target, old_value) = self.old_values();
         self.document.storage.processing_instruction_set_target(self.node, new_target);
+        self.forget_markup();
+        self.document.notify(ProcessingInstructionChanged(*self, old_target, old_value));
     }
 
     pub fn set_value(&self, new_value: Option<&str>) {
+        let (old_target, old_value) = self.old_values();
         self.document.storage.processing_instruction_set_value(self.node, new_value);
+        self.forget_markup();
+        self.document.notify(ProcessingInstructionChanged(*self, old_target, old_value));
+    }
+
+    fn old_values(&self) -> (String, Option<String>) {
+        (self.target().to_string(), self.value().map(|v| v.to_string()))
     }
 
     pub fn parent(&self) -> Option<ParentOfChild<'d>> {
@@ -309,7 +1122,7 @@ macro_rules! unpack(
     )
 )
 
-#[deriving(PartialEq,Show)]
+#[deriving(PartialEq,Show,Clone)]
 pub enum ChildOfRoot<'d> {
     ElementCOR(Element<'d>),
     CommentCOR(Comment<'d>),
@@ -330,7 +1143,7 @@ impl<'d> ChildOfRoot<'d> {
     }
 }
 
-#[deriving(PartialEq,Show)]
+#[deriving(PartialEq,Show,Clone)]
 pub enum ChildOfElement<'d> {
     ElementCOE(Element<'d>),
     TextCOE(Text<'d>),
@@ -352,23 +1165,193 @@ impl<'d> ChildOfElement<'d> {
             &ProcessingInstructionCOE(n) => raw::ProcessingInstructionCOE(n.node),
         }
     }
+
+    pub fn parent(&self) -> Option<ParentOfChild<'d>> {
+        match *self {
+            ElementCOE(n) => n.parent(),
+            TextCOE(n) => n.parent().map(|e| ElementPOC(e)),
+            CommentCOE(n) => n.parent(),
+            ProcessingInstructionCOE(n) => n.parent(),
+        }
+    }
 }
 
-#[deriving(PartialEq,Show)]
+#[deriving(PartialEq,Show,Clone)]
 pub enum ParentOfChild<'d> {
     RootPOC(Root<'d>),
     ElementPOC(Element<'d>),
 }
 
-macro_rules! conversion_trait(
-    ($tr_name:ident, $method:ident, $res_type:ident,
-        { $($leaf_type:ident => $variant:ident),* }
-    ) => (
-        pub trait $tr_name<'d> {
-            fn $method(self) -> $res_type<'d>;
+impl<'d> ParentOfChild<'d> {
+    pub fn as_raw(&self) -> raw::ParentOfChild {
+        match self {
+            &RootPOC(n) => raw::RootPOC(n.node),
+            &ElementPOC(n) => raw::ElementPOC(n.node),
         }
+    }
 
-        impl<'d> $tr_name<'d> for $res_type<'d> {
+    pub fn children(&self) -> Vec<ChildOfElement<'d>> {
+        match *self {
+            RootPOC(n) => n.children().into_iter().map(|c| c.to_child_of_element()).collect(),
+            ElementPOC(n) => n.children(),
+        }
+    }
+
+    /// Inserts the child before the one at `index`. Text cannot be a
+    /// child of the root and is ignored.
+    pub fn insert_child(&self, index: uint, child: ChildOfElement<'d>) {
+        match *self {
+            ElementPOC(n) => n.insert_child(index, child),
+            RootPOC(n) => match child {
+                ElementCOE(c) => n.insert_child(index, c),
+                CommentCOE(c) => n.insert_child(index, c),
+                ProcessingInstructionCOE(c) => n.insert_child(index, c),
+                TextCOE(..) => {},
+            },
+        }
+    }
+
+    pub fn remove_child(&self, child: ChildOfElement<'d>) {
+        match *self {
+            ElementPOC(n) => n.remove_child(child),
+            RootPOC(n) => match child {
+                ElementCOE(c) => n.remove_child(c),
+                CommentCOE(c) => n.remove_child(c),
+                ProcessingInstructionCOE(c) => n.remove_child(c),
+                TextCOE(..) => {},
+            },
+        }
+    }
+
+    fn position_of(&self, child: ChildOfElement<'d>) -> uint {
+        self.children().iter().position(|c| *c == child).unwrap_or(0)
+    }
+}
+
+/// Any node of a document, for use with `navigator::Navigator`
+#[deriving(PartialEq,Show,Clone)]
+pub enum Node<'d> {
+    RootNode(Root<'d>),
+    ElementNode(Element<'d>),
+    AttributeNode(Attribute<'d>),
+    TextNode(Text<'d>),
+    CommentNode(Comment<'d>),
+    ProcessingInstructionNode(ProcessingInstruction<'d>),
+}
+
+impl<'d> Node<'d> {
+    fn address(&self) -> uint {
+        match *self {
+            RootNode(n)                  => n.node as uint,
+            ElementNode(n)               => n.node as uint,
+            AttributeNode(n)             => n.node as uint,
+            TextNode(n)                  => n.node as uint,
+            CommentNode(n)               => n.node as uint,
+            ProcessingInstructionNode(n) => n.node as uint,
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        match *self {
+            RootNode(n)                  => n.id(),
+            ElementNode(n)               => n.id(),
+            AttributeNode(n)             => n.id(),
+            TextNode(n)                  => n.id(),
+            CommentNode(n)               => n.id(),
+            ProcessingInstructionNode(n) => n.id(),
+        }
+    }
+}
+
+impl<'d> ChildOfElement<'d> {
+    pub fn to_node(self) -> Node<'d> {
+        match self {
+            ElementCOE(n)               => ElementNode(n),
+            TextCOE(n)                  => TextNode(n),
+            CommentCOE(n)               => CommentNode(n),
+            ProcessingInstructionCOE(n) => ProcessingInstructionNode(n),
+        }
+    }
+}
+
+impl<'d> ParentOfChild<'d> {
+    pub fn to_node(self) -> Node<'d> {
+        match self {
+            RootPOC(n)    => RootNode(n),
+            ElementPOC(n) => ElementNode(n),
+        }
+    }
+}
+
+/// Nodes know their own document, so any document can navigate them
+impl<'d> Navigator<Node<'d>> for Document<'d> {
+    fn kind(&self, node: &Node<'d>) -> NodeKind {
+        match *node {
+            RootNode(..)                  => navigator::Root,
+            ElementNode(..)               => navigator::Element,
+            AttributeNode(..)             => navigator::Attribute,
+            TextNode(..)                  => navigator::Text,
+            CommentNode(..)               => navigator::Comment,
+            ProcessingInstructionNode(..) => navigator::ProcessingInstruction,
+        }
+    }
+
+    fn parent(&self, node: &Node<'d>) -> Option<Node<'d>> {
+        match *node {
+            RootNode(..)                 => None,
+            ElementNode(n)               => n.parent().map(|p| p.to_node()),
+            AttributeNode(n)             => n.parent().map(ElementNode),
+            TextNode(n)                  => n.parent().map(ElementNode),
+            CommentNode(n)               => n.parent().map(|p| p.to_node()),
+            ProcessingInstructionNode(n) => n.parent().map(|p| p.to_node()),
+        }
+    }
+
+    fn children(&self, node: &Node<'d>) -> Vec<Node<'d>> {
+        match *node {
+            RootNode(n)    => n.children().into_iter().map(|c| c.to_child_of_element().to_node()).collect(),
+            ElementNode(n) => n.children().into_iter().map(|c| c.to_node()).collect(),
+            _              => Vec::new(),
+        }
+    }
+
+    fn attributes(&self, node: &Node<'d>) -> Vec<Node<'d>> {
+        match *node {
+            ElementNode(n) => n.attributes().into_iter().map(AttributeNode).collect(),
+            _              => Vec::new(),
+        }
+    }
+
+    fn name<'a>(&'a self, node: &'a Node<'d>) -> Option<&'a str> {
+        match *node {
+            ElementNode(ref n)               => Some(n.name()),
+            AttributeNode(ref n)             => Some(n.name()),
+            ProcessingInstructionNode(ref n) => Some(n.target()),
+            _                                => None,
+        }
+    }
+
+    fn string_value(&self, node: &Node<'d>) -> String {
+        match *node {
+            RootNode(n)                  => n.text_content(),
+            ElementNode(n)               => n.text_content(),
+            AttributeNode(n)             => n.value().to_string(),
+            TextNode(n)                  => n.text().to_string(),
+            CommentNode(n)               => n.text().to_string(),
+            ProcessingInstructionNode(n) => n.value().unwrap_or("").to_string(),
+        }
+    }
+}
+
+macro_rules! conversion_trait(
+    ($tr_name:ident, $method:ident, $res_type:ident,
+        { $($leaf_type:ident => $variant:ident),* }
+    ) => (
+        pub trait $tr_name<'d> {
+            fn $method(self) -> $res_type<'d>;
+        }
+
+        impl<'d> $tr_name<'d> for $res_type<'d> {
             fn $method(self) -> $res_type<'d> {
                 self
             }
@@ -411,6 +1394,14 @@ mod test {
     use super::{ElementCOR,CommentCOR,ProcessingInstructionCOR};
     use super::{ElementCOE,TextCOE,CommentCOE,ProcessingInstructionCOE};
     use super::{RootPOC,ElementPOC};
+    use super::{Mutation,MutationObserver,ChildAdded,ChildRemoved,AttributeChanged};
+    use super::{ElementRenamed,TextChanged,CommentChanged,ProcessingInstructionChanged};
+    use super::{RootNode,ElementNode,CommentNode,NodeId};
+    use super::{Document,ObserverId};
+    use std::cmp::Less;
+    use std::collections::HashSet;
+    use std::rc::Rc;
+    use std::cell::{Cell,RefCell};
 
     #[test]
     fn the_root_belongs_to_a_document() {
@@ -454,6 +1445,23 @@ mod test {
         assert_eq!(children[0], ElementCOR(beta));
     }
 
+    #[test]
+    fn root_can_have_a_doctype() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let root = doc.root();
+        let doctype = doc.create_document_type("alpha", None, Some("alpha.dtd"), None);
+
+        root.set_doctype(Some(doctype));
+
+        let doctype = root.doctype().unwrap();
+        assert_eq!(doctype.name(), "alpha");
+        assert_eq!(doctype.public_id(), None);
+        assert_eq!(doctype.system_id(), Some("alpha.dtd"));
+        assert_eq!(doctype.internal_subset(), None);
+    }
+
     #[test]
     fn root_can_have_comment_children() {
         let package = Package::new();
@@ -570,243 +1578,889 @@ mod test {
     }
 
     #[test]
-    fn elements_can_be_renamed() {
+    fn element_children_can_be_inserted() {
         let package = Package::new();
         let doc = package.as_document();
 
+        let greek = doc.create_element("greek");
         let alpha = doc.create_element("alpha");
-        alpha.set_name("beta");
-        assert_eq!(alpha.name(), "beta");
+        let beta  = doc.create_element("beta");
+        let omega = doc.create_element("omega");
+
+        greek.append_child(alpha);
+        greek.append_child(omega);
+        greek.insert_child(1, beta);
+
+        let children = greek.children();
+
+        assert_eq!(children[0], ElementCOE(alpha));
+        assert_eq!(children[1], ElementCOE(beta));
+        assert_eq!(children[2], ElementCOE(omega));
+        assert_eq!(Some(ElementPOC(greek)), beta.parent());
     }
 
     #[test]
-    fn attributes_belong_to_a_document() {
+    fn element_children_can_be_removed() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let element = doc.create_element("alpha");
-        let attr = element.set_attribute_value("hello", "world");
+        let alpha = doc.create_element("alpha");
+        let beta  = doc.create_element("beta");
 
-        assert_eq!(&doc, attr.document());
+        alpha.append_child(beta);
+        alpha.remove_child(beta);
+
+        assert!(alpha.children().is_empty());
+        assert_eq!(None, beta.parent());
     }
 
     #[test]
-    fn elements_have_attributes() {
+    fn removing_a_child_of_another_element_does_nothing() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let element = doc.create_element("element");
+        let parent1 = doc.create_element("parent1");
+        let parent2 = doc.create_element("parent2");
+        let child = doc.create_element("child");
 
-        element.set_attribute_value("hello", "world");
+        parent1.append_child(child);
+        parent2.remove_child(child);
 
-        assert_eq!(Some("world"), element.attribute_value("hello"));
+        assert_eq!(1, parent1.children().len());
+        assert_eq!(Some(ElementPOC(parent1)), child.parent());
     }
 
     #[test]
-    fn attributes_know_their_element() {
+    fn root_children_can_be_inserted_and_removed() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let element = doc.create_element("element");
-        let attr = element.set_attribute_value("hello", "world");
+        let root = doc.root();
+        let alpha = doc.create_element("alpha");
+        let comment = doc.create_comment("Now is the winter of our discontent.");
 
-        assert_eq!(Some(element), attr.parent());
+        root.append_child(alpha);
+        root.insert_child(0, comment);
+
+        let children = root.children();
+        assert_eq!(2, children.len());
+        assert_eq!(children[0], CommentCOR(comment));
+        assert_eq!(children[1], ElementCOR(alpha));
+
+        root.remove_child(alpha);
+
+        assert_eq!(1, root.children().len());
+        assert_eq!(None, alpha.parent());
     }
 
     #[test]
-    fn attributes_can_be_reset() {
+    fn elements_can_be_renamed() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let element = doc.create_element("element");
+        let alpha = doc.create_element("alpha");
+        alpha.set_name("beta");
+        assert_eq!(alpha.name(), "beta");
+    }
 
-        element.set_attribute_value("hello", "world");
-        element.set_attribute_value("hello", "galaxy");
+    #[test]
+    fn elements_can_be_found_by_xml_id() {
+        let package = Package::new();
+        let doc = package.as_document();
 
-        assert_eq!(Some("galaxy"), element.attribute_value("hello"));
+        let alpha = doc.create_element("alpha");
+        let beta  = doc.create_element("beta");
+        doc.root().append_child(alpha);
+        alpha.append_child(beta);
+
+        beta.set_attribute_value("xml:id", "first");
+        assert_eq!(Some(beta), doc.element_by_id("first"));
+
+        beta.set_attribute_value("xml:id", "second");
+        assert_eq!(None, doc.element_by_id("first"));
+        assert_eq!(Some(beta), doc.element_by_id("second"));
     }
 
     #[test]
-    fn attributes_can_be_iterated() {
+    fn elements_outside_the_document_are_not_found_by_id() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let element = doc.create_element("element");
+        let alpha = doc.create_element("alpha");
+        alpha.set_attribute_value("xml:id", "first");
+        assert_eq!(None, doc.element_by_id("first"));
 
-        element.set_attribute_value("name1", "value1");
-        element.set_attribute_value("name2", "value2");
+        doc.root().append_child(alpha);
+        assert_eq!(Some(alpha), doc.element_by_id("first"));
+    }
 
-        let mut attrs = element.attributes();
-        attrs.sort_by(|a, b| a.name().cmp(b.name()));
+    #[test]
+    fn duplicate_ids_are_reported() {
+        let package = Package::new();
+        let doc = package.as_document();
 
-        assert_eq!(2, attrs.len());
-        assert_eq!("name1",  attrs[0].name());
-        assert_eq!("value1", attrs[0].value());
-        assert_eq!("name2",  attrs[1].name());
-        assert_eq!("value2", attrs[1].value());
+        let alpha = doc.create_element("alpha");
+        let beta  = doc.create_element("beta");
+        let gamma = doc.create_element("gamma");
+        doc.root().append_child(alpha);
+        alpha.append_child(beta);
+        alpha.append_child(gamma);
+
+        beta.set_attribute_value("xml:id", "same");
+        gamma.set_attribute_value("xml:id", "same");
+
+        assert_eq!(Some(beta), doc.element_by_id("same"));
+        assert_eq!(vec![(gamma, "same".to_string())], doc.duplicate_ids());
     }
 
     #[test]
-    fn text_belongs_to_a_document() {
+    fn the_first_element_in_document_order_has_a_shared_id() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let text = doc.create_text("Now is the winter of our discontent.");
+        let alpha = doc.create_element("alpha");
+        let beta  = doc.create_element("beta");
+        let gamma = doc.create_element("gamma");
+        doc.root().append_child(alpha);
+        alpha.append_child(beta);
+        alpha.append_child(gamma);
 
-        assert_eq!(&doc, text.document());
+        gamma.set_attribute_value("xml:id", "same");
+        beta.set_attribute_value("xml:id", "same");
+
+        assert_eq!(Some(beta), doc.element_by_id("same"));
+        assert_eq!(vec![(gamma, "same".to_string())], doc.duplicate_ids());
     }
 
     #[test]
-    fn elements_can_have_text_children() {
+    fn replaced_document_elements_leave_the_document() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let sentence = doc.create_element("sentence");
-        let text = doc.create_text("Now is the winter of our discontent.");
+        let alpha = doc.create_element("alpha");
+        let child = doc.create_element("child");
+        let beta  = doc.create_element("beta");
+        doc.root().append_child(alpha);
+        alpha.append_child(child);
+        child.set_attribute_value("xml:id", "old");
 
-        sentence.append_child(text);
+        doc.root().append_child(beta);
+        beta.set_attribute_value("xml:id", "old");
 
-        let children = sentence.children();
-        assert_eq!(1, children.len());
-        assert_eq!(children[0], TextCOE(text));
+        assert_eq!(None, alpha.parent());
+        assert_eq!(Some(beta), doc.element_by_id("old"));
+        assert!(doc.duplicate_ids().is_empty());
     }
 
     #[test]
-    fn text_knows_its_parent() {
+    fn renamed_elements_are_indexed_by_their_declared_ids() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let sentence = doc.create_element("sentence");
-        let text = doc.create_text("Now is the winter of our discontent.");
+        let alpha = doc.create_element("alpha");
+        doc.root().append_child(alpha);
+        alpha.set_attribute_value("key", "one");
+        doc.declare_id_attribute("beta", "key");
+        assert_eq!(None, doc.element_by_id("one"));
 
-        sentence.append_child(text);
+        alpha.set_name("beta");
+        assert_eq!(Some(alpha), doc.element_by_id("one"));
 
-        assert_eq!(text.parent(), Some(sentence));
+        alpha.set_name("alpha");
+        assert_eq!(None, doc.element_by_id("one"));
     }
 
     #[test]
-    fn text_can_be_changed() {
+    fn declared_id_attributes_are_indexed() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let text = doc.create_text("Now is the winter of our discontent.");
+        let alpha = doc.create_element("alpha");
+        let beta  = doc.create_element("beta");
+        doc.root().append_child(alpha);
+        alpha.append_child(beta);
+        alpha.set_attribute_value("key", "one");
+        beta.set_attribute_value("key", "two");
 
-        text.set_text("Made glorious summer by this sun of York");
+        doc.declare_id_attribute("beta", "key");
 
-        assert_eq!(text.text(), "Made glorious summer by this sun of York");
+        assert_eq!(None, doc.element_by_id("one"));
+        assert_eq!(Some(beta), doc.element_by_id("two"));
     }
 
-    #[test]
-    fn comment_belongs_to_a_document() {
+    fn elements_named_with_and_without_an_index(index: bool) {
         let package = Package::new();
         let doc = package.as_document();
+        if index { doc.enable_name_index() }
 
-        let comment = doc.create_comment("Now is the winter of our discontent.");
+        let list  = doc.create_element("list");
+        let item1 = doc.create_element("item");
+        let item2 = doc.create_element("item");
+        let group = doc.create_element("group");
+        let item3 = doc.create_element("item");
 
-        assert_eq!(&doc, comment.document());
+        doc.root().append_child(list);
+        list.append_child(item1);
+        list.append_child(group);
+        group.append_child(item3);
+        list.insert_child(0, item2);
+
+        assert_eq!(vec![item2, item1, item3], doc.elements_named("item"));
+        assert_eq!(vec![item3], group.descendants_named("item"));
+
+        list.remove_child(item1);
+        item2.set_name("entry");
+
+        assert_eq!(vec![item3], doc.elements_named("item"));
+        assert_eq!(vec![item2], list.descendants_named("entry"));
+
+        group.append_child(item2);
+        assert_eq!(vec![item2], group.descendants_named("entry"));
     }
 
     #[test]
-    fn elements_can_have_comment_children() {
+    fn elements_can_be_found_by_name() {
+        elements_named_with_and_without_an_index(false);
+    }
+
+    #[test]
+    fn elements_can_be_found_by_name_with_an_index() {
+        elements_named_with_and_without_an_index(true);
+    }
+
+    #[test]
+    fn name_index_includes_existing_elements() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let sentence = doc.create_element("sentence");
-        let comment = doc.create_comment("Now is the winter of our discontent.");
+        let alpha = doc.create_element("alpha");
+        let beta  = doc.create_element("beta");
+        doc.root().append_child(alpha);
+        alpha.append_child(beta);
 
-        sentence.append_child(comment);
+        doc.enable_name_index();
 
-        let children = sentence.children();
-        assert_eq!(1, children.len());
-        assert_eq!(children[0], CommentCOE(comment));
+        assert_eq!(vec![beta], doc.elements_named("beta"));
+        assert_eq!(vec![alpha], doc.elements_named("alpha"));
     }
 
     #[test]
-    fn comment_knows_its_parent() {
+    fn replacing_the_root_element_removes_it_from_the_name_index() {
         let package = Package::new();
         let doc = package.as_document();
+        doc.enable_name_index();
 
-        let sentence = doc.create_element("sentence");
-        let comment = doc.create_comment("Now is the winter of our discontent.");
-
-        sentence.append_child(comment);
+        let alpha = doc.create_element("alpha");
+        let beta  = doc.create_element("alpha");
+        doc.root().append_child(alpha);
+        doc.root().append_child(beta);
 
-        assert_eq!(comment.parent(), Some(ElementPOC(sentence)));
+        assert_eq!(vec![beta], doc.elements_named("alpha"));
     }
 
     #[test]
-    fn comment_can_be_changed() {
+    fn the_subtree_of_a_replaced_root_element_leaves_the_name_index() {
         let package = Package::new();
         let doc = package.as_document();
+        doc.enable_name_index();
 
-        let comment = doc.create_comment("Now is the winter of our discontent.");
+        let alpha = doc.create_element("alpha");
+        let old_item = doc.create_element("item");
+        let beta = doc.create_element("beta");
+        let new_item = doc.create_element("item");
+        doc.root().append_child(alpha);
+        alpha.append_child(old_item);
+        beta.append_child(new_item);
 
-        comment.set_text("Made glorious summer by this sun of York");
+        doc.root().append_child(beta);
 
-        assert_eq!(comment.text(), "Made glorious summer by this sun of York");
+        assert_eq!(None, alpha.parent());
+        assert_eq!(vec![new_item], doc.elements_named("item"));
+        assert_eq!(vec![old_item], alpha.descendants_named("item"));
+        assert!(doc.elements_named("alpha").is_empty());
+
+        doc.root().append_child(alpha);
+        assert_eq!(vec![old_item], doc.elements_named("item"));
     }
 
     #[test]
-    fn processing_instruction_belongs_to_a_document() {
+    fn attributes_belong_to_a_document() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let pi = doc.create_processing_instruction("device", None);
+        let element = doc.create_element("alpha");
+        let attr = element.set_attribute_value("hello", "world");
 
-        assert_eq!(&doc, pi.document());
+        assert_eq!(&doc, attr.document());
     }
 
     #[test]
-    fn elements_can_have_processing_instruction_children() {
+    fn elements_have_attributes() {
         let package = Package::new();
         let doc = package.as_document();
 
         let element = doc.create_element("element");
-        let pi = doc.create_processing_instruction("device", None);
 
-        element.append_child(pi);
+        element.set_attribute_value("hello", "world");
 
-        let children = element.children();
-        assert_eq!(1, children.len());
-        assert_eq!(children[0], ProcessingInstructionCOE(pi));
+        assert_eq!(Some("world"), element.attribute_value("hello"));
     }
 
     #[test]
-    fn processing_instruction_knows_its_parent() {
+    fn attributes_can_be_removed() {
         let package = Package::new();
         let doc = package.as_document();
 
         let element = doc.create_element("element");
-        let pi = doc.create_processing_instruction("device", None);
+        doc.root().append_child(element);
+        element.set_attribute_value("xml:id", "first");
 
-        element.append_child(pi);
+        element.remove_attribute("xml:id");
 
-        assert_eq!(pi.parent(), Some(ElementPOC(element)));
+        assert_eq!(None, element.attribute_value("xml:id"));
+        assert_eq!(None, doc.element_by_id("first"));
     }
 
     #[test]
-    fn processing_instruction_can_be_changed() {
+    fn element_text_content_includes_descendant_text() {
         let package = Package::new();
         let doc = package.as_document();
 
-        let pi = doc.create_processing_instruction("device", None);
-
-        pi.set_target("output");
-        pi.set_value(Some("full-screen"));
+        let title = doc.create_element("title");
+        let b = doc.create_element("b");
+        title.append_child(doc.create_text("Hello "));
+        title.append_child(doc.create_comment("ignored"));
+        title.append_child(b);
+        b.append_child(doc.create_text("world"));
+        title.append_child(doc.create_text("!"));
 
-        assert_eq!(pi.target(), "output");
-        assert_eq!(pi.value(), Some("full-screen"));
+        assert_eq!(title.text_content().as_slice(), "Hello world!");
+        assert_eq!(b.text_content().as_slice(), "world");
     }
 
     #[test]
-    fn can_return_a_populated_package() {
-        fn populate() -> Package {
-            let package = Package::new();
-            {
-                let doc = package.as_document();
+    fn element_text_content_can_be_replaced() {
+        let package = Package::new();
+        let doc = package.as_document();
 
-                let element = doc.create_element("hello");
-                doc.root().append_child(element);
+        let title = doc.create_element("title");
+        let b = doc.create_element("b");
+        title.append_child(b);
+
+        title.set_text_content("Goodbye");
+
+        let children = title.children();
+        assert_eq!(1, children.len());
+        assert_eq!(children[0].text().unwrap().text(), "Goodbye");
+        assert_eq!(b.parent(), None);
+
+        title.set_text_content("");
+        assert!(title.children().is_empty());
+    }
+
+    #[test]
+    fn root_text_content_is_that_of_the_document_element() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        assert_eq!(doc.root().text_content().as_slice(), "");
+
+        let title = doc.create_element("title");
+        title.append_child(doc.create_text("Hello"));
+        doc.root().append_child(doc.create_comment("ignored"));
+        doc.root().append_child(title);
+
+        assert_eq!(doc.root().text_content().as_slice(), "Hello");
+    }
+
+    #[test]
+    fn attribute_text_content_is_the_value() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        doc.root().append_child(element);
+        let attr = element.set_attribute_value("xml:id", "first");
+        assert_eq!(attr.text_content().as_slice(), "first");
+
+        attr.set_text_content("second");
+
+        assert_eq!(element.attribute_value("xml:id"), Some("second"));
+        assert_eq!(doc.element_by_id("first"), None);
+        assert_eq!(doc.element_by_id("second"), Some(element));
+    }
+
+    #[test]
+    fn attributes_know_their_element() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let attr = element.set_attribute_value("hello", "world");
+
+        assert_eq!(Some(element), attr.parent());
+    }
+
+    #[test]
+    fn attributes_can_be_reset() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+
+        element.set_attribute_value("hello", "world");
+        element.set_attribute_value("hello", "galaxy");
+
+        assert_eq!(Some("galaxy"), element.attribute_value("hello"));
+    }
+
+    #[test]
+    fn reset_attributes_keep_their_position() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+
+        element.set_attribute_value("first", "1");
+        element.set_attribute_value("second", "2");
+        element.set_attribute_value("first", "one");
+
+        let names: Vec<&str> = element.attributes().iter().map(|a| a.name()).collect();
+        assert_eq!(names, vec!["first", "second"]);
+    }
+
+    #[test]
+    fn nodes_hold_user_data_by_type() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        element.set_user_data(42u);
+        element.set_user_data("style".to_string());
+        element.set_user_data(7u);
+
+        assert_eq!(element.user_data::<uint>(), Some(7u));
+        assert_eq!(element.user_data::<String>(), Some("style".to_string()));
+        assert_eq!(element.user_data::<int>(), None);
+    }
+
+    #[test]
+    fn user_data_can_be_removed() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let text = doc.create_text("text");
+        text.set_user_data(true);
+
+        assert_eq!(text.remove_user_data::<bool>(), Some(true));
+        assert_eq!(text.user_data::<bool>(), None);
+    }
+
+    #[test]
+    fn removed_nodes_lose_their_user_data() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let parent = doc.create_element("parent");
+        let child = doc.create_element("child");
+        let text = doc.create_text("text");
+        parent.append_child(child);
+        child.append_child(text);
+        let attr = child.set_attribute_value("name", "value");
+        child.set_user_data(1u);
+        text.set_user_data(2u);
+        attr.set_user_data(3u);
+
+        parent.remove_child(child);
+
+        assert_eq!(child.user_data::<uint>(), None);
+        assert_eq!(text.user_data::<uint>(), None);
+        assert_eq!(attr.user_data::<uint>(), None);
+    }
+
+    #[test]
+    fn replaced_document_elements_lose_their_user_data() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let old = doc.create_element("old");
+        doc.root().append_child(old);
+        old.set_user_data(1u);
+
+        doc.root().append_child(doc.create_element("new"));
+
+        assert_eq!(old.user_data::<uint>(), None);
+    }
+
+    #[test]
+    fn saved_user_data_can_be_restored() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let parent = doc.create_element("parent");
+        let child = doc.create_element("child");
+        parent.append_child(child);
+        let attr = child.set_attribute_value("name", "value");
+        child.set_user_data(1u);
+        attr.set_user_data(2u);
+
+        let saved = doc.save_user_data(ElementNode(child));
+        parent.remove_child(child);
+        doc.restore_user_data(saved);
+
+        assert_eq!(child.user_data::<uint>(), Some(1u));
+        assert_eq!(attr.user_data::<uint>(), Some(2u));
+    }
+
+    #[test]
+    fn removed_attributes_lose_their_user_data() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let attr = element.set_attribute_value("name", "value");
+        attr.set_user_data(1u);
+
+        element.remove_attribute("name");
+
+        assert_eq!(attr.user_data::<uint>(), None);
+    }
+
+    #[test]
+    fn moved_nodes_keep_their_user_data() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        let child = doc.create_element("child");
+        alpha.append_child(child);
+        child.set_user_data(1u);
+
+        beta.append_child(child);
+
+        assert_eq!(child.user_data::<uint>(), Some(1u));
+    }
+
+    #[test]
+    fn deep_clones_copy_structure_and_user_data() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let original = doc.create_element("original");
+        let child = doc.create_element("child");
+        original.append_child(child);
+        child.append_child(doc.create_text("text"));
+        let attr = original.set_attribute_value("name", "value");
+        child.set_user_data(1u);
+        attr.set_user_data("checked".to_string());
+
+        let copy = original.deep_clone();
+        let child_copy = copy.children()[0].element().unwrap();
+
+        assert!(copy != original);
+        assert_eq!(copy.parent(), None);
+        assert_eq!(copy.attribute_value("name"), Some("value"));
+        assert_eq!(copy.attribute("name").unwrap().user_data::<String>(), Some("checked".to_string()));
+        assert_eq!(child_copy.name(), "child");
+        assert_eq!(child_copy.text_content().as_slice(), "text");
+        assert_eq!(child_copy.user_data::<uint>(), Some(1u));
+
+        child_copy.set_user_data(2u);
+        assert_eq!(child.user_data::<uint>(), Some(1u));
+    }
+
+    #[test]
+    fn interned_names_match_element_names() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let item = doc.intern_name("item");
+        let element = doc.create_element("item");
+        let other = doc.create_element("other");
+
+        assert_eq!(doc.intern_name("item"), item);
+        assert!(doc.intern_name("other") != item);
+        assert!(element.is_named(item));
+        assert!(! other.is_named(item));
+        assert_eq!(element.interned_name(), item);
+
+        other.set_name("item");
+        assert!(other.is_named(item));
+    }
+
+    #[test]
+    fn elements_can_be_created_with_interned_names() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let item = doc.intern_name("item");
+        let element = doc.create_element_named(item);
+
+        assert_eq!(element.name(), "item");
+        assert!(element.is_named(item));
+    }
+
+    #[test]
+    fn attributes_can_be_found_by_interned_name() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let attr = element.set_attribute_value("href", "/index");
+        element.set_attribute_value("title", "Index");
+
+        let href = doc.intern_name("href");
+        assert_eq!(element.attribute_named(href), Some(attr));
+        assert_eq!(element.attribute_value_named(href), Some("/index"));
+        assert_eq!(attr.interned_name(), href);
+        assert_eq!(element.attribute_named(doc.intern_name("missing")), None);
+    }
+
+    #[test]
+    fn names_from_other_packages_are_not_equal() {
+        let package1 = Package::new();
+        let package2 = Package::new();
+        let doc1 = package1.as_document();
+        let doc2 = package2.as_document();
+
+        let element = doc2.create_element("item");
+
+        assert!(doc1.intern_name("item") != doc2.intern_name("item"));
+        assert!(! element.is_named(doc1.intern_name("item")));
+    }
+
+    #[test]
+    fn nodes_have_unique_ids() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let text = doc.create_text("text");
+        let attr = element.set_attribute_value("name", "value");
+
+        let mut ids = HashSet::new();
+        ids.insert(doc.root().id());
+        ids.insert(element.id());
+        ids.insert(text.id());
+        ids.insert(attr.id());
+
+        assert_eq!(ids.len(), 4);
+    }
+
+    #[test]
+    fn nodes_can_be_found_by_id() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let comment = doc.create_comment("comment");
+        element.append_child(comment);
+
+        assert_eq!(doc.node_by_id(element.id()), Some(ElementNode(element)));
+        assert_eq!(doc.node_by_id(comment.id()), Some(CommentNode(comment)));
+        assert_eq!(doc.node_by_id(doc.root().id()), Some(RootNode(doc.root())));
+        assert_eq!(doc.node_by_id(NodeId(1000)), None);
+    }
+
+    #[test]
+    fn ids_can_be_compared_by_document_order() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let later = doc.create_element("later");
+        let earlier = doc.create_element("earlier");
+        let parent = doc.create_element("parent");
+        doc.root().append_child(parent);
+        parent.append_child(earlier);
+        parent.append_child(later);
+
+        assert_eq!(doc.compare_ids(earlier.id(), later.id()), Some(Less));
+        assert_eq!(doc.compare_ids(parent.id(), earlier.id()), Some(Less));
+        assert_eq!(doc.compare_ids(later.id(), NodeId(1000)), None);
+    }
+
+    #[test]
+    fn attributes_can_be_iterated() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+
+        element.set_attribute_value("name1", "value1");
+        element.set_attribute_value("name2", "value2");
+
+        let mut attrs = element.attributes();
+        attrs.sort_by(|a, b| a.name().cmp(b.name()));
+
+        assert_eq!(2, attrs.len());
+        assert_eq!("name1",  attrs[0].name());
+        assert_eq!("value1", attrs[0].value());
+        assert_eq!("name2",  attrs[1].name());
+        assert_eq!("value2", attrs[1].value());
+    }
+
+    #[test]
+    fn text_belongs_to_a_document() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let text = doc.create_text("Now is the winter of our discontent.");
+
+        assert_eq!(&doc, text.document());
+    }
+
+    #[test]
+    fn elements_can_have_text_children() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let sentence = doc.create_element("sentence");
+        let text = doc.create_text("Now is the winter of our discontent.");
+
+        sentence.append_child(text);
+
+        let children = sentence.children();
+        assert_eq!(1, children.len());
+        assert_eq!(children[0], TextCOE(text));
+    }
+
+    #[test]
+    fn text_knows_its_parent() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let sentence = doc.create_element("sentence");
+        let text = doc.create_text("Now is the winter of our discontent.");
+
+        sentence.append_child(text);
+
+        assert_eq!(text.parent(), Some(sentence));
+    }
+
+    #[test]
+    fn text_can_be_changed() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let text = doc.create_text("Now is the winter of our discontent.");
+
+        text.set_text("Made glorious summer by this sun of York");
+
+        assert_eq!(text.text(), "Made glorious summer by this sun of York");
+    }
+
+    #[test]
+    fn comment_belongs_to_a_document() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let comment = doc.create_comment("Now is the winter of our discontent.");
+
+        assert_eq!(&doc, comment.document());
+    }
+
+    #[test]
+    fn elements_can_have_comment_children() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let sentence = doc.create_element("sentence");
+        let comment = doc.create_comment("Now is the winter of our discontent.");
+
+        sentence.append_child(comment);
+
+        let children = sentence.children();
+        assert_eq!(1, children.len());
+        assert_eq!(children[0], CommentCOE(comment));
+    }
+
+    #[test]
+    fn comment_knows_its_parent() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let sentence = doc.create_element("sentence");
+        let comment = doc.create_comment("Now is the winter of our discontent.");
+
+        sentence.append_child(comment);
+
+        assert_eq!(comment.parent(), Some(ElementPOC(sentence)));
+    }
+
+    #[test]
+    fn comment_can_be_changed() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let comment = doc.create_comment("Now is the winter of our discontent.");
+
+        comment.set_text("Made glorious summer by this sun of York");
+
+        assert_eq!(comment.text(), "Made glorious summer by this sun of York");
+    }
+
+    #[test]
+    fn processing_instruction_belongs_to_a_document() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let pi = doc.create_processing_instruction("device", None);
+
+        assert_eq!(&doc, pi.document());
+    }
+
+    #[test]
+    fn elements_can_have_processing_instruction_children() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let pi = doc.create_processing_instruction("device", None);
+
+        element.append_child(pi);
+
+        let children = element.children();
+        assert_eq!(1, children.len());
+        assert_eq!(children[0], ProcessingInstructionCOE(pi));
+    }
+
+    #[test]
+    fn processing_instruction_knows_its_parent() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let pi = doc.create_processing_instruction("device", None);
+
+        element.append_child(pi);
+
+        assert_eq!(pi.parent(), Some(ElementPOC(element)));
+    }
+
+    #[test]
+    fn processing_instruction_can_be_changed() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let pi = doc.create_processing_instruction("device", None);
+
+        pi.set_target("output");
+        pi.set_value(Some("full-screen"));
+
+        assert_eq!(pi.target(), "output");
+        assert_eq!(pi.value(), Some("full-screen"));
+    }
+
+    #[test]
+    fn can_return_a_populated_package() {
+        fn populate() -> Package {
+            let package = Package::new();
+            {
+                let doc = package.as_document();
+
+                let element = doc.create_element("hello");
+                doc.root().append_child(element);
             }
 
             package
@@ -818,6 +2472,274 @@ mod test {
         assert_eq!(element.name(), "hello");
     }
 
+    struct Recorder<'d> {
+        mutations: Rc<RefCell<Vec<Mutation<'d>>>>,
+    }
+
+    impl<'d> MutationObserver<'d> for Recorder<'d> {
+        fn mutated(&mut self, mutation: &Mutation<'d>) {
+            self.mutations.borrow_mut().push(mutation.clone());
+        }
+    }
+
+    fn recorder<'d>() -> (Box<MutationObserver<'d> + 'd>, Rc<RefCell<Vec<Mutation<'d>>>>) {
+        let mutations = Rc::new(RefCell::new(Vec::new()));
+        let recorder = Recorder { mutations: mutations.clone() };
+        (box recorder as Box<MutationObserver<'d> + 'd>, mutations)
+    }
+
+    #[test]
+    fn observers_are_told_about_added_and_removed_children() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let text = doc.create_text("hello");
+        let (observer, mutations) = recorder();
+        doc.add_observer(observer);
+
+        doc.root().append_child(alpha);
+        alpha.append_child(text);
+        alpha.remove_child(text);
+
+        assert_eq!(*mutations.borrow(), vec![
+            ChildAdded(RootPOC(doc.root()), ElementCOE(alpha)),
+            ChildAdded(ElementPOC(alpha), TextCOE(text)),
+            ChildRemoved(ElementPOC(alpha), TextCOE(text), 0),
+        ]);
+    }
+
+    #[test]
+    fn observers_are_told_about_changes_made_through_other_documents() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let (observer, mutations) = recorder();
+        doc.add_observer(observer);
+
+        {
+            let other = package.as_document();
+            let alpha = other.create_element("alpha");
+            other.root().append_child(alpha);
+            alpha.set_name("beta");
+        }
+
+        let beta = doc.root().children()[0].element().unwrap();
+        assert_eq!(*mutations.borrow(), vec![
+            ChildAdded(RootPOC(doc.root()), ElementCOE(beta)),
+            ElementRenamed(beta, "alpha".to_string()),
+        ]);
+        match mutations.borrow()[1] {
+            ElementRenamed(element, _) => assert_eq!(&doc, element.document()),
+            _ => panic!("expected a rename"),
+        }
+    }
+
+    #[test]
+    fn observers_are_removed_with_their_document() {
+        let package = Package::new();
+        let calls = Rc::new(Cell::new(0u));
+        {
+            let doc = package.as_document();
+            doc.add_observer(box Counter { calls: calls.clone() } as Box<MutationObserver>);
+        }
+
+        let doc = package.as_document();
+        doc.root().append_child(doc.create_element("alpha"));
+
+        assert_eq!(calls.get(), 0);
+    }
+
+    struct Counter {
+        calls: Rc<Cell<uint>>,
+    }
+
+    impl<'d> MutationObserver<'d> for Counter {
+        fn mutated(&mut self, _: &Mutation<'d>) {
+            self.calls.set(self.calls.get() + 1);
+        }
+    }
+
+    #[test]
+    fn observers_are_told_about_the_removal_when_a_child_moves() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        let child = doc.create_element("child");
+        alpha.append_child(child);
+        let (observer, mutations) = recorder();
+        doc.add_observer(observer);
+
+        beta.append_child(child);
+
+        assert_eq!(*mutations.borrow(), vec![
+            ChildRemoved(ElementPOC(alpha), ElementCOE(child), 0),
+            ChildAdded(ElementPOC(beta), ElementCOE(child)),
+        ]);
+    }
+
+    #[test]
+    fn observers_are_told_when_the_root_element_is_replaced() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        doc.root().append_child(alpha);
+        let (observer, mutations) = recorder();
+        doc.add_observer(observer);
+
+        doc.root().append_child(beta);
+
+        assert_eq!(*mutations.borrow(), vec![
+            ChildRemoved(RootPOC(doc.root()), ElementCOE(alpha), 0),
+            ChildAdded(RootPOC(doc.root()), ElementCOE(beta)),
+        ]);
+    }
+
+    #[test]
+    fn removing_a_child_of_another_parent_is_not_reported() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        let child = doc.create_element("child");
+        alpha.append_child(child);
+        let (observer, mutations) = recorder();
+        doc.add_observer(observer);
+
+        beta.remove_child(child);
+
+        assert!(mutations.borrow().is_empty());
+    }
+
+    #[test]
+    fn observers_are_given_previous_attribute_values() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let (observer, mutations) = recorder();
+        doc.add_observer(observer);
+
+        let world = element.set_attribute_value("hello", "world");
+        element.set_attribute_value("hello", "galaxy");
+
+        assert_eq!(*mutations.borrow(), vec![
+            AttributeChanged(element, "hello".to_string(), None, None),
+            AttributeChanged(element, "hello".to_string(), Some("world".to_string()), Some(world)),
+        ]);
+    }
+
+    #[test]
+    fn observers_are_given_previous_names_and_text() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("before");
+        let text = doc.create_text("old text");
+        let comment = doc.create_comment("old comment");
+        let pi = doc.create_processing_instruction("device", None);
+        let (observer, mutations) = recorder();
+        doc.add_observer(observer);
+
+        element.set_name("after");
+        text.set_text("new text");
+        comment.set_text("new comment");
+        pi.set_value(Some("full-screen"));
+
+        assert_eq!(*mutations.borrow(), vec![
+            ElementRenamed(element, "before".to_string()),
+            TextChanged(text, "old text".to_string()),
+            CommentChanged(comment, "old comment".to_string()),
+            ProcessingInstructionChanged(pi, "device".to_string(), None),
+        ]);
+    }
+
+    #[test]
+    fn removed_observers_are_no_longer_told_about_changes() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let (observer, mutations) = recorder();
+        let id = doc.add_observer(observer);
+
+        element.set_name("first");
+        assert!(doc.remove_observer(id).is_some());
+        element.set_name("second");
+
+        assert_eq!(*mutations.borrow(), vec![
+            ElementRenamed(element, "element".to_string()),
+        ]);
+    }
+
+    /// Removes an observer the first time it is told about a change
+    struct Remover<'d> {
+        document: &'d Document<'d>,
+        target: Rc<RefCell<Option<ObserverId>>>,
+        calls: Rc<Cell<uint>>,
+        given_back: Rc<Cell<bool>>,
+    }
+
+    impl<'d> MutationObserver<'d> for Remover<'d> {
+        fn mutated(&mut self, _mutation: &Mutation<'d>) {
+            self.calls.set(self.calls.get() + 1);
+            if let Some(id) = self.target.borrow_mut().take() {
+                self.given_back.set(self.document.remove_observer(id).is_some());
+            }
+        }
+    }
+
+    fn remover<'d>(document: &'d Document<'d>, target: Rc<RefCell<Option<ObserverId>>>)
+                   -> (Box<MutationObserver<'d> + 'd>, Rc<Cell<uint>>, Rc<Cell<bool>>)
+    {
+        let calls = Rc::new(Cell::new(0u));
+        let given_back = Rc::new(Cell::new(false));
+        let remover = Remover { document: document, target: target, calls: calls.clone(), given_back: given_back.clone() };
+        (box remover as Box<MutationObserver<'d> + 'd>, calls, given_back)
+    }
+
+    #[test]
+    fn observers_can_remove_other_observers_while_being_told() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let (observer, mutations) = recorder();
+        let recorder_id = doc.add_observer(observer);
+        let (remover, _, given_back) = remover(&doc, Rc::new(RefCell::new(Some(recorder_id))));
+        doc.add_observer(remover);
+
+        element.set_name("first");
+        element.set_name("second");
+
+        assert!(given_back.get());
+        assert_eq!(*mutations.borrow(), vec![
+            ElementRenamed(element, "element".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn observers_can_remove_themselves_while_being_told() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let target = Rc::new(RefCell::new(None));
+        let (remover, calls, given_back) = remover(&doc, target.clone());
+        let id = doc.add_observer(remover);
+        *target.borrow_mut() = Some(id);
+
+        element.set_name("first");
+        element.set_name("second");
+
+        assert_eq!(calls.get(), 1);
+        assert!(! given_back.get());
+    }
+
     // #[test]
     // #[compile_failure]
     // fn nodes_cannot_live_outside_of_the_document() {
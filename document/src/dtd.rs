@@ -0,0 +1,1527 @@
+//! Validates documents against a document type definition (DTD)
+//!
+//! ### Example
+//!
+//! ```
+//! use document::parser::Parser;
+//! use document::dtd::Dtd;
+//!
+//! let xml = r#"<!DOCTYPE greeting [
+//!   <!ELEMENT greeting (#PCDATA)>
+//!   <!ATTLIST greeting lang NMTOKEN "en">
+//! ]><greeting>Hello</greeting>"#;
+//!
+//! let package = Parser::new().parse(xml).ok().expect("Failed to parse");
+//! let doc = package.as_document();
+//!
+//! let dtd = Dtd::from_document(&doc, None).ok().expect("Invalid DTD");
+//! assert!(dtd.validate(&doc).is_empty());
+//!
+//! dtd.apply_defaults(&doc);
+//! ```
+//!
+//! External parameter entities are loaded through the DTD's
+//! `EntityResolver`, set with `Dtd::set_resolver` or given to
+//! `Dtd::from_document_with_resolver`. By default nothing is loaded,
+//! and referring to one is reported as `ExternalParameterEntity`.
+//!
+//! Expanding entities is bounded by the entity expansion limits of
+//! `Limits::untrusted`, so that a few nested declarations cannot
+//! produce an enormous amount of text. Use `Dtd::with_limits` to
+//! change them.
+//!
+//! ### Known issues
+//!
+//! - Parameter entity references inside quoted literals are not expanded.
+
+use std::cell::Cell;
+use std::char::from_u32;
+use std::collections::{HashMap,HashSet};
+use std::collections::hash_map::{Occupied,Vacant};
+use std::num::from_str_radix;
+
+use super::dom4;
+use super::dom4::{ElementCOE,TextCOE};
+use super::parser::{EntityResolver,DenyAll,Limits,Limit,EntityExpansions,ExpandedLength};
+use super::xmlstr::{XmlStr,XmlChar,predefined_entity,is_name,is_nmtoken};
+
+/// How many times a content particle may appear
+#[deriving(Show,Clone,PartialEq)]
+pub enum Occurrence {
+    Once,
+    /// `?`
+    Optional,
+    /// `*`
+    ZeroOrMore,
+    /// `+`
+    OneOrMore,
+}
+
+#[deriving(Show,Clone,PartialEq)]
+pub enum ContentParticle {
+    NameParticle(String, Occurrence),
+    /// `(a, b, c)`
+    SequenceParticle(Vec<ContentParticle>, Occurrence),
+    /// `(a | b | c)`
+    ChoiceParticle(Vec<ContentParticle>, Occurrence),
+}
+
+/// The allowed children of an element
+#[deriving(Show,Clone,PartialEq)]
+pub enum ContentSpec {
+    EmptyContent,
+    AnyContent,
+    /// Text mixed with any of the named elements, `(#PCDATA | a | b)*`
+    MixedContent(Vec<String>),
+    ChildrenContent(ContentParticle),
+}
+
+#[deriving(Show,Clone,PartialEq)]
+pub enum AttributeType {
+    CDataType,
+    IdType,
+    IdRefType,
+    IdRefsType,
+    EntityType,
+    EntitiesType,
+    NmTokenType,
+    NmTokensType,
+    NotationType(Vec<String>),
+    EnumerationType(Vec<String>),
+}
+
+#[deriving(Show,Clone,PartialEq)]
+pub enum DefaultDecl {
+    RequiredValue,
+    ImpliedValue,
+    FixedValue(String),
+    DefaultValue(String),
+}
+
+#[deriving(Show,Clone,PartialEq)]
+pub struct AttributeDecl {
+    pub name: String,
+    pub kind: AttributeType,
+    pub default: DefaultDecl,
+}
+
+#[deriving(Show,Clone,PartialEq)]
+pub struct ExternalId {
+    pub public_id: Option<String>,
+    pub system_id: Option<String>,
+}
+
+#[deriving(Show,Clone,PartialEq)]
+pub enum EntityDefinition {
+    /// The replacement text is given in the declaration
+    InternalEntity(String),
+    /// The replacement text is stored elsewhere. Unparsed entities
+    /// also name their notation.
+    ExternalEntity(ExternalId, Option<String>),
+}
+
+/// Why a DTD could not be read
+#[deriving(Show,Clone,PartialEq)]
+pub enum DtdError {
+    /// The document has no document type declaration
+    MissingDocumentType,
+    /// The markup at this byte offset could not be understood
+    MalformedDeclaration(uint),
+    UndeclaredParameterEntity(String),
+    /// The parameter entity refers to itself, directly or indirectly
+    RecursiveParameterEntity(String),
+    /// The parameter entity's content is in an external resource
+    /// that the resolver could not provide
+    ExternalParameterEntity(String),
+    /// Expanding entities exceeded one of the DTD's `Limits`
+    LimitExceeded(Limit),
+}
+
+/// A way in which a document does not match its DTD. Each variant
+/// carries the element where the problem was found and, for
+/// attribute problems, the attribute name.
+#[deriving(Show,PartialEq)]
+pub enum ValidationError<'d> {
+    /// The document element is not the one named by the doctype
+    RootElementMismatch(dom4::Element<'d>),
+    UndeclaredElement(dom4::Element<'d>),
+    /// The element's children do not match its content model
+    InvalidContent(dom4::Element<'d>),
+    UndeclaredAttribute(dom4::Element<'d>, String),
+    MissingRequiredAttribute(dom4::Element<'d>, String),
+    /// The value does not fit the attribute's type or `#FIXED` value
+    InvalidAttributeValue(dom4::Element<'d>, String),
+    /// Another element already has this ID
+    DuplicateId(dom4::Element<'d>, String),
+    /// An IDREF or IDREFS attribute refers to an ID that does not exist
+    UnknownIdReference(dom4::Element<'d>, String),
+}
+
+type DeclResult<T> = Result<T, ()>;
+
+/// Upper bound on nested general entity references in attribute defaults
+static MAX_ENTITY_DEPTH: uint = 16;
+
+impl ContentParticle {
+    fn occurrence(&self) -> Occurrence {
+        match *self {
+            NameParticle(_, o)     |
+            SequenceParticle(_, o) |
+            ChoiceParticle(_, o)   => o,
+        }
+    }
+
+    /// Does the particle match exactly this sequence of element names?
+    pub fn matches(&self, names: &[&str]) -> bool {
+        self.match_at(names, 0).contains(&names.len())
+    }
+
+    /// Every position that the particle could stop at when started at `position`
+    fn match_at(&self, names: &[&str], position: uint) -> Vec<uint> {
+        let mut ends = match self.occurrence() {
+            Once => self.match_once(names, position),
+            Optional => {
+                let mut ends = self.match_once(names, position);
+                ends.push(position);
+                ends
+            },
+            ZeroOrMore | OneOrMore => {
+                let mut reached = Vec::new();
+                if self.occurrence() == ZeroOrMore { reached.push(position) }
+
+                let mut frontier = vec![position];
+                while ! frontier.is_empty() {
+                    let mut next = Vec::new();
+                    for &p in frontier.iter() {
+                        for end in self.match_once(names, p).into_iter() {
+                            if ! reached.contains(&end) {
+                                reached.push(end);
+                                next.push(end);
+                            }
+                        }
+                    }
+                    frontier = next;
+                }
+
+                reached
+            },
+        };
+
+        ends.sort();
+        ends.dedup();
+        ends
+    }
+
+    fn match_once(&self, names: &[&str], position: uint) -> Vec<uint> {
+        match *self {
+            NameParticle(ref name, _) => {
+                if position < names.len() && names[position] == name.as_slice() {
+                    vec![position + 1]
+                } else {
+                    vec![]
+                }
+            },
+            SequenceParticle(ref items, _) => {
+                let mut ends = vec![position];
+                for item in items.iter() {
+                    let mut next = Vec::new();
+                    for &p in ends.iter() {
+                        next.extend(item.match_at(names, p).into_iter());
+                    }
+                    next.sort();
+                    next.dedup();
+                    ends = next;
+                }
+                ends
+            },
+            ChoiceParticle(ref items, _) => {
+                let mut ends = Vec::new();
+                for item in items.iter() {
+                    ends.extend(item.match_at(names, position).into_iter());
+                }
+                ends
+            },
+        }
+    }
+}
+
+struct Scanner<'s> {
+    s: &'s str,
+    position: uint,
+}
+
+impl<'s> Scanner<'s> {
+    fn new(s: &'s str) -> Scanner<'s> {
+        Scanner { s: s, position: 0 }
+    }
+
+    fn rest(&self) -> &'s str {
+        self.s.slice_from(self.position)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position == self.s.len()
+    }
+
+    fn advance(&mut self, len: Option<uint>) -> DeclResult<&'s str> {
+        match len {
+            Some(len) => {
+                let value = self.rest().slice_to(len);
+                self.position += len;
+                Ok(value)
+            },
+            None => Err(()),
+        }
+    }
+
+    fn skip_space(&mut self) -> bool {
+        let len = self.rest().end_of_space();
+        self.advance(len).is_ok()
+    }
+
+    fn require_space(&mut self) -> DeclResult<()> {
+        if self.skip_space() { Ok(()) } else { Err(()) }
+    }
+
+    fn consume(&mut self, literal: &str) -> bool {
+        let len = self.rest().end_of_literal(literal);
+        self.advance(len).is_ok()
+    }
+
+    fn expect(&mut self, literal: &str) -> DeclResult<()> {
+        if self.consume(literal) { Ok(()) } else { Err(()) }
+    }
+
+    fn name(&mut self) -> DeclResult<&'s str> {
+        let len = self.rest().end_of_name();
+        self.advance(len)
+    }
+
+    fn nmtoken(&mut self) -> DeclResult<&'s str> {
+        let len = self.rest().end_of_start_rest(|c| c.is_name_char(), |c| c.is_name_char());
+        self.advance(len)
+    }
+
+    fn at_quote(&self) -> bool {
+        self.rest().starts_with("'") || self.rest().starts_with("\"")
+    }
+
+    fn quoted(&mut self) -> DeclResult<&'s str> {
+        if ! self.at_quote() { return Err(()) }
+
+        let rest = self.rest();
+        let end = try!(rest.slice_from(1).find(rest.char_at(0)).ok_or(()));
+        self.position += end + 2;
+        Ok(rest.slice(1, end + 1))
+    }
+
+    fn occurrence(&mut self) -> Occurrence {
+        if self.consume("?") {
+            Optional
+        } else if self.consume("*") {
+            ZeroOrMore
+        } else if self.consume("+") {
+            OneOrMore
+        } else {
+            Once
+        }
+    }
+}
+
+fn parse_content_particle(sc: &mut Scanner) -> DeclResult<ContentParticle> {
+    if sc.consume("(") {
+        sc.skip_space();
+        parse_group_rest(sc)
+    } else {
+        let name = try!(sc.name());
+        Ok(NameParticle(name.to_string(), sc.occurrence()))
+    }
+}
+
+/// Parses a sequence or choice after its opening parenthesis
+fn parse_group_rest(sc: &mut Scanner) -> DeclResult<ContentParticle> {
+    let mut items = vec![try!(parse_content_particle(sc))];
+
+    sc.skip_space();
+    let separator = if sc.rest().starts_with("|") { "|" } else { "," };
+
+    loop {
+        sc.skip_space();
+        if sc.consume(")") { break }
+        try!(sc.expect(separator));
+        sc.skip_space();
+        items.push(try!(parse_content_particle(sc)));
+    }
+
+    let occurrence = sc.occurrence();
+    if separator == "|" {
+        Ok(ChoiceParticle(items, occurrence))
+    } else {
+        Ok(SequenceParticle(items, occurrence))
+    }
+}
+
+/// Parses `(#PCDATA | a | b)*` after `#PCDATA`
+fn parse_mixed_rest(sc: &mut Scanner) -> DeclResult<ContentSpec> {
+    let mut names = Vec::new();
+
+    loop {
+        sc.skip_space();
+        if sc.consume(")") { break }
+        try!(sc.expect("|"));
+        sc.skip_space();
+        names.push(try!(sc.name()).to_string());
+    }
+
+    if ! sc.consume("*") && ! names.is_empty() {
+        return Err(());
+    }
+
+    Ok(MixedContent(names))
+}
+
+fn parse_content_spec(sc: &mut Scanner) -> DeclResult<ContentSpec> {
+    if sc.consume("EMPTY") { return Ok(EmptyContent) }
+    if sc.consume("ANY") { return Ok(AnyContent) }
+
+    try!(sc.expect("("));
+    sc.skip_space();
+
+    if sc.consume("#PCDATA") {
+        parse_mixed_rest(sc)
+    } else {
+        Ok(ChildrenContent(try!(parse_group_rest(sc))))
+    }
+}
+
+fn parse_token_list(sc: &mut Scanner, names: bool) -> DeclResult<Vec<String>> {
+    try!(sc.expect("("));
+
+    let mut tokens = Vec::new();
+    loop {
+        sc.skip_space();
+        let token = if names { try!(sc.name()) } else { try!(sc.nmtoken()) };
+        tokens.push(token.to_string());
+        sc.skip_space();
+        if sc.consume(")") { break }
+        try!(sc.expect("|"));
+    }
+
+    Ok(tokens)
+}
+
+fn parse_attribute_type(sc: &mut Scanner) -> DeclResult<AttributeType> {
+    if sc.rest().starts_with("(") {
+        return Ok(EnumerationType(try!(parse_token_list(sc, false))));
+    }
+
+    let kind = match try!(sc.name()) {
+        "CDATA"    => CDataType,
+        "ID"       => IdType,
+        "IDREF"    => IdRefType,
+        "IDREFS"   => IdRefsType,
+        "ENTITY"   => EntityType,
+        "ENTITIES" => EntitiesType,
+        "NMTOKEN"  => NmTokenType,
+        "NMTOKENS" => NmTokensType,
+        "NOTATION" => {
+            try!(sc.require_space());
+            NotationType(try!(parse_token_list(sc, true)))
+        },
+        _ => return Err(()),
+    };
+
+    Ok(kind)
+}
+
+/// Parses `SYSTEM 'x'` or `PUBLIC 'y' 'x'`. Notations may omit the
+/// system literal of a public identifier.
+fn parse_external_id(sc: &mut Scanner, public_only_allowed: bool) -> DeclResult<ExternalId> {
+    if sc.consume("SYSTEM") {
+        try!(sc.require_space());
+        let system_id = try!(sc.quoted());
+        return Ok(ExternalId { public_id: None, system_id: Some(system_id.to_string()) });
+    }
+
+    try!(sc.expect("PUBLIC"));
+    try!(sc.require_space());
+    let public_id = try!(sc.quoted());
+
+    let before = sc.position;
+    let system_id = if sc.skip_space() && sc.at_quote() {
+        Some(try!(sc.quoted()).to_string())
+    } else {
+        sc.position = before;
+        None
+    };
+
+    if system_id.is_none() && ! public_only_allowed {
+        return Err(());
+    }
+
+    Ok(ExternalId { public_id: Some(public_id.to_string()), system_id: system_id })
+}
+
+/// Finds the `>` that ends a declaration, ignoring any in quotes
+fn end_of_declaration(s: &str) -> Option<uint> {
+    let mut quote = None;
+
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '>')                   => return Some(i),
+            (None, '"') | (None, '\'')    => quote = Some(c),
+            (Some(q), c) if q == c        => quote = None,
+            _                             => {},
+        }
+    }
+
+    None
+}
+
+/// Finds the end of a conditional section, allowing them to nest
+fn end_of_conditional_section(s: &str) -> Option<uint> {
+    let mut depth = 0u;
+    let mut position = 0;
+
+    while position < s.len() {
+        let rest = s.slice_from(position);
+
+        if rest.starts_with("<![") {
+            depth += 1;
+            position += 3;
+        } else if rest.starts_with("]]>") {
+            depth -= 1;
+            position += 3;
+            if depth == 0 { return Some(position) }
+        } else {
+            position += rest.char_at(0).len_utf8_bytes();
+        }
+    }
+
+    None
+}
+
+fn character_reference(reference: &str) -> Option<char> {
+    let code = if reference.starts_with("#x") {
+        from_str_radix::<u32>(reference.slice_from(2), 16)
+    } else if reference.starts_with("#") {
+        from_str_radix::<u32>(reference.slice_from(1), 10)
+    } else {
+        None
+    };
+
+    code.and_then(|c| from_u32(c))
+}
+
+fn normalize_tokens(value: &str) -> String {
+    let tokens: Vec<&str> = value.words().collect();
+    tokens.connect(" ")
+}
+
+/// The document's elements in document order
+fn elements<'d>(doc: &'d dom4::Document<'d>) -> Vec<dom4::Element<'d>> {
+    let mut elements = Vec::new();
+    let mut todo: Vec<dom4::Element<'d>> = doc.root().children().into_iter().filter_map(|c| c.element()).collect();
+
+    while ! todo.is_empty() {
+        let element = todo.pop().unwrap();
+        elements.push(element);
+
+        let mut children: Vec<dom4::Element<'d>> = element.children().into_iter().filter_map(|c| c.element()).collect();
+        children.reverse();
+        todo.extend(children.into_iter());
+    }
+
+    elements
+}
+
+/// The element, attribute, entity and notation declarations of a
+/// document type. When something is declared more than once, the
+/// first declaration wins.
+pub struct Dtd {
+    name: Option<String>,
+    elements: HashMap<String, ContentSpec>,
+    attributes: HashMap<String, Vec<AttributeDecl>>,
+    entities: HashMap<String, EntityDefinition>,
+    parameter_entities: HashMap<String, EntityDefinition>,
+    notations: HashMap<String, ExternalId>,
+    resolver: Box<EntityResolver + 'static>,
+    limits: Limits,
+    expansions: Cell<uint>,
+    expanded_length: Cell<uint>,
+    /// The limit that stopped the declaration being read, if any
+    exceeded: Cell<Option<Limit>>,
+}
+
+impl Dtd {
+    pub fn new() -> Dtd {
+        Dtd::with_limits(Limits::untrusted())
+    }
+
+    /// Bounds entity expansion with the `EntityExpansions` and
+    /// `ExpandedLength` limits. The other limits are not used.
+    pub fn with_limits(limits: Limits) -> Dtd {
+        Dtd {
+            name: None,
+            elements: HashMap::new(),
+            attributes: HashMap::new(),
+            entities: HashMap::new(),
+            parameter_entities: HashMap::new(),
+            notations: HashMap::new(),
+            resolver: box DenyAll as Box<EntityResolver + 'static>,
+            limits: limits,
+            expansions: Cell::new(0),
+            expanded_length: Cell::new(0),
+            exceeded: Cell::new(None),
+        }
+    }
+
+    /// Replaces the resolver used to load external parameter entities
+    pub fn set_resolver<R: EntityResolver + 'static>(&mut self, resolver: R) {
+        self.resolver = box resolver as Box<EntityResolver + 'static>;
+    }
+
+    /// Reads a DTD from the text of a subset
+    pub fn parse(subset: &str) -> Result<Dtd, DtdError> {
+        let mut dtd = Dtd::new();
+        try!(dtd.add_subset(subset));
+        Ok(dtd)
+    }
+
+    /// Reads the DTD of a document from its internal subset and,
+    /// when given, the text of its external subset. Without one, the
+    /// external subset the parser resolved is used, if any. The
+    /// internal subset is read first, so its declarations take
+    /// precedence.
+    pub fn from_document<'d>(doc: &'d dom4::Document<'d>, external_subset: Option<&str>) -> Result<Dtd, DtdError> {
+        Dtd::from_document_with_resolver(doc, external_subset, DenyAll)
+    }
+
+    /// Like `from_document`, loading external parameter entities
+    /// through the resolver. Give it the resolver the document was
+    /// parsed with, so that they are found where the external subset
+    /// was.
+    pub fn from_document_with_resolver<'d, R: EntityResolver + 'static>(doc: &'d dom4::Document<'d>,
+                                                                     external_subset: Option<&str>,
+                                                                     resolver: R)
+                                                                     -> Result<Dtd, DtdError>
+    {
+        let doctype = try!(doc.root().doctype().ok_or(MissingDocumentType));
+
+        let mut dtd = Dtd::new();
+        dtd.set_resolver(resolver);
+        dtd.name = Some(doctype.name().to_string());
+
+        if let Some(subset) = doctype.internal_subset() {
+            try!(dtd.add_subset(subset));
+        }
+        if let Some(subset) = external_subset.or(doctype.external_subset()) {
+            try!(dtd.add_subset(subset));
+        }
+
+        Ok(dtd)
+    }
+
+    /// Adds the declarations of another subset
+    pub fn add_subset(&mut self, subset: &str) -> Result<(), DtdError> {
+        self.parse_subset(subset, &mut Vec::new())
+    }
+
+    /// The name the document element must have, if known
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|n| n.as_slice())
+    }
+
+    pub fn element(&self, name: &str) -> Option<&ContentSpec> {
+        self.elements.get(name)
+    }
+
+    pub fn attributes(&self, element: &str) -> &[AttributeDecl] {
+        match self.attributes.get(element) {
+            Some(decls) => decls.as_slice(),
+            None => &[],
+        }
+    }
+
+    pub fn entity(&self, name: &str) -> Option<&EntityDefinition> {
+        self.entities.get(name)
+    }
+
+    pub fn parameter_entity(&self, name: &str) -> Option<&EntityDefinition> {
+        self.parameter_entities.get(name)
+    }
+
+    /// The text a reference to the general entity stands for, with
+    /// the references inside it replaced. `None` when the entity is
+    /// not declared, is external, contains markup, or refers to one
+    /// that does.
+    pub fn replacement_text(&self, name: &str) -> Result<Option<String>, Limit> {
+        let text = match self.entities.get(name) {
+            Some(&InternalEntity(ref text)) if ! text.contains_char('<') => text,
+            _ => return Ok(None),
+        };
+
+        try!(self.expand(text.len()));
+        match self.replace_references(text.as_slice(), true, 0) {
+            Ok(replaced) => Ok(Some(replaced)),
+            Err(()) => match self.exceeded.get() {
+                Some(limit) => Err(limit),
+                None => Ok(None),
+            },
+        }
+    }
+
+    pub fn notation(&self, name: &str) -> Option<&ExternalId> {
+        self.notations.get(name)
+    }
+
+    fn parse_subset(&mut self, text: &str, expanding: &mut Vec<String>) -> Result<(), DtdError> {
+        let mut position = 0;
+
+        loop {
+            let rest = text.slice_from(position);
+
+            if let Some(len) = rest.end_of_space() {
+                position += len;
+                continue;
+            }
+            if rest.is_empty() { return Ok(()) }
+
+            let malformed = MalformedDeclaration(position);
+
+            let len = if rest.starts_with("<!--") {
+                try!(rest.find_str("-->").map(|e| e + "-->".len()).ok_or(malformed))
+            } else if rest.starts_with("<?") {
+                try!(rest.find_str("?>").map(|e| e + "?>".len()).ok_or(malformed))
+            } else if rest.starts_with("<![") {
+                let end = try!(end_of_conditional_section(rest).ok_or(malformed.clone()));
+                let body = rest.slice("<![".len(), end - "]]>".len());
+                try!(self.parse_conditional_section(body, expanding, malformed));
+                end
+            } else if rest.starts_with("<!") {
+                let end = try!(end_of_declaration(rest).ok_or(malformed.clone()));
+                let decl = try!(self.expand_parameter_entities(rest.slice(2, end), expanding));
+                if self.parse_declaration(decl.as_slice()).is_err() {
+                    return Err(match self.exceeded.get() {
+                        Some(limit) => LimitExceeded(limit),
+                        None => malformed,
+                    });
+                }
+                end + 1
+            } else if rest.starts_with("%") {
+                let end = try!(rest.find(';').ok_or(malformed.clone()));
+                let name = rest.slice(1, end);
+                if ! is_name(name) { return Err(malformed) }
+
+                let replacement = try!(self.parameter_entity_text(name, expanding));
+                expanding.push(name.to_string());
+                let result = self.parse_subset(replacement.as_slice(), expanding);
+                expanding.pop();
+                try!(result);
+
+                end + 1
+            } else {
+                return Err(malformed);
+            };
+
+            position += len;
+        }
+    }
+
+    fn parse_conditional_section(&mut self, body: &str, expanding: &mut Vec<String>, malformed: DtdError)
+                                 -> Result<(), DtdError>
+    {
+        let open = try!(body.find('[').ok_or(malformed.clone()));
+        let keyword = try!(self.expand_parameter_entities(body.slice_to(open), expanding));
+        let content = body.slice_from(open + 1);
+
+        match keyword.as_slice().trim() {
+            "INCLUDE" => self.parse_subset(content, expanding),
+            "IGNORE" => Ok(()),
+            _ => Err(malformed),
+        }
+    }
+
+    /// Counts a reference that does not name a declared entity, such
+    /// as a character reference, against the same limits as entities
+    pub fn count_expansion(&self, length: uint) -> Result<(), Limit> {
+        self.expand(length)
+    }
+
+    /// Counts an entity about to be expanded into text of this length
+    /// against the limits
+    fn expand(&self, length: uint) -> Result<(), Limit> {
+        let expansions = self.expansions.get() + 1;
+        let expanded_length = self.expanded_length.get() + length;
+        self.expansions.set(expansions);
+        self.expanded_length.set(expanded_length);
+
+        let exceeds = |limit: Limit, value: uint| self.limits.maximum(limit).map_or(false, |max| value > max);
+
+        let exceeded = if exceeds(EntityExpansions, expansions) {
+            Some(EntityExpansions)
+        } else if exceeds(ExpandedLength, expanded_length) {
+            Some(ExpandedLength)
+        } else {
+            None
+        };
+
+        match exceeded {
+            Some(limit) => {
+                self.exceeded.set(Some(limit));
+                Err(limit)
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn parameter_entity_text(&self, name: &str, expanding: &Vec<String>) -> Result<String, DtdError> {
+        if expanding.iter().any(|n| n.as_slice() == name) {
+            return Err(RecursiveParameterEntity(name.to_string()));
+        }
+
+        let text = try!(self.lookup_parameter_entity(name));
+        try!(self.expand(text.len()).map_err(LimitExceeded));
+        Ok(text)
+    }
+
+    fn lookup_parameter_entity(&self, name: &str) -> Result<String, DtdError> {
+        match self.parameter_entities.get(name) {
+            Some(&InternalEntity(ref text)) => Ok(text.clone()),
+            Some(&ExternalEntity(ref id, _)) => {
+                let public_id = id.public_id.as_ref().map(|v| v.as_slice());
+                let system_id = id.system_id.as_ref().map(|v| v.as_slice());
+                self.resolver.resolve(public_id, system_id)
+                    .map_err(|_| ExternalParameterEntity(name.to_string()))
+            },
+            None => Err(UndeclaredParameterEntity(name.to_string())),
+        }
+    }
+
+    /// Replaces parameter entity references outside of quoted literals
+    fn expand_parameter_entities(&self, text: &str, expanding: &mut Vec<String>) -> Result<String, DtdError> {
+        let mut expanded = String::new();
+        let mut quote = None;
+        let mut position = 0;
+
+        while position < text.len() {
+            let rest = text.slice_from(position);
+            let c = rest.char_at(0);
+
+            let reference = match (quote, c) {
+                (None, '%') => {
+                    rest.slice_from(1).end_of_name().and_then(|len| {
+                        if rest.slice_from(1 + len).starts_with(";") { Some(len) } else { None }
+                    })
+                },
+                _ => None,
+            };
+
+            match reference {
+                Some(len) => {
+                    let name = rest.slice(1, 1 + len);
+                    let replacement = try!(self.parameter_entity_text(name, expanding));
+
+                    expanding.push(name.to_string());
+                    let replacement = self.expand_parameter_entities(replacement.as_slice(), expanding);
+                    expanding.pop();
+
+                    expanded.push(' ');
+                    expanded.push_str(try!(replacement).as_slice());
+                    expanded.push(' ');
+                    position += 1 + len + 1;
+                },
+                None => {
+                    match (quote, c) {
+                        (None, '"') | (None, '\'') => quote = Some(c),
+                        (Some(q), c) if q == c     => quote = None,
+                        _                          => {},
+                    }
+                    expanded.push(c);
+                    position += c.len_utf8_bytes();
+                },
+            }
+        }
+
+        Ok(expanded)
+    }
+
+    fn parse_declaration(&mut self, decl: &str) -> DeclResult<()> {
+        let mut sc = Scanner::new(decl);
+
+        let keyword = try!(sc.name());
+        try!(sc.require_space());
+
+        match keyword {
+            "ELEMENT"  => try!(self.parse_element_declaration(&mut sc)),
+            "ATTLIST"  => try!(self.parse_attlist_declaration(&mut sc)),
+            "ENTITY"   => try!(self.parse_entity_declaration(&mut sc)),
+            "NOTATION" => try!(self.parse_notation_declaration(&mut sc)),
+            _ => return Err(()),
+        }
+
+        sc.skip_space();
+        if sc.is_empty() { Ok(()) } else { Err(()) }
+    }
+
+    fn parse_element_declaration(&mut self, sc: &mut Scanner) -> DeclResult<()> {
+        let name = try!(sc.name());
+        try!(sc.require_space());
+        let spec = try!(parse_content_spec(sc));
+
+        if ! self.elements.contains_key(name) {
+            self.elements.insert(name.to_string(), spec);
+        }
+
+        Ok(())
+    }
+
+    fn parse_attlist_declaration(&mut self, sc: &mut Scanner) -> DeclResult<()> {
+        let element = try!(sc.name());
+
+        loop {
+            let separated = sc.skip_space();
+            if sc.is_empty() { break }
+            if ! separated { return Err(()) }
+
+            let name = try!(sc.name());
+            try!(sc.require_space());
+            let kind = try!(parse_attribute_type(sc));
+            try!(sc.require_space());
+
+            let default = if sc.consume("#REQUIRED") {
+                RequiredValue
+            } else if sc.consume("#IMPLIED") {
+                ImpliedValue
+            } else if sc.consume("#FIXED") {
+                try!(sc.require_space());
+                FixedValue(try!(self.normalize_default(try!(sc.quoted()), &kind)))
+            } else {
+                DefaultValue(try!(self.normalize_default(try!(sc.quoted()), &kind)))
+            };
+
+            let decls = match self.attributes.entry(element.to_string()) {
+                Occupied(entry) => entry.into_mut(),
+                Vacant(entry) => entry.set(Vec::new()),
+            };
+
+            if ! decls.iter().any(|d| d.name.as_slice() == name) {
+                decls.push(AttributeDecl {
+                    name: name.to_string(),
+                    kind: kind,
+                    default: default,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_entity_declaration(&mut self, sc: &mut Scanner) -> DeclResult<()> {
+        let parameter = sc.consume("%");
+        if parameter { try!(sc.require_space()) }
+
+        let name = try!(sc.name());
+        try!(sc.require_space());
+
+        let definition = if sc.at_quote() {
+            InternalEntity(try!(self.replace_references(try!(sc.quoted()), false, 0)))
+        } else {
+            let id = try!(parse_external_id(sc, false));
+
+            let before = sc.position;
+            let notation = if ! parameter && sc.skip_space() && sc.consume("NDATA") {
+                try!(sc.require_space());
+                Some(try!(sc.name()).to_string())
+            } else {
+                sc.position = before;
+                None
+            };
+
+            ExternalEntity(id, notation)
+        };
+
+        let entities = if parameter { &mut self.parameter_entities } else { &mut self.entities };
+        if ! entities.contains_key(name) {
+            entities.insert(name.to_string(), definition);
+        }
+
+        Ok(())
+    }
+
+    fn parse_notation_declaration(&mut self, sc: &mut Scanner) -> DeclResult<()> {
+        let name = try!(sc.name());
+        try!(sc.require_space());
+        let id = try!(parse_external_id(sc, true));
+
+        if ! self.notations.contains_key(name) {
+            self.notations.insert(name.to_string(), id);
+        }
+
+        Ok(())
+    }
+
+    /// Expands character references and, when `entities` is set,
+    /// predefined and internal general entities
+    fn replace_references(&self, value: &str, entities: bool, depth: uint) -> DeclResult<String> {
+        if depth > MAX_ENTITY_DEPTH { return Err(()) }
+
+        let mut replaced = String::new();
+        let mut rest = value;
+
+        loop {
+            let start = match rest.find('&') {
+                Some(start) => start,
+                None => break,
+            };
+
+            replaced.push_str(rest.slice_to(start));
+            let after = rest.slice_from(start + 1);
+            let end = try!(after.find(';').ok_or(()));
+            let reference = after.slice_to(end);
+
+            if reference.starts_with("#") {
+                replaced.push(try!(character_reference(reference).ok_or(())));
+            } else if ! entities {
+                replaced.push_str(rest.slice(start, start + 1 + end + 1));
+            } else if let Some(text) = predefined_entity(reference) {
+                replaced.push_str(text);
+            } else {
+                match self.entities.get(reference) {
+                    Some(&InternalEntity(ref text)) if ! text.contains_char('<') => {
+                        try!(self.expand(text.len()).map_err(|_| ()));
+                        let text = try!(self.replace_references(text.as_slice(), true, depth + 1));
+                        replaced.push_str(text.as_slice());
+                    },
+                    _ => return Err(()),
+                }
+            }
+
+            rest = after.slice_from(end + 1);
+        }
+
+        replaced.push_str(rest);
+        Ok(replaced)
+    }
+
+    fn normalize_default(&self, value: &str, kind: &AttributeType) -> DeclResult<String> {
+        let value: String = value.chars().map(|c| if c.is_space_char() { ' ' } else { c }).collect();
+        let value = try!(self.replace_references(value.as_slice(), true, 0));
+
+        if *kind == CDataType {
+            Ok(value)
+        } else {
+            Ok(normalize_tokens(value.as_slice()))
+        }
+    }
+
+    fn is_unparsed_entity(&self, name: &str) -> bool {
+        match self.entities.get(name) {
+            Some(&ExternalEntity(_, Some(_))) => true,
+            _ => false,
+        }
+    }
+
+    /// Checks the document against the declarations, returning every
+    /// problem found in document order
+    pub fn validate<'d>(&self, doc: &'d dom4::Document<'d>) -> Vec<ValidationError<'d>> {
+        let mut errors = Vec::new();
+        let mut ids = HashSet::new();
+        let mut references = Vec::new();
+
+        let elements = elements(doc);
+
+        if let (Some(name), Some(&document_element)) = (self.name(), elements.iter().next()) {
+            if document_element.name() != name {
+                errors.push(RootElementMismatch(document_element));
+            }
+        }
+
+        for &element in elements.iter() {
+            match self.element(element.name()) {
+                None => errors.push(UndeclaredElement(element)),
+                Some(spec) => {
+                    if ! self.content_is_valid(element, spec) {
+                        errors.push(InvalidContent(element));
+                    }
+                },
+            }
+
+            self.validate_attributes(element, &mut ids, &mut references, &mut errors);
+        }
+
+        for (element, id) in references.into_iter() {
+            if ! ids.contains(&id) {
+                errors.push(UnknownIdReference(element, id));
+            }
+        }
+
+        errors
+    }
+
+    fn content_is_valid(&self, element: dom4::Element, spec: &ContentSpec) -> bool {
+        let children = element.children();
+
+        match *spec {
+            EmptyContent => children.is_empty(),
+            AnyContent => true,
+            MixedContent(ref names) => {
+                children.iter().all(|c| match *c {
+                    ElementCOE(e) => names.iter().any(|n| n.as_slice() == e.name()),
+                    _ => true,
+                })
+            },
+            ChildrenContent(ref particle) => {
+                let mut names = Vec::new();
+                for child in children.iter() {
+                    match *child {
+                        ElementCOE(e) => names.push(e.name()),
+                        TextCOE(t) => {
+                            if ! t.text().chars().all(|c| c.is_space_char()) { return false }
+                        },
+                        _ => {},
+                    }
+                }
+                particle.matches(names.as_slice())
+            },
+        }
+    }
+
+    fn validate_attributes<'d>(&self,
+                               element: dom4::Element<'d>,
+                               ids: &mut HashSet<String>,
+                               references: &mut Vec<(dom4::Element<'d>, String)>,
+                               errors: &mut Vec<ValidationError<'d>>)
+    {
+        let decls = self.attributes(element.name());
+
+        for attr in element.attributes().iter() {
+            let decl = match decls.iter().find(|d| d.name.as_slice() == attr.name()) {
+                Some(decl) => decl,
+                None => {
+                    errors.push(UndeclaredAttribute(element, attr.name().to_string()));
+                    continue;
+                },
+            };
+
+            let value = if decl.kind == CDataType {
+                attr.value().to_string()
+            } else {
+                normalize_tokens(attr.value())
+            };
+            let value = value.as_slice();
+            let tokens: Vec<&str> = value.words().collect();
+
+            let valid = match decl.kind {
+                CDataType => true,
+                IdType => {
+                    if is_name(value) && ! ids.insert(value.to_string()) {
+                        errors.push(DuplicateId(element, value.to_string()));
+                    }
+                    is_name(value)
+                },
+                IdRefType | IdRefsType => {
+                    let valid = ! tokens.is_empty() && tokens.iter().all(|t| is_name(*t)) &&
+                        (decl.kind == IdRefsType || tokens.len() == 1);
+                    if valid {
+                        for t in tokens.iter() {
+                            references.push((element, t.to_string()));
+                        }
+                    }
+                    valid
+                },
+                EntityType   => self.is_unparsed_entity(value),
+                EntitiesType => ! tokens.is_empty() && tokens.iter().all(|t| self.is_unparsed_entity(*t)),
+                NmTokenType  => is_nmtoken(value),
+                NmTokensType => ! tokens.is_empty() && tokens.iter().all(|t| is_nmtoken(*t)),
+                NotationType(ref names) => {
+                    names.iter().any(|n| n.as_slice() == value) && self.notations.contains_key(value)
+                },
+                EnumerationType(ref values) => values.iter().any(|v| v.as_slice() == value),
+            };
+
+            let fixed = match decl.default {
+                FixedValue(ref fixed) => fixed.as_slice() == value,
+                _ => true,
+            };
+
+            if ! valid || ! fixed {
+                errors.push(InvalidAttributeValue(element, attr.name().to_string()));
+            }
+        }
+
+        for decl in decls.iter() {
+            if decl.default == RequiredValue && element.attribute_value(decl.name.as_slice()).is_none() {
+                errors.push(MissingRequiredAttribute(element, decl.name.clone()));
+            }
+        }
+    }
+
+    /// Adds attributes with a default or `#FIXED` value to each
+    /// element that does not already specify them
+    pub fn apply_defaults<'d>(&self, doc: &'d dom4::Document<'d>) {
+        for element in elements(doc).into_iter() {
+            for decl in self.attributes(element.name()).iter() {
+                match decl.default {
+                    DefaultValue(ref value) | FixedValue(ref value) => {
+                        if element.attribute_value(decl.name.as_slice()).is_none() {
+                            element.set_attribute_value(decl.name.as_slice(), value.as_slice());
+                        }
+                    },
+                    _ => {},
+                }
+            }
+        }
+    }
+
+    /// Declares each attribute of type `ID` to the document, so that
+    /// `Document::element_by_id` finds elements by it
+    pub fn declare_ids<'d>(&self, doc: &'d dom4::Document<'d>) {
+        for (element, decls) in self.attributes.iter() {
+            for decl in decls.iter() {
+                if decl.kind == IdType {
+                    doc.declare_id_attribute(element.as_slice(), decl.name.as_slice());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Package;
+    use super::super::parser::{Parser,EntityResolver,ResolveError,UnknownIdentifier};
+    use super::{Dtd,ContentParticle,NameParticle,SequenceParticle,ChoiceParticle};
+    use super::{Once,Optional,ZeroOrMore,OneOrMore};
+    use super::{EmptyContent,MixedContent,ChildrenContent};
+    use super::{AttributeDecl,EnumerationType,RequiredValue,DefaultValue,InternalEntity};
+    use super::{MalformedDeclaration,RecursiveParameterEntity,MissingDocumentType,ExternalParameterEntity};
+    use super::LimitExceeded;
+    use super::super::parser::{Limits,EntityExpansions,ExpandedLength};
+    use super::{RootElementMismatch,UndeclaredElement,InvalidContent,UndeclaredAttribute};
+    use super::{MissingRequiredAttribute,InvalidAttributeValue,DuplicateId,UnknownIdReference};
+
+    fn parse(xml: &str) -> Package {
+        Parser::new().parse(xml).ok().expect("Failed to parse the XML string")
+    }
+
+    fn name(n: &str) -> ContentParticle {
+        NameParticle(n.to_string(), Once)
+    }
+
+    #[test]
+    fn element_declarations() {
+        let dtd = Dtd::parse("<!ELEMENT a (b, (c | d)*)> <!ELEMENT b EMPTY> <!ELEMENT c (#PCDATA | b)*>").unwrap();
+
+        let expected = SequenceParticle(vec![
+            name("b"),
+            ChoiceParticle(vec![name("c"), name("d")], ZeroOrMore),
+        ], Once);
+
+        assert_eq!(dtd.element("a"), Some(&ChildrenContent(expected)));
+        assert_eq!(dtd.element("b"), Some(&EmptyContent));
+        assert_eq!(dtd.element("c"), Some(&MixedContent(vec!["b".to_string()])));
+        assert_eq!(dtd.element("d"), None);
+    }
+
+    #[test]
+    fn content_models_match_sequences_of_names() {
+        let model = SequenceParticle(vec![
+            name("a"),
+            ChoiceParticle(vec![name("b"), name("c")], ZeroOrMore),
+            NameParticle("d".to_string(), Optional),
+            NameParticle("e".to_string(), OneOrMore),
+        ], Once);
+
+        assert!(model.matches(&["a", "e"]));
+        assert!(model.matches(&["a", "c", "b", "c", "d", "e", "e"]));
+        assert!(! model.matches(&["a"]));
+        assert!(! model.matches(&["a", "d", "d", "e"]));
+        assert!(! model.matches(&["b", "e"]));
+    }
+
+    #[test]
+    fn attribute_declarations() {
+        let dtd = Dtd::parse("<!ATTLIST a size (small|large) 'small' name CDATA #REQUIRED>").unwrap();
+
+        assert_eq!(dtd.attributes("a"), [
+            AttributeDecl {
+                name: "size".to_string(),
+                kind: EnumerationType(vec!["small".to_string(), "large".to_string()]),
+                default: DefaultValue("small".to_string()),
+            },
+            AttributeDecl {
+                name: "name".to_string(),
+                kind: super::CDataType,
+                default: RequiredValue,
+            },
+        ].as_slice());
+    }
+
+    #[test]
+    fn parameter_entities_and_conditional_sections() {
+        let dtd = Dtd::parse(r#"
+            <!ENTITY % content "(b | c)">
+            <!ENTITY % draft "INCLUDE">
+            <!ENTITY % decls "<!ELEMENT d EMPTY>">
+            <![%draft;[ <!ELEMENT a %content;> ]]>
+            <![IGNORE[ <!ELEMENT a ANY> <![INCLUDE[ ]]> ]]>
+            %decls;
+            <!ENTITY copy "&#169;">
+        "#).unwrap();
+
+        let expected = ChoiceParticle(vec![name("b"), name("c")], Once);
+        assert_eq!(dtd.element("a"), Some(&ChildrenContent(expected)));
+        assert_eq!(dtd.element("d"), Some(&EmptyContent));
+        assert_eq!(dtd.entity("copy"), Some(&InternalEntity("©".to_string())));
+    }
+
+    #[test]
+    fn recursive_parameter_entities_are_an_error() {
+        let result = Dtd::parse("<!ENTITY % a '%b;'><!ENTITY % b '%a;'><!ELEMENT x %a;>");
+        assert_eq!(result.err(), Some(RecursiveParameterEntity("a".to_string())));
+    }
+
+    #[test]
+    fn malformed_declarations_are_an_error() {
+        let result = Dtd::parse("<!ELEMENT a ANY>\n<!ELEMENT b (c,>");
+        assert_eq!(result.err(), Some(MalformedDeclaration(17)));
+    }
+
+    #[test]
+    fn documents_without_a_doctype_have_no_dtd() {
+        let package = parse("<a/>");
+        let doc = package.as_document();
+        assert_eq!(Dtd::from_document(&doc, None).err(), Some(MissingDocumentType));
+    }
+
+    #[test]
+    fn valid_document() {
+        let package = parse(r#"<!DOCTYPE a [
+            <!ELEMENT a (b+)>
+            <!ELEMENT b (#PCDATA)>
+            <!ATTLIST b id ID #IMPLIED ref IDREF #IMPLIED>
+        ]><a> <b id="x">one</b> <b ref="x"/> </a>"#);
+        let doc = package.as_document();
+        let dtd = Dtd::from_document(&doc, None).unwrap();
+
+        assert!(dtd.validate(&doc).is_empty());
+    }
+
+    #[test]
+    fn content_violations() {
+        let package = parse(r#"<!DOCTYPE a [
+            <!ELEMENT a (b, c)>
+            <!ELEMENT b EMPTY>
+            <!ELEMENT c (#PCDATA)>
+        ]><a>text<b>x</b><c><b/></c><d/></a>"#);
+        let doc = package.as_document();
+        let dtd = Dtd::from_document(&doc, None).unwrap();
+
+        let a = doc.root().children()[0].element().unwrap();
+        let children: Vec<_> = a.children().into_iter().filter_map(|c| c.element()).collect();
+        let (b, c, d) = (children[0], children[1], children[2]);
+
+        assert_eq!(dtd.validate(&doc), vec![
+            InvalidContent(a),
+            InvalidContent(b),
+            InvalidContent(c),
+            UndeclaredElement(d),
+        ]);
+    }
+
+    #[test]
+    fn attribute_violations() {
+        let package = parse(r#"<!DOCTYPE a [
+            <!ELEMENT a EMPTY>
+            <!ATTLIST a size (small|large) #REQUIRED
+                        version CDATA #FIXED "1"
+                        name CDATA #REQUIRED>
+        ]><a size="medium" version="2" extra="x"/>"#);
+        let doc = package.as_document();
+        let dtd = Dtd::from_document(&doc, None).unwrap();
+        let a = doc.root().children()[0].element().unwrap();
+
+        assert_eq!(dtd.validate(&doc), vec![
+            InvalidAttributeValue(a, "size".to_string()),
+            InvalidAttributeValue(a, "version".to_string()),
+            UndeclaredAttribute(a, "extra".to_string()),
+            MissingRequiredAttribute(a, "name".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn id_violations() {
+        let package = parse(r#"<!DOCTYPE a [
+            <!ELEMENT a (b*)>
+            <!ELEMENT b EMPTY>
+            <!ATTLIST b id ID #IMPLIED refs IDREFS #IMPLIED>
+        ]><a><b id="x"/><b id="x"/><b refs="x y"/></a>"#);
+        let doc = package.as_document();
+        let dtd = Dtd::from_document(&doc, None).unwrap();
+
+        let a = doc.root().children()[0].element().unwrap();
+        let children: Vec<_> = a.children().into_iter().filter_map(|c| c.element()).collect();
+
+        assert_eq!(dtd.validate(&doc), vec![
+            DuplicateId(children[1], "x".to_string()),
+            UnknownIdReference(children[2], "y".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn notations_and_unparsed_entities() {
+        let package = parse(r#"<!DOCTYPE a [
+            <!NOTATION png PUBLIC "image/png">
+            <!ENTITY logo SYSTEM "logo.png" NDATA png>
+            <!ELEMENT a EMPTY>
+            <!ATTLIST a image ENTITY #REQUIRED format NOTATION (png|gif) #REQUIRED>
+        ]><a image="logo" format="gif"/>"#);
+        let doc = package.as_document();
+        let dtd = Dtd::from_document(&doc, None).unwrap();
+        let a = doc.root().children()[0].element().unwrap();
+
+        assert_eq!(dtd.validate(&doc), vec![InvalidAttributeValue(a, "format".to_string())]);
+    }
+
+    #[test]
+    fn external_subset_and_root_element() {
+        let package = parse("<!DOCTYPE a SYSTEM 'a.dtd' [<!ELEMENT b ANY>]><b/>");
+        let doc = package.as_document();
+        let dtd = Dtd::from_document(&doc, Some("<!ELEMENT a ANY><!ELEMENT b EMPTY>")).unwrap();
+        let b = doc.root().children()[0].element().unwrap();
+
+        // The internal subset's declaration of b wins
+        assert_eq!(dtd.element("b"), Some(&super::AnyContent));
+        assert_eq!(dtd.validate(&doc), vec![RootElementMismatch(b)]);
+    }
+
+    struct ModulesResolver;
+
+    impl EntityResolver for ModulesResolver {
+        fn resolve(&self, _public_id: Option<&str>, system_id: Option<&str>) -> Result<String, ResolveError> {
+            match system_id {
+                Some("modules.ent") => Ok("<!ELEMENT a EMPTY>".to_string()),
+                _ => Err(UnknownIdentifier),
+            }
+        }
+    }
+
+    #[test]
+    fn external_parameter_entities_are_not_loaded_by_default() {
+        let result = Dtd::parse(r#"<!ENTITY % modules SYSTEM "modules.ent"> %modules;"#);
+
+        assert_eq!(result.err(), Some(ExternalParameterEntity("modules".to_string())));
+    }
+
+    #[test]
+    fn external_parameter_entities_are_loaded_by_the_resolver() {
+        let mut dtd = Dtd::new();
+        dtd.set_resolver(ModulesResolver);
+        dtd.add_subset(r#"<!ENTITY % modules SYSTEM "modules.ent"> %modules;"#).unwrap();
+
+        assert_eq!(dtd.element("a"), Some(&EmptyContent));
+    }
+
+    #[test]
+    fn external_subset_from_the_parser_is_used() {
+        let mut parser = Parser::new();
+        parser.set_resolver(ModulesResolver);
+        let package = parser.parse("<!DOCTYPE a SYSTEM 'modules.ent'><a/>").ok().expect("Failed to parse");
+        let doc = package.as_document();
+        let dtd = Dtd::from_document(&doc, None).unwrap();
+
+        assert_eq!(dtd.element("a"), Some(&EmptyContent));
+    }
+
+    #[test]
+    fn external_parameter_entities_of_a_document_use_its_resolver() {
+        let mut parser = Parser::new();
+        parser.set_resolver(ModulesResolver);
+        let package = parser.parse(r#"<!DOCTYPE a [<!ENTITY % modules SYSTEM "modules.ent"> %modules;]><a/>"#)
+            .ok().expect("Failed to parse");
+        let doc = package.as_document();
+
+        assert_eq!(Dtd::from_document(&doc, None).err(), Some(ExternalParameterEntity("modules".to_string())));
+
+        let dtd = Dtd::from_document_with_resolver(&doc, None, ModulesResolver).unwrap();
+        assert_eq!(dtd.element("a"), Some(&EmptyContent));
+    }
+
+    #[test]
+    fn declared_ids_can_be_looked_up() {
+        let package = parse(r#"<!DOCTYPE a [
+            <!ELEMENT a (b*)>
+            <!ELEMENT b EMPTY>
+            <!ATTLIST b key ID #IMPLIED>
+        ]><a key="top"><b key="x"/></a>"#);
+        let doc = package.as_document();
+        let dtd = Dtd::from_document(&doc, None).unwrap();
+
+        assert_eq!(doc.element_by_id("x"), None);
+
+        dtd.declare_ids(&doc);
+
+        let a = doc.root().children()[0].element().unwrap();
+        let b = a.children()[0].element().unwrap();
+        assert_eq!(doc.element_by_id("x"), Some(b));
+        assert_eq!(doc.element_by_id("top"), None);
+    }
+
+    #[test]
+    fn defaults_can_be_applied() {
+        let package = parse(r#"<!DOCTYPE a [
+            <!ENTITY who "world">
+            <!ELEMENT a EMPTY>
+            <!ATTLIST a greeting CDATA "hello &who;" kind NMTOKEN #FIXED " x " given CDATA "no">
+        ]><a given="yes"/>"#);
+        let doc = package.as_document();
+        let dtd = Dtd::from_document(&doc, None).unwrap();
+        let a = doc.root().children()[0].element().unwrap();
+
+        dtd.apply_defaults(&doc);
+
+        assert_eq!(a.attribute_value("greeting"), Some("hello world"));
+        assert_eq!(a.attribute_value("kind"), Some("x"));
+        assert_eq!(a.attribute_value("given"), Some("yes"));
+    }
+
+    /// Ten levels of entities, each referring to the one before ten
+    /// times. `prefix` is `%` for parameter entities.
+    fn laughs(prefix: &str, innermost: &str) -> String {
+        let mut subset = format!("<!ENTITY {} lol0 \"{}\">", prefix, innermost);
+        for level in range(1u, 10) {
+            let mut text = String::new();
+            for _ in range(0u, 10) {
+                text.push_str(format!("{}lol{};", if prefix.is_empty() { "&" } else { "%" }, level - 1).as_slice());
+            }
+            subset.push_str(format!("<!ENTITY {} lol{} \"{}\">", prefix, level, text).as_slice());
+        }
+        subset
+    }
+
+    #[test]
+    fn general_entity_expansion_is_limited() {
+        let mut subset = laughs("", "lol");
+        subset.push_str("<!ATTLIST a b CDATA \"&lol9;\">");
+
+        assert_eq!(Dtd::parse(subset.as_slice()).err(), Some(LimitExceeded(EntityExpansions)));
+    }
+
+    #[test]
+    fn parameter_entity_expansion_is_limited() {
+        let mut subset = laughs("%", "");
+        subset.push_str("%lol9;");
+
+        assert_eq!(Dtd::parse(subset.as_slice()).err(), Some(LimitExceeded(EntityExpansions)));
+    }
+
+    #[test]
+    fn expanded_length_is_limited() {
+        let limits = Limits { max_expanded_length: Some(10), ..Limits::none() };
+        let mut dtd = Dtd::with_limits(limits);
+        let result = dtd.add_subset(r#"<!ENTITY e "0123456789"> <!ATTLIST a b CDATA "&e;&e;">"#);
+
+        assert_eq!(result, Err(LimitExceeded(ExpandedLength)));
+    }
+}
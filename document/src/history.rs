@@ -0,0 +1,545 @@
+//! Reversible edits to a document
+//!
+//! A `Transaction` records every change made through a document while
+//! it is open. Committing it gives back the changes as a `Changeset`,
+//! which can be reverted later; rolling it back reverts them straight
+//! away.
+//!
+//! ```
+//! use document::Package;
+//! use document::history::{History,Transaction};
+//!
+//! let package = Package::new();
+//! let doc = package.as_document();
+//! let mut history = History::new(&doc);
+//!
+//! let hello = doc.create_element("hello");
+//! doc.root().append_child(hello);
+//!
+//! let transaction = Transaction::begin(&doc);
+//! hello.set_attribute_value("planet", "Earth");
+//! history.record(transaction.commit());
+//!
+//! history.undo();
+//! assert_eq!(hello.attribute_value("planet"), None);
+//! history.redo();
+//! assert_eq!(hello.attribute_value("planet"), Some("Earth"));
+//! ```
+//!
+//! Nodes are never deallocated, so undoing the addition of a node
+//! only detaches it. Undoing the removal or replacement of a node
+//! attaches the very same node again, with the user data that removing
+//! it cleared.
+//!
+//! ### Known issues
+//!
+//! - Setting the document type declaration is not recorded.
+
+use std::mem;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use super::dom4::{Document,Mutation,MutationObserver,ObserverId,SavedUserData};
+use super::dom4::{ChildAdded,ChildRemoved,AttributeChanged,ElementRenamed};
+use super::dom4::{TextChanged,CommentChanged,ProcessingInstructionChanged};
+use super::dom4::AttributeNode;
+
+/// The changes recorded so far, along with the user data of each
+/// removed child or attribute
+struct Log<'d> {
+    mutations: Vec<Mutation<'d>>,
+    user_data: Vec<Option<SavedUserData<'d>>>,
+}
+
+struct Recorder<'d> {
+    document: &'d Document<'d>,
+    log: Rc<RefCell<Log<'d>>>,
+}
+
+impl<'d> MutationObserver<'d> for Recorder<'d> {
+    fn mutated(&mut self, mutation: &Mutation<'d>) {
+        // Observers are told before the user data is cleared
+        let user_data = match *mutation {
+            ChildRemoved(_, child, _) => Some(self.document.save_user_data(child.to_node())),
+            AttributeChanged(_, _, _, Some(old)) => Some(self.document.save_user_data(AttributeNode(old))),
+            _ => None,
+        };
+
+        let mut log = self.log.borrow_mut();
+        log.mutations.push(mutation.clone());
+        log.user_data.push(user_data);
+    }
+}
+
+/// Records the changes made through a document until it is committed
+/// or rolled back. Dropping an unfinished transaction stops recording
+/// and keeps the changes, as if it were committed and the changeset
+/// discarded.
+///
+/// Transactions may be nested; the outer one records the changes of
+/// the inner one, including those made by rolling it back.
+pub struct Transaction<'d> {
+    document: &'d Document<'d>,
+    observer: ObserverId,
+    log: Rc<RefCell<Log<'d>>>,
+}
+
+impl<'d> Transaction<'d> {
+    pub fn begin(document: &'d Document<'d>) -> Transaction<'d> {
+        let log = Rc::new(RefCell::new(Log { mutations: Vec::new(), user_data: Vec::new() }));
+        let recorder = Recorder { document: document, log: log.clone() };
+        let observer = document.add_observer(box recorder as Box<MutationObserver<'d> + 'd>);
+
+        Transaction {
+            document: document,
+            observer: observer,
+            log: log,
+        }
+    }
+
+    /// Stops recording and keeps the changes
+    pub fn commit(self) -> Changeset<'d> {
+        self.document.remove_observer(self.observer.clone());
+        let mut log = self.log.borrow_mut();
+        Changeset {
+            document: self.document,
+            mutations: mem::replace(&mut log.mutations, Vec::new()),
+            user_data: mem::replace(&mut log.user_data, Vec::new()),
+        }
+    }
+
+    /// Stops recording and reverts the changes
+    pub fn rollback(self) {
+        self.commit().revert();
+    }
+}
+
+#[unsafe_destructor]
+impl<'d> Drop for Transaction<'d> {
+    fn drop(&mut self) {
+        self.document.remove_observer(self.observer.clone());
+    }
+}
+
+/// Changes to a document, oldest first
+pub struct Changeset<'d> {
+    document: &'d Document<'d>,
+    mutations: Vec<Mutation<'d>>,
+    /// The user data of each removed child or attribute, by mutation
+    user_data: Vec<Option<SavedUserData<'d>>>,
+}
+
+impl<'d> Changeset<'d> {
+    pub fn mutations(&self) -> &[Mutation<'d>] {
+        self.mutations.as_slice()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mutations.is_empty()
+    }
+
+    /// Undoes the changes, newest first. The changes made in doing so
+    /// are returned, and reverting them redoes the original changes.
+    ///
+    /// The document should be as these changes left it; anything
+    /// changed since then should be reverted first.
+    pub fn revert(self) -> Changeset<'d> {
+        let Changeset { document, mutations, user_data } = self;
+        let transaction = Transaction::begin(document);
+        for (mutation, user_data) in mutations.iter().zip(user_data.into_iter()).rev() {
+            undo(mutation);
+            if let Some(user_data) = user_data {
+                document.restore_user_data(user_data);
+            }
+        }
+        transaction.commit()
+    }
+}
+
+fn undo<'d>(mutation: &Mutation<'d>) {
+    match *mutation {
+        ChildAdded(parent, child) => parent.remove_child(child),
+        ChildRemoved(parent, child, index) => parent.insert_child(index, child),
+        AttributeChanged(element, _, _, Some(old)) => element.set_attribute(old),
+        AttributeChanged(element, ref name, Some(ref value), None) => {
+            if let Some(attribute) = element.attribute(name.as_slice()) {
+                attribute.set_text_content(value.as_slice());
+            }
+        },
+        AttributeChanged(element, ref name, None, None) => element.remove_attribute(name.as_slice()),
+        ElementRenamed(element, ref name) => element.set_name(name.as_slice()),
+        TextChanged(text, ref old) => text.set_text(old.as_slice()),
+        CommentChanged(comment, ref old) => comment.set_text(old.as_slice()),
+        ProcessingInstructionChanged(pi, ref target, ref value) => {
+            pi.set_target(target.as_slice());
+            pi.set_value(value.as_ref().map(|v| v.as_slice()));
+        },
+    }
+}
+
+/// Undo and redo stacks of changesets
+pub struct History<'d> {
+    document: &'d Document<'d>,
+    undo: Vec<Changeset<'d>>,
+    redo: Vec<Changeset<'d>>,
+}
+
+impl<'d> History<'d> {
+    pub fn new(document: &'d Document<'d>) -> History<'d> {
+        History {
+            document: document,
+            undo: Vec::new(),
+            redo: Vec::new(),
+        }
+    }
+
+    /// Starts a transaction on the document, to be recorded once
+    /// committed
+    pub fn begin(&self) -> Transaction<'d> {
+        Transaction::begin(self.document)
+    }
+
+    /// Makes the changes the next to be undone. Anything that was
+    /// undone can no longer be redone. Empty changesets are ignored.
+    pub fn record(&mut self, changes: Changeset<'d>) {
+        if changes.is_empty() { return }
+        self.undo.push(changes);
+        self.redo.clear();
+    }
+
+    pub fn can_undo(&self) -> bool { ! self.undo.is_empty() }
+
+    pub fn can_redo(&self) -> bool { ! self.redo.is_empty() }
+
+    /// Reverts the most recently recorded or redone changes. Returns
+    /// false when there is nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        match self.undo.pop() {
+            Some(changes) => {
+                self.redo.push(changes.revert());
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Reapplies the most recently undone changes. Returns false when
+    /// there is nothing to redo.
+    pub fn redo(&mut self) -> bool {
+        match self.redo.pop() {
+            Some(changes) => {
+                self.undo.push(changes.revert());
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Package;
+    use super::super::dom4::{ElementCOE,TextCOE};
+    use super::{History,Transaction};
+
+    #[test]
+    fn dropped_transactions_stop_recording() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+
+        let transaction = Transaction::begin(&doc);
+        let log = transaction.log.clone();
+        element.set_attribute_value("before", "drop");
+        drop(transaction);
+        element.set_attribute_value("after", "drop");
+
+        assert_eq!(log.borrow().mutations.len(), 1);
+        assert_eq!(element.attribute_value("before"), Some("drop"));
+    }
+
+    #[test]
+    fn rollback_restores_attributes() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        element.set_attribute_value("kept", "before");
+
+        let transaction = Transaction::begin(&doc);
+        element.set_attribute_value("kept", "after");
+        element.set_attribute_value("added", "value");
+        transaction.rollback();
+
+        assert_eq!(element.attribute_value("kept"), Some("before"));
+        assert_eq!(element.attribute_value("added"), None);
+    }
+
+    #[test]
+    fn rollback_restores_removed_attributes() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        element.set_attribute_value("hello", "world");
+
+        let transaction = Transaction::begin(&doc);
+        element.remove_attribute("hello");
+        assert_eq!(element.attribute_value("hello"), None);
+        transaction.rollback();
+
+        assert_eq!(element.attribute_value("hello"), Some("world"));
+    }
+
+    #[test]
+    fn rollback_restores_the_replaced_attribute_node() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let original = element.set_attribute_value("hello", "world");
+        original.set_user_data(1u);
+
+        let transaction = Transaction::begin(&doc);
+        element.set_attribute_value("hello", "galaxy");
+        transaction.rollback();
+
+        assert_eq!(element.attribute("hello"), Some(original));
+        assert_eq!(original.parent(), Some(element));
+        assert_eq!(original.user_data::<uint>(), Some(1u));
+    }
+
+    #[test]
+    fn rollback_restores_the_removed_attribute_node() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let original = element.set_attribute_value("hello", "world");
+        original.set_user_data(1u);
+
+        let transaction = Transaction::begin(&doc);
+        element.remove_attribute("hello");
+        transaction.rollback();
+
+        assert_eq!(element.attribute("hello"), Some(original));
+        assert_eq!(original.user_data::<uint>(), Some(1u));
+    }
+
+    #[test]
+    fn rollback_restores_attribute_values_changed_in_place() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let original = element.set_attribute_value("hello", "world");
+
+        let transaction = Transaction::begin(&doc);
+        original.set_text_content("galaxy");
+        transaction.rollback();
+
+        assert_eq!(element.attribute("hello"), Some(original));
+        assert_eq!(original.value(), "world");
+    }
+
+    #[test]
+    fn rollback_restores_names_and_text() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("before");
+        let text = doc.create_text("old text");
+        let comment = doc.create_comment("old comment");
+        let pi = doc.create_processing_instruction("device", Some("old"));
+
+        let transaction = Transaction::begin(&doc);
+        element.set_name("after");
+        text.set_text("new text");
+        comment.set_text("new comment");
+        pi.set_target("output");
+        pi.set_value(None);
+        transaction.rollback();
+
+        assert_eq!(element.name(), "before");
+        assert_eq!(text.text(), "old text");
+        assert_eq!(comment.text(), "old comment");
+        assert_eq!(pi.target(), "device");
+        assert_eq!(pi.value(), Some("old"));
+    }
+
+    #[test]
+    fn rollback_restores_children_in_their_positions() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let parent = doc.create_element("parent");
+        let a = doc.create_element("a");
+        let b = doc.create_text("b");
+        let c = doc.create_element("c");
+        parent.append_child(a);
+        parent.append_child(b);
+        parent.append_child(c);
+        let other = doc.create_element("other");
+
+        let transaction = Transaction::begin(&doc);
+        parent.remove_child(b);
+        other.append_child(a);
+        parent.append_child(doc.create_element("d"));
+        transaction.rollback();
+
+        assert_eq!(parent.children(), vec![ElementCOE(a), TextCOE(b), ElementCOE(c)]);
+        assert!(other.children().is_empty());
+    }
+
+    #[test]
+    fn rollback_restores_the_user_data_of_removed_children() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let parent = doc.create_element("parent");
+        let child = doc.create_element("child");
+        parent.append_child(child);
+        child.set_user_data(1u);
+
+        let transaction = Transaction::begin(&doc);
+        parent.remove_child(child);
+        transaction.rollback();
+
+        assert_eq!(parent.children(), vec![ElementCOE(child)]);
+        assert_eq!(child.user_data::<uint>(), Some(1u));
+    }
+
+    #[test]
+    fn rollback_restores_the_replaced_root_element() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        doc.root().append_child(alpha);
+
+        let transaction = Transaction::begin(&doc);
+        doc.root().append_child(beta);
+        transaction.rollback();
+
+        assert_eq!(doc.root().children()[0].element(), Some(alpha));
+        assert_eq!(beta.parent(), None);
+    }
+
+    #[test]
+    fn commit_keeps_the_changes() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+
+        let transaction = Transaction::begin(&doc);
+        element.set_attribute_value("hello", "world");
+        let changes = transaction.commit();
+
+        assert_eq!(changes.mutations().len(), 1);
+        assert_eq!(element.attribute_value("hello"), Some("world"));
+    }
+
+    #[test]
+    fn changes_after_commit_are_not_recorded() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+
+        let transaction = Transaction::begin(&doc);
+        let changes = transaction.commit();
+        element.set_attribute_value("hello", "world");
+
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn nested_rollback_leaves_outer_changes() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+
+        let outer = Transaction::begin(&doc);
+        element.set_attribute_value("outer", "1");
+        let inner = Transaction::begin(&doc);
+        element.set_attribute_value("inner", "2");
+        inner.rollback();
+        let changes = outer.commit();
+
+        assert_eq!(element.attribute_value("outer"), Some("1"));
+        assert_eq!(element.attribute_value("inner"), None);
+
+        changes.revert();
+        assert_eq!(element.attribute_value("outer"), None);
+    }
+
+    #[test]
+    fn history_undoes_and_redoes_in_order() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let mut history = History::new(&doc);
+
+        let element = doc.create_element("element");
+
+        let transaction = history.begin();
+        element.set_attribute_value("step", "1");
+        history.record(transaction.commit());
+
+        let transaction = history.begin();
+        element.set_attribute_value("step", "2");
+        element.set_name("renamed");
+        history.record(transaction.commit());
+
+        assert!(history.undo());
+        assert_eq!(element.attribute_value("step"), Some("1"));
+        assert_eq!(element.name(), "element");
+
+        assert!(history.undo());
+        assert_eq!(element.attribute_value("step"), None);
+        assert!(! history.undo());
+
+        assert!(history.redo());
+        assert!(history.redo());
+        assert_eq!(element.attribute_value("step"), Some("2"));
+        assert_eq!(element.name(), "renamed");
+        assert!(! history.redo());
+    }
+
+    #[test]
+    fn recording_clears_the_redo_stack() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let mut history = History::new(&doc);
+
+        let element = doc.create_element("element");
+
+        let transaction = history.begin();
+        element.set_attribute_value("step", "1");
+        history.record(transaction.commit());
+        history.undo();
+        assert!(history.can_redo());
+
+        let transaction = history.begin();
+        element.set_attribute_value("other", "1");
+        history.record(transaction.commit());
+
+        assert!(! history.can_redo());
+        assert!(history.can_undo());
+    }
+
+    #[test]
+    fn empty_changesets_are_not_recorded() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let mut history = History::new(&doc);
+
+        let transaction = history.begin();
+        history.record(transaction.commit());
+
+        assert!(! history.can_undo());
+    }
+}
@@ -0,0 +1,451 @@
+//! Converts documents to and from JSON using the BadgerFish convention
+//!
+//! ### Convention
+//!
+//! - An element becomes a member named after the element whose value
+//!   is an object: `<a/>` is `{"a":{}}`.
+//! - Attributes become members prefixed with `@`:
+//!   `<a b='c'/>` is `{"a":{"@b":"c"}}`.
+//! - Text becomes the `$` member. In mixed content, all the text
+//!   children are concatenated: `<a>x<b/>y</a>` is `{"a":{"$":"xy","b":{}}}`.
+//! - Repeated children with the same name become an array:
+//!   `<a><b/><b/></a>` is `{"a":{"b":[{},{}]}}`.
+//! - Namespace declarations are collected into the `@xmlns` member,
+//!   with the default namespace under `$`:
+//!   `<a xmlns='x' xmlns:p='y'/>` is `{"a":{"@xmlns":{"$":"x","p":"y"}}}`.
+//!
+//! Comments and processing instructions are not represented. Members
+//! are written in document order, grouped by name, but when reading
+//! JSON the relative order of differently-named children is not
+//! preserved.
+//!
+//! ### Example
+//! ```
+//! use document::Package;
+//! use document::json;
+//!
+//! let package = Package::new();
+//! let doc = package.as_document();
+//!
+//! let hello = doc.create_element("hello");
+//! hello.set_attribute_value("planet", "Earth");
+//! doc.root().append_child(hello);
+//!
+//! let mut output = std::io::stdio::stdout();
+//! json::format_document(&doc, &mut output).ok().expect("unable to output JSON");
+//!
+//! let package = json::parse(r#"{"hello":{"@planet":"Earth"}}"#).ok().expect("invalid JSON");
+//! ```
+
+use std::io::IoResult;
+use serialize::json;
+use serialize::json::Json;
+
+use super::Package;
+use super::dom4;
+use super::xmlstr::is_name;
+use super::dom4::{ElementCOE,TextCOE,ElementCOR};
+
+static NAMESPACE_PREFIX: &'static str = "xmlns:";
+static NAMESPACE_DEFAULT: &'static str = "xmlns";
+
+#[deriving(Show,Clone,PartialEq)]
+pub enum Error {
+    /// The input was not valid JSON
+    InvalidJson(json::ParserError),
+    /// The top-level object had no members
+    MissingRootElement,
+    /// The top-level object had more than one member or array item
+    MultipleRootElements,
+    /// The member with this name had a value of the wrong type
+    UnexpectedValue(String),
+    /// The member with this name does not name a valid element,
+    /// attribute or namespace prefix
+    InvalidName(String),
+}
+
+/// The name, when it may be used in the document
+fn valid_name<'a>(name: &'a str, key: &str) -> Result<&'a str, Error> {
+    if is_name(name) { Ok(name) } else { Err(InvalidName(key.to_string())) }
+}
+
+fn write_json_string<W : Writer>(s: &str, writer: &mut W) -> IoResult<()> {
+    try!(writer.write_str("\""));
+
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        let escaped = match c {
+            '"'  => "\\\"",
+            '\\' => "\\\\",
+            '\n' => "\\n",
+            '\r' => "\\r",
+            '\t' => "\\t",
+            c if (c as u32) < 0x20 => "",
+            _ => continue,
+        };
+
+        try!(writer.write_str(s.slice(start, i)));
+        if escaped.is_empty() {
+            try!(write!(writer, "\\u{:04x}", c as u32));
+        } else {
+            try!(writer.write_str(escaped));
+        }
+        // Only ASCII characters are escaped
+        start = i + 1;
+    }
+
+    try!(writer.write_str(s.slice_from(start)));
+    writer.write_str("\"")
+}
+
+fn write_key<W : Writer>(key: &str, first: &mut bool, writer: &mut W) -> IoResult<()> {
+    if ! *first {
+        try!(writer.write_str(","));
+    }
+    *first = false;
+
+    try!(write_json_string(key, writer));
+    writer.write_str(":")
+}
+
+fn format_element<W : Writer>(element: dom4::Element, writer: &mut W) -> IoResult<()> {
+    let mut first = true;
+    try!(writer.write_str("{"));
+
+    let (namespaces, attributes) = element.attributes().partition(|a| {
+        a.name() == NAMESPACE_DEFAULT || a.name().starts_with(NAMESPACE_PREFIX)
+    });
+
+    if ! namespaces.is_empty() {
+        try!(write_key("@xmlns", &mut first, writer));
+
+        let mut first_namespace = true;
+        try!(writer.write_str("{"));
+        for ns in namespaces.iter() {
+            let prefix = if ns.name() == NAMESPACE_DEFAULT {
+                "$"
+            } else {
+                ns.name().slice_from(NAMESPACE_PREFIX.len())
+            };
+            try!(write_key(prefix, &mut first_namespace, writer));
+            try!(write_json_string(ns.value(), writer));
+        }
+        try!(writer.write_str("}"));
+    }
+
+    for attr in attributes.iter() {
+        try!(write_key(format!("@{}", attr.name()).as_slice(), &mut first, writer));
+        try!(write_json_string(attr.value(), writer));
+    }
+
+    let mut text = String::new();
+    let mut groups: Vec<Vec<dom4::Element>> = Vec::new();
+
+    for child in element.children().into_iter() {
+        match child {
+            TextCOE(t) => text.push_str(t.text()),
+            ElementCOE(e) => {
+                let mut found = false;
+                for group in groups.iter_mut() {
+                    if group[0].name() == e.name() {
+                        group.push(e);
+                        found = true;
+                        break;
+                    }
+                }
+                if ! found { groups.push(vec![e]) }
+            },
+            _ => {},
+        }
+    }
+
+    if ! text.is_empty() {
+        try!(write_key("$", &mut first, writer));
+        try!(write_json_string(text.as_slice(), writer));
+    }
+
+    for elements in groups.iter() {
+        try!(write_key(elements[0].name(), &mut first, writer));
+
+        if elements.len() == 1 {
+            try!(format_element(elements[0], writer));
+        } else {
+            try!(writer.write_str("["));
+            for (i, e) in elements.iter().enumerate() {
+                if i != 0 {
+                    try!(writer.write_str(","));
+                }
+                try!(format_element(*e, writer));
+            }
+            try!(writer.write_str("]"));
+        }
+    }
+
+    writer.write_str("}")
+}
+
+/// Formats a document into a Writer as JSON
+pub fn format_document<'d, W : Writer>(doc: &'d dom4::Document<'d>, writer: &mut W) -> IoResult<()> {
+    let mut first = true;
+    try!(writer.write_str("{"));
+
+    for child in doc.root().children().into_iter() {
+        if let ElementCOR(e) = child {
+            try!(write_key(e.name(), &mut first, writer));
+            try!(format_element(e, writer));
+        }
+    }
+
+    writer.write_str("}")
+}
+
+fn scalar_text(value: &Json) -> Option<String> {
+    match *value {
+        json::String(ref s) => Some(s.clone()),
+        json::I64(n)        => Some(n.to_string()),
+        json::U64(n)        => Some(n.to_string()),
+        json::F64(n)        => Some(n.to_string()),
+        json::Boolean(b)    => Some(b.to_string()),
+        _                   => None,
+    }
+}
+
+fn build_element<'d>(doc: &'d dom4::Document<'d>, name: &str, value: &Json) -> Result<dom4::Element<'d>, Error> {
+    let element = doc.create_element(try!(valid_name(name, name)));
+
+    let members = match *value {
+        json::Object(ref members) => members,
+        json::Null => return Ok(element),
+        _ => {
+            // Be lenient and treat `{"a":"b"}` like `{"a":{"$":"b"}}`
+            let text = try!(scalar_text(value).ok_or(UnexpectedValue(name.to_string())));
+            element.append_child(doc.create_text(text.as_slice()));
+            return Ok(element);
+        },
+    };
+
+    for (key, value) in members.iter() {
+        let key = key.as_slice();
+
+        if key == "$" {
+            let text = try!(scalar_text(value).ok_or(UnexpectedValue(key.to_string())));
+            element.append_child(doc.create_text(text.as_slice()));
+        } else if key == "@xmlns" {
+            let namespaces = match *value {
+                json::Object(ref namespaces) => namespaces,
+                _ => return Err(UnexpectedValue(key.to_string())),
+            };
+
+            for (prefix, uri) in namespaces.iter() {
+                let uri = try!(scalar_text(uri).ok_or(UnexpectedValue(prefix.clone())));
+                let name = if prefix.as_slice() == "$" {
+                    NAMESPACE_DEFAULT.to_string()
+                } else {
+                    try!(valid_name(prefix.as_slice(), prefix.as_slice()));
+                    format!("{}{}", NAMESPACE_PREFIX, prefix)
+                };
+                element.set_attribute_value(name.as_slice(), uri.as_slice());
+            }
+        } else if key.starts_with("@") {
+            let name = try!(valid_name(key.slice_from(1), key));
+            let attr_value = try!(scalar_text(value).ok_or(UnexpectedValue(key.to_string())));
+            element.set_attribute_value(name, attr_value.as_slice());
+        } else {
+            for child in try!(build_elements(doc, key, value)).into_iter() {
+                element.append_child(child);
+            }
+        }
+    }
+
+    Ok(element)
+}
+
+fn build_elements<'d>(doc: &'d dom4::Document<'d>, name: &str, value: &Json) -> Result<Vec<dom4::Element<'d>>, Error> {
+    match *value {
+        json::List(ref items) => {
+            let mut elements = Vec::new();
+            for item in items.iter() {
+                elements.push(try!(build_element(doc, name, item)));
+            }
+            Ok(elements)
+        },
+        _ => Ok(vec![try!(build_element(doc, name, value))]),
+    }
+}
+
+/// Builds a new document from JSON
+pub fn parse(input: &str) -> Result<Package, Error> {
+    let value = try!(json::from_str(input).map_err(InvalidJson));
+
+    let members = match value {
+        json::Object(members) => members,
+        _ => return Err(MissingRootElement),
+    };
+
+    if members.len() > 1 { return Err(MultipleRootElements) }
+
+    let package = Package::new();
+    {
+        let doc = package.as_document();
+
+        let (name, value) = try!(members.iter().next().ok_or(MissingRootElement));
+        let mut elements = try!(build_elements(&doc, name.as_slice(), value));
+
+        if elements.len() > 1 { return Err(MultipleRootElements) }
+        let element = try!(elements.pop().ok_or(MissingRootElement));
+
+        doc.root().append_child(element);
+    }
+
+    Ok(package)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::MemWriter;
+
+    use super::super::{Package,dom4,writer};
+    use super::{format_document,parse,MissingRootElement,MultipleRootElements,UnexpectedValue,InvalidName};
+
+    macro_rules! assert_str_eq(
+        ($l:expr, $r:expr) => (assert_eq!($l.as_slice(), $r.as_slice()));
+    )
+
+    fn format_json<'d>(doc: &'d dom4::Document<'d>) -> String {
+        let mut w = MemWriter::new();
+        format_document(doc, &mut w).ok().expect("Not formatted");
+        String::from_utf8(w.unwrap()).ok().expect("Not a string")
+    }
+
+    fn round_trip_xml(json: &str) -> String {
+        let p = parse(json).ok().expect("Not parsed");
+        let d = p.as_document();
+        let mut w = MemWriter::new();
+        writer::format_document(&d, &mut w).ok().expect("Not formatted");
+        String::from_utf8(w.unwrap()).ok().expect("Not a string")
+    }
+
+    #[test]
+    fn element_with_text() {
+        let p = Package::new();
+        let d = p.as_document();
+        let alice = d.create_element("alice");
+        alice.append_child(d.create_text("bob"));
+        d.root().append_child(alice);
+
+        assert_str_eq!(format_json(&d), r#"{"alice":{"$":"bob"}}"#);
+    }
+
+    #[test]
+    fn element_with_attributes() {
+        let p = Package::new();
+        let d = p.as_document();
+        let alice = d.create_element("alice");
+        alice.set_attribute_value("charlie", "david");
+        d.root().append_child(alice);
+
+        assert_str_eq!(format_json(&d), r#"{"alice":{"@charlie":"david"}}"#);
+    }
+
+    #[test]
+    fn repeated_children_become_an_array() {
+        let p = Package::new();
+        let d = p.as_document();
+        let alice = d.create_element("alice");
+        alice.append_child(d.create_element("bob"));
+        alice.append_child(d.create_element("charlie"));
+        alice.append_child(d.create_element("bob"));
+        d.root().append_child(alice);
+
+        assert_str_eq!(format_json(&d), r#"{"alice":{"bob":[{},{}],"charlie":{}}}"#);
+    }
+
+    #[test]
+    fn mixed_content_text_is_concatenated() {
+        let p = Package::new();
+        let d = p.as_document();
+        let alice = d.create_element("alice");
+        alice.append_child(d.create_text("x"));
+        alice.append_child(d.create_element("bob"));
+        alice.append_child(d.create_text("y"));
+        d.root().append_child(alice);
+
+        assert_str_eq!(format_json(&d), r#"{"alice":{"$":"xy","bob":{}}}"#);
+    }
+
+    #[test]
+    fn namespace_declarations_are_grouped() {
+        let p = Package::new();
+        let d = p.as_document();
+        let alice = d.create_element("alice");
+        alice.set_attribute_value("xmlns", "urn:default");
+        alice.set_attribute_value("xmlns:c", "urn:c");
+        d.root().append_child(alice);
+
+        assert_str_eq!(format_json(&d), r#"{"alice":{"@xmlns":{"$":"urn:default","c":"urn:c"}}}"#);
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        let p = Package::new();
+        let d = p.as_document();
+        let alice = d.create_element("alice");
+        alice.append_child(d.create_text("\"quoted\"\n\\"));
+        d.root().append_child(alice);
+
+        assert_str_eq!(format_json(&d), r#"{"alice":{"$":"\"quoted\"\n\\"}}"#);
+    }
+
+    #[test]
+    fn parses_text_and_attributes() {
+        let xml = round_trip_xml(r#"{"alice":{"@charlie":"david","$":"bob"}}"#);
+        assert_str_eq!(xml, "<?xml version='1.0'?><alice charlie='david'>bob</alice>");
+    }
+
+    #[test]
+    fn parses_arrays_as_repeated_children() {
+        let xml = round_trip_xml(r#"{"alice":{"bob":[{"$":1},{"$":true}]}}"#);
+        assert_str_eq!(xml, "<?xml version='1.0'?><alice><bob>1</bob><bob>true</bob></alice>");
+    }
+
+    #[test]
+    fn parses_namespace_declarations() {
+        let xml = round_trip_xml(r#"{"alice":{"@xmlns":{"$":"urn:default"}}}"#);
+        assert_str_eq!(xml, "<?xml version='1.0'?><alice xmlns='urn:default'/>");
+    }
+
+    #[test]
+    fn parse_fails_without_a_root_element() {
+        assert_eq!(parse("{}"), Err(MissingRootElement));
+    }
+
+    #[test]
+    fn parse_fails_with_multiple_root_elements() {
+        assert_eq!(parse(r#"{"a":{},"b":{}}"#), Err(MultipleRootElements));
+        assert_eq!(parse(r#"{"a":[{},{}]}"#), Err(MultipleRootElements));
+    }
+
+    #[test]
+    fn parse_fails_with_an_unexpected_value() {
+        assert_eq!(parse(r#"{"a":{"@b":{}}}"#), Err(UnexpectedValue("@b".to_string())));
+    }
+
+    #[test]
+    fn parse_fails_with_invalid_element_names() {
+        assert_eq!(parse(r#"{"":{}}"#), Err(InvalidName("".to_string())));
+        assert_eq!(parse(r#"{"1 bad":{}}"#), Err(InvalidName("1 bad".to_string())));
+        assert_eq!(parse(r#"{"a":{"a<b":{}}}"#), Err(InvalidName("a<b".to_string())));
+    }
+
+    #[test]
+    fn parse_fails_with_invalid_attribute_names() {
+        assert_eq!(parse(r#"{"a":{"@":"x"}}"#), Err(InvalidName("@".to_string())));
+        assert_eq!(parse(r#"{"a":{"@1 bad":"x"}}"#), Err(InvalidName("@1 bad".to_string())));
+        assert_eq!(parse(r#"{"a":{"@a<b":"x"}}"#), Err(InvalidName("@a<b".to_string())));
+    }
+
+    #[test]
+    fn parse_fails_with_invalid_namespace_prefixes() {
+        assert_eq!(parse(r#"{"a":{"@xmlns":{"a<b":"urn:x"}}}"#), Err(InvalidName("a<b".to_string())));
+    }
+}
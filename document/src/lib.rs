@@ -23,23 +23,40 @@
 #![feature(macro_rules)]
 #![feature(if_let)]
 #![feature(default_type_params)]
+#![feature(unsafe_destructor)]
 
 extern crate arena;
+extern crate regex;
+extern crate serialize;
 extern crate test;
 extern crate xxhash;
 
 use std::fmt;
+use std::kinds::marker::ContravariantLifetime;
 
 mod string_pool;
 mod raw;
+mod xmlstr;
+mod datatypes;
+pub mod navigator;
 pub mod thindom4;
 pub mod dom4;
 pub mod parser;
 pub mod writer;
+pub mod json;
+pub mod dtd;
+pub mod xsd;
+pub mod relaxng;
+pub mod xinclude;
+pub mod catalog;
+pub mod history;
+pub mod stream;
+pub mod snapshot;
 
 pub struct Package {
     storage: raw::Storage,
     connections: raw::Connections,
+    observers: dom4::Observers,
 }
 
 impl Package {
@@ -49,11 +66,12 @@ impl Package {
         Package {
             storage: s,
             connections: raw::Connections::new(root),
+            observers: dom4::Observers::new(),
         }
     }
 
     pub fn as_document(&self) -> dom4::Document {
-        dom4::Document::new(&self.storage, &self.connections)
+        dom4::Document::new(&self.storage, &self.connections, &self.observers)
     }
 
     pub fn as_thin_document(&self) -> (thindom4::Storage, thindom4::Connections) {
@@ -74,3 +92,50 @@ impl fmt::Show for Package {
         write!(f, "Package")
     }
 }
+
+/// A package whose names and text may point directly into the string
+/// it was parsed from, instead of being copied into the package's own
+/// storage. Created by `parser::Parser::parse_borrowed`.
+///
+/// The package cannot outlive the string it was parsed from.
+pub struct BorrowedPackage<'i> {
+    storage: raw::Storage,
+    connections: raw::Connections,
+    observers: dom4::Observers,
+    lifetime: ContravariantLifetime<'i>,
+}
+
+impl<'i> BorrowedPackage<'i> {
+    fn new() -> BorrowedPackage<'i> {
+        let s = raw::Storage::new();
+        let root = s.create_root();
+        BorrowedPackage {
+            storage: s,
+            connections: raw::Connections::new(root),
+            observers: dom4::Observers::new(),
+            lifetime: ContravariantLifetime,
+        }
+    }
+
+    pub fn as_document(&self) -> dom4::Document {
+        dom4::Document::new(&self.storage, &self.connections, &self.observers)
+    }
+
+    pub fn as_thin_document(&self) -> (thindom4::Storage, thindom4::Connections) {
+        let s = thindom4::Storage::new(&self.storage);
+        let c = thindom4::Connections::new(&self.connections);
+        (s, c)
+    }
+}
+
+impl<'i> PartialEq for BorrowedPackage<'i> {
+    fn eq(&self, other: &BorrowedPackage<'i>) -> bool {
+        self as *const BorrowedPackage == other as *const BorrowedPackage
+    }
+}
+
+impl<'i> fmt::Show for BorrowedPackage<'i> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BorrowedPackage")
+    }
+}
@@ -0,0 +1,321 @@
+//! Read-only access to any tree of nodes
+//!
+//! `Navigator` describes how to move around a tree and what can be
+//! learned about each node, so that queries and other algorithms can be
+//! written once for every tree representation. It is implemented by
+//! `dom4::Document` for `dom4::Node` and by `thindom4::Connections` for
+//! `thindom4::Node`; other trees, such as an application's own syntax
+//! tree, can implement it as well.
+//!
+//! The model follows XPath: attributes belong to an element but are
+//! not among its children, and only the root has no parent.
+//!
+//! ### Example
+//! ```
+//! use document::Package;
+//! use document::dom4::{RootNode,ElementNode};
+//! use document::navigator::{Navigator,Element};
+//!
+//! let package = Package::new();
+//! let doc = package.as_document();
+//!
+//! let hello = doc.create_element("hello");
+//! hello.set_attribute_value("planet", "Earth");
+//! hello.append_child(doc.create_text("Greetings"));
+//! doc.root().append_child(hello);
+//!
+//! let root = RootNode(doc.root());
+//! let children = doc.children(&root);
+//! assert_eq!(children, vec![ElementNode(hello)]);
+//! assert_eq!(doc.kind(&children[0]), Element);
+//! assert_eq!(doc.name(&children[0]), Some("hello"));
+//! assert_eq!(doc.string_value(&root).as_slice(), "Greetings");
+//! ```
+
+use std::cmp::Ordering;
+
+/// What a node is, as far as a `Navigator` is concerned
+#[deriving(Show,Clone,PartialEq)]
+pub enum NodeKind {
+    Root,
+    Element,
+    Attribute,
+    Text,
+    Comment,
+    ProcessingInstruction,
+}
+
+/// Moves around a tree whose nodes are of type `N`. Only the required
+/// methods need to be implemented; the axes are built from them.
+pub trait Navigator<N : Clone + PartialEq> {
+    fn kind(&self, node: &N) -> NodeKind;
+
+    /// The element or root containing the node. For an attribute, this
+    /// is the element it belongs to.
+    fn parent(&self, node: &N) -> Option<N>;
+
+    /// The children of a root or element, in document order
+    fn children(&self, node: &N) -> Vec<N>;
+
+    /// The attributes of an element, in document order
+    fn attributes(&self, node: &N) -> Vec<N>;
+
+    /// The name of an element or attribute, or the target of a
+    /// processing instruction
+    fn name<'a>(&'a self, node: &'a N) -> Option<&'a str>;
+
+    /// The text of the node as XPath defines it. For a root or element,
+    /// this is all of the text beneath it.
+    fn string_value(&self, node: &N) -> String;
+
+    /// Every ancestor of the node, nearest first
+    fn ancestors(&self, node: &N) -> Vec<N> {
+        let mut ancestors = Vec::new();
+        let mut current = node.clone();
+
+        loop {
+            match self.parent(&current) {
+                Some(parent) => {
+                    ancestors.push(parent.clone());
+                    current = parent;
+                },
+                None => return ancestors,
+            }
+        }
+    }
+
+    /// Every node beneath the node, in document order, not including
+    /// attributes
+    fn descendants(&self, node: &N) -> Vec<N> {
+        let mut descendants = Vec::new();
+        let mut todo = self.children(node);
+        todo.reverse();
+
+        while ! todo.is_empty() {
+            let node = todo.pop().unwrap();
+            let mut children = self.children(&node);
+            children.reverse();
+            todo.extend(children.into_iter());
+            descendants.push(node);
+        }
+
+        descendants
+    }
+
+    /// The siblings after the node, in document order. Attributes have
+    /// no siblings.
+    fn following_siblings(&self, node: &N) -> Vec<N> {
+        let (siblings, index) = match self.siblings(node) {
+            Some(found) => found,
+            None => return Vec::new(),
+        };
+        siblings.slice_from(index + 1).to_vec()
+    }
+
+    /// The siblings before the node, nearest first. Attributes have no
+    /// siblings.
+    fn preceding_siblings(&self, node: &N) -> Vec<N> {
+        let (siblings, index) = match self.siblings(node) {
+            Some(found) => found,
+            None => return Vec::new(),
+        };
+        siblings.slice_to(index).iter().rev().map(|n| n.clone()).collect()
+    }
+
+    /// The children of the node's parent, along with the node's index
+    /// among them
+    fn siblings(&self, node: &N) -> Option<(Vec<N>, uint)> {
+        if self.kind(node) == Attribute { return None }
+
+        let siblings = match self.parent(node) {
+            Some(parent) => self.children(&parent),
+            None => return None,
+        };
+
+        let index = siblings.iter().position(|n| n == node);
+        match index {
+            Some(index) => Some((siblings, index)),
+            None => None,
+        }
+    }
+
+    /// Where the node is in its tree, as the steps to take from the
+    /// top down to it. An element's attributes come before its
+    /// children. Comparing positions gives document order.
+    fn position(&self, node: &N) -> Vec<uint> {
+        let mut steps = Vec::new();
+        let mut current = node.clone();
+
+        loop {
+            let parent = match self.parent(&current) {
+                Some(parent) => parent,
+                None => break,
+            };
+
+            let attributes = self.attributes(&parent);
+            let step = if self.kind(&current) == Attribute {
+                attributes.iter().position(|a| *a == current)
+            } else {
+                self.children(&parent).iter().position(|c| *c == current).map(|i| attributes.len() + i)
+            };
+
+            steps.push(step.unwrap_or(0));
+            current = parent;
+        }
+
+        steps.reverse();
+        steps
+    }
+
+    /// Compares two nodes of the same tree by document order
+    fn document_order(&self, a: &N, b: &N) -> Ordering {
+        self.position(a).cmp(&self.position(b))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Package;
+    use super::super::dom4::{RootNode,ElementNode,AttributeNode,TextNode,CommentNode};
+    use super::{Navigator,NodeKind,Root,Element};
+
+    #[test]
+    fn ancestors_are_nearest_first() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        let text = doc.create_text("gamma");
+        doc.root().append_child(alpha);
+        alpha.append_child(beta);
+        beta.append_child(text);
+
+        assert_eq!(doc.ancestors(&TextNode(text)),
+                   vec![ElementNode(beta), ElementNode(alpha), RootNode(doc.root())]);
+    }
+
+    #[test]
+    fn descendants_are_in_document_order() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let alpha = doc.create_element("alpha");
+        let beta = doc.create_element("beta");
+        let text = doc.create_text("gamma");
+        let comment = doc.create_comment("delta");
+        doc.root().append_child(alpha);
+        alpha.append_child(beta);
+        beta.append_child(text);
+        alpha.append_child(comment);
+        alpha.set_attribute_value("epsilon", "1");
+
+        assert_eq!(doc.descendants(&RootNode(doc.root())),
+                   vec![ElementNode(alpha), ElementNode(beta), TextNode(text), CommentNode(comment)]);
+    }
+
+    #[test]
+    fn siblings_are_nearest_first() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let parent = doc.create_element("parent");
+        let a = doc.create_element("a");
+        let b = doc.create_element("b");
+        let c = doc.create_element("c");
+        parent.append_child(a);
+        parent.append_child(b);
+        parent.append_child(c);
+
+        assert_eq!(doc.following_siblings(&ElementNode(a)), vec![ElementNode(b), ElementNode(c)]);
+        assert_eq!(doc.preceding_siblings(&ElementNode(c)), vec![ElementNode(b), ElementNode(a)]);
+        assert!(doc.following_siblings(&ElementNode(c)).is_empty());
+    }
+
+    #[test]
+    fn attributes_have_no_siblings() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let element = doc.create_element("element");
+        let first = element.set_attribute_value("first", "1");
+        element.set_attribute_value("second", "2");
+
+        assert!(doc.following_siblings(&AttributeNode(first)).is_empty());
+    }
+
+    #[test]
+    fn attributes_come_before_children_in_document_order() {
+        let package = Package::new();
+        let doc = package.as_document();
+
+        let parent = doc.create_element("parent");
+        let child = doc.create_element("child");
+        let grandchild = doc.create_element("grandchild");
+        let sibling = doc.create_element("sibling");
+        doc.root().append_child(parent);
+        parent.append_child(child);
+        child.append_child(grandchild);
+        parent.append_child(sibling);
+        let attribute = parent.set_attribute_value("name", "value");
+
+        let mut nodes = vec![
+            ElementNode(sibling), ElementNode(grandchild), AttributeNode(attribute),
+            ElementNode(child), ElementNode(parent), RootNode(doc.root()),
+        ];
+        nodes.sort_by(|a, b| doc.document_order(a, b));
+
+        assert_eq!(nodes, vec![
+            RootNode(doc.root()), ElementNode(parent), AttributeNode(attribute),
+            ElementNode(child), ElementNode(grandchild), ElementNode(sibling),
+        ]);
+    }
+
+    /// A tree that is nothing like a DOM: each node is an index, and
+    /// only its parent is stored
+    struct Outline {
+        parents: Vec<Option<uint>>,
+        names: Vec<&'static str>,
+    }
+
+    impl Navigator<uint> for Outline {
+        fn kind(&self, node: &uint) -> NodeKind {
+            if *node == 0 { Root } else { Element }
+        }
+
+        fn parent(&self, node: &uint) -> Option<uint> {
+            self.parents[*node]
+        }
+
+        fn children(&self, node: &uint) -> Vec<uint> {
+            range(0, self.parents.len()).filter(|&n| self.parents[n] == Some(*node)).collect()
+        }
+
+        fn attributes(&self, _: &uint) -> Vec<uint> {
+            Vec::new()
+        }
+
+        fn name<'a>(&'a self, node: &'a uint) -> Option<&'a str> {
+            if *node == 0 { None } else { Some(self.names[*node]) }
+        }
+
+        fn string_value(&self, _: &uint) -> String {
+            String::new()
+        }
+    }
+
+    #[test]
+    fn other_trees_can_be_navigated() {
+        let outline = Outline {
+            parents: vec![None, Some(0), Some(1), Some(0), Some(1)],
+            names: vec!["", "server", "port", "client", "host"],
+        };
+
+        let descendants = outline.descendants(&0);
+        let names: Vec<&str> = descendants.iter().filter_map(|n| outline.name(n)).collect();
+
+        assert_eq!(names, vec!["server", "port", "host", "client"]);
+        assert_eq!(outline.ancestors(&4), vec![1, 0]);
+        assert_eq!(outline.following_siblings(&2), vec![4]);
+    }
+}
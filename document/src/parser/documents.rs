@@ -0,0 +1,518 @@
+//! Parses a stream of documents written back to back.
+//!
+//! The extent of each document is found first by looking only at the
+//! markup: tags are counted until the document element is closed.
+//! Only then is the document handed to the strict parser, so a
+//! malformed document never affects where the next one starts.
+//!
+//! An XML declaration can only begin a document, so one appearing
+//! part of the way through a document ends that document early.
+//! This keeps an element that is never closed from swallowing the
+//! rest of a stream whose documents have declarations.
+//!
+//! Each document is bounded by the parser's `DocumentSize` limit.
+//! When a document read from a stream grows past it without ending,
+//! it is reported as `Malformed` and nothing more is read.
+
+use std::io::{IoError,IoResult,EndOfFile};
+use std::str;
+
+use super::{Parser,ParseError,SyntaxError,LimitExceeded,DocumentSize};
+use super::super::Package;
+
+static CHUNK_SIZE: uint = 8192;
+
+/// Why a document of a stream read by `Parser::read_stream` could not
+/// be parsed. Offsets are into the whole stream.
+#[deriving(Show,Clone,PartialEq)]
+pub enum StreamError {
+    /// The underlying reader failed; nothing more is read
+    ReadFailure(IoError),
+    /// The document starting at this offset is not UTF-8
+    NotUtf8(uint),
+    /// The document is not well-formed
+    Malformed(ParseError),
+}
+
+fn moved_by(error: ParseError, offset: uint) -> ParseError {
+    match error {
+        SyntaxError(o) => SyntaxError(o + offset),
+        LimitExceeded(limit, o) => LimitExceeded(limit, o + offset),
+    }
+}
+
+fn is_space(b: u8) -> bool {
+    b == b' ' || b == b'\t' || b == b'\r' || b == b'\n'
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<uint> {
+    if needle.len() > haystack.len() { return None }
+    range(0, haystack.len() - needle.len() + 1).find(|&i| {
+        haystack.slice_from(i).starts_with(needle)
+    })
+}
+
+/// Whether the input ends part of the way through the literal, so
+/// that more input is needed to tell if it is there
+fn may_become(input: &[u8], literal: &[u8]) -> bool {
+    input.len() < literal.len() && literal.starts_with(input)
+}
+
+/// The length of a start tag, up to and including its `>`, skipping
+/// over quoted attribute values
+fn start_tag_length(tag: &[u8]) -> Option<uint> {
+    let mut quote = None;
+    for (i, &b) in tag.iter().enumerate() {
+        match quote {
+            Some(q) => if b == q { quote = None },
+            None if b == b'"' || b == b'\'' => quote = Some(b),
+            None if b == b'>' => return Some(i + 1),
+            None => {},
+        }
+    }
+    None
+}
+
+/// The length of a document type declaration. Only the brackets of
+/// the internal subset are looked for, not its declarations.
+fn doctype_length(doctype: &[u8]) -> Option<uint> {
+    let bracket = doctype.iter().position(|&b| b == b'[');
+    let close = doctype.iter().position(|&b| b == b'>');
+
+    match (bracket, close) {
+        (Some(b), Some(c)) if b < c => {
+            let after = b + 1;
+            find(doctype.slice_from(after), b"]").and_then(|e| {
+                let after = after + e;
+                find(doctype.slice_from(after), b">").map(|c| after + c + 1)
+            })
+        },
+        (_, close) => close.map(|c| c + 1),
+    }
+}
+
+/// Finds where each document starts and ends. Input may arrive a
+/// piece at a time; scanning resumes at the last piece of markup that
+/// was not yet complete.
+struct Boundaries {
+    /// Where the current document starts, once anything other than
+    /// whitespace has been seen
+    start: Option<uint>,
+    /// Where scanning resumes
+    position: uint,
+    depth: uint,
+}
+
+impl Boundaries {
+    fn new() -> Boundaries {
+        Boundaries { start: None, position: 0, depth: 0 }
+    }
+
+    /// Scans as much of the input as possible, returning the extent
+    /// of the current document once its document element is closed
+    fn next(&mut self, input: &[u8]) -> Option<(uint, uint)> {
+        loop {
+            let rest = input.slice_from(self.position);
+
+            if self.start.is_none() {
+                match rest.iter().position(|&b| ! is_space(b)) {
+                    Some(n) => {
+                        self.position += n;
+                        self.start = Some(self.position);
+                        continue;
+                    },
+                    None => {
+                        self.position = input.len();
+                        return None;
+                    },
+                }
+            }
+
+            if rest.is_empty() { return None }
+
+            if rest[0] != b'<' {
+                match rest.iter().position(|&b| b == b'<') {
+                    Some(n) => self.position += n,
+                    None => self.position = input.len(),
+                }
+                continue;
+            }
+
+            if rest.len() < 2 { return None }
+
+            if rest[1] == b'?' {
+                if rest.len() < 6 { return None }
+                let declaration = rest.starts_with(b"<?xml") && is_space(rest[5]);
+                if declaration && Some(self.position) != self.start {
+                    let position = self.position;
+                    return Some(self.finish(position));
+                }
+
+                match find(rest, b"?>") {
+                    Some(n) => self.position += n + 2,
+                    None => return None,
+                }
+            } else if rest[1] == b'!' {
+                if may_become(rest, b"<!--") || may_become(rest, b"<![CDATA[") { return None }
+
+                let length = if rest.starts_with(b"<!--") {
+                    find(rest, b"-->").map(|n| n + 3)
+                } else if rest.starts_with(b"<![CDATA[") {
+                    find(rest, b"]]>").map(|n| n + 3)
+                } else {
+                    doctype_length(rest)
+                };
+
+                match length {
+                    Some(n) => self.position += n,
+                    None => return None,
+                }
+            } else if rest[1] == b'/' {
+                let length = match rest.iter().position(|&b| b == b'>') {
+                    Some(n) => n + 1,
+                    None => return None,
+                };
+
+                self.position += length;
+                if self.depth <= 1 {
+                    let position = self.position;
+                    return Some(self.finish(position));
+                }
+                self.depth -= 1;
+            } else {
+                let length = match start_tag_length(rest) {
+                    Some(n) => n,
+                    None => return None,
+                };
+
+                self.position += length;
+                if rest[length - 2] != b'/' {
+                    self.depth += 1;
+                } else if self.depth == 0 {
+                    let position = self.position;
+                    return Some(self.finish(position));
+                }
+            }
+        }
+    }
+
+    fn finish(&mut self, end: uint) -> (uint, uint) {
+        let start = self.start.take().expect("No document started");
+        self.position = end;
+        self.depth = 0;
+        (start, end)
+    }
+
+    /// Ends the current document with the input, when it was never
+    /// finished
+    fn rest(&mut self, input: &[u8]) -> Option<(uint, uint)> {
+        match self.start {
+            Some(_) => Some(self.finish(input.len())),
+            None => None,
+        }
+    }
+
+    /// Forgets the first part of the input, which must already have
+    /// been scanned
+    fn discard(&mut self, length: uint) {
+        self.position -= length;
+        self.start = self.start.map(|s| s - length);
+    }
+}
+
+/// The documents of a string, created by `Parser::parse_stream`
+pub struct Documents<'p, 'a> {
+    parser: &'p Parser,
+    xml: &'a str,
+    boundaries: Boundaries,
+}
+
+impl<'p, 'a> Iterator<Result<Package, ParseError>> for Documents<'p, 'a> {
+    fn next(&mut self) -> Option<Result<Package, ParseError>> {
+        let input = self.xml.as_bytes();
+        let extent = match self.boundaries.next(input) {
+            Some(extent) => extent,
+            None => match self.boundaries.rest(input) {
+                Some(extent) => extent,
+                None => return None,
+            },
+        };
+
+        let (start, end) = extent;
+        Some(self.parser.parse(self.xml.slice(start, end)).map_err(|e| moved_by(e, start)))
+    }
+}
+
+/// The documents of a reader, created by `Parser::read_stream`
+pub struct ReadDocuments<'p, R> {
+    parser: &'p Parser,
+    reader: R,
+    buffer: Vec<u8>,
+    /// How much of the stream came before the buffer
+    consumed: uint,
+    boundaries: Boundaries,
+    at_end: bool,
+}
+
+impl<'p, R : Reader> ReadDocuments<'p, R> {
+    fn fill(&mut self) -> IoResult<()> {
+        let mut chunk = [0u8, ..CHUNK_SIZE];
+        match self.reader.read(&mut chunk) {
+            Ok(n) => {
+                self.buffer.push_all(chunk.slice_to(n));
+                Ok(())
+            },
+            Err(ref e) if e.kind == EndOfFile => {
+                self.at_end = true;
+                Ok(())
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Ends the stream after an error
+    fn stop(&mut self) {
+        self.at_end = true;
+        self.buffer.clear();
+        self.boundaries = Boundaries::new();
+    }
+
+    /// Whether the unfinished document starting at `start` is already
+    /// larger than the limit allows
+    fn oversized(&self, start: uint) -> bool {
+        match self.parser.limits.maximum(DocumentSize) {
+            Some(max) => self.buffer.len() - start > max,
+            None => false,
+        }
+    }
+
+    fn discard(&mut self, length: uint) {
+        self.buffer = self.buffer.slice_from(length).to_vec();
+        self.consumed += length;
+        self.boundaries.discard(length);
+    }
+
+    fn take(&mut self, start: uint, end: uint) -> Result<Package, StreamError> {
+        let offset = self.consumed + start;
+        let result = match str::from_utf8(self.buffer.slice(start, end)) {
+            Some(xml) => self.parser.parse(xml).map_err(|e| Malformed(moved_by(e, offset))),
+            None => Err(NotUtf8(offset)),
+        };
+        self.discard(end);
+        result
+    }
+}
+
+impl<'p, R : Reader> Iterator<Result<Package, StreamError>> for ReadDocuments<'p, R> {
+    fn next(&mut self) -> Option<Result<Package, StreamError>> {
+        loop {
+            let extent = self.boundaries.next(self.buffer.as_slice());
+            if let Some((start, end)) = extent {
+                return Some(self.take(start, end));
+            }
+
+            if self.at_end {
+                let extent = self.boundaries.rest(self.buffer.as_slice());
+                return match extent {
+                    Some((start, end)) => Some(self.take(start, end)),
+                    None => None,
+                };
+            }
+
+            match self.boundaries.start {
+                Some(start) if self.oversized(start) => {
+                    let max = self.parser.limits.maximum(DocumentSize).unwrap_or(0);
+                    let offset = self.consumed + start + max;
+                    self.stop();
+                    return Some(Err(Malformed(LimitExceeded(DocumentSize, offset))));
+                },
+                Some(_) => {},
+                // Whitespace between documents is not kept
+                None => {
+                    let scanned = self.boundaries.position;
+                    self.discard(scanned);
+                },
+            }
+
+            if let Err(e) = self.fill() {
+                self.stop();
+                return Some(Err(ReadFailure(e)));
+            }
+        }
+    }
+}
+
+impl Parser {
+    /// Parses each of the documents in the string, which are written
+    /// one after another. Error offsets are into the whole string.
+    pub fn parse_stream<'p, 'a>(&'p self, xml: &'a str) -> Documents<'p, 'a> {
+        Documents {
+            parser: self,
+            xml: xml,
+            boundaries: Boundaries::new(),
+        }
+    }
+
+    /// Like `parse_stream`, reading only as much as is needed for each
+    /// document
+    pub fn read_stream<'p, R : Reader>(&'p self, reader: R) -> ReadDocuments<'p, R> {
+        ReadDocuments {
+            parser: self,
+            reader: reader,
+            buffer: Vec::new(),
+            consumed: 0,
+            boundaries: Boundaries::new(),
+            at_end: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp::min;
+    use std::io::{BufReader,IoError,IoResult,OtherIoError,EndOfFile,standard_error};
+    use std::slice::bytes::copy_memory;
+    use super::super::{Parser,Limits,SyntaxError,LimitExceeded,DocumentSize};
+    use super::super::super::Package;
+    use super::{ReadFailure,NotUtf8,Malformed};
+
+    fn root_name(package: &Package) -> String {
+        let doc = package.as_document();
+        doc.root().children()[0].element().unwrap().name().to_string()
+    }
+
+    fn names<E>(results: Vec<Result<Package, E>>) -> Vec<Option<String>> {
+        results.iter().map(|r| r.as_ref().ok().map(|p| root_name(p))).collect()
+    }
+
+    #[test]
+    fn each_document_is_parsed() {
+        let parser = Parser::new();
+        let results = parser.parse_stream("<a>1</a><b/>\n<c x='>'><d/></c>").collect();
+
+        assert_eq!(names(results), vec![Some("a".to_string()), Some("b".to_string()), Some("c".to_string())]);
+    }
+
+    #[test]
+    fn prologs_belong_to_the_following_document() {
+        let parser = Parser::new();
+        let xml = "<?xml version='1.0'?><!-- first --><a/>\n<?xml version='1.0'?><!DOCTYPE b [<!ELEMENT b ANY>]><b/>";
+        let results: Vec<_> = parser.parse_stream(xml).collect();
+
+        assert_eq!(results.len(), 2);
+        let doc = results[1].as_ref().unwrap().as_document();
+        assert_eq!(doc.root().doctype().map(|d| d.name().to_string()), Some("b".to_string()));
+    }
+
+    #[test]
+    fn malformed_documents_do_not_affect_the_others() {
+        let parser = Parser::new();
+        let results: Vec<_> = parser.parse_stream("<a/><b></c><d/>").collect();
+
+        assert_eq!(results[1].as_ref().err(), Some(&SyntaxError(7)));
+        assert_eq!(names(results), vec![Some("a".to_string()), None, Some("d".to_string())]);
+    }
+
+    #[test]
+    fn declarations_end_unclosed_documents() {
+        let parser = Parser::new();
+        let xml = "<?xml version='1.0'?><a><b>\n<?xml version='1.0'?><c/>";
+        let results = parser.parse_stream(xml).collect();
+
+        assert_eq!(names(results), vec![None, Some("c".to_string())]);
+    }
+
+    #[test]
+    fn large_documents_are_rejected() {
+        let parser = Parser::with_limits(Limits { max_document_size: Some(8), ..Limits::none() });
+        let results: Vec<_> = parser.parse_stream("<a/><b>too long</b><c/>").collect();
+
+        assert_eq!(results[1].as_ref().err(), Some(&LimitExceeded(DocumentSize, 12)));
+        assert_eq!(names(results), vec![Some("a".to_string()), None, Some("c".to_string())]);
+    }
+
+    #[test]
+    fn trailing_whitespace_is_not_a_document() {
+        let parser = Parser::new();
+        assert_eq!(parser.parse_stream("  \n").count(), 0);
+        assert_eq!(parser.parse_stream("<a/>\n\n").count(), 1);
+    }
+
+    /// Gives out its input a few bytes at a time
+    struct Trickle<'a> {
+        input: &'a [u8],
+    }
+
+    impl<'a> Reader for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+            if self.input.is_empty() { return Err(standard_error(EndOfFile)) }
+
+            let n = min(min(3, self.input.len()), buf.len());
+            copy_memory(buf, self.input.slice_to(n));
+            self.input = self.input.slice_from(n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn documents_can_be_read_a_piece_at_a_time() {
+        let parser = Parser::new();
+        let input = Trickle { input: b"<a><!-- <b> --></a> <b x='/>'/><c><![CDATA[</c>]]></c>" };
+        let results = parser.read_stream(input).collect();
+
+        assert_eq!(names(results), vec![Some("a".to_string()), Some("b".to_string()), Some("c".to_string())]);
+    }
+
+    #[test]
+    fn read_errors_are_offset_into_the_stream() {
+        let parser = Parser::new();
+        let input = BufReader::new(b"<a/>\n<b></c>\n<d\xff/>");
+        let results: Vec<_> = parser.read_stream(input).collect();
+
+        assert_eq!(results[1].as_ref().err(), Some(&Malformed(SyntaxError(8))));
+        assert_eq!(results[2].as_ref().err(), Some(&NotUtf8(13)));
+    }
+
+    /// A document element whose children never end
+    struct Endless {
+        started: bool,
+    }
+
+    impl Reader for Endless {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+            let markup: &[u8] = if self.started { b"<x/>" } else { b"<stream>" };
+            self.started = true;
+            copy_memory(buf, markup);
+            Ok(markup.len())
+        }
+    }
+
+    #[test]
+    fn reading_stops_when_a_document_grows_too_large() {
+        let parser = Parser::with_limits(Limits { max_document_size: Some(100), ..Limits::none() });
+        let results: Vec<_> = parser.read_stream(Endless { started: false }).collect();
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].as_ref().err(), Some(&Malformed(LimitExceeded(DocumentSize, 100))));
+    }
+
+    struct Broken;
+
+    impl Reader for Broken {
+        fn read(&mut self, _: &mut [u8]) -> IoResult<uint> {
+            Err(IoError { kind: OtherIoError, desc: "broken", detail: None })
+        }
+    }
+
+    #[test]
+    fn reading_stops_when_the_reader_fails() {
+        let parser = Parser::new();
+        let results: Vec<_> = parser.read_stream(Broken).collect();
+
+        assert_eq!(results.len(), 1);
+        match results[0] {
+            Err(ReadFailure(ref e)) => assert_eq!(e.kind, OtherIoError),
+            _ => panic!("expected a read failure"),
+        }
+    }
+}
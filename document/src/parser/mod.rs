@@ -29,6 +29,112 @@
 //! the *most interesting error* is the one that occurred last in the input.
 //! We assume that this will be closest to what the user intended.
 //!
+//! Exceeding one of the configured `Limits` is never recovered from;
+//! it immediately stops parsing and is reported as `LimitExceeded`.
+//!
+//! ### Resource limits
+//!
+//! By default, the parser only bounds how much entity expansion may
+//! produce, with `Limits::default()`. `Limits::none()` lifts that
+//! bound too. When parsing untrusted input, use `Parser::with_limits`:
+//!
+//! ```
+//! use document::parser::{Parser,Limits};
+//! let parser = Parser::with_limits(Limits::untrusted());
+//! let doc = parser.parse("<hello/>").ok().expect("Failed to parse");
+//! ```
+//!
+//! ### External resources
+//!
+//! When a document type declaration has a public or system
+//! identifier, the parser asks its `EntityResolver` for the external
+//! subset and records it on the `DocumentType`. The default resolver,
+//! `DenyAll`, refuses every request so that parsing never reads files
+//! or the network. Use `Parser::set_resolver` with a resolver such as
+//! `catalog::Catalog` to allow specific resources. A subset that cannot
+//! be resolved is skipped.
+//!
+//! References to general entities declared in either subset are
+//! replaced by their text, within the same expansion limits. A
+//! reference to an entity that is not declared is a syntax error.
+//!
+//! ### Fragments
+//!
+//! `Parser::parse_fragment` parses element content, such as
+//! `<b>bold</b> text`, and appends it to an existing element:
+//!
+//! ```
+//! use document::Package;
+//! use document::parser::Parser;
+//! let package = Package::new();
+//! let doc = package.as_document();
+//! let p = doc.create_element("p");
+//!
+//! Parser::new().parse_fragment("<b>bold</b> text", p).ok().expect("Failed to parse");
+//! assert_eq!(p.children().len(), 2);
+//! ```
+//!
+//! Prefixed names are kept as written, so they resolve against the
+//! namespace declarations in scope at the element.
+//!
+//! ### Preserving the input
+//!
+//! `Parser::parse_lossless` also records how each node was written:
+//! quotes and spacing in tags, references, CDATA sections, whitespace
+//! outside the document element and the XML declaration. The writer
+//! reproduces the recorded markup exactly for nodes that have not been
+//! changed since, so editing one attribute changes only that
+//! attribute in the output.
+//!
+//! ```
+//! use std::io::MemWriter;
+//! use document::parser::Parser;
+//! use document::writer::format_document;
+//! let xml = "<project  version=\"1.0\">&#169; 2014</project>\n";
+//! let package = Parser::new().parse_lossless(xml).ok().expect("Failed to parse");
+//! let doc = package.as_document();
+//!
+//! let project = doc.root().children()[0].element().unwrap();
+//! project.set_attribute_value("version", "1.1");
+//!
+//! let mut output = MemWriter::new();
+//! format_document(&doc, &mut output).ok().expect("Failed to format");
+//! assert_eq!(output.unwrap().as_slice(), b"<project  version=\"1.1\">&#169; 2014</project>\n");
+//! ```
+//!
+//! ### Recovering from errors
+//!
+//! `Parser::parse_leniently` never gives up. It repairs what it can,
+//! such as closing unclosed elements and skipping malformed attributes,
+//! and reports each repair as a `Diagnostic`:
+//!
+//! ```
+//! use document::parser::{Parser,UnclosedElement};
+//! let (_package, diagnostics) = Parser::new().parse_leniently("<a><b>text</a>");
+//! assert_eq!(diagnostics, vec![UnclosedElement(3)]);
+//! ```
+//!
+//! ### Streaming
+//!
+//! `Parser::parse_events` reports the document to an `EventHandler`
+//! instead of building a tree, for callers that only need to look at
+//! it once.
+//!
+//! Some inputs hold many documents back to back, such as logs or
+//! message queue dumps. `Parser::parse_stream` and
+//! `Parser::read_stream` yield one package per document; a malformed
+//! document is reported as an error without affecting the others:
+//!
+//! ```
+//! use document::parser::Parser;
+//! let parser = Parser::new();
+//! let results: Vec<_> = parser.parse_stream("<a/>\n<b></c>\n<d/>").collect();
+//! assert_eq!(results.len(), 3);
+//! assert!(results[0].is_ok());
+//! assert!(results[1].is_err());
+//! assert!(results[2].is_ok());
+//! ```
+//!
 //! ### Unresolved questions:
 //!
 //! - Should zero-or-one mimic zero-or-more?
@@ -37,7 +143,7 @@
 //!
 //! ### Known issues
 //!
-//! - `panic!` is used in recoverable situations.
+//! - Entities whose replacement text contains markup are not expanded.
 //!
 //! ### Influences
 //!
@@ -46,22 +152,190 @@
 use std::ascii::AsciiExt;
 use std::char::from_u32;
 use std::num::from_str_radix;
-use std::cell::RefCell;
+use std::cell::{Cell,RefCell};
+use std::default::Default;
+use std::io::IoError;
+use std::mem;
 
-use self::xmlstr::XmlStr;
+use super::xmlstr::{XmlStr,XmlChar,predefined_entity};
 
 use super::dom4;
+use super::dtd;
+use super::dtd::Dtd;
+use super::raw;
+use super::string_pool::InternedString;
+
+pub use self::recovery::{Diagnostic,UnclosedElement,UnmatchedEndTag,MalformedAttribute};
+pub use self::recovery::{UnknownReference,MalformedMarkup,ContentOutsideRoot,ExtraRootElement};
+pub use self::recovery::StoppedAtLimit;
+pub use self::documents::{Documents,ReadDocuments,StreamError,ReadFailure,NotUtf8,Malformed};
+
+mod recovery;
+mod documents;
+
+pub struct Parser {
+    limits: Limits,
+    resolver: Box<EntityResolver + 'static>,
+    depth: Cell<uint>,
+    nodes: Cell<uint>,
+    attributes: Cell<uint>,
+    /// The declarations of the document being parsed. It also counts
+    /// every expansion, so that references in the document and in the
+    /// declarations share one limit.
+    dtd: RefCell<Dtd>,
+}
+
+/// The resource that was exhausted when a `Limits` was exceeded
+#[deriving(Show,Clone,PartialEq)]
+pub enum Limit {
+    Depth,
+    Attributes,
+    NameLength,
+    TextLength,
+    Nodes,
+    EntityExpansions,
+    ExpandedLength,
+    DocumentSize,
+}
+
+/// Bounds on how much of the input the parser will accept.
+/// `None` means that aspect is unbounded.
+#[deriving(Show,Clone,PartialEq)]
+pub struct Limits {
+    /// How deeply elements may be nested
+    pub max_depth: Option<uint>,
+    /// How many attributes a single element may have
+    pub max_attributes: Option<uint>,
+    /// The longest element, attribute or processing instruction name, in bytes
+    pub max_name_length: Option<uint>,
+    /// The longest single run of text, CDATA, comment,
+    /// processing instruction value or attribute value, in bytes
+    pub max_text_length: Option<uint>,
+    /// How many elements, attributes, texts, comments and processing
+    /// instructions the document may contain in total
+    pub max_nodes: Option<uint>,
+    /// How many entity and character references may be expanded in total
+    pub max_entity_expansions: Option<uint>,
+    /// How much text, in bytes, expanding entities may produce in total
+    pub max_expanded_length: Option<uint>,
+    /// The longest document, in bytes, including each document of a
+    /// stream
+    pub max_document_size: Option<uint>,
+}
+
+impl Limits {
+    /// No limits at all
+    pub fn none() -> Limits {
+        Limits {
+            max_depth: None,
+            max_attributes: None,
+            max_name_length: None,
+            max_text_length: None,
+            max_nodes: None,
+            max_entity_expansions: None,
+            max_expanded_length: None,
+            max_document_size: None,
+        }
+    }
+
+    /// Limits suitable for documents from an untrusted source
+    pub fn untrusted() -> Limits {
+        Limits {
+            max_depth: Some(256),
+            max_attributes: Some(256),
+            max_name_length: Some(1024),
+            max_text_length: Some(10 * 1024 * 1024),
+            max_nodes: Some(1000000),
+            max_entity_expansions: Some(100000),
+            max_expanded_length: Some(10 * 1024 * 1024),
+            max_document_size: Some(64 * 1024 * 1024),
+        }
+    }
+
+    /// The bound on the resource, if there is one
+    pub fn maximum(&self, limit: Limit) -> Option<uint> {
+        match limit {
+            Depth            => self.max_depth,
+            Attributes       => self.max_attributes,
+            NameLength       => self.max_name_length,
+            TextLength       => self.max_text_length,
+            Nodes            => self.max_nodes,
+            EntityExpansions => self.max_entity_expansions,
+            ExpandedLength   => self.max_expanded_length,
+            DocumentSize     => self.max_document_size,
+        }
+    }
+}
+
+/// Bounds entity expansion as `untrusted` does, and nothing else, so
+/// that a few declarations cannot expand into gigabytes of text.
+/// `Parser::new` uses these; ask for `Limits::none()` to lift them.
+impl Default for Limits {
+    fn default() -> Limits {
+        let untrusted = Limits::untrusted();
+        Limits {
+            max_entity_expansions: untrusted.max_entity_expansions,
+            max_expanded_length: untrusted.max_expanded_length,
+            ..Limits::none()
+        }
+    }
+}
+
+/// Why an external resource could not be retrieved
+#[deriving(Show,Clone,PartialEq)]
+pub enum ResolveError {
+    /// The resolver does not allow this resource to be loaded
+    ResolutionDenied,
+    /// The resolver does not know where to find this resource
+    UnknownIdentifier,
+    /// The resource was found but could not be read
+    UnreadableResource(IoError),
+}
+
+/// Maps the public and system identifiers of external entities and
+/// DTD subsets to their content
+pub trait EntityResolver {
+    fn resolve(&self, public_id: Option<&str>, system_id: Option<&str>) -> Result<String, ResolveError>;
+}
 
-mod xmlstr;
+/// Refuses to resolve anything, which prevents XML external entity
+/// (XXE) attacks
+pub struct DenyAll;
 
-pub struct Parser;
+impl EntityResolver for DenyAll {
+    fn resolve(&self, _public_id: Option<&str>, _system_id: Option<&str>) -> Result<String, ResolveError> {
+        Err(ResolutionDenied)
+    }
+}
+
+/// Why the input could not be parsed.
+/// Each variant carries the byte offset into the input.
+#[deriving(Show,Clone,PartialEq)]
+pub enum ParseError {
+    /// The input is not well-formed XML
+    SyntaxError(uint),
+    /// The input exceeded one of the parser's `Limits`
+    LimitExceeded(Limit, uint),
+}
+
+impl ParseError {
+    pub fn offset(&self) -> uint {
+        match *self {
+            SyntaxError(offset) => offset,
+            LimitExceeded(_, offset) => offset,
+        }
+    }
+}
 
 #[deriving(Show)]
 enum AttributeValue<'a> {
-    ReferenceAttributeValue(Reference<'a>),
+    ReferenceAttributeValue(String),
     LiteralAttributeValue(&'a str),
 }
 
+/// The public and system identifiers of an external resource
+type ExternalId<'a> = (Option<&'a str>, Option<&'a str>);
+
 #[deriving(Show)]
 enum Reference<'a> {
     EntityReference(&'a str),
@@ -88,7 +362,10 @@ impl<'a> BestFailure<'a> {
 
     fn push(&mut self, failure: ParseFailure<'a>) {
         if let Some(old) = self.failure {
-            if failure.point.offset <= old.point.offset {
+            if old.is_fatal() {
+                return;
+            }
+            if ! failure.is_fatal() && failure.point.offset <= old.point.offset {
                 return;
             }
         }
@@ -141,7 +418,10 @@ macro_rules! parse_optional(
         match $parser {
             Success((value, next)) => (Some(value), next),
             Partial((value, _, next)) => (Some(value), next),
-            Failure(_) => (None, $start),
+            Failure(pf) => {
+                if pf.is_fatal() { return Failure(pf) }
+                (None, $start)
+            },
         }
     })
 )
@@ -159,10 +439,14 @@ macro_rules! parse_alternate_rec(
             Success((val, next)) => Success(($transformer(val), next)),
             Partial((_, pf, _)) |
             Failure(pf) => {
-                $errors.push(pf);
-                parse_alternate_rec!($start, $errors, {
-                    $([$parser_rest -> $transformer_rest],)*
-                })
+                if pf.is_fatal() {
+                    Failure(pf)
+                } else {
+                    $errors.push(pf);
+                    parse_alternate_rec!($start, $errors, {
+                        $([$parser_rest -> $transformer_rest],)*
+                    })
+                }
             },
         }
     );
@@ -190,6 +474,7 @@ macro_rules! parse_zero_or_more(
                 Success(x) => x,
                 Partial((_, pf, _)) |
                 Failure(pf) => {
+                    if pf.is_fatal() { return Failure(pf) }
                     err = Some(pf);
                     break
                 }
@@ -209,6 +494,11 @@ struct StartPoint<'a> {
 }
 
 impl<'a> StartPoint<'a> {
+    /// The input from here to a later point
+    fn up_to(&self, end: StartPoint<'a>) -> &'a str {
+        self.s.slice_to(end.offset - self.offset)
+    }
+
     fn slice_at(&self, position: uint) -> (&'a str, StartPoint<'a>) {
         (self.s.slice_to(position), StartPoint{offset: self.offset + position,
                                                s: self.s.slice_from(position)})
@@ -216,7 +506,7 @@ impl<'a> StartPoint<'a> {
 
     fn consume_to(&self, l: Option<uint>) -> ParseResult<'a, &'a str> {
         match l {
-            None => Failure(ParseFailure{point: self.clone()}),
+            None => Failure(ParseFailure::syntax(self.clone())),
             Some(position) => Success(self.slice_at(position)),
         }
     }
@@ -268,10 +558,41 @@ impl<'a> StartPoint<'a> {
     fn consume_start_tag(&self) -> ParseResult<'a, &'a str> {
         self.consume_to(self.s.end_of_start_tag())
     }
+
+    fn consume_system_literal(&self, quote: &str) -> ParseResult<'a, &'a str> {
+        self.consume_to(self.s.end_of_system_literal(quote))
+    }
+
+    fn consume_internal_subset(&self) -> ParseResult<'a, &'a str> {
+        self.consume_to(self.s.end_of_internal_subset())
+    }
 }
 
 struct ParseFailure<'a> {
     point: StartPoint<'a>,
+    limit: Option<Limit>,
+}
+
+impl<'a> ParseFailure<'a> {
+    fn syntax(point: StartPoint<'a>) -> ParseFailure<'a> {
+        ParseFailure { point: point, limit: None }
+    }
+
+    fn exceeded(point: StartPoint<'a>, limit: Limit) -> ParseFailure<'a> {
+        ParseFailure { point: point, limit: Some(limit) }
+    }
+
+    /// Fatal failures stop parsing instead of trying alternatives
+    fn is_fatal(&self) -> bool {
+        self.limit.is_some()
+    }
+
+    fn to_error(&self) -> ParseError {
+        match self.limit {
+            Some(limit) => LimitExceeded(limit, self.point.offset),
+            None => SyntaxError(self.point.offset),
+        }
+    }
 }
 
 enum ParseResult<'a, T> {
@@ -281,8 +602,59 @@ enum ParseResult<'a, T> {
 }
 
 impl Parser {
+    /// A parser with the `Limits::default()` bounds on entity
+    /// expansion
     pub fn new() -> Parser {
-        Parser
+        Parser::with_limits(Default::default())
+    }
+
+    pub fn with_limits(limits: Limits) -> Parser {
+        Parser {
+            resolver: box DenyAll as Box<EntityResolver + 'static>,
+            depth: Cell::new(0),
+            nodes: Cell::new(0),
+            attributes: Cell::new(0),
+            dtd: RefCell::new(Dtd::with_limits(limits.clone())),
+            limits: limits,
+        }
+    }
+
+    /// Replaces the resolver used to retrieve external subsets
+    pub fn set_resolver<R: EntityResolver + 'static>(&mut self, resolver: R) {
+        self.resolver = box resolver as Box<EntityResolver + 'static>;
+    }
+
+    fn enforce<'a>(&self, xml: StartPoint<'a>, limit: Limit, value: uint) -> ParseResult<'a, ()> {
+        match self.limits.maximum(limit) {
+            Some(max) if value > max => Failure(ParseFailure::exceeded(xml, limit)),
+            _ => Success(((), xml)),
+        }
+    }
+
+    fn count<'a>(&self, xml: StartPoint<'a>, counter: &Cell<uint>, limit: Limit) -> ParseResult<'a, ()> {
+        let value = counter.get() + 1;
+        try_parse!(self.enforce(xml, limit, value));
+        counter.set(value);
+        Success(((), xml))
+    }
+
+    fn count_node<'a>(&self, xml: StartPoint<'a>) -> ParseResult<'a, ()> {
+        self.count(xml, &self.nodes, Nodes)
+    }
+
+    fn reset_counters(&self) {
+        self.depth.set(0);
+        self.nodes.set(0);
+        self.attributes.set(0);
+        *self.dtd.borrow_mut() = Dtd::with_limits(self.limits.clone());
+    }
+
+    /// Counts a reference about to be replaced by text of this length
+    fn count_expansion<'a>(&self, xml: StartPoint<'a>, length: uint) -> ParseResult<'a, ()> {
+        match self.dtd.borrow().count_expansion(length) {
+            Ok(()) => Success(((), xml)),
+            Err(limit) => Failure(ParseFailure::exceeded(xml, limit)),
+        }
     }
 
     fn parse_eq<'a>(&self, xml: StartPoint<'a>) -> ParseResult<'a, ()> {
@@ -304,7 +676,8 @@ impl Parser {
         Success((version, xml))
     }
 
-    fn parse_xml_declaration<'a>(&self, xml: StartPoint<'a>) -> ParseResult<'a, ()> {
+    fn parse_xml_declaration<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+        let start = xml;
         let (_, xml) = try_parse!(xml.consume_literal("<?xml"));
         let (_version, xml) = try_parse!(self.parse_version_info(xml));
         // let (encoding, xml) = parse_optional!(self.parse_encoding_declaration(xml));
@@ -312,23 +685,114 @@ impl Parser {
         let (_, xml) = parse_optional!(xml.consume_space(), xml);
         let (_, xml) = try_parse!(xml.consume_literal("?>"));
 
+        sink.declaration_markup(start.up_to(xml));
+
+        Success(((), xml))
+    }
+
+    fn parse_space<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+        let (space, xml) = try_parse!(xml.consume_space());
+
+        sink.whitespace(space);
+
         Success(((), xml))
     }
 
-    fn parse_misc<'a, 's, S : ParserSink>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+    fn parse_misc<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
         parse_alternate!(xml, {
             [|xml: StartPoint<'a>| self.parse_comment(xml, sink) -> |_| ()],
             [|xml: StartPoint<'a>| self.parse_pi(xml, sink)      -> |_| ()],
-            [|xml: StartPoint<'a>| xml.consume_space()           -> |_| ()],
+            [|xml: StartPoint<'a>| self.parse_space(xml, sink)   -> |_| ()],
         })
     }
 
-    fn parse_miscs<'a, 's, S : ParserSink>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+    fn parse_miscs<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
         parse_zero_or_more!(xml, |xml| self.parse_misc(xml, sink))
     }
 
-    fn parse_prolog<'a, 's, S : ParserSink>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
-        let (_, xml) = parse_optional!(self.parse_xml_declaration(xml), xml);
+    fn parse_system_literal<'a>(&self, xml: StartPoint<'a>) -> ParseResult<'a, &'a str> {
+        self.parse_quoted_value(xml, |xml, quote| xml.consume_system_literal(quote))
+    }
+
+    fn parse_system_id<'a>(&self, xml: StartPoint<'a>) -> ParseResult<'a, ExternalId<'a>> {
+        let (_, xml) = try_parse!(xml.consume_literal("SYSTEM"));
+        let (_, xml) = try_parse!(xml.consume_space());
+        let (system, xml) = try_parse!(self.parse_system_literal(xml));
+
+        Success(((None, Some(system)), xml))
+    }
+
+    fn parse_public_id<'a>(&self, xml: StartPoint<'a>) -> ParseResult<'a, ExternalId<'a>> {
+        let (_, xml) = try_parse!(xml.consume_literal("PUBLIC"));
+        let (_, xml) = try_parse!(xml.consume_space());
+        let (public, xml) = try_parse!(self.parse_system_literal(xml));
+        let (_, xml) = try_parse!(xml.consume_space());
+        let (system, xml) = try_parse!(self.parse_system_literal(xml));
+
+        Success(((Some(public), Some(system)), xml))
+    }
+
+    fn parse_external_id<'a>(&self, xml: StartPoint<'a>) -> ParseResult<'a, ExternalId<'a>> {
+        let (_, xml) = try_parse!(xml.consume_space());
+
+        parse_alternate!(xml, {
+            [|xml| self.parse_system_id(xml) -> |ids| ids],
+            [|xml| self.parse_public_id(xml) -> |ids| ids],
+        })
+    }
+
+    fn parse_internal_subset<'a>(&self, xml: StartPoint<'a>) -> ParseResult<'a, &'a str> {
+        let (_, xml) = try_parse!(xml.consume_literal("["));
+        let (subset, xml) = try_parse!(xml.consume_internal_subset());
+        let (_, xml) = try_parse!(xml.consume_literal("]"));
+        let (_, xml) = parse_optional!(xml.consume_space(), xml);
+
+        Success((subset, xml))
+    }
+
+    fn parse_doctype_declaration<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+        let start = xml;
+        let (_, xml) = try_parse!(xml.consume_literal("<!DOCTYPE"));
+        let (_, xml) = try_parse!(xml.consume_space());
+        let (name, xml) = try_parse!(xml.consume_name());
+        let (ids, xml) = parse_optional!(self.parse_external_id(xml), xml);
+        let (_, xml) = parse_optional!(xml.consume_space(), xml);
+        let (subset, xml) = parse_optional!(self.parse_internal_subset(xml), xml);
+        let (_, xml) = try_parse!(xml.consume_literal(">"));
+
+        try_parse!(self.enforce(start, NameLength, name.len()));
+        try_parse!(self.enforce(start, TextLength, subset.map_or(0, |s| s.len())));
+        try_parse!(self.count_node(start));
+
+        let (public_id, system_id) = ids.unwrap_or((None, None));
+        sink.document_type(name, public_id, system_id, subset);
+        sink.doctype_markup(start.up_to(xml));
+
+        let mut external = None;
+        if public_id.is_some() || system_id.is_some() {
+            if let Ok(text) = self.resolver.resolve(public_id, system_id) {
+                try_parse!(self.enforce(start, TextLength, text.len()));
+                sink.external_subset(text.as_slice());
+                external = Some(text);
+            }
+        }
+
+        // Malformed declarations only matter to validation; a limit
+        // being exceeded stops the parse.
+        let mut dtd = self.dtd.borrow_mut();
+        for subset in subset.iter().map(|s| *s).chain(external.iter().map(|s| s.as_slice())) {
+            if let Err(dtd::LimitExceeded(limit)) = dtd.add_subset(subset) {
+                return Failure(ParseFailure::exceeded(start, limit));
+            }
+        }
+
+        Success(((), xml))
+    }
+
+    fn parse_prolog<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+        let (_, xml) = parse_optional!(self.parse_xml_declaration(xml, sink), xml);
+        let (_, xml) = parse_optional!(self.parse_miscs(xml, sink), xml);
+        let (_, xml) = parse_optional!(self.parse_doctype_declaration(xml, sink), xml);
         self.parse_miscs(xml, sink)
     }
 
@@ -356,22 +820,35 @@ impl Parser {
         })
     }
 
-    fn parse_attribute_values<'a, 's, S : ParserSink>(&self, xml: StartPoint<'a>, sink: &'s mut S,
+    fn parse_attribute_values<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S,
                                                   quote: &str)
                                   -> ParseResult<'a, ()>
     {
         parse_zero_or_more!(xml, |xml|
             parse_alternate!(xml, {
-                [|xml: StartPoint<'a>| xml.consume_attribute_value(quote) -> |v| sink.attribute_value(LiteralAttributeValue(v))],
-                [|xml: StartPoint<'a>| self.parse_reference(xml)          -> |e| sink.attribute_value(ReferenceAttributeValue(e))],
+                [|xml: StartPoint<'a>| self.parse_attribute_literal(xml, quote) -> |v| sink.attribute_value(LiteralAttributeValue(v))],
+                [|xml: StartPoint<'a>| self.parse_reference(xml)          -> |t| sink.attribute_value(ReferenceAttributeValue(t))],
             }))
     }
 
-    fn parse_attribute<'a, 's, S : ParserSink>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+    fn parse_attribute_literal<'a>(&self, xml: StartPoint<'a>, quote: &str) -> ParseResult<'a, &'a str> {
+        let (value, after) = try_parse!(xml.consume_attribute_value(quote));
+        try_parse!(self.enforce(xml, TextLength, value.len()));
+
+        Success((value, after))
+    }
+
+    fn parse_attribute<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+        let leading = xml;
         let (_, xml) = try_parse!(xml.consume_space());
 
+        let start = xml;
         let (name, xml) = try_parse!(xml.consume_name());
 
+        try_parse!(self.enforce(start, NameLength, name.len()));
+        try_parse!(self.count(start, &self.attributes, Attributes));
+        try_parse!(self.count_node(start));
+
         sink.attribute_start(name);
 
         let (_, xml) = try_parse!(self.parse_eq(xml));
@@ -381,11 +858,12 @@ impl Parser {
         );
 
         sink.attribute_end(name);
+        sink.attribute_markup(name, leading.up_to(xml));
 
         Success(((), xml))
     }
 
-    fn parse_attributes<'a, 's, S : ParserSink>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+    fn parse_attributes<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
         parse_zero_or_more!(xml, |xml| self.parse_attribute(xml, sink))
     }
 
@@ -397,24 +875,42 @@ impl Parser {
         Success((name, xml))
     }
 
-    fn parse_char_data<'a, 's, S : ParserSink>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+    fn parse_char_data<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+        let start = xml;
         let (text, xml) = try_parse!(xml.consume_char_data());
 
+        try_parse!(self.enforce(start, TextLength, text.len()));
+        try_parse!(self.count_node(start));
+
         sink.text(text);
 
         Success(((), xml))
     }
 
-    fn parse_cdata<'a, 's, S : ParserSink>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+    fn parse_cdata<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+        let start = xml;
         let (_, xml) = try_parse!(xml.consume_literal("<![CDATA["));
         let (text, xml) = try_parse!(xml.consume_cdata());
         let (_, xml) = try_parse!(xml.consume_literal("]]>"));
 
+        try_parse!(self.enforce(start, TextLength, text.len()));
+        try_parse!(self.count_node(start));
+
         sink.text(text);
+        sink.child_markup(start.up_to(xml));
 
         Success(((), xml))
     }
 
+    fn parse_content_reference<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+        let (text, after) = try_parse!(self.parse_reference(xml));
+
+        sink.reference(text.as_slice());
+        sink.child_markup(xml.up_to(after));
+
+        Success(((), after))
+    }
+
     fn parse_entity_ref<'a>(&self, xml: StartPoint<'a>) -> ParseResult<'a, Reference<'a>> {
         let (_, xml) = try_parse!(xml.consume_literal("&"));
         let (name, xml) = try_parse!(xml.consume_name());
@@ -439,20 +935,68 @@ impl Parser {
         Success((HexCharReference(hex), xml))
     }
 
-    fn parse_reference<'a>(&self, xml: StartPoint<'a>) -> ParseResult<'a, Reference<'a>> {
-        parse_alternate!(xml, {
+    fn parse_reference_markup<'a>(&self, xml: StartPoint<'a>) -> ParseResult<'a, Reference<'a>> {
+        let (reference, after) = try_parse!(parse_alternate!(xml, {
             [|xml| self.parse_entity_ref(xml)       -> |e| e],
             [|xml| self.parse_decimal_char_ref(xml) -> |d| d],
             [|xml| self.parse_hex_char_ref(xml)     -> |h| h],
-        })
+        }));
+
+        Success((reference, after))
+    }
+
+    /// The text a reference stands for, counted as an expansion.
+    /// References to characters that may not appear in a document,
+    /// and to entities that are neither predefined nor declared in the
+    /// document's DTD, are syntax errors.
+    fn decode_reference<'a>(&self, xml: StartPoint<'a>, reference: Reference) -> ParseResult<'a, String> {
+        let code = match reference {
+            EntityReference(e) => {
+                if let Some(text) = predefined_entity(e) {
+                    try_parse!(self.count_expansion(xml, text.len()));
+                    return Success((text.to_string(), xml));
+                }
+
+                // The DTD counts the expansion itself
+                let replaced = self.dtd.borrow().replacement_text(e);
+                return match replaced {
+                    Ok(Some(text)) => Success((text, xml)),
+                    Ok(None) => Failure(ParseFailure::syntax(xml)),
+                    Err(limit) => Failure(ParseFailure::exceeded(xml, limit)),
+                };
+            },
+            DecimalCharReference(d) => from_str_radix::<u32>(d, 10),
+            HexCharReference(h) => from_str_radix::<u32>(h, 16),
+        };
+
+        match code.and_then(from_u32) {
+            Some(c) if c.is_char() => {
+                let text = String::from_char(1, c);
+                try_parse!(self.count_expansion(xml, text.len()));
+                Success((text, xml))
+            },
+            _ => Failure(ParseFailure::syntax(xml)),
+        }
+    }
+
+    fn parse_reference<'a>(&self, xml: StartPoint<'a>) -> ParseResult<'a, String> {
+        let (reference, after) = try_parse!(self.parse_reference_markup(xml));
+        let (text, _) = try_parse!(self.decode_reference(xml, reference));
+
+        Success((text, after))
     }
 
-    fn parse_comment<'a, 's, S : ParserSink>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+    fn parse_comment<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+        let start = xml;
         let (_, xml) = try_parse!(xml.consume_literal("<!--"));
         let (text, xml) = try_parse!(xml.consume_comment());
         let (_, xml) = try_parse!(xml.consume_literal("-->"));
 
+        try_parse!(self.enforce(start, TextLength, text.len()));
+        try_parse!(self.count_node(start));
+
         sink.comment(text);
+        sink.child_markup(start.up_to(xml));
 
         Success(((), xml))
     }
@@ -462,22 +1006,31 @@ impl Parser {
         xml.consume_pi_value()
     }
 
-    fn parse_pi<'a, 's, S : ParserSink>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+    fn parse_pi<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+        let start = xml;
         let (_, xml) = try_parse!(xml.consume_literal("<?"));
-        let (target, xml) = try_parse!(xml.consume_name());
-        let (value, xml) = parse_optional!(self.parse_pi_value(xml), xml);
-        let (_, xml) = try_parse!(xml.consume_literal("?>"));
+        let (target, after_target) = try_parse!(xml.consume_name());
 
+        // Reserved for the XML declaration
         if target.eq_ignore_ascii_case("xml") {
-            panic!("Can't use xml as a PI target");
+            return Failure(ParseFailure::syntax(xml));
         }
 
+        let xml = after_target;
+        let (value, xml) = parse_optional!(self.parse_pi_value(xml), xml);
+        let (_, xml) = try_parse!(xml.consume_literal("?>"));
+
+        try_parse!(self.enforce(start, NameLength, target.len()));
+        try_parse!(self.enforce(start, TextLength, value.map_or(0, |v| v.len())));
+        try_parse!(self.count_node(start));
+
         sink.processing_instruction(target, value);
+        sink.child_markup(start.up_to(xml));
 
         Success(((), xml))
     }
 
-    fn parse_content<'a, 's, S : ParserSink>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+    fn parse_content<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
         let (_, xml) = parse_optional!(self.parse_char_data(xml, sink), xml);
 
         // Pattern: zero-or-more
@@ -486,7 +1039,7 @@ impl Parser {
             let xxx = parse_alternate!(start, {
                 [|xml| self.parse_element(xml, sink) -> |_| ()],
                 [|xml| self.parse_cdata(xml, sink)   -> |_| ()],
-                [|xml| self.parse_reference(xml)     -> |r| sink.reference(r)],
+                [|xml| self.parse_content_reference(xml, sink) -> |_| ()],
                 [|xml| self.parse_comment(xml, sink) -> |_| ()],
                 [|xml| self.parse_pi(xml, sink)      -> |_| ()],
             });
@@ -494,7 +1047,10 @@ impl Parser {
             let (_, after) = match xxx {
                 Success(x) => x,
                 Partial((_, pf, _)) |
-                Failure(pf) => return Partial(((), pf, start)),
+                Failure(pf) => {
+                    if pf.is_fatal() { return Failure(pf) }
+                    return Partial(((), pf, start))
+                },
             };
 
             let (_, xml) = parse_optional!(self.parse_char_data(after, sink), after);
@@ -503,40 +1059,63 @@ impl Parser {
         }
     }
 
-    fn parse_empty_element_tail<'a>(&self, xml: StartPoint<'a>) -> ParseResult<'a, ()> {
+    fn parse_empty_element_tail<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S, tag_end: StartPoint<'a>) -> ParseResult<'a, ()> {
         let (_, xml) = try_parse!(xml.consume_literal("/>"));
 
+        sink.start_tag_end(tag_end.up_to(xml));
+
         Success(((), xml))
     }
 
-    fn parse_non_empty_element_tail<'a, 's, S : ParserSink>(&self, xml: StartPoint<'a>, sink: &'s mut S, start_name: &str) -> ParseResult<'a, ()> {
+    fn parse_non_empty_element_tail<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S, start_name: &str, tag_end: StartPoint<'a>) -> ParseResult<'a, ()> {
         let (_, xml) = try_parse!(xml.consume_literal(">"));
 
+        sink.start_tag_end(tag_end.up_to(xml));
+
         let (_, f, xml) = try_partial_parse!(self.parse_content(xml, sink));
 
+        let end_tag = xml;
         let (end_name, xml) = try_resume_after_partial_failure!(f, self.parse_element_end(xml));
 
         if start_name != end_name {
-            panic!("tags do not match!");
+            return Failure(ParseFailure::syntax(end_tag));
         }
 
+        sink.end_tag(end_tag.up_to(xml));
+
         Success(((), xml))
     }
 
-    fn parse_element<'a, 's, S : ParserSink>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+    fn parse_element<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+        let start = xml;
         let (_, xml) = try_parse!(xml.consume_start_tag());
         let (name, xml) = try_parse!(xml.consume_name());
 
+        let depth = self.depth.get() + 1;
+        try_parse!(self.enforce(start, NameLength, name.len()));
+        try_parse!(self.enforce(start, Depth, depth));
+        try_parse!(self.count_node(start));
+
+        self.depth.set(depth);
+        let result = self.parse_element_rest(xml, sink, name);
+        self.depth.set(depth - 1);
+
+        result
+    }
+
+    fn parse_element_rest<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S, name: &'a str) -> ParseResult<'a, ()> {
         sink.element_start(name);
 
+        self.attributes.set(0);
         let (_, f, xml) = try_partial_parse!(self.parse_attributes(xml, sink));
 
+        let tag_end = xml;
         let (_, xml) = parse_optional!(xml.consume_space(), xml);
 
         let (_, xml) = try_resume_after_partial_failure!(f,
             parse_alternate!(xml, {
-                [|xml| self.parse_empty_element_tail(xml)                 -> |_| ()],
-                [|xml| self.parse_non_empty_element_tail(xml, sink, name) -> |_| ()],
+                [|xml| self.parse_empty_element_tail(xml, sink, tag_end)              -> |_| ()],
+                [|xml| self.parse_non_empty_element_tail(xml, sink, name, tag_end) -> |_| ()],
             })
         );
 
@@ -545,7 +1124,7 @@ impl Parser {
         Success(((), xml))
     }
 
-    fn parse_document<'a, 's, S : ParserSink>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
+    fn parse_document<'a, 's, S : ParserSink<'a>>(&self, xml: StartPoint<'a>, sink: &'s mut S) -> ParseResult<'a, ()> {
         let (_, f, xml) = try_partial_parse!(self.parse_prolog(xml, sink));
         let (_, xml) = try_resume_after_partial_failure!(f, self.parse_element(xml, sink));
         let (_, xml) = parse_optional!(self.parse_miscs(xml, sink), xml);
@@ -553,43 +1132,174 @@ impl Parser {
         Success(((), xml))
     }
 
-    pub fn parse<'a>(&self, xml: &'a str) -> Result<super::Package, uint> {
+    fn parse_with_sink<'a, S : ParserSink<'a>>(&self, xml: &'a str, sink: &mut S) -> Result<(), ParseError> {
         let xml = StartPoint{offset: 0, s: xml};
+
+        self.reset_counters();
+
+        if let Some(max) = self.limits.maximum(DocumentSize) {
+            if xml.s.len() > max { return Err(LimitExceeded(DocumentSize, max)) }
+        }
+
+        // TODO: Check fully parsed
+        match self.parse_document(xml, sink) {
+            Success(_) => Ok(()),
+            Partial((_, pf, _)) |
+            Failure(pf) => Err(pf.to_error()),
+        }
+    }
+
+    pub fn parse<'a>(&self, xml: &'a str) -> Result<super::Package, ParseError> {
         let package = super::Package::new();
 
         {
             let doc = package.as_document();
             let mut hydrator = SaxHydrator::new(&doc);
 
-            match self.parse_document(xml, &mut hydrator) {
-                Success(x) => x,
-                Partial((_, pf, _)) |
-                Failure(pf) => return Err(pf.point.offset),
-            };
+            try!(self.parse_with_sink(xml, &mut hydrator));
         }
 
-        // TODO: Check fully parsed
+        Ok(package)
+    }
+
+    /// Parses the string, recording the markup of each node so that
+    /// the writer can reproduce the input exactly
+    pub fn parse_lossless<'a>(&self, xml: &'a str) -> Result<super::Package, ParseError> {
+        let package = super::Package::new();
+
+        {
+            let doc = package.as_document();
+            let mut hydrator = SaxHydrator::new(&doc);
+            hydrator.lossless = true;
+
+            try!(self.parse_with_sink(xml, &mut hydrator));
+            hydrator.finish();
+        }
+
+        Ok(package)
+    }
+
+    /// Parses the string as element content, a mix of text, elements,
+    /// comments, processing instructions and references, and appends
+    /// it to `parent`. The appended nodes are returned. Nothing is
+    /// appended when the content is not well-formed.
+    pub fn parse_fragment<'d>(&self, xml: &str, parent: dom4::Element<'d>)
+                              -> Result<Vec<dom4::ChildOfElement<'d>>, ParseError>
+    {
+        let doc = parent.document();
+        let xml = StartPoint{offset: 0, s: xml};
+
+        self.reset_counters();
+        if let Err(limit) = self.declare_entities_of(doc) {
+            return Err(ParseFailure::exceeded(xml, limit).to_error());
+        }
+
+        // The content is built detached first so that a failure part
+        // of the way through leaves the parent untouched
+        let mut hydrator = SaxHydrator::detached(doc);
+        match self.parse_content(xml, &mut hydrator) {
+            Success(_) => {},
+            Partial((_, pf, rest)) => {
+                if ! rest.s.is_empty() { return Err(pf.to_error()) }
+            },
+            Failure(pf) => return Err(pf.to_error()),
+        }
+
+        let children = mem::replace(&mut hydrator.detached, Vec::new());
+        for child in children.iter() {
+            parent.append_child(*child);
+        }
+
+        Ok(children)
+    }
+
+    /// Declares the entities of the document's DTD, so that a fragment
+    /// parsed into it may refer to them
+    fn declare_entities_of<'d>(&self, doc: &'d dom4::Document<'d>) -> Result<(), Limit> {
+        let doctype = match doc.root().doctype() {
+            Some(doctype) => doctype,
+            None => return Ok(()),
+        };
+        let internal = doctype.internal_subset();
+        let external = doctype.external_subset();
+
+        // As when parsing the declaration, malformed declarations
+        // are skipped
+        let mut dtd = self.dtd.borrow_mut();
+        for subset in internal.iter().chain(external.iter()) {
+            if let Err(dtd::LimitExceeded(limit)) = dtd.add_subset(*subset) {
+                return Err(limit);
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses the string, reporting each part of the document to the
+    /// handler instead of building a tree
+    pub fn parse_events<H: EventHandler>(&self, xml: &str, handler: &mut H) -> Result<(), ParseError> {
+        let mut sink = EventSink { handler: handler, attr_value: String::new() };
+        self.parse_with_sink(xml, &mut sink)
+    }
+
+    /// Parses the string without copying names or text into the
+    /// package; they refer to the input instead. Only strings that
+    /// needed references expanded are allocated.
+    pub fn parse_borrowed<'a>(&self, xml: &'a str) -> Result<super::BorrowedPackage<'a>, ParseError> {
+        let package = super::BorrowedPackage::new();
+
+        {
+            let mut hydrator = BorrowingHydrator::new(&package.storage, &package.connections);
+
+            try!(self.parse_with_sink(xml, &mut hydrator));
+        }
 
         Ok(package)
     }
 }
 
-trait ParserSink {
-    fn element_start(&mut self, name: &str);
-    fn element_end(&mut self, name: &str);
-    fn comment(&mut self, text: &str);
-    fn processing_instruction(&mut self, target: &str, value: Option<&str>);
-    fn text(&mut self, text: &str);
-    fn reference(&mut self, reference: Reference);
-    fn attribute_start(&mut self, name: &str);
-    fn attribute_value(&mut self, value: AttributeValue);
-    fn attribute_end(&mut self, name: &str);
+trait ParserSink<'a> {
+    fn document_type(&mut self,
+                     name: &'a str,
+                     public_id: Option<&'a str>,
+                     system_id: Option<&'a str>,
+                     internal_subset: Option<&'a str>);
+    fn external_subset(&mut self, _subset: &str) {}
+    fn element_start(&mut self, name: &'a str);
+    fn element_end(&mut self, name: &'a str);
+    fn comment(&mut self, text: &'a str);
+    fn processing_instruction(&mut self, target: &'a str, value: Option<&'a str>);
+    fn text(&mut self, text: &'a str);
+    /// The text a reference stood for
+    fn reference(&mut self, text: &str);
+    fn attribute_start(&mut self, name: &'a str);
+    fn attribute_value(&mut self, value: AttributeValue<'a>);
+    fn attribute_end(&mut self, name: &'a str);
+    // The markup of what was just reported
+    fn declaration_markup(&mut self, _markup: &'a str) {}
+    fn doctype_markup(&mut self, _markup: &'a str) {}
+    fn child_markup(&mut self, _markup: &'a str) {}
+    fn attribute_markup(&mut self, _name: &'a str, _markup: &'a str) {}
+    fn start_tag_end(&mut self, _markup: &'a str) {}
+    fn end_tag(&mut self, _markup: &'a str) {}
+    /// Whitespace outside of the document element
+    fn whitespace(&mut self, _space: &'a str) {}
 }
 
 struct SaxHydrator<'d> {
     doc: &'d dom4::Document<'d>,
     stack: Vec<dom4::Element<'d>>,
     attr_value: RefCell<String>,
+    /// Whether to record the markup of each node
+    lossless: bool,
+    /// Whitespace outside of the document element that has not been
+    /// recorded yet
+    space: String,
+    /// The node appended most recently, which markup belongs to
+    last: Option<dom4::ChildOfElement<'d>>,
+    /// Whether nodes outside of any element are kept in `detached`
+    /// instead of being appended to the root
+    fragment: bool,
+    detached: Vec<dom4::ChildOfElement<'d>>,
 }
 
 impl<'d> SaxHydrator<'d> {
@@ -598,111 +1308,444 @@ impl<'d> SaxHydrator<'d> {
             doc: doc,
             stack: Vec::new(),
             attr_value: RefCell::new(String::new()),
+            lossless: false,
+            space: String::new(),
+            last: None,
+            fragment: false,
+            detached: Vec::new(),
         }
     }
 
-    fn current_element(&self) -> &dom4::Element<'d> {
-        self.stack.last().expect("No element to append to")
+    /// Leaves the outermost nodes detached instead of appending them
+    /// to the root
+    fn detached(doc: &'d dom4::Document<'d>) -> SaxHydrator<'d> {
+        SaxHydrator {
+            fragment: true,
+            ..SaxHydrator::new(doc)
+        }
     }
 
-    fn append_text(&self, text: dom4::Text<'d>) {
-        self.current_element().append_child(text);
+    fn take_space(&mut self) -> Option<String> {
+        if self.space.is_empty() { return None }
+        Some(mem::replace(&mut self.space, String::new()))
     }
 
-    fn append_to_either<T : dom4::ToChildOfRoot<'d>>(&self, child: T) {
-        match self.stack.last() {
-            None => self.doc.root().append_child(child),
-            Some(parent) => parent.append_child(child.to_child_of_root()),
+    /// Records the XML declaration, even when there was none, and the
+    /// whitespace at the end
+    fn finish(&mut self) {
+        let root = self.doc.root();
+        let mut source = root.source();
+        if source.markup.is_none() {
+            source.markup = Some(String::new());
         }
+        source.after = self.take_space();
+        root.set_source(source);
     }
 
-    fn decode_reference<T>(&self, ref_data: Reference, cb: |&str| -> T) -> T {
-        match ref_data {
-            DecimalCharReference(d) => {
-                let code: u32 = from_str_radix(d, 10).expect("Not valid decimal");
-                let c: char = from_u32(code).expect("Not a valid codepoint");
-                let s = String::from_char(1, c);
-                cb(s.as_slice())
-            },
-            HexCharReference(h) => {
-                let code: u32 = from_str_radix(h, 16).expect("Not valid hex");
-                let c: char = from_u32(code).expect("Not a valid codepoint");
-                let s = String::from_char(1, c);
-                cb(s.as_slice())
-            },
-            EntityReference(e) => {
-                let s = match e {
-                    "amp"  => "&",
-                    "lt"   => "<",
-                    "gt"   => ">",
-                    "apos" => "'",
-                    "quot" => "\"",
-                    _      => panic!("unknown entity"),
-                };
-                cb(s)
-            }
+    fn set_child_source(&self, child: dom4::ChildOfElement<'d>, source: dom4::Source) {
+        match child {
+            dom4::ElementCOE(n) => n.set_source(source),
+            dom4::TextCOE(n) => n.set_source(source),
+            dom4::CommentCOE(n) => n.set_source(source),
+            dom4::ProcessingInstructionCOE(n) => n.set_source(source),
+        }
+    }
+
+    fn child_source(&self, child: dom4::ChildOfElement<'d>) -> dom4::Source {
+        match child {
+            dom4::ElementCOE(n) => n.source(),
+            dom4::TextCOE(n) => n.source(),
+            dom4::CommentCOE(n) => n.source(),
+            dom4::ProcessingInstructionCOE(n) => n.source(),
+        }
+    }
+
+    fn current_element(&self) -> &dom4::Element<'d> {
+        self.stack.last().expect("No element to append to")
+    }
+
+    fn append_text(&mut self, text: dom4::Text<'d>) {
+        if self.fragment && self.stack.is_empty() {
+            self.detached.push(dom4::TextCOE(text));
+        } else {
+            self.current_element().append_child(text);
+        }
+        self.last = Some(dom4::TextCOE(text));
+    }
+
+    fn append_to_either<T : dom4::ToChildOfRoot<'d>>(&mut self, child: T) {
+        let child = child.to_child_of_root();
+        match self.stack.last() {
+            None if self.fragment => self.detached.push(child.to_child_of_element()),
+            None => self.doc.root().append_child(child),
+            Some(parent) => parent.append_child(child),
+        }
+        self.last = Some(child.to_child_of_element());
+
+        if self.lossless && self.stack.is_empty() {
+            let before = self.take_space();
+            let child = child.to_child_of_element();
+            let mut source = self.child_source(child);
+            source.before = before;
+            self.set_child_source(child, source);
         }
     }
 }
 
-impl<'d> ParserSink for SaxHydrator<'d> {
-    fn element_start(&mut self, name: &str) {
+impl<'a, 'd> ParserSink<'a> for SaxHydrator<'d> {
+    fn document_type(&mut self,
+                     name: &'a str,
+                     public_id: Option<&'a str>,
+                     system_id: Option<&'a str>,
+                     internal_subset: Option<&'a str>)
+    {
+        let doctype = self.doc.create_document_type(name, public_id, system_id, internal_subset);
+        self.doc.root().set_doctype(Some(doctype));
+        // Without its position, the writer would move the declaration
+        // before any comments and processing instructions
+        doctype.set_source(dom4::Source {
+            position: Some(self.doc.root().children().len()),
+            ..Default::default()
+        });
+    }
+
+    fn external_subset(&mut self, subset: &str) {
+        if let Some(doctype) = self.doc.root().doctype() {
+            doctype.set_external_subset(Some(subset));
+        }
+    }
+
+    fn element_start(&mut self, name: &'a str) {
         let element = self.doc.create_element(name);
         self.append_to_either(element);
         self.stack.push(element);
     }
 
-    fn element_end(&mut self, _name: &str) {
+    fn element_end(&mut self, _name: &'a str) {
         self.stack.pop();
     }
 
-    fn comment(&mut self, text: &str) {
+    fn comment(&mut self, text: &'a str) {
         let comment = self.doc.create_comment(text);
         self.append_to_either(comment);
     }
 
-    fn processing_instruction(&mut self, target: &str, value: Option<&str>) {
+    fn processing_instruction(&mut self, target: &'a str, value: Option<&'a str>) {
         let pi = self.doc.create_processing_instruction(target, value);
         self.append_to_either(pi);
     }
 
-    fn text(&mut self, text: &str) {
+    fn text(&mut self, text: &'a str) {
         let text = self.doc.create_text(text);
         self.append_text(text);
     }
 
-    fn reference(&mut self, reference: Reference) {
-        let text = self.decode_reference(reference, |s| self.doc.create_text(s));
+    fn reference(&mut self, text: &str) {
+        let text = self.doc.create_text(text);
         self.append_text(text);
     }
 
-    fn attribute_start(&mut self, _name: &str) {
+    fn attribute_start(&mut self, _name: &'a str) {
         self.attr_value.borrow_mut().clear();
     }
 
-    fn attribute_value(&mut self, value: AttributeValue) {
+    fn attribute_value(&mut self, value: AttributeValue<'a>) {
         match value {
             LiteralAttributeValue(v) => self.attr_value.borrow_mut().push_str(v),
-            ReferenceAttributeValue(r) => self.decode_reference(r, |s| self.attr_value.borrow_mut().push_str(s)),
+            ReferenceAttributeValue(t) => self.attr_value.borrow_mut().push_str(t.as_slice()),
         }
     }
 
-    fn attribute_end(&mut self, name: &str) {
+    fn attribute_end(&mut self, name: &'a str) {
         self.current_element().set_attribute_value(name, self.attr_value.borrow().as_slice());
     }
+
+    fn declaration_markup(&mut self, markup: &'a str) {
+        if ! self.lossless { return }
+        let root = self.doc.root();
+        root.set_source(dom4::Source { markup: Some(markup.to_string()), ..Default::default() });
+    }
+
+    fn doctype_markup(&mut self, markup: &'a str) {
+        if ! self.lossless { return }
+        if let Some(doctype) = self.doc.root().doctype() {
+            doctype.set_source(dom4::Source {
+                before: self.take_space(),
+                markup: Some(markup.to_string()),
+                ..doctype.source()
+            });
+        }
+    }
+
+    fn child_markup(&mut self, markup: &'a str) {
+        if ! self.lossless { return }
+        if let Some(child) = self.last {
+            let mut source = self.child_source(child);
+            source.markup = Some(markup.to_string());
+            self.set_child_source(child, source);
+        }
+    }
+
+    fn attribute_markup(&mut self, name: &'a str, markup: &'a str) {
+        if ! self.lossless { return }
+        if let Some(attribute) = self.current_element().attribute(name) {
+            attribute.set_source(dom4::Source { markup: Some(markup.to_string()), ..Default::default() });
+        }
+    }
+
+    fn start_tag_end(&mut self, markup: &'a str) {
+        if ! self.lossless { return }
+        let element = *self.current_element();
+        let mut source = element.source();
+        source.markup = Some(markup.to_string());
+        if markup.ends_with("/>") {
+            source.after = Some(String::new());
+        }
+        element.set_source(source);
+    }
+
+    fn end_tag(&mut self, markup: &'a str) {
+        if ! self.lossless { return }
+        let element = *self.current_element();
+        let mut source = element.source();
+        source.after = Some(markup.to_string());
+        element.set_source(source);
+    }
+
+    fn whitespace(&mut self, space: &'a str) {
+        if ! self.lossless { return }
+        self.space.push_str(space);
+    }
+}
+
+enum AttributeBuffer<'a> {
+    EmptyAttribute,
+    BorrowedAttribute(&'a str),
+    OwnedAttribute(String),
+}
+
+impl<'a> AttributeBuffer<'a> {
+    fn into_owned(self) -> String {
+        match self {
+            EmptyAttribute => String::new(),
+            BorrowedAttribute(v) => v.to_string(),
+            OwnedAttribute(s) => s,
+        }
+    }
+}
+
+/// Builds a package directly from the raw storage, pointing names and
+/// text at the input string instead of copying them.
+///
+/// Names are still interned so that repeated names share one string.
+struct BorrowingHydrator<'a, 'p> {
+    storage: &'p raw::Storage,
+    connections: &'p raw::Connections,
+    stack: Vec<*mut raw::Element>,
+    attr_value: AttributeBuffer<'a>,
+}
+
+impl<'a, 'p> BorrowingHydrator<'a, 'p> {
+    fn new(storage: &'p raw::Storage, connections: &'p raw::Connections) -> BorrowingHydrator<'a, 'p> {
+        BorrowingHydrator {
+            storage: storage,
+            connections: connections,
+            stack: Vec::new(),
+            attr_value: EmptyAttribute,
+        }
+    }
+
+    fn current_element(&self) -> *mut raw::Element {
+        *self.stack.last().expect("No element to append to")
+    }
+
+    fn append_text(&self, text: *mut raw::Text) {
+        self.connections.append_element_child(self.current_element(), text);
+    }
+
+    fn append_to_either(&self, child: raw::ChildOfRoot) {
+        match self.stack.last() {
+            None => self.connections.append_root_child(child),
+            Some(&parent) => self.connections.append_element_child(parent, child.to_child_of_element()),
+        }
+    }
+
+    fn borrow_name(&self, name: &'a str) -> InternedString {
+        // This is safe because the package cannot outlive the input
+        unsafe { self.storage.intern_borrowed(name) }
+    }
+}
+
+impl<'a, 'p> ParserSink<'a> for BorrowingHydrator<'a, 'p> {
+    fn document_type(&mut self,
+                     name: &'a str,
+                     public_id: Option<&'a str>,
+                     system_id: Option<&'a str>,
+                     internal_subset: Option<&'a str>)
+    {
+        let name = self.borrow_name(name);
+        let public_id = public_id.map(|v| InternedString::from_str(v));
+        let system_id = system_id.map(|v| InternedString::from_str(v));
+        let internal_subset = internal_subset.map(|v| InternedString::from_str(v));
+        let doctype = self.storage.create_document_type_from(name, public_id, system_id, internal_subset);
+        self.connections.set_root_doctype(Some(doctype));
+        let position = unsafe { self.connections.root_children() }.len();
+        self.storage.set_source(doctype as uint, dom4::Source { position: Some(position), ..Default::default() });
+    }
+
+    fn external_subset(&mut self, subset: &str) {
+        if let Some(doctype) = self.connections.root_doctype() {
+            self.storage.document_type_set_external_subset(doctype, Some(subset));
+        }
+    }
+
+    fn element_start(&mut self, name: &'a str) {
+        let element = self.storage.create_element_from(self.borrow_name(name));
+        self.append_to_either(raw::ElementCOR(element));
+        self.stack.push(element);
+    }
+
+    fn element_end(&mut self, _name: &'a str) {
+        self.stack.pop();
+    }
+
+    fn comment(&mut self, text: &'a str) {
+        let comment = self.storage.create_comment_from(InternedString::from_str(text));
+        self.append_to_either(raw::CommentCOR(comment));
+    }
+
+    fn processing_instruction(&mut self, target: &'a str, value: Option<&'a str>) {
+        let target = self.borrow_name(target);
+        let value = value.map(|v| InternedString::from_str(v));
+        let pi = self.storage.create_processing_instruction_from(target, value);
+        self.append_to_either(raw::ProcessingInstructionCOR(pi));
+    }
+
+    fn text(&mut self, text: &'a str) {
+        let text = self.storage.create_text_from(InternedString::from_str(text));
+        self.append_text(text);
+    }
+
+    fn reference(&mut self, text: &str) {
+        let text = self.storage.create_text(text);
+        self.append_text(text);
+    }
+
+    fn attribute_start(&mut self, _name: &'a str) {
+        self.attr_value = EmptyAttribute;
+    }
+
+    fn attribute_value(&mut self, value: AttributeValue<'a>) {
+        let previous = mem::replace(&mut self.attr_value, EmptyAttribute);
+
+        self.attr_value = match (previous, value) {
+            (EmptyAttribute, LiteralAttributeValue(v)) => BorrowedAttribute(v),
+            (previous, LiteralAttributeValue(v)) => {
+                let mut s = previous.into_owned();
+                s.push_str(v);
+                OwnedAttribute(s)
+            },
+            (previous, ReferenceAttributeValue(t)) => {
+                let mut s = previous.into_owned();
+                s.push_str(t.as_slice());
+                OwnedAttribute(s)
+            },
+        };
+    }
+
+    fn attribute_end(&mut self, name: &'a str) {
+        let name = self.borrow_name(name);
+        let value = match mem::replace(&mut self.attr_value, EmptyAttribute) {
+            EmptyAttribute => InternedString::from_str(""),
+            BorrowedAttribute(v) => InternedString::from_str(v),
+            OwnedAttribute(s) => self.storage.intern(s.as_slice()),
+        };
+
+        let attr = self.storage.create_attribute_from(name, value);
+        self.connections.set_attribute(self.current_element(), attr);
+    }
+}
+
+/// Receives the parts of a document as they are parsed. Attributes are
+/// reported between `start_element` and the first child, with their
+/// references already expanded. Text may arrive in several pieces.
+pub trait EventHandler {
+    fn start_element(&mut self, name: &str);
+    fn attribute(&mut self, name: &str, value: &str);
+    fn text(&mut self, text: &str);
+    fn end_element(&mut self, name: &str);
+    fn comment(&mut self, _text: &str) {}
+    fn processing_instruction(&mut self, _target: &str, _value: Option<&str>) {}
+}
+
+struct EventSink<'h, H: 'h> {
+    handler: &'h mut H,
+    attr_value: String,
+}
+
+impl<'a, 'h, H: EventHandler> ParserSink<'a> for EventSink<'h, H> {
+    fn document_type(&mut self,
+                     _name: &'a str,
+                     _public_id: Option<&'a str>,
+                     _system_id: Option<&'a str>,
+                     _internal_subset: Option<&'a str>)
+    {}
+
+    fn element_start(&mut self, name: &'a str) {
+        self.handler.start_element(name);
+    }
+
+    fn element_end(&mut self, name: &'a str) {
+        self.handler.end_element(name);
+    }
+
+    fn comment(&mut self, text: &'a str) {
+        self.handler.comment(text);
+    }
+
+    fn processing_instruction(&mut self, target: &'a str, value: Option<&'a str>) {
+        self.handler.processing_instruction(target, value);
+    }
+
+    fn text(&mut self, text: &'a str) {
+        self.handler.text(text);
+    }
+
+    fn reference(&mut self, text: &str) {
+        self.handler.text(text);
+    }
+
+    fn attribute_start(&mut self, _name: &'a str) {
+        self.attr_value.clear();
+    }
+
+    fn attribute_value(&mut self, value: AttributeValue<'a>) {
+        match value {
+            LiteralAttributeValue(v) => self.attr_value.push_str(v),
+            ReferenceAttributeValue(t) => self.attr_value.push_str(t.as_slice()),
+        }
+    }
+
+    fn attribute_end(&mut self, name: &'a str) {
+        self.handler.attribute(name, self.attr_value.as_slice());
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::Parser;
+    use super::{Parser,Limits,ParseError,SyntaxError,LimitExceeded,EventHandler};
+    use super::{EntityResolver,ResolveError,UnknownIdentifier};
+    use super::{Depth,Attributes,NameLength,TextLength,Nodes,EntityExpansions,ExpandedLength,DocumentSize};
     use super::super::Package;
     use super::super::dom4;
+    use std::rc::Rc;
+    use std::cell::RefCell;
 
     macro_rules! assert_str_eq(
         ($l:expr, $r:expr) => (assert_eq!($l.as_slice(), $r.as_slice()));
     )
 
-    fn full_parse(xml: &str) -> Result<Package, uint> {
+    fn full_parse(xml: &str) -> Result<Package, ParseError> {
         Parser::new()
             .parse(xml)
     }
@@ -713,6 +1756,11 @@ mod test {
             .expect("Failed to parse the XML string")
     }
 
+    fn limited_parse(xml: &str, limits: Limits) -> Result<Package, ParseError> {
+        Parser::with_limits(limits)
+            .parse(xml)
+    }
+
     fn top<'d>(doc: &'d dom4::Document<'d>) -> dom4::Element<'d> {
         doc.root().children()[0].element().unwrap()
     }
@@ -920,6 +1968,218 @@ mod test {
         assert_eq!(pi2.value(), None);
     }
 
+    #[test]
+    fn a_document_with_a_doctype() {
+        let package = quick_parse("<?xml version='1.0'?><!-- x --><!DOCTYPE hello SYSTEM 'hello.dtd'><hello/>");
+        let doc = package.as_document();
+        let doctype = doc.root().doctype().unwrap();
+
+        assert_str_eq!(doctype.name(), "hello");
+        assert_eq!(doctype.public_id(), None);
+        assert_eq!(doctype.system_id(), Some("hello.dtd"));
+        assert_eq!(doctype.internal_subset(), None);
+        assert_str_eq!(top(&doc).name(), "hello");
+    }
+
+    #[test]
+    fn a_document_with_a_public_doctype() {
+        let package = quick_parse(r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Strict//EN" "xhtml1-strict.dtd"><html/>"#);
+        let doc = package.as_document();
+        let doctype = doc.root().doctype().unwrap();
+
+        assert_eq!(doctype.public_id(), Some("-//W3C//DTD XHTML 1.0 Strict//EN"));
+        assert_eq!(doctype.system_id(), Some("xhtml1-strict.dtd"));
+    }
+
+    #[test]
+    fn a_document_with_an_internal_subset() {
+        let package = quick_parse("<!DOCTYPE hello [<!ELEMENT hello (#PCDATA)><!ENTITY rsb ']'>] ><hello/>");
+        let doc = package.as_document();
+        let doctype = doc.root().doctype().unwrap();
+
+        assert_eq!(doctype.system_id(), None);
+        assert_eq!(doctype.internal_subset(), Some("<!ELEMENT hello (#PCDATA)><!ENTITY rsb ']'>"));
+    }
+
+    #[test]
+    fn parsed_xml_ids_are_indexed() {
+        let package = quick_parse("<hello><world xml:id='earth'/></hello>");
+        let doc = package.as_document();
+        let world = top(&doc).children()[0].element().unwrap();
+
+        assert_eq!(doc.element_by_id("earth"), Some(world));
+    }
+
+    #[test]
+    fn a_fragment_with_mixed_content() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let p = doc.create_element("p");
+
+        let appended = Parser::new().parse_fragment("<b>bold</b> text<!--note--><?pi?>", p)
+            .ok().expect("Failed to parse");
+
+        let children = p.children();
+        assert_eq!(appended, children);
+        assert_eq!(4, children.len());
+        let b = children[0].element().unwrap();
+        assert_str_eq!(b.name(), "b");
+        assert_str_eq!(b.children()[0].text().unwrap().text(), "bold");
+        assert_str_eq!(children[1].text().unwrap().text(), " text");
+        assert_str_eq!(children[2].comment().unwrap().text(), "note");
+        assert_str_eq!(children[3].processing_instruction().unwrap().target(), "pi");
+    }
+
+    #[test]
+    fn a_fragment_with_several_elements_and_references() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let list = doc.create_element("list");
+
+        Parser::new().parse_fragment("<item a='1'/>&amp;<item a='2'/>", list)
+            .ok().expect("Failed to parse");
+
+        let children = list.children();
+        assert_eq!(3, children.len());
+        assert_eq!(children[0].element().unwrap().attribute_value("a"), Some("1"));
+        assert_str_eq!(children[1].text().unwrap().text(), "&");
+        assert_eq!(children[2].element().unwrap().attribute_value("a"), Some("2"));
+    }
+
+    #[test]
+    fn a_fragment_is_appended_after_existing_children() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let p = doc.create_element("p");
+        let existing = doc.create_text("before ");
+        p.append_child(existing);
+
+        Parser::new().parse_fragment("after", p).ok().expect("Failed to parse");
+
+        let children = p.children();
+        assert_eq!(2, children.len());
+        assert_eq!(children[0].text(), Some(existing));
+        assert_str_eq!(children[1].text().unwrap().text(), "after");
+    }
+
+    #[test]
+    fn an_empty_fragment_appends_nothing() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let p = doc.create_element("p");
+
+        let appended = Parser::new().parse_fragment("", p).ok().expect("Failed to parse");
+
+        assert!(appended.is_empty());
+        assert!(p.children().is_empty());
+    }
+
+    #[test]
+    fn a_malformed_fragment_appends_nothing() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let p = doc.create_element("p");
+
+        let r = Parser::new().parse_fragment("<b>bold</b> text </p>", p);
+
+        assert!(r.is_err());
+        assert!(p.children().is_empty());
+    }
+
+    struct MutationLog<'d> {
+        mutations: Rc<RefCell<Vec<dom4::Mutation<'d>>>>,
+    }
+
+    impl<'d> dom4::MutationObserver<'d> for MutationLog<'d> {
+        fn mutated(&mut self, mutation: &dom4::Mutation<'d>) {
+            self.mutations.borrow_mut().push(mutation.clone());
+        }
+    }
+
+    #[test]
+    fn a_fragment_is_reported_as_its_outermost_nodes_being_added() {
+        let package = Package::new();
+        let doc = package.as_document();
+        let p = doc.create_element("p");
+        let mutations = Rc::new(RefCell::new(Vec::new()));
+        let log = MutationLog { mutations: mutations.clone() };
+        doc.add_observer(box log as Box<dom4::MutationObserver>);
+
+        let appended = Parser::new().parse_fragment("<b/> text<!--note-->", p)
+            .ok().expect("Failed to parse");
+
+        let added: Vec<dom4::Mutation> = appended.iter().map(|c| {
+            dom4::ChildAdded(dom4::ElementPOC(p), *c)
+        }).collect();
+        assert_eq!(*mutations.borrow(), added);
+    }
+
+    #[test]
+    fn a_fragment_may_refer_to_entities_declared_by_its_document() {
+        let package = quick_parse(r#"<!DOCTYPE p [<!ENTITY planet "Earth">]><p/>"#);
+        let doc = package.as_document();
+        let p = doc.root().children()[0].element().unwrap();
+
+        Parser::new().parse_fragment("Hello, &planet;!", p).ok().expect("Failed to parse");
+
+        assert_str_eq!(p.text_content(), "Hello, Earth!");
+    }
+
+    #[test]
+    fn a_fragment_uses_namespaces_declared_by_its_parent() {
+        use std::io::MemWriter;
+        use super::super::writer;
+
+        let package = Package::new();
+        let doc = package.as_document();
+        let p = doc.create_element("p");
+        p.set_attribute_value("xmlns:x", "urn:x");
+        doc.root().append_child(p);
+
+        Parser::new().parse_fragment("<x:b>bold</x:b>", p).ok().expect("Failed to parse");
+
+        let mut w = MemWriter::new();
+        writer::format_document(&doc, &mut w).ok().expect("Not formatted");
+        let xml = String::from_utf8(w.unwrap()).unwrap();
+        assert_str_eq!(xml, "<?xml version='1.0'?><p xmlns:x='urn:x'><x:b>bold</x:b></p>");
+    }
+
+    #[test]
+    fn external_subsets_are_not_resolved_by_default() {
+        let package = quick_parse("<!DOCTYPE hello SYSTEM 'file:///etc/passwd'><hello/>");
+        let doc = package.as_document();
+        let doctype = doc.root().doctype().unwrap();
+
+        assert_eq!(doctype.external_subset(), None);
+    }
+
+    struct FixedResolver;
+
+    impl EntityResolver for FixedResolver {
+        fn resolve(&self, public_id: Option<&str>, system_id: Option<&str>) -> Result<String, ResolveError> {
+            match (public_id, system_id) {
+                (None, Some("hello.dtd")) => Ok("<!ELEMENT hello EMPTY>".to_string()),
+                _ => Err(UnknownIdentifier),
+            }
+        }
+    }
+
+    #[test]
+    fn external_subsets_are_retrieved_from_the_resolver() {
+        let mut parser = Parser::new();
+        parser.set_resolver(FixedResolver);
+
+        let package = parser.parse("<!DOCTYPE hello SYSTEM 'hello.dtd'><hello/>").ok().expect("Failed to parse");
+        let doc = package.as_document();
+        let doctype = doc.root().doctype().unwrap();
+        assert_eq!(doctype.external_subset(), Some("<!ELEMENT hello EMPTY>"));
+
+        let package = parser.parse("<!DOCTYPE hello SYSTEM 'other.dtd'><hello/>").ok().expect("Failed to parse");
+        let doc = package.as_document();
+        let doctype = doc.root().doctype().unwrap();
+        assert_eq!(doctype.external_subset(), None);
+    }
+
     #[test]
     fn element_with_decimal_char_reference() {
         let package = quick_parse("<math>2 &#62; 1</math>");
@@ -962,6 +2222,24 @@ mod test {
         assert_str_eq!(text3.text(), "3 math");
     }
 
+    #[test]
+    fn element_with_declared_entity_reference() {
+        let package = quick_parse(r#"<!DOCTYPE a [<!ENTITY e "x &amp; y">]><a>&e;</a>"#);
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_str_eq!(a.children()[0].text().unwrap().text(), "x & y");
+    }
+
+    #[test]
+    fn attribute_with_declared_entity_reference() {
+        let package = quick_parse(r#"<!DOCTYPE a [<!ENTITY e "x">]><a b="&e;&e;"/>"#);
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_str_eq!(a.attribute_value("b").unwrap(), "xx");
+    }
+
     #[test]
     fn element_with_mixed_children() {
         let package = quick_parse("<hello>to <!--fixme--><a><![CDATA[the]]></a><?world?></hello>");
@@ -979,92 +2257,289 @@ mod test {
         assert_str_eq!(pi.target(),    "world");
     }
 
+    #[test]
+    fn a_borrowed_document_refers_to_the_input() {
+        let xml = "<hello>world</hello>";
+        let package = Parser::new().parse_borrowed(xml).ok().expect("Failed to parse");
+        let doc = package.as_document();
+        let hello = top(&doc);
+        let text = hello.children()[0].text().unwrap();
+
+        assert_str_eq!(hello.name(), "hello");
+        assert_str_eq!(text.text(), "world");
+        assert_eq!(hello.name().as_ptr(), xml.slice_from(1).as_ptr());
+        assert_eq!(text.text().as_ptr(), xml.slice_from(7).as_ptr());
+    }
+
+    #[test]
+    fn a_borrowed_document_expands_references_in_attributes() {
+        let xml = "<log msg='I &lt;3 math' level='info' />";
+        let package = Parser::new().parse_borrowed(xml).ok().expect("Failed to parse");
+        let doc = package.as_document();
+        let top = top(&doc);
+
+        assert_str_eq!(top.attribute_value("msg").unwrap(), "I <3 math");
+        assert_str_eq!(top.attribute_value("level").unwrap(), "info");
+    }
+
+    #[test]
+    fn a_borrowed_document_shares_repeated_names() {
+        let xml = "<a><b/><b/></a>";
+        let package = Parser::new().parse_borrowed(xml).ok().expect("Failed to parse");
+        let doc = package.as_document();
+        let a = top(&doc);
+        let b1 = a.children()[0].element().unwrap();
+        let b2 = a.children()[1].element().unwrap();
+
+        assert_eq!(b1.name().as_ptr(), b2.name().as_ptr());
+    }
+
     #[test]
     fn failure_no_open_brace() {
         let r = full_parse("hi />");
 
-        assert_eq!(r, Err(0));
+        assert_eq!(r, Err(SyntaxError(0)));
     }
 
     #[test]
     fn failure_unclosed_tag() {
         let r = full_parse("<hi");
 
-        assert_eq!(r, Err(3));
+        assert_eq!(r, Err(SyntaxError(3)));
     }
 
     #[test]
     fn failure_unexpected_space() {
         let r = full_parse("<hi / >");
 
-        assert_eq!(r, Err(4));
+        assert_eq!(r, Err(SyntaxError(4)));
     }
 
     #[test]
     fn failure_attribute_without_open_quote() {
         let r = full_parse("<hi oops=value' />");
-        assert_eq!(r, Err(9));
+        assert_eq!(r, Err(SyntaxError(9)));
     }
 
     #[test]
     fn failure_attribute_without_close_quote() {
         let r = full_parse("<hi oops='value />");
 
-        assert_eq!(r, Err(18));
+        assert_eq!(r, Err(SyntaxError(18)));
     }
 
     #[test]
     fn failure_unclosed_attribute_and_tag() {
         let r = full_parse("<hi oops='value");
 
-        assert_eq!(r, Err(15));
+        assert_eq!(r, Err(SyntaxError(15)));
+    }
+
+    #[test]
+    fn failure_mismatched_end_tag() {
+        let r = full_parse("<hi></bye>");
+
+        assert_eq!(r, Err(SyntaxError(4)));
     }
 
     #[test]
     fn failure_nested_unclosed_tag() {
         let r = full_parse("<hi><oops</hi>");
 
-        assert_eq!(r, Err(9));
+        assert_eq!(r, Err(SyntaxError(9)));
     }
 
     #[test]
     fn failure_nested_unexpected_space() {
         let r = full_parse("<hi><oops / ></hi>");
 
-        assert_eq!(r, Err(10));
+        assert_eq!(r, Err(SyntaxError(10)));
     }
 
     #[test]
     fn failure_malformed_entity_reference() {
         let r = full_parse("<hi>Entity: &;</hi>");
 
-        assert_eq!(r, Err(13));
+        assert_eq!(r, Err(SyntaxError(13)));
+    }
+
+    #[test]
+    fn failure_xml_as_processing_instruction_target() {
+        let r = full_parse("<hi><?xml version='1.0'?></hi>");
+
+        assert_eq!(r, Err(SyntaxError(6)));
+    }
+
+    #[test]
+    fn failure_undeclared_entity_reference() {
+        let r = full_parse("<hi>&nbsp;</hi>");
+
+        assert_eq!(r, Err(SyntaxError(4)));
+    }
+
+    #[test]
+    fn failure_reference_to_illegal_character() {
+        let r = full_parse("<hi>&#0;</hi>");
+
+        assert_eq!(r, Err(SyntaxError(4)));
     }
 
     #[test]
     fn failure_nested_malformed_entity_reference() {
         let r = full_parse("<hi><bye>Entity: &;</bye></hi>");
 
-        assert_eq!(r, Err(18));
+        assert_eq!(r, Err(SyntaxError(18)));
     }
 
     #[test]
     fn failure_nested_attribute_without_open_quote() {
         let r = full_parse("<hi><bye oops=value' /></hi>");
-        assert_eq!(r, Err(14));
+        assert_eq!(r, Err(SyntaxError(14)));
     }
 
     #[test]
     fn failure_nested_attribute_without_close_quote() {
         let r = full_parse("<hi><bye oops='value /></hi>");
 
-        assert_eq!(r, Err(23));
+        assert_eq!(r, Err(SyntaxError(23)));
     }
 
     #[test]
     fn failure_nested_unclosed_attribute_and_tag() {
         let r = full_parse("<hi><bye oops='value</hi>");
 
-        assert_eq!(r, Err(20));
+        assert_eq!(r, Err(SyntaxError(20)));
+    }
+
+    #[test]
+    fn limit_on_depth() {
+        let limits = Limits { max_depth: Some(2), ..Limits::none() };
+        let r = limited_parse("<a><b><c/></b></a>", limits);
+
+        assert_eq!(r, Err(LimitExceeded(Depth, 6)));
+    }
+
+    #[test]
+    fn limit_on_depth_allows_siblings() {
+        let limits = Limits { max_depth: Some(2), ..Limits::none() };
+        let r = limited_parse("<a><b/><b/><b/></a>", limits);
+
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn limit_on_attributes() {
+        let limits = Limits { max_attributes: Some(2), ..Limits::none() };
+        let r = limited_parse("<a x='1' y='2' z='3'/>", limits);
+
+        assert_eq!(r, Err(LimitExceeded(Attributes, 15)));
+    }
+
+    #[test]
+    fn limit_on_name_length() {
+        let limits = Limits { max_name_length: Some(3), ..Limits::none() };
+        let r = limited_parse("<abcdef/>", limits);
+
+        assert_eq!(r, Err(LimitExceeded(NameLength, 0)));
+    }
+
+    #[test]
+    fn limit_on_text_length() {
+        let limits = Limits { max_text_length: Some(3), ..Limits::none() };
+        let r = limited_parse("<a>hello</a>", limits);
+
+        assert_eq!(r, Err(LimitExceeded(TextLength, 3)));
+    }
+
+    #[test]
+    fn limit_on_attribute_value_length() {
+        let limits = Limits { max_text_length: Some(3), ..Limits::none() };
+        let r = limited_parse("<a b='hello'/>", limits);
+
+        assert_eq!(r, Err(LimitExceeded(TextLength, 6)));
+    }
+
+    #[test]
+    fn limit_on_nodes() {
+        let limits = Limits { max_nodes: Some(2), ..Limits::none() };
+        let r = limited_parse("<a><b/><c/></a>", limits);
+
+        assert_eq!(r, Err(LimitExceeded(Nodes, 7)));
+    }
+
+    #[test]
+    fn limit_on_entity_expansions() {
+        let limits = Limits { max_entity_expansions: Some(2), ..Limits::none() };
+        let r = limited_parse("<a>&lt;&lt;&lt;</a>", limits);
+
+        assert_eq!(r, Err(LimitExceeded(EntityExpansions, 11)));
+    }
+
+    #[test]
+    fn limit_on_expanded_length() {
+        let limits = Limits { max_expanded_length: Some(50), ..Limits::none() };
+        let r = limited_parse(r#"<!DOCTYPE a [<!ENTITY a "0123456789"><!ENTITY b "&a;&a;&a;&a;&a;&a;">]><a>&b;</a>"#, limits);
+
+        assert_eq!(r, Err(LimitExceeded(ExpandedLength, 74)));
+    }
+
+    /// A document whose element holds a reference to the last of
+    /// `depth` entities, each naming the one before it ten times
+    fn laughs(depth: uint) -> String {
+        let mut xml = r#"<!DOCTYPE a [<!ENTITY lol0 "lol">"#.to_string();
+        for i in range(1, depth + 1) {
+            let refs = format!("&lol{};", i - 1).as_slice().repeat(10);
+            xml.push_str(format!(r#"<!ENTITY lol{} "{}">"#, i, refs).as_slice());
+        }
+        xml.push_str(format!("]><a>&lol{};</a>", depth).as_slice());
+        xml
+    }
+
+    #[test]
+    fn entity_expansion_is_bounded_by_default() {
+        let xml = laughs(9);
+        let offset = xml.as_slice().find_str("<a>").unwrap() + 3;
+
+        let r = Parser::new().parse(xml.as_slice());
+
+        assert_eq!(r, Err(LimitExceeded(EntityExpansions, offset)));
+    }
+
+    #[test]
+    fn no_limits_lifts_the_bound_on_entity_expansion() {
+        let xml = laughs(5);
+
+        assert!(Parser::new().parse(xml.as_slice()).is_err());
+        assert!(limited_parse(xml.as_slice(), Limits::none()).is_ok());
+    }
+
+    #[test]
+    fn limit_on_document_size() {
+        let limits = Limits { max_document_size: Some(8), ..Limits::none() };
+        let r = limited_parse("<a>hello</a>", limits);
+
+        assert_eq!(r, Err(LimitExceeded(DocumentSize, 8)));
+    }
+
+    struct Recorder {
+        events: Vec<String>,
+    }
+
+    impl EventHandler for Recorder {
+        fn start_element(&mut self, name: &str) { self.events.push(format!("<{}", name)) }
+        fn attribute(&mut self, name: &str, value: &str) { self.events.push(format!("{}={}", name, value)) }
+        fn text(&mut self, text: &str) { self.events.push(text.to_string()) }
+        fn end_element(&mut self, name: &str) { self.events.push(format!("{}>", name)) }
+    }
+
+    #[test]
+    fn events_are_reported_in_document_order() {
+        let mut recorder = Recorder { events: Vec::new() };
+        let r = Parser::new().parse_events("<a b='1&amp;2'><!-- c -->x&lt;<d/></a>", &mut recorder);
+
+        assert_eq!(r, Ok(()));
+        assert_eq!(recorder.events, vec!["<a".to_string(), "b=1&2".to_string(),
+                                         "x".to_string(), "<".to_string(),
+                                         "<d".to_string(), "d>".to_string(), "a>".to_string()]);
     }
 }
@@ -0,0 +1,609 @@
+//! Parses as much of a malformed document as possible.
+//!
+//! Instead of trying alternatives like the strict parser,
+//! this looks at the next few characters to decide what comes next.
+//! Well-formed pieces of markup are handed to the strict parser;
+//! when one fails, the smallest reasonable amount of input is skipped
+//! or kept as text and a `Diagnostic` is recorded.
+
+use std::ascii::AsciiExt;
+
+use super::{Parser,ParseFailure,StartPoint,ParserSink,SaxHydrator};
+use super::{ParseResult,Success,Partial,Failure};
+use super::{Limit,Depth,NameLength,TextLength,DocumentSize};
+use super::super::xmlstr::XmlStr;
+
+use super::super::Package;
+use super::super::dom4;
+
+/// A problem in the input that `Parser::parse_leniently` worked
+/// around. Each variant carries the byte offset into the input.
+#[deriving(Show,Clone,PartialEq)]
+pub enum Diagnostic {
+    /// The element starting here was never closed; it was closed
+    /// when its parent was closed or the input ended
+    UnclosedElement(uint),
+    /// This end tag did not match any open element and was ignored
+    UnmatchedEndTag(uint),
+    /// The attribute starting here could not be parsed and was skipped
+    MalformedAttribute(uint),
+    /// This reference could not be expanded and was kept as text
+    UnknownReference(uint),
+    /// This markup could not be parsed and was kept as text
+    MalformedMarkup(uint),
+    /// Text or a reference outside of the document element was dropped
+    ContentOutsideRoot(uint),
+    /// This element followed the document element,
+    /// so it was added as the document element's last child
+    ExtraRootElement(uint),
+    /// One of the parser's `Limits` was exceeded; parsing stopped here
+    StoppedAtLimit(Limit, uint),
+}
+
+impl Diagnostic {
+    pub fn offset(&self) -> uint {
+        match *self {
+            UnclosedElement(o)    |
+            UnmatchedEndTag(o)    |
+            MalformedAttribute(o) |
+            UnknownReference(o)   |
+            MalformedMarkup(o)    |
+            ContentOutsideRoot(o) |
+            ExtraRootElement(o)   |
+            StoppedAtLimit(_, o)  => o,
+        }
+    }
+}
+
+struct OpenElement<'a> {
+    name: &'a str,
+    offset: uint,
+    reopened: bool,
+}
+
+struct Recovery<'a, 'p, 'd> {
+    parser: &'p Parser,
+    hydrator: SaxHydrator<'d>,
+    open: Vec<OpenElement<'a>>,
+    document_element: Option<dom4::Element<'d>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a, 'p, 'd> Recovery<'a, 'p, 'd> {
+    fn new(parser: &'p Parser, doc: &'d dom4::Document<'d>) -> Recovery<'a, 'p, 'd> {
+        Recovery {
+            parser: parser,
+            hydrator: SaxHydrator::new(doc),
+            open: Vec::new(),
+            document_element: None,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn run(&mut self, xml: StartPoint<'a>) {
+        self.parser.reset_counters();
+
+        let mut xml = match self.parser.parse_xml_declaration(xml, &mut self.hydrator) {
+            Success((_, next)) => next,
+            _ => xml,
+        };
+
+        while ! xml.s.is_empty() {
+            xml = match self.step(xml) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+
+        self.close_all();
+    }
+
+    fn step(&mut self, xml: StartPoint<'a>) -> Option<StartPoint<'a>> {
+        let s = xml.s;
+
+        if s.starts_with("<!--") {
+            let result = self.parser.parse_comment(xml, &mut self.hydrator);
+            self.after_markup(xml, result)
+        } else if s.starts_with("<![CDATA[") {
+            if self.open.is_empty() {
+                self.diagnostics.push(ContentOutsideRoot(xml.offset));
+                Some(skip_past(xml, "]]>"))
+            } else {
+                let result = self.parser.parse_cdata(xml, &mut self.hydrator);
+                self.after_markup(xml, result)
+            }
+        } else if s.starts_with("<?") {
+            if is_xml_declaration(s) {
+                self.diagnostics.push(MalformedMarkup(xml.offset));
+                Some(skip_past(xml, "?>"))
+            } else {
+                let result = self.parser.parse_pi(xml, &mut self.hydrator);
+                self.after_markup(xml, result)
+            }
+        } else if s.starts_with("<!DOCTYPE") && self.document_element.is_none() {
+            let result = self.parser.parse_doctype_declaration(xml, &mut self.hydrator);
+            self.after_markup(xml, result)
+        } else if s.starts_with("<!") {
+            self.diagnostics.push(MalformedMarkup(xml.offset));
+            Some(skip_past(xml, ">"))
+        } else if s.starts_with("</") {
+            Some(self.end_tag(xml))
+        } else if s.starts_with("<") {
+            self.start_tag(xml)
+        } else if s.starts_with("&") {
+            self.reference(xml)
+        } else {
+            match xml.consume_char_data() {
+                Success((text, next)) => {
+                    if ! self.open.is_empty() && ! self.text_allowed(xml, text) { return None }
+                    self.text(text, xml.offset);
+                    Some(next)
+                },
+                _ => {
+                    // Only a stray ]]> is not character data
+                    self.diagnostics.push(MalformedMarkup(xml.offset));
+                    Some(self.literal_text(xml, "]]>".len()))
+                },
+            }
+        }
+    }
+
+    /// Records a failure that stops parsing altogether
+    fn stopped(&mut self, failure: ParseFailure<'a>) -> bool {
+        match failure.limit {
+            Some(limit) => {
+                self.diagnostics.push(StoppedAtLimit(limit, failure.point.offset));
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Whether the limits checked by the strict parser allow this,
+    /// recording the limit that was exceeded if not
+    fn allowed(&mut self, result: ParseResult<'a, ()>) -> bool {
+        match result {
+            Success(_) => true,
+            Partial((_, pf, _)) |
+            Failure(pf) => ! self.stopped(pf),
+        }
+    }
+
+    fn element_allowed(&mut self, xml: StartPoint<'a>, name: &str) -> bool {
+        let parser = self.parser;
+        let depth = self.open.len() + 1;
+
+        self.allowed(parser.enforce(xml, NameLength, name.len())) &&
+            self.allowed(parser.enforce(xml, Depth, depth)) &&
+            self.allowed(parser.count_node(xml))
+    }
+
+    fn text_allowed(&mut self, xml: StartPoint<'a>, text: &str) -> bool {
+        let parser = self.parser;
+
+        self.allowed(parser.enforce(xml, TextLength, text.len())) &&
+            self.allowed(parser.count_node(xml))
+    }
+
+    fn after_markup(&mut self, xml: StartPoint<'a>, result: ParseResult<'a, ()>) -> Option<StartPoint<'a>> {
+        match result {
+            Success((_, next)) => Some(next),
+            Partial((_, pf, _)) |
+            Failure(pf) => {
+                if self.stopped(pf) { return None }
+                self.diagnostics.push(MalformedMarkup(xml.offset));
+                Some(self.literal_text(xml, 1))
+            },
+        }
+    }
+
+    fn literal_text(&mut self, xml: StartPoint<'a>, len: uint) -> StartPoint<'a> {
+        let (text, next) = xml.slice_at(len);
+        self.text(text, xml.offset);
+        next
+    }
+
+    fn text(&mut self, text: &'a str, offset: uint) {
+        if ! self.open.is_empty() {
+            self.hydrator.text(text);
+        } else if text.chars().any(|c| ! c.is_whitespace()) {
+            self.diagnostics.push(ContentOutsideRoot(offset));
+        }
+    }
+
+    fn reference(&mut self, xml: StartPoint<'a>) -> Option<StartPoint<'a>> {
+        match self.parser.parse_reference_markup(xml) {
+            Success((reference, next)) => {
+                if self.open.is_empty() {
+                    self.diagnostics.push(ContentOutsideRoot(xml.offset));
+                    return Some(next);
+                }
+
+                match self.parser.decode_reference(xml, reference) {
+                    Success((text, _)) => self.hydrator.reference(text.as_slice()),
+                    Partial((_, pf, _)) |
+                    Failure(pf) => {
+                        if self.stopped(pf) { return None }
+                        self.diagnostics.push(UnknownReference(xml.offset));
+                        let (raw, _) = xml.slice_at(next.offset - xml.offset);
+                        self.hydrator.text(raw);
+                    },
+                }
+                Some(next)
+            },
+            Partial((_, pf, _)) |
+            Failure(pf) => {
+                if self.stopped(pf) { return None }
+                self.diagnostics.push(MalformedMarkup(xml.offset));
+                Some(self.literal_text(xml, 1))
+            },
+        }
+    }
+
+    fn start_tag(&mut self, xml: StartPoint<'a>) -> Option<StartPoint<'a>> {
+        let (_, after) = xml.slice_at(1);
+        let (name, after) = match after.consume_name() {
+            Success(x) => x,
+            _ => {
+                self.diagnostics.push(MalformedMarkup(xml.offset));
+                return Some(self.literal_text(xml, 1));
+            },
+        };
+
+        if self.open.is_empty() {
+            if let Some(root) = self.document_element {
+                self.diagnostics.push(ExtraRootElement(xml.offset));
+                self.hydrator.stack.push(root);
+                self.open.push(OpenElement { name: "", offset: xml.offset, reopened: true });
+            }
+        }
+
+        if ! self.element_allowed(xml, name) { return None }
+
+        self.hydrator.element_start(name);
+        if self.document_element.is_none() {
+            self.document_element = self.hydrator.stack.last().map(|e| *e);
+        }
+
+        let open = OpenElement { name: name, offset: xml.offset, reopened: false };
+        self.parser.attributes.set(0);
+
+        let mut attrs = after;
+        loop {
+            let next = match attrs.consume_space() {
+                Success((_, next)) => next,
+                _ => attrs,
+            };
+
+            if next.s.starts_with("/>") {
+                self.hydrator.element_end(name);
+                let (_, next) = next.slice_at(2);
+                return Some(next);
+            } else if next.s.starts_with(">") {
+                self.open.push(open);
+                let (_, next) = next.slice_at(1);
+                return Some(next);
+            } else if next.s.is_empty() || next.s.starts_with("<") {
+                // The start tag was never finished
+                self.diagnostics.push(MalformedMarkup(next.offset));
+                self.open.push(open);
+                return Some(next);
+            }
+
+            attrs = match self.parser.parse_attribute(attrs, &mut self.hydrator) {
+                Success((_, after_attr)) => after_attr,
+                Partial((_, pf, _)) |
+                Failure(pf) => {
+                    if self.stopped(pf) { return None }
+                    self.diagnostics.push(MalformedAttribute(next.offset));
+                    skip_attribute(next)
+                },
+            };
+        }
+    }
+
+    fn end_tag(&mut self, xml: StartPoint<'a>) -> StartPoint<'a> {
+        let (_, after) = xml.slice_at(2);
+        let (name, after) = match after.consume_name() {
+            Success(x) => x,
+            _ => {
+                self.diagnostics.push(MalformedMarkup(xml.offset));
+                return self.literal_text(xml, 1);
+            },
+        };
+
+        let after = match after.consume_space() {
+            Success((_, next)) => next,
+            _ => after,
+        };
+
+        let after = match after.consume_literal(">") {
+            Success((_, next)) => next,
+            _ => {
+                self.diagnostics.push(MalformedMarkup(after.offset));
+                after
+            },
+        };
+
+        let matching = self.open.iter().enumerate().rev()
+            .find(|&(_, e)| ! e.reopened && e.name == name)
+            .map(|(i, _)| i);
+
+        match matching {
+            Some(idx) => self.close_to(idx),
+            None => self.diagnostics.push(UnmatchedEndTag(xml.offset)),
+        }
+
+        after
+    }
+
+    fn close_one(&mut self, report: bool) {
+        let e = self.open.pop().expect("No element to close");
+        if report && ! e.reopened {
+            self.diagnostics.push(UnclosedElement(e.offset));
+        }
+        self.hydrator.element_end(e.name);
+    }
+
+    fn close_to(&mut self, idx: uint) {
+        while self.open.len() > idx + 1 {
+            self.close_one(true);
+        }
+        self.close_one(false);
+    }
+
+    fn close_all(&mut self) {
+        while ! self.open.is_empty() {
+            self.close_one(true);
+        }
+    }
+}
+
+fn is_xml_declaration(s: &str) -> bool {
+    let after = s.slice_from(2);
+    match after.end_of_name() {
+        Some(len) => after.slice_to(len).eq_ignore_ascii_case("xml"),
+        None => false,
+    }
+}
+
+/// Skips to just after the delimiter, or to the end of the input
+fn skip_past<'a>(xml: StartPoint<'a>, delimiter: &str) -> StartPoint<'a> {
+    let len = match xml.s.find_str(delimiter) {
+        Some(offset) => offset + delimiter.len(),
+        None => xml.s.len(),
+    };
+    let (_, next) = xml.slice_at(len);
+    next
+}
+
+/// Skips at least one character, stopping before the next attribute
+/// or the end of the tag. Quoted values are skipped as a unit, unless
+/// they contain a `<`, which cannot appear in an attribute value.
+fn skip_attribute<'a>(xml: StartPoint<'a>) -> StartPoint<'a> {
+    let mut quote = None;
+    let mut end = xml.s.len();
+
+    for (offset, c) in xml.s.char_indices() {
+        if offset == 0 {
+            if c == '\'' || c == '"' { quote = Some(c) }
+            continue;
+        }
+
+        if c == '<' {
+            end = offset;
+            break;
+        }
+
+        match quote {
+            Some(q) if q == c => quote = None,
+            Some(_) => {},
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == '>' || c == '/' || c.is_whitespace() => {
+                end = offset;
+                break;
+            },
+            None => {},
+        }
+    }
+
+    let (_, next) = xml.slice_at(end);
+    next
+}
+
+impl Parser {
+    /// Parses the input without ever failing. Problems are repaired
+    /// as well as possible and returned alongside the document.
+    ///
+    /// - Elements left open are closed when an ancestor closes or the input ends.
+    /// - End tags that do not match an open element are ignored.
+    /// - Malformed attributes are skipped.
+    /// - Unknown entities and malformed markup are kept as text.
+    /// - Content after the document element is added to it.
+    ///
+    /// Exceeding one of the parser's `Limits` stops parsing,
+    /// returning whatever was parsed up to that point. Input longer
+    /// than the `DocumentSize` limit is not parsed at all.
+    pub fn parse_leniently(&self, xml: &str) -> (Package, Vec<Diagnostic>) {
+        let package = Package::new();
+
+        if let Some(max) = self.limits.maximum(DocumentSize) {
+            if xml.len() > max { return (package, vec![StoppedAtLimit(DocumentSize, max)]) }
+        }
+
+        let diagnostics = {
+            let doc = package.as_document();
+            let mut recovery = Recovery::new(self, &doc);
+            recovery.run(StartPoint{offset: 0, s: xml});
+            recovery.diagnostics
+        };
+
+        (package, diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Parser,Limits,Depth,TextLength,Nodes,DocumentSize};
+    use super::super::super::dom4;
+    use super::{UnclosedElement,UnmatchedEndTag,MalformedAttribute,UnknownReference};
+    use super::{MalformedMarkup,ContentOutsideRoot,ExtraRootElement,StoppedAtLimit};
+
+    macro_rules! assert_str_eq(
+        ($l:expr, $r:expr) => (assert_eq!($l.as_slice(), $r.as_slice()));
+    )
+
+    fn top<'d>(doc: &'d dom4::Document<'d>) -> dom4::Element<'d> {
+        doc.root().children()[0].element().unwrap()
+    }
+
+    #[test]
+    fn well_formed_input_has_no_diagnostics() {
+        let (package, diagnostics) = Parser::new().parse_leniently("<a b='c'><!--x--><?pi v?>t&amp;</a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert!(diagnostics.is_empty());
+        assert_str_eq!(a.attribute_value("b").unwrap(), "c");
+        assert_eq!(a.children().len(), 4);
+    }
+
+    #[test]
+    fn unclosed_elements_are_closed_by_their_parent() {
+        let (package, diagnostics) = Parser::new().parse_leniently("<a><b>text</a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+        let b = a.children()[0].element().unwrap();
+        let text = b.children()[0].text().unwrap();
+
+        assert_eq!(diagnostics, vec![UnclosedElement(3)]);
+        assert_str_eq!(text.text(), "text");
+    }
+
+    #[test]
+    fn unclosed_elements_are_closed_at_the_end() {
+        let (package, diagnostics) = Parser::new().parse_leniently("<a><b>");
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_eq!(diagnostics, vec![UnclosedElement(3), UnclosedElement(0)]);
+        assert_str_eq!(a.children()[0].element().unwrap().name(), "b");
+    }
+
+    #[test]
+    fn unmatched_end_tags_are_ignored() {
+        let (package, diagnostics) = Parser::new().parse_leniently("<a></b></a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_eq!(diagnostics, vec![UnmatchedEndTag(3)]);
+        assert!(a.children().is_empty());
+    }
+
+    #[test]
+    fn malformed_attributes_are_skipped() {
+        let (package, diagnostics) = Parser::new().parse_leniently("<a x=oops y='1'>hi</a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_eq!(diagnostics, vec![MalformedAttribute(3)]);
+        assert_eq!(a.attribute_value("x"), None);
+        assert_str_eq!(a.attribute_value("y").unwrap(), "1");
+    }
+
+    #[test]
+    fn unclosed_attribute_values_stop_at_the_next_tag() {
+        let (package, diagnostics) = Parser::new().parse_leniently("<hi><bye oops='value</hi>");
+        let doc = package.as_document();
+        let hi = top(&doc);
+
+        assert_eq!(diagnostics, vec![MalformedAttribute(9), MalformedMarkup(20), UnclosedElement(4)]);
+        assert_str_eq!(hi.children()[0].element().unwrap().name(), "bye");
+    }
+
+    #[test]
+    fn unknown_entities_are_kept_as_text() {
+        let (package, diagnostics) = Parser::new().parse_leniently("<a>&nbsp;x</a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_eq!(diagnostics, vec![UnknownReference(3)]);
+        assert_str_eq!(a.children()[0].text().unwrap().text(), "&nbsp;");
+        assert_str_eq!(a.children()[1].text().unwrap().text(), "x");
+    }
+
+    #[test]
+    fn stray_ampersands_are_kept_as_text() {
+        let (package, diagnostics) = Parser::new().parse_leniently("<a>AT&T</a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_eq!(diagnostics, vec![MalformedMarkup(5)]);
+        let texts: Vec<String> = a.children().into_iter().map(|c| c.text().unwrap().text().to_string()).collect();
+        assert_str_eq!(texts.as_slice().concat(), "AT&T");
+    }
+
+    #[test]
+    fn text_outside_the_document_element_is_dropped() {
+        let (package, diagnostics) = Parser::new().parse_leniently("oops<a/>");
+        let doc = package.as_document();
+
+        assert_eq!(diagnostics, vec![ContentOutsideRoot(0)]);
+        assert_eq!(doc.root().children().len(), 1);
+    }
+
+    #[test]
+    fn extra_root_elements_are_added_to_the_document_element() {
+        let (package, diagnostics) = Parser::new().parse_leniently("<a/><b/>");
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_eq!(diagnostics, vec![ExtraRootElement(4)]);
+        assert_str_eq!(a.children()[0].element().unwrap().name(), "b");
+    }
+
+    #[test]
+    fn doctypes_are_kept() {
+        let (package, diagnostics) = Parser::new().parse_leniently("<!DOCTYPE a [<!ELEMENT a ANY>]><a>");
+        let doc = package.as_document();
+
+        assert_eq!(diagnostics, vec![UnclosedElement(31)]);
+        assert_str_eq!(doc.root().doctype().unwrap().name(), "a");
+    }
+
+    #[test]
+    fn deep_elements_stop_parsing() {
+        let parser = Parser::with_limits(Limits { max_depth: Some(2), ..Limits::none() });
+        let (package, diagnostics) = parser.parse_leniently("<a><b><c/></b></a>");
+        let doc = package.as_document();
+        let a = top(&doc);
+
+        assert_eq!(diagnostics, vec![StoppedAtLimit(Depth, 6), UnclosedElement(3), UnclosedElement(0)]);
+        assert_str_eq!(a.children()[0].element().unwrap().name(), "b");
+    }
+
+    #[test]
+    fn long_text_stops_parsing() {
+        let parser = Parser::with_limits(Limits { max_text_length: Some(3), ..Limits::none() });
+        let (_, diagnostics) = parser.parse_leniently("<a>hello</a>");
+
+        assert_eq!(diagnostics, vec![StoppedAtLimit(TextLength, 3), UnclosedElement(0)]);
+    }
+
+    #[test]
+    fn elements_count_as_nodes() {
+        let parser = Parser::with_limits(Limits { max_nodes: Some(2), ..Limits::none() });
+        let (_, diagnostics) = parser.parse_leniently("<a><b/><c/></a>");
+
+        assert_eq!(diagnostics, vec![StoppedAtLimit(Nodes, 7), UnclosedElement(0)]);
+    }
+
+    #[test]
+    fn long_documents_are_not_parsed() {
+        let parser = Parser::with_limits(Limits { max_document_size: Some(8), ..Limits::none() });
+        let (package, diagnostics) = parser.parse_leniently("<a>hello</a>");
+
+        assert_eq!(diagnostics, vec![StoppedAtLimit(DocumentSize, 8)]);
+        assert!(package.as_document().root().children().is_empty());
+    }
+}
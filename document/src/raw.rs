@@ -1,11 +1,48 @@
+use std::any::{Any,AnyRefExt};
+use std::cmp;
+use std::cell::RefCell;
+use std::intrinsics::TypeId;
+use std::collections::HashMap;
+use std::collections::hash_map::{Occupied,Vacant};
+
 use arena::TypedArena;
 use string_pool::{StringPool,InternedString};
 
+/// Identifies a node within its package. IDs are not ordered; use
+/// `Document::compare_ids` to put them in document order.
+#[deriving(Show,Clone,PartialEq,Eq,Hash)]
+pub struct NodeId(pub u64);
+
 pub struct Root {
+    id: NodeId,
+    doctype: Option<*mut DocumentType>,
     children: Vec<ChildOfRoot>,
 }
 
+impl Root {
+    pub fn id(&self) -> NodeId { self.id }
+}
+
+pub struct DocumentType {
+    id: NodeId,
+    name: InternedString,
+    public_id: Option<InternedString>,
+    system_id: Option<InternedString>,
+    internal_subset: Option<InternedString>,
+    external_subset: Option<InternedString>,
+}
+
+impl DocumentType {
+    pub fn id(&self) -> NodeId { self.id }
+    pub fn name(&self) -> &str { self.name.as_slice() }
+    pub fn public_id(&self) -> Option<&str> { self.public_id.as_ref().map(|v| v.as_slice()) }
+    pub fn system_id(&self) -> Option<&str> { self.system_id.as_ref().map(|v| v.as_slice()) }
+    pub fn internal_subset(&self) -> Option<&str> { self.internal_subset.as_ref().map(|v| v.as_slice()) }
+    pub fn external_subset(&self) -> Option<&str> { self.external_subset.as_ref().map(|v| v.as_slice()) }
+}
+
 pub struct Element {
+    id: NodeId,
     name: InternedString,
     children: Vec<ChildOfElement>,
     parent: Option<ParentOfChild>,
@@ -13,45 +50,56 @@ pub struct Element {
 }
 
 impl Element {
+    pub fn id(&self) -> NodeId { self.id }
     pub fn name(&self) -> &str { self.name.as_slice() }
+    pub fn interned_name(&self) -> InternedString { self.name }
 }
 
 pub struct Attribute {
+    id: NodeId,
     name: InternedString,
     value: InternedString,
     parent: Option<*mut Element>,
 }
 
 impl Attribute {
+    pub fn id(&self) -> NodeId { self.id }
     pub fn name(&self)  -> &str { self.name.as_slice() }
+    pub fn interned_name(&self) -> InternedString { self.name }
     pub fn value(&self) -> &str { self.value.as_slice() }
 }
 
 pub struct Text {
+    id: NodeId,
     text: InternedString,
     parent: Option<*mut Element>,
 }
 
 impl Text {
+    pub fn id(&self) -> NodeId { self.id }
     pub fn text(&self) -> &str { self.text.as_slice() }
 }
 
 pub struct Comment {
+    id: NodeId,
     text: InternedString,
     parent: Option<ParentOfChild>,
 }
 
 impl Comment {
+    pub fn id(&self) -> NodeId { self.id }
     pub fn text(&self) -> &str { self.text.as_slice() }
 }
 
 pub struct ProcessingInstruction {
+    id: NodeId,
     target: InternedString,
     value: Option<InternedString>,
     parent: Option<ParentOfChild>,
 }
 
 impl ProcessingInstruction {
+    pub fn id(&self) -> NodeId { self.id }
     pub fn target(&self) -> &str { self.target.as_slice() }
     pub fn value(&self) -> Option<&str> { self.value.as_ref().map(|v| v.as_slice()) }
 }
@@ -72,7 +120,7 @@ impl ChildOfRoot {
         }
     }
 
-    fn to_child_of_element(self) -> ChildOfElement {
+    pub fn to_child_of_element(self) -> ChildOfElement {
         match self {
             ElementCOR(n) => ElementCOE(n),
             CommentCOR(n) => CommentCOE(n),
@@ -80,12 +128,26 @@ impl ChildOfRoot {
         }
     }
 
+    pub fn id(&self) -> NodeId {
+        self.to_child_of_element().id()
+    }
+
+    fn clear_parent(&self) {
+        self.to_child_of_element().clear_parent()
+    }
+
     fn replace_parent(&self, parent: *mut Root) {
         match self {
             &ElementCOR(n) => {
+                // The document element being replaced leaves the document
                 let parent_r = unsafe { &mut *parent };
-                let n = unsafe { &mut *n };
+                for c in parent_r.children.iter() {
+                    if let &ElementCOR(old) = c {
+                        if old != n { c.clear_parent() }
+                    }
+                }
                 parent_r.children.retain(|c| !c.is_element());
+                let n = unsafe { &mut *n };
                 replace_parent(*self, RootPOC(parent), &mut n.parent);
             },
             &CommentCOR(n) => {
@@ -129,6 +191,28 @@ fn replace_parent(child: ChildOfRoot, parent: ParentOfChild, parent_field: &mut
 
 
 impl ChildOfElement {
+    pub fn id(&self) -> NodeId {
+        unsafe {
+            match *self {
+                ElementCOE(n) => (*n).id,
+                TextCOE(n) => (*n).id,
+                CommentCOE(n) => (*n).id,
+                ProcessingInstructionCOE(n) => (*n).id,
+            }
+        }
+    }
+
+    fn clear_parent(&self) {
+        unsafe {
+            match self {
+                &ElementCOE(n) => (*n).parent = None,
+                &TextCOE(n) => (*n).parent = None,
+                &CommentCOE(n) => (*n).parent = None,
+                &ProcessingInstructionCOE(n) => (*n).parent = None,
+            }
+        }
+    }
+
     fn replace_parent(&self, parent: *mut Element) {
         match self {
             &ElementCOE(n) => {
@@ -162,6 +246,31 @@ pub enum ParentOfChild {
     ElementPOC(*mut Element),
 }
 
+/// Any node, as found by its ID
+#[allow(raw_pointer_deriving)]
+#[deriving(Clone,PartialEq)]
+pub enum Node {
+    RootNode(*mut Root),
+    DocumentTypeNode(*mut DocumentType),
+    ElementNode(*mut Element),
+    AttributeNode(*mut Attribute),
+    TextNode(*mut Text),
+    CommentNode(*mut Comment),
+    ProcessingInstructionNode(*mut ProcessingInstruction),
+}
+
+impl Node {
+    pub fn to_child_of_element(&self) -> Option<ChildOfElement> {
+        match *self {
+            ElementNode(n) => Some(ElementCOE(n)),
+            TextNode(n) => Some(TextCOE(n)),
+            CommentNode(n) => Some(CommentCOE(n)),
+            ProcessingInstructionNode(n) => Some(ProcessingInstructionCOE(n)),
+            _ => None,
+        }
+    }
+}
+
 macro_rules! conversion_trait(
     ($tr_name:ident, $method:ident, $res_type:ident,
         { $($leaf_type:ident => $variant:ident),* }
@@ -193,9 +302,53 @@ conversion_trait!(ToChildOfRoot, to_child_of_root, ChildOfRoot, {
     Element => ElementCOR
 })
 
+/// How a node was written in the text it was parsed from. What each
+/// part holds depends on the kind of node.
+#[deriving(Show,Clone,PartialEq,Default)]
+pub struct Source {
+    /// Whitespace before a child of the root or the document type
+    /// declaration
+    pub before: Option<String>,
+    /// All of the markup of text, comments, processing instructions,
+    /// attributes (with the whitespace before them) and the document
+    /// type declaration. For an element, the end of the start tag
+    /// after the attributes. For the root, the XML declaration, which
+    /// is empty when there was none.
+    pub markup: Option<String>,
+    /// The end tag of an element, which is empty for an empty-element
+    /// tag. For the root, the whitespace after its last child.
+    pub after: Option<String>,
+    /// How many children of the root came before the document type
+    /// declaration
+    pub position: Option<uint>,
+}
+
+/// A value attached to a node by the application, which can be copied
+/// without knowing its type
+pub trait UserData {
+    fn as_any(&self) -> &Any;
+    fn clone_data(&self) -> Box<UserData + 'static>;
+}
+
+impl<T : Clone + 'static> UserData for T {
+    fn as_any(&self) -> &Any { self as &Any }
+
+    fn clone_data(&self) -> Box<UserData + 'static> {
+        box self.clone() as Box<UserData + 'static>
+    }
+}
+
+/// Every value attached to one node, by type
+pub type UserDataValues = HashMap<TypeId, Box<UserData + 'static>>;
+
 pub struct Storage {
     strings: StringPool,
+    sources: RefCell<HashMap<uint, Source>>,
+    user_data: RefCell<HashMap<uint, UserDataValues>>,
+    /// Every node created, by ID
+    nodes: RefCell<Vec<Node>>,
     roots: TypedArena<Root>,
+    document_types: TypedArena<DocumentType>,
     elements: TypedArena<Element>,
     attributes: TypedArena<Attribute>,
     texts: TypedArena<Text>,
@@ -207,7 +360,11 @@ impl Storage {
     pub fn new() -> Storage {
         Storage {
             strings: StringPool::new(),
+            sources: RefCell::new(HashMap::new()),
+            user_data: RefCell::new(HashMap::new()),
+            nodes: RefCell::new(Vec::new()),
             roots: TypedArena::new(),
+            document_types: TypedArena::new(),
             elements: TypedArena::new(),
             attributes: TypedArena::new(),
             texts: TypedArena::new(),
@@ -216,67 +373,161 @@ impl Storage {
         }
     }
 
-    fn intern(&self, s: &str) -> InternedString {
+    pub fn intern(&self, s: &str) -> InternedString {
         let interned = self.strings.intern(s);
         InternedString::from_str(interned)
     }
 
+    /// Interns a string that is known to outlive this storage,
+    /// without copying it if it has not been seen before.
+    pub unsafe fn intern_borrowed(&self, s: &str) -> InternedString {
+        let interned = self.strings.intern_borrowed(s);
+        InternedString::from_str(interned)
+    }
+
+    fn next_id(&self) -> NodeId {
+        NodeId(self.nodes.borrow().len() as u64)
+    }
+
+    fn register(&self, node: Node) {
+        self.nodes.borrow_mut().push(node);
+    }
+
+    /// The node with this ID, if this storage created it
+    pub fn node(&self, id: NodeId) -> Option<Node> {
+        let NodeId(id) = id;
+        self.nodes.borrow().as_slice().get(id as uint).map(|n| n.clone())
+    }
+
+    /// Every node created, in the order of their IDs
+    pub fn nodes(&self) -> Vec<Node> {
+        self.nodes.borrow().clone()
+    }
+
     pub fn create_root(&self) -> *mut Root {
-        self.roots.alloc(Root {
+        let root = self.roots.alloc(Root {
+            id: self.next_id(),
+            doctype: None,
             children: Vec::new(),
-        })
+        });
+        self.register(RootNode(root));
+        root
+    }
+
+    pub fn create_document_type(&self,
+                                name: &str,
+                                public_id: Option<&str>,
+                                system_id: Option<&str>,
+                                internal_subset: Option<&str>)
+                                -> *mut DocumentType
+    {
+        let name = self.intern(name);
+        let public_id = public_id.map(|v| self.intern(v));
+        let system_id = system_id.map(|v| self.intern(v));
+        let internal_subset = internal_subset.map(|v| self.intern(v));
+        self.create_document_type_from(name, public_id, system_id, internal_subset)
+    }
+
+    pub fn create_document_type_from(&self,
+                                     name: InternedString,
+                                     public_id: Option<InternedString>,
+                                     system_id: Option<InternedString>,
+                                     internal_subset: Option<InternedString>)
+                                     -> *mut DocumentType
+    {
+        let doctype = self.document_types.alloc(DocumentType {
+            id: self.next_id(),
+            name: name,
+            public_id: public_id,
+            system_id: system_id,
+            internal_subset: internal_subset,
+            external_subset: None,
+        });
+        self.register(DocumentTypeNode(doctype));
+        doctype
     }
 
     pub fn create_element(&self, name: &str) -> *mut Element {
         let name = self.intern(name);
+        self.create_element_from(name)
+    }
 
-        self.elements.alloc(Element {
+    pub fn create_element_from(&self, name: InternedString) -> *mut Element {
+        let element = self.elements.alloc(Element {
+            id: self.next_id(),
             name: name,
             children: Vec::new(),
             parent: None,
             attributes: Vec::new(),
-        })
+        });
+        self.register(ElementNode(element));
+        element
     }
 
     pub fn create_attribute(&self, name: &str, value: &str) -> *mut Attribute {
         let name = self.intern(name);
         let value = self.intern(value);
+        self.create_attribute_from(name, value)
+    }
 
-        self.attributes.alloc(Attribute {
+    pub fn create_attribute_from(&self, name: InternedString, value: InternedString) -> *mut Attribute {
+        let attribute = self.attributes.alloc(Attribute {
+            id: self.next_id(),
             name: name,
             value: value,
             parent: None,
-        })
+        });
+        self.register(AttributeNode(attribute));
+        attribute
     }
 
     pub fn create_text(&self, text: &str) -> *mut Text {
         let text = self.intern(text);
+        self.create_text_from(text)
+    }
 
-        self.texts.alloc(Text {
+    pub fn create_text_from(&self, text: InternedString) -> *mut Text {
+        let text = self.texts.alloc(Text {
+            id: self.next_id(),
             text: text,
             parent: None,
-        })
+        });
+        self.register(TextNode(text));
+        text
     }
 
     pub fn create_comment(&self, text: &str) -> *mut Comment {
         let text = self.intern(text);
+        self.create_comment_from(text)
+    }
 
-        self.comments.alloc(Comment {
+    pub fn create_comment_from(&self, text: InternedString) -> *mut Comment {
+        let comment = self.comments.alloc(Comment {
+            id: self.next_id(),
             text: text,
             parent: None,
-        })
+        });
+        self.register(CommentNode(comment));
+        comment
     }
 
     pub fn create_processing_instruction(&self, target: &str, value: Option<&str>)
                                          -> *mut ProcessingInstruction {
         let target = self.intern(target);
         let value = value.map(|v| self.intern(v));
+        self.create_processing_instruction_from(target, value)
+    }
 
-        self.processing_instructions.alloc(ProcessingInstruction {
+    pub fn create_processing_instruction_from(&self, target: InternedString, value: Option<InternedString>)
+                                              -> *mut ProcessingInstruction {
+        let pi = self.processing_instructions.alloc(ProcessingInstruction {
+            id: self.next_id(),
             target: target,
             value: value,
             parent: None,
-        })
+        });
+        self.register(ProcessingInstructionNode(pi));
+        pi
     }
 
     pub fn element_set_name(&self, element: *mut Element, name: &str) {
@@ -285,12 +536,94 @@ impl Storage {
         element_r.name = name;
     }
 
+    pub fn document_type_set_external_subset(&self, doctype: *mut DocumentType, subset: Option<&str>) {
+        let subset = subset.map(|v| self.intern(v));
+        let doctype_r = unsafe { &mut * doctype };
+        doctype_r.external_subset = subset;
+    }
+
     pub fn text_set_text(&self, text: *mut Text, new_text: &str) {
         let new_text = self.intern(new_text);
         let text_r = unsafe { &mut * text };
         text_r.text = new_text;
     }
 
+    /// The recorded source of any node, given by its address
+    pub fn source(&self, node: uint) -> Source {
+        self.sources.borrow().get(&node).map(|s| s.clone()).unwrap_or_default()
+    }
+
+    pub fn set_source(&self, node: uint, source: Source) {
+        let mut sources = self.sources.borrow_mut();
+        if source == Default::default() {
+            sources.remove(&node);
+        } else {
+            sources.insert(node, source);
+        }
+    }
+
+    /// The value of type `T` attached to any node, given by its address
+    pub fn user_data<T : Clone + 'static>(&self, node: uint) -> Option<T> {
+        let user_data = self.user_data.borrow();
+        user_data.get(&node)
+            .and_then(|values| values.get(&TypeId::of::<T>()))
+            .and_then(|value| value.as_any().downcast_ref::<T>())
+            .map(|value| value.clone())
+    }
+
+    pub fn set_user_data<T : Clone + 'static>(&self, node: uint, value: T) {
+        let mut user_data = self.user_data.borrow_mut();
+        let values = match user_data.entry(node) {
+            Occupied(entry) => entry.into_mut(),
+            Vacant(entry) => entry.set(HashMap::new()),
+        };
+        values.insert(TypeId::of::<T>(), box value as Box<UserData + 'static>);
+    }
+
+    pub fn remove_user_data<T : Clone + 'static>(&self, node: uint) -> Option<T> {
+        let value = self.user_data::<T>(node);
+        if let Some(values) = self.user_data.borrow_mut().get_mut(&node) {
+            values.remove(&TypeId::of::<T>());
+        }
+        value
+    }
+
+    /// Drops every value attached to the node
+    pub fn clear_user_data(&self, node: uint) {
+        self.user_data.borrow_mut().remove(&node);
+    }
+
+    /// Copies of every value attached to the node
+    pub fn user_data_values(&self, node: uint) -> UserDataValues {
+        match self.user_data.borrow().get(&node) {
+            Some(values) => values.iter().map(|(k, v)| (*k, v.clone_data())).collect(),
+            None => HashMap::new(),
+        }
+    }
+
+    /// Replaces every value attached to the node
+    pub fn set_user_data_values(&self, node: uint, values: UserDataValues) {
+        let mut user_data = self.user_data.borrow_mut();
+        if values.is_empty() {
+            user_data.remove(&node);
+        } else {
+            user_data.insert(node, values);
+        }
+    }
+
+    /// Attaches copies of the values of one node to another
+    pub fn copy_user_data(&self, from: uint, to: uint) {
+        let copies = self.user_data_values(from);
+        if copies.is_empty() { return }
+        self.set_user_data_values(to, copies);
+    }
+
+    pub fn attribute_set_value(&self, attribute: *mut Attribute, new_value: &str) {
+        let new_value = self.intern(new_value);
+        let attribute_r = unsafe { &mut * attribute };
+        attribute_r.value = new_value;
+    }
+
     pub fn comment_set_text(&self, comment: *mut Comment, new_text: &str) {
         let new_text = self.intern(new_text);
         let comment_r = unsafe { &mut * comment };
@@ -310,14 +643,155 @@ impl Storage {
     }
 }
 
+static XML_ID: &'static str = "xml:id";
+
+/// The elements in the document that have one name
+struct NamedElements {
+    elements: Vec<*mut Element>,
+    /// Whether `elements` is known to be in document order
+    sorted: bool,
+}
+
+impl NamedElements {
+    fn in_document_order(&mut self) -> &[*mut Element] {
+        if ! self.sorted {
+            let mut keyed: Vec<(Vec<uint>, *mut Element)> =
+                self.elements.iter().map(|e| (document_position(*e), *e)).collect();
+            keyed.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+            self.elements = keyed.into_iter().map(|(_, e)| e).collect();
+            self.sorted = true;
+        }
+        self.elements.as_slice()
+    }
+}
+
+/// Keyed by the names interned by the package's storage, so that
+/// indexing an element does not copy its name
+struct NameIndex {
+    elements: HashMap<InternedString, NamedElements>,
+}
+
+impl NameIndex {
+    fn add(&mut self, element: *mut Element, in_order: bool) {
+        let element_r = unsafe { &*element };
+        let named = match self.elements.entry(element_r.name) {
+            Occupied(entry) => entry.into_mut(),
+            Vacant(entry) => entry.set(NamedElements { elements: Vec::new(), sorted: true }),
+        };
+        named.elements.push(element);
+        named.sorted = named.sorted && in_order;
+    }
+
+    fn remove(&mut self, element: *mut Element) {
+        let element_r = unsafe { &*element };
+        if let Some(named) = self.elements.get_mut(&element_r.name) {
+            named.elements.retain(|e| *e != element);
+        }
+    }
+}
+
+/// The element and its descendant elements, in document order
+fn subtree_elements(element: *mut Element) -> Vec<*mut Element> {
+    let mut elements = Vec::new();
+    let mut todo = vec![element];
+
+    while ! todo.is_empty() {
+        let e = todo.pop().unwrap();
+        let e_r = unsafe { &*e };
+        elements.push(e);
+
+        for child in e_r.children.iter().rev() {
+            if let &ElementCOE(c) = child {
+                todo.push(c);
+            }
+        }
+    }
+
+    elements
+}
+
+/// The index of the element and each of its ancestors among their
+/// siblings, starting from the root. These sort into document order.
+fn document_position(element: *mut Element) -> Vec<uint> {
+    let mut position = Vec::new();
+    let mut current = element;
+
+    loop {
+        let current_r = unsafe { &*current };
+        match current_r.parent {
+            Some(RootPOC(root)) => {
+                let root_r = unsafe { &*root };
+                position.push(root_r.children.iter().position(|c| *c == ElementCOR(current)).unwrap_or(0));
+                break;
+            },
+            Some(ElementPOC(parent)) => {
+                let parent_r = unsafe { &*parent };
+                position.push(parent_r.children.iter().position(|c| *c == ElementCOE(current)).unwrap_or(0));
+                current = parent;
+            },
+            None => break,
+        }
+    }
+
+    position.reverse();
+    position
+}
+
+/// Whether no element follows this one in the document, other than
+/// its own descendants
+fn is_last_in_document(element: *mut Element) -> bool {
+    let mut current = element;
+
+    loop {
+        let current_r = unsafe { &*current };
+        match current_r.parent {
+            Some(RootPOC(_)) => return true,
+            Some(ElementPOC(parent)) => {
+                let parent_r = unsafe { &*parent };
+                let last = parent_r.children.iter().rev().filter_map(|c| match *c {
+                    ElementCOE(e) => Some(e),
+                    _ => None,
+                }).next();
+                if last != Some(current) { return false }
+                current = parent;
+            },
+            None => return false,
+        }
+    }
+}
+
+fn is_descendant(element: *mut Element, ancestor: *mut Element) -> bool {
+    let mut current = element;
+
+    loop {
+        let current_r = unsafe { &*current };
+        match current_r.parent {
+            Some(ElementPOC(parent)) if parent == ancestor => return true,
+            Some(ElementPOC(parent)) => current = parent,
+            _ => return false,
+        }
+    }
+}
+
 pub struct Connections {
     root: *mut Root,
+    /// The elements in the document with an ID attribute of each
+    /// value. Entries are checked again when looked up, in case an
+    /// ID was declared after they were indexed.
+    ids: RefCell<HashMap<String, Vec<*mut Element>>>,
+    /// Attributes other than `xml:id` that are IDs, as pairs of
+    /// element and attribute names
+    id_attributes: RefCell<Vec<(String, String)>>,
+    name_index: RefCell<Option<NameIndex>>,
 }
 
 impl Connections {
     pub fn new(root: *mut Root) -> Connections {
         Connections {
             root: root,
+            ids: RefCell::new(HashMap::new()),
+            id_attributes: RefCell::new(Vec::new()),
+            name_index: RefCell::new(None),
         }
     }
 
@@ -325,6 +799,16 @@ impl Connections {
         self.root
     }
 
+    pub fn root_doctype(&self) -> Option<*mut DocumentType> {
+        let root_r = unsafe { &*self.root };
+        root_r.doctype
+    }
+
+    pub fn set_root_doctype(&self, doctype: Option<*mut DocumentType>) {
+        let root_r = unsafe { &mut *self.root };
+        root_r.doctype = doctype;
+    }
+
     pub fn element_parent(&self, child: *mut Element) -> Option<ParentOfChild> {
         let child_r = unsafe { &*child };
         child_r.parent
@@ -349,16 +833,256 @@ impl Connections {
         let child = child.to_child_of_root();
         let parent_r = unsafe { &mut *self.root };
 
+        self.before_root_attach(child);
         child.replace_parent(self.root);
         parent_r.children.push(child);
+        self.after_attach(child.to_child_of_element());
     }
 
     pub fn append_element_child<C : ToChildOfElement>(&self, parent: *mut Element, child: C) {
         let child = child.to_child_of_element();
         let parent_r = unsafe { &mut *parent };
 
+        self.before_attach(child);
         child.replace_parent(parent);
         parent_r.children.push(child);
+        self.after_attach(child);
+    }
+
+    /// Inserts the child before the one at `index`, or at the end when
+    /// there are fewer children
+    pub fn insert_root_child<C : ToChildOfRoot>(&self, index: uint, child: C) {
+        let child = child.to_child_of_root();
+        let parent_r = unsafe { &mut *self.root };
+
+        self.before_root_attach(child);
+        child.replace_parent(self.root);
+        let index = cmp::min(index, parent_r.children.len());
+        parent_r.children.insert(index, child);
+        self.after_attach(child.to_child_of_element());
+    }
+
+    pub fn remove_root_child<C : ToChildOfRoot>(&self, child: C) {
+        let child = child.to_child_of_root();
+        let parent_r = unsafe { &mut *self.root };
+
+        if ! parent_r.children.contains(&child) { return }
+
+        self.before_attach(child.to_child_of_element());
+        parent_r.children.retain(|c| *c != child);
+        child.clear_parent();
+    }
+
+    /// Inserts the child before the one at `index`, or at the end when
+    /// there are fewer children
+    pub fn insert_element_child<C : ToChildOfElement>(&self, parent: *mut Element, index: uint, child: C) {
+        let child = child.to_child_of_element();
+        let parent_r = unsafe { &mut *parent };
+
+        self.before_attach(child);
+        child.replace_parent(parent);
+        let index = cmp::min(index, parent_r.children.len());
+        parent_r.children.insert(index, child);
+        self.after_attach(child);
+    }
+
+    pub fn remove_element_child<C : ToChildOfElement>(&self, parent: *mut Element, child: C) {
+        let child = child.to_child_of_element();
+        let parent_r = unsafe { &mut *parent };
+
+        if ! parent_r.children.contains(&child) { return }
+
+        self.before_attach(child);
+        parent_r.children.retain(|c| *c != child);
+        child.clear_parent();
+    }
+
+    /// A new element child of the root replaces the old one, which
+    /// leaves the document
+    fn before_root_attach(&self, child: ChildOfRoot) {
+        if let ElementCOR(_) = child {
+            let root_r = unsafe { &*self.root };
+            for c in root_r.children.iter() {
+                if let &ElementCOR(e) = c {
+                    self.unindex_names(e);
+                    self.unindex_ids(e);
+                }
+            }
+        }
+        self.before_attach(child.to_child_of_element());
+    }
+
+    /// The child is leaving its current parent, so it and its
+    /// descendants may be leaving the document
+    fn before_attach(&self, child: ChildOfElement) {
+        if let ElementCOE(e) = child {
+            self.unindex_names(e);
+            self.unindex_ids(e);
+        }
+    }
+
+    fn after_attach(&self, child: ChildOfElement) {
+        if let ElementCOE(e) = child {
+            self.index_names(e);
+            self.index_ids(e);
+        }
+    }
+
+    /// Indexes the IDs of the element and its descendants, if they
+    /// are in the document
+    fn index_ids(&self, element: *mut Element) {
+        if ! self.is_connected(element) { return }
+
+        for e in subtree_elements(element).into_iter() {
+            let e_r = unsafe { &*e };
+            for a in e_r.attributes.iter() {
+                let a_r: &Attribute = unsafe { &**a };
+                if self.is_id(e_r.name(), a_r.name()) {
+                    self.index_id(e, a_r.value());
+                }
+            }
+        }
+    }
+
+    fn unindex_ids(&self, element: *mut Element) {
+        if self.ids.borrow().is_empty() || ! self.is_connected(element) { return }
+
+        for e in subtree_elements(element).into_iter() {
+            let e_r = unsafe { &*e };
+            for a in e_r.attributes.iter() {
+                let a_r: &Attribute = unsafe { &**a };
+                if self.may_be_id(a_r.name()) {
+                    self.unindex_id(e, a_r.value());
+                }
+            }
+        }
+    }
+
+    /// Starts keeping track of the elements in the document by name,
+    /// so that `elements_named` does not need to visit every element
+    pub fn enable_name_index(&self) {
+        if self.name_index.borrow().is_some() { return }
+
+        let mut index = NameIndex { elements: HashMap::new() };
+        let root_r = unsafe { &*self.root };
+        for c in root_r.children.iter() {
+            if let &ElementCOR(e) = c {
+                for element in subtree_elements(e).into_iter() {
+                    index.add(element, true);
+                }
+            }
+        }
+
+        *self.name_index.borrow_mut() = Some(index);
+    }
+
+    fn index_names(&self, element: *mut Element) {
+        let mut index = self.name_index.borrow_mut();
+        let index = match index.as_mut() {
+            Some(index) => index,
+            None => return,
+        };
+        if ! self.is_connected(element) { return }
+
+        // Elements appended after everything else are already in
+        // document order, which is the common case while parsing
+        let in_order = is_last_in_document(element);
+        for e in subtree_elements(element).into_iter() {
+            index.add(e, in_order);
+        }
+    }
+
+    fn unindex_names(&self, element: *mut Element) {
+        let mut index = self.name_index.borrow_mut();
+        let index = match index.as_mut() {
+            Some(index) => index,
+            None => return,
+        };
+        if ! self.is_connected(element) { return }
+
+        for e in subtree_elements(element).into_iter() {
+            index.remove(e);
+        }
+    }
+
+    /// Moves the element to its new name in the name index, and
+    /// indexes the attributes that are IDs under its new name
+    pub fn element_renamed(&self, element: *mut Element, old_name: &str) {
+        self.id_owner_renamed(element);
+
+        let mut index = self.name_index.borrow_mut();
+        let index = match index.as_mut() {
+            Some(index) => index,
+            None => return,
+        };
+        if ! self.is_connected(element) { return }
+
+        if let Some(named) = index.elements.get_mut(&InternedString::from_str(old_name)) {
+            named.elements.retain(|e| *e != element);
+        }
+        index.add(element, false);
+    }
+
+    fn id_owner_renamed(&self, element: *mut Element) {
+        if ! self.is_connected(element) { return }
+
+        let element_r = unsafe { &*element };
+        for a in element_r.attributes.iter() {
+            let a_r: &Attribute = unsafe { &**a };
+            if self.is_id(element_r.name(), a_r.name()) {
+                self.index_id(element, a_r.value());
+            } else if self.may_be_id(a_r.name()) {
+                self.unindex_id(element, a_r.value());
+            }
+        }
+    }
+
+    /// The elements with this name, in document order. When `within`
+    /// is given, only its descendants are included.
+    pub fn elements_named(&self, within: Option<*mut Element>, name: &str) -> Vec<*mut Element> {
+        let mut index = self.name_index.borrow_mut();
+
+        if let Some(index) = index.as_mut() {
+            let within_document = within.map_or(true, |w| self.is_connected(w));
+            if within_document {
+                let all = match index.elements.get_mut(&InternedString::from_str(name)) {
+                    Some(named) => named.in_document_order(),
+                    None => return Vec::new(),
+                };
+                return match within {
+                    Some(ancestor) => all.iter().map(|e| *e).filter(|e| is_descendant(*e, ancestor)).collect(),
+                    None => all.to_vec(),
+                };
+            }
+        }
+
+        let starts: Vec<*mut Element> = match within {
+            Some(ancestor) => {
+                let ancestor_r = unsafe { &*ancestor };
+                ancestor_r.children.iter().filter_map(|c| match *c {
+                    ElementCOE(e) => Some(e),
+                    _ => None,
+                }).collect()
+            },
+            None => {
+                let root_r = unsafe { &*self.root };
+                root_r.children.iter().filter_map(|c| match *c {
+                    ElementCOR(e) => Some(e),
+                    _ => None,
+                }).collect()
+            },
+        };
+
+        let mut found = Vec::new();
+        for start in starts.into_iter() {
+            for e in subtree_elements(start).into_iter() {
+                let e_r = unsafe { &*e };
+                if e_r.name() == name {
+                    found.push(e);
+                }
+            }
+        }
+        found
     }
 
     pub unsafe fn root_children(&self) -> &[ChildOfRoot] {
@@ -389,15 +1113,200 @@ impl Connections {
         }).map(|a| *a)
     }
 
+    /// Like `attribute`, for a name interned by this package's storage
+    pub fn attribute_interned(&self, element: *mut Element, name: InternedString) -> Option<*mut Attribute> {
+        let element_r = unsafe { &*element };
+        element_r.attributes.iter().find(|a| {
+            let a_r: &Attribute = unsafe { &***a };
+            a_r.name.is(&name)
+        }).map(|a| *a)
+    }
+
     pub fn set_attribute(&self, parent: *mut Element, attribute: *mut Attribute) {
         let parent_r = unsafe { &mut *parent };
         let attr_r = unsafe { &mut *attribute };
 
-        parent_r.attributes.retain(|a| {
+        if let Some(old) = self.attribute(parent, attr_r.name()) {
+            let old_r = unsafe { &mut *old };
+            if self.may_be_id(old_r.name()) {
+                self.unindex_id(parent, old_r.value());
+            }
+            old_r.parent = None;
+        }
+
+        // A replaced attribute keeps its place
+        let existing = parent_r.attributes.iter().position(|a| {
             let a_r: &Attribute = unsafe { &**a };
-            a_r.name != attr_r.name
+            a_r.name == attr_r.name
         });
-        parent_r.attributes.push(attribute);
+        match existing {
+            Some(index) => {
+                parent_r.attributes.remove(index);
+                parent_r.attributes.insert(index, attribute);
+            },
+            None => parent_r.attributes.push(attribute),
+        }
         attr_r.parent = Some(parent);
+
+        if self.is_id(parent_r.name(), attr_r.name()) && self.is_connected(parent) {
+            self.index_id(parent, attr_r.value());
+        }
+    }
+
+    pub fn remove_attribute(&self, parent: *mut Element, name: &str) {
+        let parent_r = unsafe { &mut *parent };
+
+        if let Some(old) = self.attribute(parent, name) {
+            let old_r = unsafe { &mut *old };
+            if self.may_be_id(old_r.name()) {
+                self.unindex_id(parent, old_r.value());
+            }
+            old_r.parent = None;
+        }
+
+        parent_r.attributes.retain(|a| {
+            let a_r: &Attribute = unsafe { &**a };
+            a_r.name.as_slice() != name
+        });
+    }
+
+    /// Keeps the ID index current after the value of an attribute
+    /// has been changed in place
+    pub fn attribute_value_changed(&self, attribute: *mut Attribute, old_value: &str) {
+        let attr_r = unsafe { &*attribute };
+        if let Some(parent) = attr_r.parent {
+            if self.may_be_id(attr_r.name()) {
+                self.unindex_id(parent, old_value);
+                let parent_r = unsafe { &*parent };
+                if self.is_id(parent_r.name(), attr_r.name()) && self.is_connected(parent) {
+                    self.index_id(parent, attr_r.value());
+                }
+            }
+        }
+    }
+
+    /// Whether an attribute with this name is an ID on any element
+    fn may_be_id(&self, attribute: &str) -> bool {
+        attribute == XML_ID ||
+            self.id_attributes.borrow().iter().any(|&(_, ref a)| a.as_slice() == attribute)
+    }
+
+    fn is_id(&self, element: &str, attribute: &str) -> bool {
+        attribute == XML_ID ||
+            self.id_attributes.borrow().iter().any(|&(ref e, ref a)| {
+                e.as_slice() == element && a.as_slice() == attribute
+            })
+    }
+
+    fn index_id(&self, element: *mut Element, value: &str) {
+        let mut ids = self.ids.borrow_mut();
+        let elements = match ids.entry(value.trim().to_string()) {
+            Occupied(entry) => entry.into_mut(),
+            Vacant(entry) => entry.set(Vec::new()),
+        };
+        if ! elements.contains(&element) {
+            elements.push(element);
+        }
+    }
+
+    fn unindex_id(&self, element: *mut Element, value: &str) {
+        let mut ids = self.ids.borrow_mut();
+        let now_empty = match ids.get_mut(value.trim()) {
+            Some(elements) => {
+                elements.retain(|e| *e != element);
+                elements.is_empty()
+            },
+            None => false,
+        };
+        if now_empty {
+            ids.remove(value.trim());
+        }
+    }
+
+    /// Treats the attribute as an ID on elements with this name, such
+    /// as when a DTD declares it to be one. Elements already in the
+    /// document are indexed.
+    pub fn declare_id_attribute(&self, element_name: &str, attribute: &str) {
+        if self.is_id(element_name, attribute) { return }
+        self.id_attributes.borrow_mut().push((element_name.to_string(), attribute.to_string()));
+
+        let root_r = unsafe { &*self.root };
+        let mut todo: Vec<*mut Element> = root_r.children.iter().filter_map(|c| match *c {
+            ElementCOR(e) => Some(e),
+            _ => None,
+        }).collect();
+
+        while ! todo.is_empty() {
+            let element = todo.pop().unwrap();
+            let element_r = unsafe { &*element };
+
+            if element_r.name() == element_name {
+                if let Some(attr) = self.attribute(element, attribute) {
+                    let attr_r = unsafe { &*attr };
+                    self.index_id(element, attr_r.value());
+                }
+            }
+
+            for child in element_r.children.iter() {
+                if let &ElementCOE(child) = child {
+                    todo.push(child);
+                }
+            }
+        }
+    }
+
+    fn is_connected(&self, element: *mut Element) -> bool {
+        let mut current = element;
+        loop {
+            let current_r = unsafe { &*current };
+            match current_r.parent {
+                Some(RootPOC(root)) => return root == self.root,
+                Some(ElementPOC(parent)) => current = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// Whether the element is in the document and has an ID attribute
+    /// with this value
+    fn has_id(&self, element: *mut Element, id: &str) -> bool {
+        let element_r = unsafe { &*element };
+        self.is_connected(element) && element_r.attributes.iter().any(|a| {
+            let a_r: &Attribute = unsafe { &**a };
+            self.is_id(element_r.name(), a_r.name()) && a_r.value().trim() == id
+        })
+    }
+
+    /// The first element in document order with this ID
+    pub fn element_by_id(&self, id: &str) -> Option<*mut Element> {
+        let ids = self.ids.borrow();
+        ids.get(id).and_then(|elements| {
+            elements.iter()
+                .filter(|e| self.has_id(**e, id))
+                .map(|e| (document_position(*e), *e))
+                .min_by(|&(ref position, _)| position.clone())
+                .map(|(_, e)| e)
+        })
+    }
+
+    /// Every element whose ID was already given to an element before
+    /// it in the document, along with the ID, ordered by ID
+    pub fn duplicate_ids(&self) -> Vec<(*mut Element, String)> {
+        let ids = self.ids.borrow();
+
+        let mut duplicates = Vec::new();
+        for (id, elements) in ids.iter() {
+            let mut elements: Vec<(Vec<uint>, *mut Element)> = elements.iter()
+                .filter(|e| self.has_id(**e, id.as_slice()))
+                .map(|e| (document_position(*e), *e))
+                .collect();
+            elements.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+            for &(_, e) in elements.iter().skip(1) {
+                duplicates.push((e, id.clone()));
+            }
+        }
+
+        duplicates.sort_by(|&(_, ref a), &(_, ref b)| a.cmp(b));
+        duplicates
     }
 }
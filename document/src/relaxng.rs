@@ -0,0 +1,1998 @@
+//! Validates documents against a RELAX NG schema
+//!
+//! Schemas may be written in the XML syntax or the compact syntax.
+//! Validation uses derivatives of the schema's patterns, so it can run
+//! over a dom4 document or directly over the parser's events without
+//! building a tree.
+//!
+//! ### Example
+//!
+//! ```
+//! use document::parser::Parser;
+//! use document::relaxng::Schema;
+//!
+//! let schema = Schema::parse_compact("element age { xsd:nonNegativeInteger }")
+//!     .ok().expect("Invalid schema");
+//!
+//! let package = Parser::new().parse("<age>42</age>").ok().expect("Failed to parse");
+//! let doc = package.as_document();
+//! assert!(schema.validate(&doc).is_ok());
+//!
+//! let mut validator = schema.validator();
+//! Parser::new().parse_events("<age>-1</age>", &mut validator).ok().expect("Failed to parse");
+//! assert!(validator.finish().is_err());
+//! ```
+//!
+//! Schemas that use `include` or `externalRef` should be loaded with
+//! `Schema::from_file`, so that the referenced files are found
+//! relative to the file that refers to them. Files whose names end in
+//! `.rnc` are read as compact syntax.
+//!
+//! ### Known issues
+//!
+//! - Namespaces are not supported. Names are matched by their local
+//!   part, `ns` attributes and namespace declarations are ignored, and
+//!   schemas that use `nsName` are rejected.
+//! - In the XML syntax, elements with a different prefix than the
+//!   document element are treated as annotations.
+//! - Only the built-in datatypes and the XML Schema datatypes library
+//!   are known. XML Schema values are compared as strings after
+//!   whitespace normalization.
+//! - Derivatives are not memoized, so large interleaves can be slow.
+//! - The compact syntax does not understand `\x{...}` escapes.
+//! - Validation stops at the first problem.
+
+use std::collections::HashMap;
+use std::io::{File,IoError};
+use std::mem;
+use std::rc::Rc;
+
+use super::datatypes::{Facets,Builtins,Collapse,InvalidFacetPattern};
+use super::datatypes::{is_builtin,normalize,builtin_white_space,is_builtin_list};
+use super::dom4;
+use super::dom4::{ElementCOE,TextCOE};
+use super::parser::{Parser,ParseError,EventHandler};
+use super::xmlstr::{XmlChar,prefix_of,local_name,is_namespace_declaration};
+
+static XSD_DATATYPES: &'static str = "http://www.w3.org/2001/XMLSchema-datatypes";
+
+/// Why a schema could not be loaded. The location is the path of the
+/// schema file, or empty for a schema given as a string.
+#[deriving(Show,Clone,PartialEq)]
+pub enum SchemaError {
+    ReadFailure(String, IoError),
+    ParseFailure(String, ParseError),
+    /// The compact syntax could not be understood at this line
+    CompactSyntaxError(String, uint),
+    /// A pattern is missing a required part or has a value that cannot
+    /// be understood
+    InvalidComponent(String),
+    /// A `ref` or `parentRef` names no definition
+    UnresolvedReference(String),
+    /// The datatype is not in a known datatype library
+    UnknownDatatype(String),
+    /// A grammar has no `start`
+    MissingStart,
+    /// A `pattern` parameter is not a regular expression
+    InvalidPattern(String),
+    /// The schema relies on namespaces, which are not supported
+    UnsupportedNamespaces(String),
+}
+
+/// The first way in which a document does not match its schema. Each
+/// variant carries the name of the element where it was found.
+#[deriving(Show,Clone,PartialEq)]
+pub enum ValidationError {
+    /// The element is not allowed here
+    UnexpectedElement(String),
+    /// The attribute is not allowed on the element, or its value does
+    /// not match
+    UnexpectedAttribute(String, String),
+    /// The element lacks an attribute that it requires
+    MissingAttribute(String),
+    /// The element's text does not match its pattern
+    InvalidText(String),
+    /// The element ended before its content was complete
+    IncompleteElement(String),
+    /// There is no document element
+    IncompleteDocument,
+}
+
+enum NameClass {
+    /// Any name except those in the optional name class
+    AnyName(Option<Box<NameClass>>),
+    LocalName(String),
+    NameChoice(Box<NameClass>, Box<NameClass>),
+}
+
+enum Datatype {
+    StringType,
+    TokenType,
+    /// A type from the XML Schema datatypes library, restricted by the
+    /// facets given as parameters
+    XsdType(String, Facets),
+}
+
+enum Pattern {
+    EmptyPattern,
+    NotAllowedPattern,
+    TextPattern,
+    ChoicePattern(Rc<Pattern>, Rc<Pattern>),
+    InterleavePattern(Rc<Pattern>, Rc<Pattern>),
+    GroupPattern(Rc<Pattern>, Rc<Pattern>),
+    OneOrMorePattern(Rc<Pattern>),
+    ListPattern(Rc<Pattern>),
+    /// A datatype and the pattern its values must not match
+    DataPattern(Rc<Datatype>, Option<Rc<Pattern>>),
+    ValuePattern(Rc<Datatype>, String),
+    AttributePattern(Rc<NameClass>, Rc<Pattern>),
+    /// The index of the element's content in the schema, which lets
+    /// elements contain themselves
+    ElementPattern(Rc<NameClass>, uint),
+    /// The first pattern matches the rest of the current element and
+    /// the second what follows its end tag
+    AfterPattern(Rc<Pattern>, Rc<Pattern>),
+}
+
+fn is_whitespace(s: &str) -> bool {
+    s.chars().all(|c| c.is_space_char())
+}
+
+impl NameClass {
+    fn contains(&self, name: &str) -> bool {
+        match *self {
+            AnyName(ref except) => except.as_ref().map_or(true, |e| ! e.contains(name)),
+            LocalName(ref local) => local.as_slice() == local_name(name),
+            NameChoice(ref a, ref b) => a.contains(name) || b.contains(name),
+        }
+    }
+}
+
+impl Datatype {
+    fn normalize(&self, value: &str) -> String {
+        match *self {
+            StringType => value.to_string(),
+            TokenType => normalize(value, Collapse),
+            XsdType(ref name, ref facets) => {
+                let white_space = match facets.white_space {
+                    Some(white_space) => white_space,
+                    None => builtin_white_space(name.as_slice()),
+                };
+                normalize(value, white_space)
+            },
+        }
+    }
+
+    fn allows(&self, builtins: &Builtins, value: &str) -> bool {
+        match *self {
+            XsdType(ref name, ref facets) => {
+                let name = name.as_slice();
+                let value = self.normalize(value);
+                let value = value.as_slice();
+                let length = if is_builtin_list(name) { value.words().count() } else { value.chars().count() };
+                builtins.check(name, value) && facets.check(value, length).is_ok()
+            },
+            _ => true,
+        }
+    }
+
+    fn equal(&self, builtins: &Builtins, value: &str, expected: &str) -> bool {
+        self.allows(builtins, value) && self.normalize(value) == self.normalize(expected)
+    }
+}
+
+fn empty() -> Rc<Pattern> {
+    Rc::new(EmptyPattern)
+}
+
+fn not_allowed() -> Rc<Pattern> {
+    Rc::new(NotAllowedPattern)
+}
+
+fn is_empty(p: &Rc<Pattern>) -> bool {
+    match **p { EmptyPattern => true, _ => false }
+}
+
+fn is_not_allowed(p: &Rc<Pattern>) -> bool {
+    match **p { NotAllowedPattern => true, _ => false }
+}
+
+fn ptr_eq<T>(a: &Rc<T>, b: &Rc<T>) -> bool {
+    &**a as *const T == &**b as *const T
+}
+
+/// Whether two patterns match the same things because they have the
+/// same structure. Dropping such duplicates from choices keeps the
+/// derivatives small.
+fn same(a: &Rc<Pattern>, b: &Rc<Pattern>) -> bool {
+    if ptr_eq(a, b) { return true }
+
+    match (&**a, &**b) {
+        (&EmptyPattern, &EmptyPattern) |
+        (&NotAllowedPattern, &NotAllowedPattern) |
+        (&TextPattern, &TextPattern) => true,
+        (&ChoicePattern(ref a1, ref a2), &ChoicePattern(ref b1, ref b2)) |
+        (&InterleavePattern(ref a1, ref a2), &InterleavePattern(ref b1, ref b2)) |
+        (&GroupPattern(ref a1, ref a2), &GroupPattern(ref b1, ref b2)) |
+        (&AfterPattern(ref a1, ref a2), &AfterPattern(ref b1, ref b2)) => same(a1, b1) && same(a2, b2),
+        (&OneOrMorePattern(ref a1), &OneOrMorePattern(ref b1)) |
+        (&ListPattern(ref a1), &ListPattern(ref b1)) => same(a1, b1),
+        (&DataPattern(ref d1, ref e1), &DataPattern(ref d2, ref e2)) => {
+            ptr_eq(d1, d2) && match (e1, e2) {
+                (&None, &None) => true,
+                (&Some(ref e1), &Some(ref e2)) => same(e1, e2),
+                _ => false,
+            }
+        },
+        (&ValuePattern(ref d1, ref v1), &ValuePattern(ref d2, ref v2)) => ptr_eq(d1, d2) && v1 == v2,
+        (&AttributePattern(ref n1, ref p1), &AttributePattern(ref n2, ref p2)) => ptr_eq(n1, n2) && same(p1, p2),
+        (&ElementPattern(_, i1), &ElementPattern(_, i2)) => i1 == i2,
+        _ => false,
+    }
+}
+
+fn choice(a: Rc<Pattern>, b: Rc<Pattern>) -> Rc<Pattern> {
+    if is_not_allowed(&a) {
+        b
+    } else if is_not_allowed(&b) || same(&a, &b) {
+        a
+    } else {
+        Rc::new(ChoicePattern(a, b))
+    }
+}
+
+fn group(a: Rc<Pattern>, b: Rc<Pattern>) -> Rc<Pattern> {
+    if is_not_allowed(&a) || is_not_allowed(&b) {
+        not_allowed()
+    } else if is_empty(&a) {
+        b
+    } else if is_empty(&b) {
+        a
+    } else {
+        Rc::new(GroupPattern(a, b))
+    }
+}
+
+fn interleave(a: Rc<Pattern>, b: Rc<Pattern>) -> Rc<Pattern> {
+    if is_not_allowed(&a) || is_not_allowed(&b) {
+        not_allowed()
+    } else if is_empty(&a) {
+        b
+    } else if is_empty(&b) {
+        a
+    } else {
+        Rc::new(InterleavePattern(a, b))
+    }
+}
+
+fn after(a: Rc<Pattern>, b: Rc<Pattern>) -> Rc<Pattern> {
+    if is_not_allowed(&a) || is_not_allowed(&b) {
+        not_allowed()
+    } else {
+        Rc::new(AfterPattern(a, b))
+    }
+}
+
+fn one_or_more(p: Rc<Pattern>) -> Rc<Pattern> {
+    if is_not_allowed(&p) { p } else { Rc::new(OneOrMorePattern(p)) }
+}
+
+/// Whether the pattern matches nothing at all
+fn nullable(p: &Rc<Pattern>) -> bool {
+    match **p {
+        EmptyPattern | TextPattern => true,
+        ChoicePattern(ref a, ref b) => nullable(a) || nullable(b),
+        GroupPattern(ref a, ref b) | InterleavePattern(ref a, ref b) => nullable(a) && nullable(b),
+        OneOrMorePattern(ref a) => nullable(a),
+        _ => false,
+    }
+}
+
+/// Applies the function to what follows each `after` in the pattern
+fn apply_after(p: &Rc<Pattern>, f: |Rc<Pattern>| -> Rc<Pattern>) -> Rc<Pattern> {
+    match **p {
+        AfterPattern(ref a, ref b) => after(a.clone(), f(b.clone())),
+        ChoicePattern(ref a, ref b) => {
+            let left = apply_after(a, |q| f(q));
+            let right = apply_after(b, |q| f(q));
+            choice(left, right)
+        },
+        _ => not_allowed(),
+    }
+}
+
+fn start_tag_close_deriv(p: &Rc<Pattern>) -> Rc<Pattern> {
+    match **p {
+        AfterPattern(ref a, ref b) => after(start_tag_close_deriv(a), b.clone()),
+        ChoicePattern(ref a, ref b) => choice(start_tag_close_deriv(a), start_tag_close_deriv(b)),
+        GroupPattern(ref a, ref b) => group(start_tag_close_deriv(a), start_tag_close_deriv(b)),
+        InterleavePattern(ref a, ref b) => interleave(start_tag_close_deriv(a), start_tag_close_deriv(b)),
+        OneOrMorePattern(ref a) => one_or_more(start_tag_close_deriv(a)),
+        AttributePattern(..) => not_allowed(),
+        _ => p.clone(),
+    }
+}
+
+fn end_tag_deriv(p: &Rc<Pattern>) -> Rc<Pattern> {
+    match **p {
+        ChoicePattern(ref a, ref b) => choice(end_tag_deriv(a), end_tag_deriv(b)),
+        AfterPattern(ref a, ref b) => if nullable(a) { b.clone() } else { not_allowed() },
+        _ => not_allowed(),
+    }
+}
+
+/// Reports an element and its descendants as the parser would
+fn walk<H: EventHandler>(element: dom4::Element, handler: &mut H) {
+    handler.start_element(element.name());
+    for attribute in element.attributes().iter() {
+        handler.attribute(attribute.name(), attribute.value());
+    }
+    for child in element.children().into_iter() {
+        match child {
+            ElementCOE(e) => walk(e, handler),
+            TextCOE(t) => handler.text(t.text()),
+            _ => {},
+        }
+    }
+    handler.end_element(element.name());
+}
+
+/// A loaded schema. It is not changed by validation, so one schema can
+/// check any number of documents.
+pub struct Schema {
+    start: Rc<Pattern>,
+    elements: Vec<Rc<Pattern>>,
+    builtins: Builtins,
+}
+
+impl Schema {
+    /// Reads a schema in the XML syntax from a string. Included files
+    /// are found relative to the current directory.
+    pub fn parse(rng: &str) -> Result<Schema, SchemaError> {
+        let mut loader = Loader::new();
+        let root = try!(loader.read_xml(rng, "", &Path::new(".")));
+        loader.finish(root)
+    }
+
+    /// Reads a schema in the compact syntax from a string. Included
+    /// files are found relative to the current directory.
+    pub fn parse_compact(rnc: &str) -> Result<Schema, SchemaError> {
+        let mut loader = Loader::new();
+        let root = try!(loader.read_compact(rnc, "", &Path::new(".")));
+        loader.finish(root)
+    }
+
+    /// Reads a schema from a file, along with every file it includes
+    /// or refers to
+    pub fn from_file(path: &Path) -> Result<Schema, SchemaError> {
+        let mut loader = Loader::new();
+        let root = try!(loader.read_file(path));
+        loader.reading.push(path.display().to_string());
+        loader.finish(root)
+    }
+
+    /// A validator to give to `Parser::parse_events`
+    pub fn validator<'s>(&'s self) -> Validator<'s> {
+        Validator {
+            schema: self,
+            pattern: self.start.clone(),
+            open: Vec::new(),
+            in_start_tag: false,
+            error: None,
+        }
+    }
+
+    /// Checks the document against the schema, stopping at the first
+    /// problem
+    pub fn validate<'d>(&self, doc: &'d dom4::Document<'d>) -> Result<(), ValidationError> {
+        let mut validator = self.validator();
+        for child in doc.root().children().into_iter() {
+            if let Some(element) = child.element() {
+                walk(element, &mut validator);
+            }
+        }
+        validator.finish()
+    }
+
+    fn text_deriv(&self, p: &Rc<Pattern>, s: &str) -> Rc<Pattern> {
+        match **p {
+            ChoicePattern(ref a, ref b) => choice(self.text_deriv(a, s), self.text_deriv(b, s)),
+            InterleavePattern(ref a, ref b) => {
+                choice(interleave(self.text_deriv(a, s), b.clone()),
+                       interleave(a.clone(), self.text_deriv(b, s)))
+            },
+            GroupPattern(ref a, ref b) => {
+                let derived = group(self.text_deriv(a, s), b.clone());
+                if nullable(a) { choice(derived, self.text_deriv(b, s)) } else { derived }
+            },
+            AfterPattern(ref a, ref b) => after(self.text_deriv(a, s), b.clone()),
+            OneOrMorePattern(ref a) => group(self.text_deriv(a, s), choice(p.clone(), empty())),
+            TextPattern => p.clone(),
+            ValuePattern(ref datatype, ref value) => {
+                if datatype.equal(&self.builtins, s, value.as_slice()) { empty() } else { not_allowed() }
+            },
+            DataPattern(ref datatype, ref except) => {
+                let excluded = except.as_ref().map_or(false, |e| nullable(&self.text_deriv(e, s)));
+                if datatype.allows(&self.builtins, s) && ! excluded { empty() } else { not_allowed() }
+            },
+            ListPattern(ref a) => {
+                let mut derived = a.clone();
+                for token in s.words() {
+                    derived = self.text_deriv(&derived, token);
+                }
+                if nullable(&derived) { empty() } else { not_allowed() }
+            },
+            _ => not_allowed(),
+        }
+    }
+
+    fn value_matches(&self, p: &Rc<Pattern>, value: &str) -> bool {
+        (nullable(p) && is_whitespace(value)) || nullable(&self.text_deriv(p, value))
+    }
+
+    fn start_tag_open_deriv(&self, p: &Rc<Pattern>, name: &str) -> Rc<Pattern> {
+        match **p {
+            ChoicePattern(ref a, ref b) => {
+                choice(self.start_tag_open_deriv(a, name), self.start_tag_open_deriv(b, name))
+            },
+            ElementPattern(ref name_class, index) => {
+                if name_class.contains(name) { after(self.elements[index].clone(), empty()) } else { not_allowed() }
+            },
+            InterleavePattern(ref a, ref b) => {
+                let left = apply_after(&self.start_tag_open_deriv(a, name), |q| interleave(q, b.clone()));
+                let right = apply_after(&self.start_tag_open_deriv(b, name), |q| interleave(a.clone(), q));
+                choice(left, right)
+            },
+            OneOrMorePattern(ref a) => {
+                apply_after(&self.start_tag_open_deriv(a, name), |q| group(q, choice(p.clone(), empty())))
+            },
+            GroupPattern(ref a, ref b) => {
+                let derived = apply_after(&self.start_tag_open_deriv(a, name), |q| group(q, b.clone()));
+                if nullable(a) { choice(derived, self.start_tag_open_deriv(b, name)) } else { derived }
+            },
+            AfterPattern(ref a, ref b) => {
+                apply_after(&self.start_tag_open_deriv(a, name), |q| after(q, b.clone()))
+            },
+            _ => not_allowed(),
+        }
+    }
+
+    fn attribute_deriv(&self, p: &Rc<Pattern>, name: &str, value: &str) -> Rc<Pattern> {
+        match **p {
+            AfterPattern(ref a, ref b) => after(self.attribute_deriv(a, name, value), b.clone()),
+            ChoicePattern(ref a, ref b) => {
+                choice(self.attribute_deriv(a, name, value), self.attribute_deriv(b, name, value))
+            },
+            GroupPattern(ref a, ref b) => {
+                choice(group(self.attribute_deriv(a, name, value), b.clone()),
+                       group(a.clone(), self.attribute_deriv(b, name, value)))
+            },
+            InterleavePattern(ref a, ref b) => {
+                choice(interleave(self.attribute_deriv(a, name, value), b.clone()),
+                       interleave(a.clone(), self.attribute_deriv(b, name, value)))
+            },
+            OneOrMorePattern(ref a) => group(self.attribute_deriv(a, name, value), choice(p.clone(), empty())),
+            AttributePattern(ref name_class, ref content) => {
+                if name_class.contains(name) && self.value_matches(content, value) { empty() } else { not_allowed() }
+            },
+            _ => not_allowed(),
+        }
+    }
+}
+
+struct OpenElement {
+    name: String,
+    has_children: bool,
+    /// The text since the last child element
+    text: String,
+}
+
+/// Checks a document as it is parsed. Give it to
+/// `Parser::parse_events`, then call `finish`.
+pub struct Validator<'s> {
+    schema: &'s Schema,
+    pattern: Rc<Pattern>,
+    open: Vec<OpenElement>,
+    in_start_tag: bool,
+    error: Option<ValidationError>,
+}
+
+impl<'s> Validator<'s> {
+    /// The first problem found, if any
+    pub fn finish(self) -> Result<(), ValidationError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        if nullable(&self.pattern) { Ok(()) } else { Err(IncompleteDocument) }
+    }
+
+    fn current_name(&self) -> String {
+        self.open.last().map_or(String::new(), |e| e.name.clone())
+    }
+
+    /// Moves on to the new pattern, or records the error if nothing
+    /// can match any more
+    fn advance(&mut self, pattern: Rc<Pattern>, error: ValidationError) -> bool {
+        if is_not_allowed(&pattern) {
+            self.error = Some(error);
+            false
+        } else {
+            self.pattern = pattern;
+            true
+        }
+    }
+
+    fn close_start_tag(&mut self) -> bool {
+        if ! self.in_start_tag { return true }
+        self.in_start_tag = false;
+
+        let pattern = start_tag_close_deriv(&self.pattern);
+        let error = MissingAttribute(self.current_name());
+        self.advance(pattern, error)
+    }
+
+    /// Matches the text since the last child element of the current
+    /// element. Whitespace between child elements is not significant.
+    fn match_text(&mut self, at_end: bool) -> bool {
+        let (text, has_children) = match self.open.last_mut() {
+            Some(element) => (mem::replace(&mut element.text, String::new()), element.has_children),
+            None => return true,
+        };
+        let text = text.as_slice();
+
+        let pattern = if at_end && ! has_children {
+            let derived = self.schema.text_deriv(&self.pattern, text);
+            if is_whitespace(text) { choice(self.pattern.clone(), derived) } else { derived }
+        } else if ! is_whitespace(text) {
+            self.schema.text_deriv(&self.pattern, text)
+        } else {
+            return true;
+        };
+
+        let error = InvalidText(self.current_name());
+        self.advance(pattern, error)
+    }
+}
+
+impl<'s> EventHandler for Validator<'s> {
+    fn start_element(&mut self, name: &str) {
+        if self.error.is_some() || ! self.close_start_tag() || ! self.match_text(false) { return }
+
+        if let Some(parent) = self.open.last_mut() {
+            parent.has_children = true;
+        }
+
+        let pattern = self.schema.start_tag_open_deriv(&self.pattern, name);
+        if self.advance(pattern, UnexpectedElement(name.to_string())) {
+            self.open.push(OpenElement { name: name.to_string(), has_children: false, text: String::new() });
+            self.in_start_tag = true;
+        }
+    }
+
+    fn attribute(&mut self, name: &str, value: &str) {
+        if self.error.is_some() || is_namespace_declaration(name) { return }
+
+        let pattern = self.schema.attribute_deriv(&self.pattern, name, value);
+        let error = UnexpectedAttribute(self.current_name(), name.to_string());
+        self.advance(pattern, error);
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.error.is_some() || ! self.close_start_tag() { return }
+
+        if let Some(element) = self.open.last_mut() {
+            element.text.push_str(text);
+        }
+    }
+
+    fn end_element(&mut self, _name: &str) {
+        if self.error.is_some() || ! self.close_start_tag() || ! self.match_text(true) { return }
+
+        let pattern = end_tag_deriv(&self.pattern);
+        let error = IncompleteElement(self.current_name());
+        if self.advance(pattern, error) {
+            self.open.pop();
+        }
+    }
+}
+
+/// A pattern in either syntax, before references are resolved. Names
+/// are local names, and `datatypeLibrary` and `href` attributes have
+/// been filled in from their context.
+#[deriving(Clone)]
+struct Node {
+    /// Tells apart elements that are reached more than once
+    id: uint,
+    name: String,
+    attributes: HashMap<String, String>,
+    children: Vec<Node>,
+    text: String,
+}
+
+fn new_node(next_id: &mut uint, name: &str) -> Node {
+    let id = *next_id;
+    *next_id += 1;
+
+    Node {
+        id: id,
+        name: name.to_string(),
+        attributes: HashMap::new(),
+        children: Vec::new(),
+        text: String::new(),
+    }
+}
+
+impl Node {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        match self.attributes.get(name) {
+            Some(value) => Some(value.as_slice()),
+            None => None,
+        }
+    }
+
+    fn required(&self, name: &str) -> Result<&str, SchemaError> {
+        match self.attribute(name) {
+            Some(value) => Ok(value),
+            None => Err(InvalidComponent(format!("{} requires a {} attribute", self.name, name))),
+        }
+    }
+
+    fn combine(&self) -> Option<String> {
+        self.attribute("combine").map(|c| c.to_string())
+    }
+}
+
+fn name_class(node: &Node) -> Result<NameClass, SchemaError> {
+    match node.name.as_slice() {
+        "name" => Ok(LocalName(local_name(node.text.as_slice().trim()).to_string())),
+        "nsName" => Err(UnsupportedNamespaces(node.name.clone())),
+        "anyName" => {
+            let except = match node.children.iter().find(|c| c.name.as_slice() == "except") {
+                Some(except) => Some(box try!(name_class_choice(except.children.as_slice()))),
+                None => None,
+            };
+            Ok(AnyName(except))
+        },
+        "choice" => name_class_choice(node.children.as_slice()),
+        _ => Err(InvalidComponent(format!("unknown name class {}", node.name))),
+    }
+}
+
+fn name_class_choice(nodes: &[Node]) -> Result<NameClass, SchemaError> {
+    let mut classes = Vec::new();
+    for node in nodes.iter() {
+        classes.push(try!(name_class(node)));
+    }
+
+    let mut classes = classes.into_iter();
+    match classes.next() {
+        Some(first) => Ok(classes.fold(first, |a, b| NameChoice(box a, box b))),
+        None => Err(InvalidComponent("a name class choice is empty".to_string())),
+    }
+}
+
+/// The name class of an `element` or `attribute`, and the patterns
+/// of its content
+fn name_class_and_content(node: &Node) -> Result<(NameClass, &[Node]), SchemaError> {
+    match node.attribute("name") {
+        Some(name) => Ok((LocalName(local_name(name).to_string()), node.children.as_slice())),
+        None if ! node.children.is_empty() => {
+            let name_class = try!(name_class(&node.children[0]));
+            Ok((name_class, node.children.slice_from(1)))
+        },
+        None => Err(InvalidComponent(format!("{} has no name", node.name))),
+    }
+}
+
+fn datatype(node: &Node, params: &[&Node]) -> Result<Datatype, SchemaError> {
+    let (library, name) = match node.attribute("type") {
+        Some(name) => (node.attribute("datatypeLibrary").unwrap_or(""), local_name(name)),
+        None if node.name.as_slice() == "value" => ("", "token"),
+        None => return Err(InvalidComponent(format!("{} requires a type attribute", node.name))),
+    };
+
+    if library.is_empty() {
+        if ! params.is_empty() {
+            return Err(InvalidComponent(format!("{} has no parameters", name)));
+        }
+        return match name {
+            "string" => Ok(StringType),
+            "token" => Ok(TokenType),
+            _ => Err(UnknownDatatype(name.to_string())),
+        };
+    }
+
+    if library != XSD_DATATYPES || ! is_builtin(name) {
+        return Err(UnknownDatatype(format!("{}#{}", library, name)));
+    }
+
+    let mut facets = Facets::new();
+    for param in params.iter() {
+        let facet = try!(param.required("name"));
+        match facets.add(facet, param.text.as_slice()) {
+            Ok(()) => {},
+            Err(InvalidFacetPattern) => return Err(InvalidPattern(param.text.clone())),
+            Err(..) => return Err(InvalidComponent(format!("invalid parameter {} = '{}'", facet, param.text))),
+        }
+    }
+
+    Ok(XsdType(name.to_string(), facets))
+}
+
+/// The definitions that references in one grammar can see
+struct Grammar {
+    id: uint,
+    defines: HashMap<String, Node>,
+    parent: Option<Rc<Grammar>>,
+}
+
+/// The `start` and `define` components of a grammar with their
+/// `combine` methods, before definitions of the same name are merged
+struct Components {
+    starts: Vec<(Option<String>, Node)>,
+    defines: Vec<(String, Option<String>, Node)>,
+}
+
+impl Components {
+    fn new() -> Components {
+        Components { starts: Vec::new(), defines: Vec::new() }
+    }
+}
+
+struct Loader {
+    next_id: uint,
+    grammars: uint,
+    /// The files being loaded, to catch files that include themselves
+    reading: Vec<String>,
+    elements: Vec<Rc<Pattern>>,
+    element_ids: HashMap<uint, (Rc<NameClass>, uint)>,
+    defines: HashMap<(uint, String), Rc<Pattern>>,
+    /// The definitions being expanded since the last element, which
+    /// may not refer to themselves
+    expanding: Vec<(uint, String)>,
+}
+
+impl Loader {
+    fn new() -> Loader {
+        Loader {
+            next_id: 0,
+            grammars: 0,
+            reading: Vec::new(),
+            elements: Vec::new(),
+            element_ids: HashMap::new(),
+            defines: HashMap::new(),
+            expanding: Vec::new(),
+        }
+    }
+
+    fn finish(mut self, root: Node) -> Result<Schema, SchemaError> {
+        let top = Rc::new(Grammar { id: 0, defines: HashMap::new(), parent: None });
+        let start = try!(self.pattern(&root, &top));
+
+        Ok(Schema { start: start, elements: self.elements, builtins: Builtins::new() })
+    }
+
+    fn node(&mut self, name: &str) -> Node {
+        new_node(&mut self.next_id, name)
+    }
+
+    fn read_file(&mut self, path: &Path) -> Result<Node, SchemaError> {
+        let location = path.display().to_string();
+        if self.reading.contains(&location) {
+            return Err(InvalidComponent(format!("{} refers to itself", location)));
+        }
+
+        let text = match File::open(path).read_to_string() {
+            Ok(text) => text,
+            Err(e) => return Err(ReadFailure(location, e)),
+        };
+
+        if path.extension_str() == Some("rnc") {
+            self.read_compact(text.as_slice(), location.as_slice(), &path.dir_path())
+        } else {
+            self.read_xml(text.as_slice(), location.as_slice(), &path.dir_path())
+        }
+    }
+
+    fn read_xml(&mut self, rng: &str, location: &str, directory: &Path) -> Result<Node, SchemaError> {
+        let package = match Parser::new().parse(rng) {
+            Ok(package) => package,
+            Err(e) => return Err(ParseFailure(location.to_string(), e)),
+        };
+        let doc = package.as_document();
+
+        let root = match doc.root().children().into_iter().filter_map(|c| c.element()).next() {
+            Some(root) => root,
+            None => return Err(InvalidComponent(format!("{} has no patterns", location))),
+        };
+
+        Ok(self.convert(root, prefix_of(root.name()).unwrap_or(""), "", directory))
+    }
+
+    fn read_compact(&mut self, rnc: &str, location: &str, directory: &Path) -> Result<Node, SchemaError> {
+        let tokens = match tokenize(rnc) {
+            Ok(tokens) => tokens,
+            Err(line) => return Err(CompactSyntaxError(location.to_string(), line)),
+        };
+        let tokens = match strip_annotations(tokens) {
+            Ok(tokens) => tokens,
+            Err(line) => return Err(CompactSyntaxError(location.to_string(), line)),
+        };
+
+        let mut datatypes = HashMap::new();
+        datatypes.insert("xsd".to_string(), XSD_DATATYPES.to_string());
+
+        let mut parser = CompactParser {
+            tokens: tokens,
+            position: 0,
+            datatypes: datatypes,
+            location: location,
+            directory: directory,
+            next_id: &mut self.next_id,
+        };
+        parser.top_level()
+    }
+
+    /// Copies the RELAX NG elements, skipping annotations
+    fn convert(&mut self, element: dom4::Element, prefix: &str, library: &str, directory: &Path) -> Node {
+        let mut node = self.node(local_name(element.name()));
+
+        for attribute in element.attributes().iter() {
+            let name = attribute.name();
+            if name.contains_char(':') || name == "xmlns" { continue }
+            node.attributes.insert(name.to_string(), attribute.value().to_string());
+        }
+
+        let library = match node.attribute("datatypeLibrary") {
+            Some(library) => library.to_string(),
+            None => library.to_string(),
+        };
+        if node.name.as_slice() == "data" || node.name.as_slice() == "value" {
+            node.attributes.insert("datatypeLibrary".to_string(), library.clone());
+        }
+
+        let href = node.attribute("href").map(|href| directory.join(href).display().to_string());
+        if let Some(href) = href {
+            node.attributes.insert("href".to_string(), href);
+        }
+
+        for child in element.children().into_iter() {
+            match child {
+                ElementCOE(e) if prefix_of(e.name()).unwrap_or("") == prefix => {
+                    let child = self.convert(e, prefix, library.as_slice(), directory);
+                    node.children.push(child);
+                },
+                TextCOE(t) => node.text.push_str(t.text()),
+                _ => {},
+            }
+        }
+
+        node
+    }
+
+    fn pattern(&mut self, node: &Node, scope: &Rc<Grammar>) -> Result<Rc<Pattern>, SchemaError> {
+        let children = node.children.as_slice();
+
+        let pattern = match node.name.as_slice() {
+            "element" => return self.element(node, scope),
+            "attribute" => {
+                let (name_class, content) = try!(name_class_and_content(node));
+                let content = if content.is_empty() { Rc::new(TextPattern) } else { try!(self.sequence(content, scope)) };
+                AttributePattern(Rc::new(name_class), content)
+            },
+            "group" => return self.sequence(children, scope),
+            "interleave" => return self.combine(children, scope, interleave),
+            "choice" => return self.combine(children, scope, choice),
+            "optional" => return Ok(choice(try!(self.sequence(children, scope)), empty())),
+            "zeroOrMore" => return Ok(choice(one_or_more(try!(self.sequence(children, scope))), empty())),
+            "oneOrMore" => return Ok(one_or_more(try!(self.sequence(children, scope)))),
+            "mixed" => return Ok(interleave(try!(self.sequence(children, scope)), Rc::new(TextPattern))),
+            "list" => ListPattern(try!(self.sequence(children, scope))),
+            "empty" => EmptyPattern,
+            "text" => TextPattern,
+            "notAllowed" => NotAllowedPattern,
+            "value" => ValuePattern(Rc::new(try!(datatype(node, &[]))), node.text.clone()),
+            "data" => {
+                let params: Vec<&Node> = children.iter().filter(|c| c.name.as_slice() == "param").collect();
+                let datatype = try!(datatype(node, params.as_slice()));
+                let except = match children.iter().find(|c| c.name.as_slice() == "except") {
+                    Some(except) => Some(try!(self.combine(except.children.as_slice(), scope, choice))),
+                    None => None,
+                };
+                DataPattern(Rc::new(datatype), except)
+            },
+            "ref" => return self.reference(scope, try!(node.required("name"))),
+            "parentRef" => {
+                let name = try!(node.required("name"));
+                return match scope.parent {
+                    Some(ref parent) => self.reference(parent, name),
+                    None => Err(UnresolvedReference(name.to_string())),
+                };
+            },
+            "externalRef" => {
+                let href = try!(node.required("href")).to_string();
+                let external = try!(self.read_file(&Path::new(href.as_slice())));
+
+                self.reading.push(href);
+                let pattern = self.pattern(&external, scope);
+                self.reading.pop();
+                return pattern;
+            },
+            "grammar" => return self.grammar(node, scope),
+            _ => return Err(InvalidComponent(format!("unknown pattern {}", node.name))),
+        };
+
+        Ok(Rc::new(pattern))
+    }
+
+    fn sequence(&mut self, nodes: &[Node], scope: &Rc<Grammar>) -> Result<Rc<Pattern>, SchemaError> {
+        self.combine(nodes, scope, group)
+    }
+
+    /// Joins the patterns, giving `empty` when there are none
+    fn combine(&mut self,
+               nodes: &[Node],
+               scope: &Rc<Grammar>,
+               join: fn(Rc<Pattern>, Rc<Pattern>) -> Rc<Pattern>)
+               -> Result<Rc<Pattern>, SchemaError>
+    {
+        let mut result = None;
+        for node in nodes.iter() {
+            let pattern = try!(self.pattern(node, scope));
+            result = Some(match result.take() {
+                Some(previous) => join(previous, pattern),
+                None => pattern,
+            });
+        }
+
+        match result {
+            Some(pattern) => Ok(pattern),
+            None => Ok(empty()),
+        }
+    }
+
+    fn element(&mut self, node: &Node, scope: &Rc<Grammar>) -> Result<Rc<Pattern>, SchemaError> {
+        if let Some(&(ref name_class, index)) = self.element_ids.get(&node.id) {
+            return Ok(Rc::new(ElementPattern(name_class.clone(), index)));
+        }
+
+        let (name_class, content) = try!(name_class_and_content(node));
+        let name_class = Rc::new(name_class);
+
+        let index = self.elements.len();
+        self.elements.push(not_allowed());
+        self.element_ids.insert(node.id, (name_class.clone(), index));
+
+        // A definition may refer to itself from inside an element
+        let outside = mem::replace(&mut self.expanding, Vec::new());
+        let content = self.sequence(content, scope);
+        self.expanding = outside;
+
+        self.elements.as_mut_slice()[index] = try!(content);
+        Ok(Rc::new(ElementPattern(name_class, index)))
+    }
+
+    fn reference(&mut self, scope: &Rc<Grammar>, name: &str) -> Result<Rc<Pattern>, SchemaError> {
+        let key = (scope.id, name.to_string());
+        if let Some(pattern) = self.defines.get(&key) {
+            return Ok(pattern.clone());
+        }
+
+        if self.expanding.contains(&key) {
+            return Err(InvalidComponent(format!("'{}' refers to itself outside of an element", name)));
+        }
+
+        let body = match scope.defines.get(name) {
+            Some(body) => body,
+            None => return Err(UnresolvedReference(name.to_string())),
+        };
+
+        self.expanding.push(key.clone());
+        let pattern = self.pattern(body, scope);
+        self.expanding.pop();
+
+        let pattern = try!(pattern);
+        self.defines.insert(key, pattern.clone());
+        Ok(pattern)
+    }
+
+    fn grammar(&mut self, node: &Node, parent: &Rc<Grammar>) -> Result<Rc<Pattern>, SchemaError> {
+        let mut components = Components::new();
+        try!(self.collect(node, &mut components));
+
+        let mut by_name: HashMap<String, Vec<(Option<String>, Node)>> = HashMap::new();
+        for (name, combine, body) in components.defines.into_iter() {
+            if ! by_name.contains_key(&name) {
+                by_name.insert(name.clone(), Vec::new());
+            }
+            by_name.get_mut(&name).unwrap().push((combine, body));
+        }
+
+        let mut defines = HashMap::new();
+        for (name, bodies) in by_name.into_iter() {
+            let body = try!(self.merge(name.as_slice(), bodies));
+            defines.insert(name, body);
+        }
+
+        if components.starts.is_empty() {
+            return Err(MissingStart);
+        }
+        let start = try!(self.merge("start", components.starts));
+
+        self.grammars += 1;
+        let scope = Rc::new(Grammar { id: self.grammars, defines: defines, parent: Some(parent.clone()) });
+
+        self.pattern(&start, &scope)
+    }
+
+    /// Merges the definitions of one name by their `combine` method
+    fn merge(&mut self, name: &str, bodies: Vec<(Option<String>, Node)>) -> Result<Node, SchemaError> {
+        if bodies.len() == 1 {
+            let (_, body) = bodies.into_iter().next().unwrap();
+            return Ok(body);
+        }
+
+        let method = {
+            let mut methods = Vec::new();
+            for &(ref combine, _) in bodies.iter() {
+                if let Some(ref combine) = *combine {
+                    methods.push(combine.as_slice());
+                }
+            }
+            let method = methods.iter().next().map_or("", |m| *m);
+            let consistent = methods.iter().all(|m| *m == method) && methods.len() + 1 >= bodies.len();
+
+            if ! consistent || (method != "choice" && method != "interleave") {
+                return Err(InvalidComponent(format!("'{}' is defined more than once without a single combine method", name)));
+            }
+            method.to_string()
+        };
+
+        let mut node = self.node(method.as_slice());
+        node.children = bodies.into_iter().map(|(_, body)| body).collect();
+        Ok(node)
+    }
+
+    /// The patterns of a `start` or `define`, grouped into one
+    fn body(&mut self, node: &Node) -> Node {
+        let mut body = self.node("group");
+        body.children = node.children.clone();
+        body
+    }
+
+    fn collect(&mut self, node: &Node, components: &mut Components) -> Result<(), SchemaError> {
+        for child in node.children.iter() {
+            match child.name.as_slice() {
+                "start" => {
+                    let body = self.body(child);
+                    components.starts.push((child.combine(), body));
+                },
+                "define" => {
+                    let name = try!(child.required("name")).to_string();
+                    let body = self.body(child);
+                    components.defines.push((name, child.combine(), body));
+                },
+                "div" => try!(self.collect(child, components)),
+                "include" => try!(self.include(child, components)),
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds the components of the included grammar, except those that
+    /// the `include` element replaces
+    fn include(&mut self, node: &Node, components: &mut Components) -> Result<(), SchemaError> {
+        let href = try!(node.required("href")).to_string();
+        let root = try!(self.read_file(&Path::new(href.as_slice())));
+        if root.name.as_slice() != "grammar" {
+            return Err(InvalidComponent(format!("{} is not a grammar", href)));
+        }
+
+        let mut included = Components::new();
+        self.reading.push(href);
+        let result = self.collect(&root, &mut included);
+        self.reading.pop();
+        try!(result);
+
+        let mut overrides = Components::new();
+        try!(self.collect(node, &mut overrides));
+
+        if ! overrides.starts.is_empty() {
+            included.starts.clear();
+        }
+        included.defines.retain(|&(ref name, _, _)| {
+            ! overrides.defines.iter().any(|&(ref replaced, _, _)| replaced == name)
+        });
+
+        components.starts.extend(included.starts.into_iter().chain(overrides.starts.into_iter()));
+        components.defines.extend(included.defines.into_iter().chain(overrides.defines.into_iter()));
+        Ok(())
+    }
+}
+
+#[deriving(Show,Clone,PartialEq)]
+enum Token {
+    /// An identifier or keyword. Escaped identifiers are never
+    /// keywords.
+    IdentifierToken(String, bool),
+    /// A prefixed name such as `xsd:integer`
+    CNameToken(String, String),
+    /// A prefix followed by `:*`
+    NsNameToken(String),
+    LiteralToken(String),
+    SymbolToken(&'static str),
+}
+
+fn is_keyword(name: &str) -> bool {
+    match name {
+        "attribute" | "default" | "datatypes" | "div" | "element" | "empty" | "external" |
+        "grammar" | "include" | "inherit" | "list" | "mixed" | "namespace" | "notAllowed" |
+        "parent" | "start" | "string" | "text" | "token" => true,
+        _ => false,
+    }
+}
+
+fn is_name_start(chars: &[char], i: uint) -> bool {
+    i < chars.len() && chars[i].is_name_start_char() && chars[i] != ':'
+}
+
+/// The end of the name that starts at `start`. Colons separate names
+/// in the compact syntax.
+fn name_end(chars: &[char], start: uint) -> uint {
+    let mut end = start + 1;
+    while end < chars.len() && chars[end].is_name_char() && chars[end] != ':' { end += 1 }
+    end
+}
+
+fn is_triple_quote(chars: &[char], i: uint, quote: char) -> bool {
+    i + 2 < chars.len() && chars[i] == quote && chars[i + 1] == quote && chars[i + 2] == quote
+}
+
+fn string_of(chars: &[char]) -> String {
+    chars.iter().map(|c| *c).collect()
+}
+
+/// Splits the compact syntax into tokens and their lines, or gives the
+/// line that could not be read
+fn tokenize(rnc: &str) -> Result<Vec<(Token, uint)>, uint> {
+    let chars: Vec<char> = rnc.chars().collect();
+    let chars = chars.as_slice();
+    let mut tokens = Vec::new();
+    let mut line = 1u;
+    let mut i = 0u;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c == '\n' {
+            line += 1;
+            i += 1;
+            continue;
+        }
+        if c.is_space_char() {
+            i += 1;
+            continue;
+        }
+        if c == '#' {
+            while i < chars.len() && chars[i] != '\n' { i += 1 }
+            continue;
+        }
+
+        if c == '"' || c == '\'' {
+            let triple = is_triple_quote(chars, i, c);
+            let quote_length = if triple { 3 } else { 1 };
+            let start_line = line;
+            i += quote_length;
+
+            let mut value = String::new();
+            loop {
+                if i >= chars.len() { return Err(start_line) }
+                if (triple && is_triple_quote(chars, i, c)) || (! triple && chars[i] == c) { break }
+                if chars[i] == '\n' {
+                    if ! triple { return Err(line) }
+                    line += 1;
+                }
+                value.push(chars[i]);
+                i += 1;
+            }
+            i += quote_length;
+
+            tokens.push((LiteralToken(value), start_line));
+            continue;
+        }
+
+        let pair = if i + 1 < chars.len() { Some((c, chars[i + 1])) } else { None };
+        let symbol = match pair {
+            Some(('|', '=')) => Some("|="),
+            Some(('&', '=')) => Some("&="),
+            Some(('>', '>')) => Some(">>"),
+            _ => None,
+        };
+        if let Some(symbol) = symbol {
+            tokens.push((SymbolToken(symbol), line));
+            i += 2;
+            continue;
+        }
+
+        let symbol = match c {
+            '{' => Some("{"), '}' => Some("}"), '(' => Some("("), ')' => Some(")"),
+            '[' => Some("["), ']' => Some("]"), '=' => Some("="), ',' => Some(","),
+            '&' => Some("&"), '|' => Some("|"), '?' => Some("?"), '*' => Some("*"),
+            '+' => Some("+"), '-' => Some("-"), '~' => Some("~"),
+            _ => None,
+        };
+        if let Some(symbol) = symbol {
+            tokens.push((SymbolToken(symbol), line));
+            i += 1;
+            continue;
+        }
+
+        let escaped = c == '\\';
+        let start = if escaped { i + 1 } else { i };
+        if ! is_name_start(chars, start) { return Err(line) }
+
+        let end = name_end(chars, start);
+        let name = string_of(chars.slice(start, end));
+
+        if ! escaped && end < chars.len() && chars[end] == ':' {
+            if end + 1 < chars.len() && chars[end + 1] == '*' {
+                tokens.push((NsNameToken(name), line));
+                i = end + 2;
+                continue;
+            }
+            if is_name_start(chars, end + 1) {
+                let local_end = name_end(chars, end + 1);
+                tokens.push((CNameToken(name, string_of(chars.slice(end + 1, local_end))), line));
+                i = local_end;
+                continue;
+            }
+        }
+
+        tokens.push((IdentifierToken(name, escaped), line));
+        i = end;
+    }
+
+    Ok(tokens)
+}
+
+/// Drops annotations: bracketed groups and the elements that follow
+/// `>>`
+fn strip_annotations(tokens: Vec<(Token, uint)>) -> Result<Vec<(Token, uint)>, uint> {
+    let mut kept = Vec::new();
+    let mut depth = 0u;
+    let mut follows_annotation = false;
+
+    for (token, line) in tokens.into_iter() {
+        match token {
+            SymbolToken("[") => depth += 1,
+            SymbolToken("]") => {
+                if depth == 0 { return Err(line) }
+                depth -= 1;
+            },
+            _ if depth > 0 => {},
+            SymbolToken(">>") => follows_annotation = true,
+            _ if follows_annotation => follows_annotation = false,
+            token => kept.push((token, line)),
+        }
+    }
+
+    if depth > 0 {
+        return Err(kept.last().map_or(1, |&(_, line)| line));
+    }
+
+    Ok(kept)
+}
+
+/// Reads the compact syntax into the same nodes as the XML syntax
+struct CompactParser<'a> {
+    tokens: Vec<(Token, uint)>,
+    position: uint,
+    /// The datatype libraries by prefix
+    datatypes: HashMap<String, String>,
+    location: &'a str,
+    directory: &'a Path,
+    next_id: &'a mut uint,
+}
+
+impl<'a> CompactParser<'a> {
+    fn node(&mut self, name: &str) -> Node {
+        new_node(&mut *self.next_id, name)
+    }
+
+    /// Reports a problem with the most recently read token
+    fn error<T>(&self) -> Result<T, SchemaError> {
+        let index = if self.position > 0 { self.position - 1 } else { 0 };
+        let line = match self.tokens.as_slice().get(index) {
+            Some(&(_, line)) => line,
+            None => self.tokens.last().map_or(1, |&(_, line)| line),
+        };
+        Err(CompactSyntaxError(self.location.to_string(), line))
+    }
+
+    fn peek_at(&self, offset: uint) -> Option<&Token> {
+        match self.tokens.as_slice().get(self.position + offset) {
+            Some(&(ref token, _)) => Some(token),
+            None => None,
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.peek_at(0)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().map(|t| t.clone());
+        if token.is_some() { self.position += 1 }
+        token
+    }
+
+    fn at_symbol(&self, symbol: &str) -> bool {
+        match self.peek() {
+            Some(&SymbolToken(s)) => s == symbol,
+            _ => false,
+        }
+    }
+
+    fn at_keyword(&self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(&IdentifierToken(ref name, false)) => name.as_slice() == keyword,
+            _ => false,
+        }
+    }
+
+    fn at_literal(&self) -> bool {
+        match self.peek() {
+            Some(&LiteralToken(..)) => true,
+            _ => false,
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), SchemaError> {
+        match self.next() {
+            Some(SymbolToken(s)) if s == symbol => Ok(()),
+            _ => self.error(),
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), SchemaError> {
+        match self.next() {
+            Some(IdentifierToken(ref name, false)) if name.as_slice() == keyword => Ok(()),
+            _ => self.error(),
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, SchemaError> {
+        match self.next() {
+            Some(IdentifierToken(name, _)) => Ok(name),
+            _ => self.error(),
+        }
+    }
+
+    /// A literal, possibly joined to others with `~`
+    fn literal(&mut self) -> Result<String, SchemaError> {
+        let mut value = match self.next() {
+            Some(LiteralToken(value)) => value,
+            _ => return self.error(),
+        };
+
+        while self.at_symbol("~") {
+            self.position += 1;
+            match self.next() {
+                Some(LiteralToken(more)) => value.push_str(more.as_slice()),
+                _ => return self.error(),
+            }
+        }
+
+        Ok(value)
+    }
+
+    fn href(&mut self) -> Result<String, SchemaError> {
+        let href = try!(self.literal());
+        if self.at_keyword("inherit") {
+            self.position += 1;
+            try!(self.expect_symbol("="));
+            try!(self.identifier());
+        }
+        Ok(self.directory.join(href.as_slice()).display().to_string())
+    }
+
+    fn top_level(&mut self) -> Result<Node, SchemaError> {
+        try!(self.declarations());
+
+        let root = if self.at_grammar_content() {
+            let mut grammar = self.node("grammar");
+            grammar.children = try!(self.grammar_content(false));
+            grammar
+        } else {
+            try!(self.pattern())
+        };
+
+        if self.position < self.tokens.len() {
+            self.position += 1;
+            return self.error();
+        }
+
+        Ok(root)
+    }
+
+    fn declarations(&mut self) -> Result<(), SchemaError> {
+        loop {
+            if self.at_keyword("datatypes") {
+                self.position += 1;
+                let prefix = try!(self.identifier());
+                try!(self.expect_symbol("="));
+                let library = try!(self.literal());
+                self.datatypes.insert(prefix, library);
+                continue;
+            }
+
+            if self.at_keyword("namespace") {
+                self.position += 1;
+                try!(self.identifier());
+            } else if self.at_keyword("default") {
+                self.position += 1;
+                try!(self.expect_keyword("namespace"));
+                if ! self.at_symbol("=") {
+                    try!(self.identifier());
+                }
+            } else {
+                return Ok(());
+            }
+
+            try!(self.expect_symbol("="));
+            if self.at_keyword("inherit") {
+                self.position += 1;
+            } else {
+                try!(self.literal());
+            }
+        }
+    }
+
+    fn at_grammar_content(&self) -> bool {
+        match (self.peek(), self.peek_at(1)) {
+            (None, _) => true,
+            (Some(&IdentifierToken(ref name, false)), _)
+                if name.as_slice() == "start" || name.as_slice() == "div" || name.as_slice() == "include" => true,
+            (Some(&IdentifierToken(..)), Some(&SymbolToken(s))) => s == "=" || s == "|=" || s == "&=",
+            _ => false,
+        }
+    }
+
+    fn grammar_content(&mut self, in_braces: bool) -> Result<Vec<Node>, SchemaError> {
+        let mut components = Vec::new();
+
+        loop {
+            if in_braces && self.at_symbol("}") {
+                self.position += 1;
+                return Ok(components);
+            }
+            if ! in_braces && self.peek().is_none() {
+                return Ok(components);
+            }
+
+            if self.at_keyword("start") {
+                self.position += 1;
+                let mut start = self.node("start");
+                try!(self.assign_method(&mut start));
+                start.children.push(try!(self.pattern()));
+                components.push(start);
+            } else if self.at_keyword("div") {
+                self.position += 1;
+                try!(self.expect_symbol("{"));
+                let mut div = self.node("div");
+                div.children = try!(self.grammar_content(true));
+                components.push(div);
+            } else if self.at_keyword("include") {
+                self.position += 1;
+                let mut include = self.node("include");
+                let href = try!(self.href());
+                include.attributes.insert("href".to_string(), href);
+                if self.at_symbol("{") {
+                    self.position += 1;
+                    include.children = try!(self.grammar_content(true));
+                }
+                components.push(include);
+            } else {
+                let mut define = self.node("define");
+                let name = try!(self.identifier());
+                define.attributes.insert("name".to_string(), name);
+                try!(self.assign_method(&mut define));
+                define.children.push(try!(self.pattern()));
+                components.push(define);
+            }
+        }
+    }
+
+    fn assign_method(&mut self, node: &mut Node) -> Result<(), SchemaError> {
+        let combine = match self.next() {
+            Some(SymbolToken("=")) => return Ok(()),
+            Some(SymbolToken("|=")) => "choice",
+            Some(SymbolToken("&=")) => "interleave",
+            _ => return self.error(),
+        };
+        node.attributes.insert("combine".to_string(), combine.to_string());
+        Ok(())
+    }
+
+    /// Particles joined by one kind of operator. Mixing operators
+    /// needs parentheses.
+    fn pattern(&mut self) -> Result<Node, SchemaError> {
+        let first = try!(self.particle());
+
+        let operator = match self.peek() {
+            Some(&SymbolToken(s)) if s == "," || s == "&" || s == "|" => s,
+            _ => return Ok(first),
+        };
+
+        let name = match operator { "," => "group", "&" => "interleave", _ => "choice" };
+        let mut node = self.node(name);
+        node.children.push(first);
+
+        while self.at_symbol(operator) {
+            self.position += 1;
+            node.children.push(try!(self.particle()));
+        }
+
+        if self.at_symbol(",") || self.at_symbol("&") || self.at_symbol("|") {
+            self.position += 1;
+            return self.error();
+        }
+
+        Ok(node)
+    }
+
+    fn particle(&mut self) -> Result<Node, SchemaError> {
+        let primary = try!(self.primary());
+
+        let name = match self.peek() {
+            Some(&SymbolToken("?")) => "optional",
+            Some(&SymbolToken("*")) => "zeroOrMore",
+            Some(&SymbolToken("+")) => "oneOrMore",
+            _ => return Ok(primary),
+        };
+        self.position += 1;
+
+        let mut node = self.node(name);
+        node.children.push(primary);
+        Ok(node)
+    }
+
+    fn primary(&mut self) -> Result<Node, SchemaError> {
+        let token = match self.next() {
+            Some(token) => token,
+            None => return self.error(),
+        };
+
+        match token {
+            IdentifierToken(ref name, false) if is_keyword(name.as_slice()) => self.keyword_pattern(name.as_slice()),
+            IdentifierToken(ref name, _) => {
+                let mut node = self.node("ref");
+                node.attributes.insert("name".to_string(), name.clone());
+                Ok(node)
+            },
+            CNameToken(ref prefix, ref local) => {
+                let library = match self.datatypes.get(prefix.as_slice()) {
+                    Some(library) => library.clone(),
+                    None => return self.error(),
+                };
+                self.datatype(library.as_slice(), local.as_slice())
+            },
+            LiteralToken(..) => {
+                self.position -= 1;
+                let mut node = self.node("value");
+                node.text = try!(self.literal());
+                Ok(node)
+            },
+            SymbolToken("(") => {
+                let pattern = try!(self.pattern());
+                try!(self.expect_symbol(")"));
+                Ok(pattern)
+            },
+            _ => self.error(),
+        }
+    }
+
+    fn keyword_pattern(&mut self, keyword: &str) -> Result<Node, SchemaError> {
+        match keyword {
+            "element" | "attribute" => {
+                let mut node = self.node(keyword);
+                node.children.push(try!(self.name_class()));
+                try!(self.expect_symbol("{"));
+                node.children.push(try!(self.pattern()));
+                try!(self.expect_symbol("}"));
+                Ok(node)
+            },
+            "list" | "mixed" => {
+                let mut node = self.node(keyword);
+                try!(self.expect_symbol("{"));
+                node.children.push(try!(self.pattern()));
+                try!(self.expect_symbol("}"));
+                Ok(node)
+            },
+            "empty" | "text" | "notAllowed" => Ok(self.node(keyword)),
+            "parent" => {
+                let mut node = self.node("parentRef");
+                let name = try!(self.identifier());
+                node.attributes.insert("name".to_string(), name);
+                Ok(node)
+            },
+            "external" => {
+                let mut node = self.node("externalRef");
+                let href = try!(self.href());
+                node.attributes.insert("href".to_string(), href);
+                Ok(node)
+            },
+            "grammar" => {
+                try!(self.expect_symbol("{"));
+                let mut node = self.node("grammar");
+                node.children = try!(self.grammar_content(true));
+                Ok(node)
+            },
+            "string" | "token" => self.datatype("", keyword),
+            _ => self.error(),
+        }
+    }
+
+    /// A value of the datatype, or the datatype with its parameters
+    /// and exceptions
+    fn datatype(&mut self, library: &str, name: &str) -> Result<Node, SchemaError> {
+        let is_value = self.at_literal();
+        let mut node = self.node(if is_value { "value" } else { "data" });
+        node.attributes.insert("type".to_string(), name.to_string());
+        node.attributes.insert("datatypeLibrary".to_string(), library.to_string());
+
+        if is_value {
+            node.text = try!(self.literal());
+            return Ok(node);
+        }
+
+        if self.at_symbol("{") {
+            self.position += 1;
+            while ! self.at_symbol("}") {
+                let mut param = self.node("param");
+                let name = try!(self.identifier());
+                param.attributes.insert("name".to_string(), name);
+                try!(self.expect_symbol("="));
+                param.text = try!(self.literal());
+                node.children.push(param);
+            }
+            self.position += 1;
+        }
+
+        if self.at_symbol("-") {
+            self.position += 1;
+            let mut except = self.node("except");
+            except.children.push(try!(self.primary()));
+            node.children.push(except);
+        }
+
+        Ok(node)
+    }
+
+    fn name_class(&mut self) -> Result<Node, SchemaError> {
+        let first = try!(self.basic_name_class());
+        if ! self.at_symbol("|") {
+            return Ok(first);
+        }
+
+        let mut choice = self.node("choice");
+        choice.children.push(first);
+        while self.at_symbol("|") {
+            self.position += 1;
+            choice.children.push(try!(self.basic_name_class()));
+        }
+        Ok(choice)
+    }
+
+    fn basic_name_class(&mut self) -> Result<Node, SchemaError> {
+        let token = match self.next() {
+            Some(token) => token,
+            None => return self.error(),
+        };
+
+        match token {
+            IdentifierToken(ref name, _) | CNameToken(_, ref name) => {
+                let mut node = self.node("name");
+                node.text = name.clone();
+                Ok(node)
+            },
+            SymbolToken("*") => self.any_name("anyName"),
+            NsNameToken(..) => self.any_name("nsName"),
+            SymbolToken("(") => {
+                let name_class = try!(self.name_class());
+                try!(self.expect_symbol(")"));
+                Ok(name_class)
+            },
+            _ => self.error(),
+        }
+    }
+
+    fn any_name(&mut self, name: &str) -> Result<Node, SchemaError> {
+        let mut node = self.node(name);
+        if self.at_symbol("-") {
+            self.position += 1;
+            let mut except = self.node("except");
+            except.children.push(try!(self.basic_name_class()));
+            node.children.push(except);
+        }
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{File,TempDir};
+
+    use super::super::parser::Parser;
+    use super::{Schema,SchemaError,CompactSyntaxError,InvalidComponent,UnresolvedReference};
+    use super::{UnknownDatatype,MissingStart,InvalidPattern,UnsupportedNamespaces};
+    use super::{ValidationError,UnexpectedElement,UnexpectedAttribute,MissingAttribute};
+    use super::{InvalidText,IncompleteElement,IncompleteDocument};
+
+    fn try_rng(body: &str) -> Result<Schema, SchemaError> {
+        let rng = format!(r#"<grammar xmlns="http://relaxng.org/ns/structure/1.0"
+                                      xmlns:a="http://relaxng.org/ns/compatibility/annotations/1.0"
+                                      datatypeLibrary="http://www.w3.org/2001/XMLSchema-datatypes">{}</grammar>"#, body);
+        Schema::parse(rng.as_slice())
+    }
+
+    fn rng(body: &str) -> Schema {
+        try_rng(body).ok().expect("Invalid schema")
+    }
+
+    fn rnc(text: &str) -> Schema {
+        Schema::parse_compact(text).ok().expect("Invalid schema")
+    }
+
+    fn validate(schema: &Schema, xml: &str) -> Result<(), ValidationError> {
+        let package = Parser::new().parse(xml).ok().expect("Failed to parse the XML string");
+        let doc = package.as_document();
+        schema.validate(&doc)
+    }
+
+    #[test]
+    fn element_with_attribute_and_text() {
+        let schema = Schema::parse(r#"
+            <element name="greeting" xmlns="http://relaxng.org/ns/structure/1.0">
+              <attribute name="lang"/>
+              <text/>
+            </element>
+        "#).ok().expect("Invalid schema");
+
+        assert_eq!(validate(&schema, "<greeting lang='en'>Hello</greeting>"), Ok(()));
+        assert_eq!(validate(&schema, "<greeting>Hello</greeting>"), Err(MissingAttribute("greeting".to_string())));
+        assert_eq!(validate(&schema, "<greeting lang='en' mood='happy'/>"),
+                   Err(UnexpectedAttribute("greeting".to_string(), "mood".to_string())));
+        assert_eq!(validate(&schema, "<farewell lang='en'/>"), Err(UnexpectedElement("farewell".to_string())));
+    }
+
+    #[test]
+    fn content_models() {
+        let schema = rng(r#"
+            <start>
+              <element name="order">
+                <optional><element name="note"><text/></element></optional>
+                <interleave>
+                  <element name="customer"><text/></element>
+                  <oneOrMore><element name="item"><empty/></element></oneOrMore>
+                </interleave>
+                <choice>
+                  <element name="cash"><empty/></element>
+                  <element name="card"><empty/></element>
+                </choice>
+              </element>
+            </start>
+        "#);
+
+        assert_eq!(validate(&schema, "<order><customer>A</customer><item/><item/><cash/></order>"), Ok(()));
+        assert_eq!(validate(&schema, "<order><note>!</note><item/><customer>A</customer><item/><card/></order>"), Ok(()));
+        assert_eq!(validate(&schema, "<order><item/><customer>A</customer></order>"),
+                   Err(IncompleteElement("order".to_string())));
+        assert_eq!(validate(&schema, "<order><customer>A</customer><cash/></order>"),
+                   Err(UnexpectedElement("cash".to_string())));
+        assert_eq!(validate(&schema, "<order>text<customer>A</customer><item/><cash/></order>"),
+                   Err(InvalidText("order".to_string())));
+    }
+
+    #[test]
+    fn whitespace_between_elements_is_ignored() {
+        let schema = rng(r#"
+            <start>
+              <element name="a"><element name="b"><empty/></element></element>
+            </start>
+        "#);
+
+        assert_eq!(validate(&schema, "<a>\n  <b>  </b>\n</a>"), Ok(()));
+    }
+
+    #[test]
+    fn datatypes_and_values() {
+        let schema = rng(r#"
+            <start>
+              <element name="reading">
+                <attribute name="unit">
+                  <choice><value type="token">celsius</value><value type="token">kelvin</value></choice>
+                </attribute>
+                <data type="decimal">
+                  <param name="minInclusive">-273.15</param>
+                  <param name="fractionDigits">2</param>
+                </data>
+              </element>
+            </start>
+        "#);
+
+        assert_eq!(validate(&schema, "<reading unit=' kelvin '>20.5</reading>"), Ok(()));
+        assert_eq!(validate(&schema, "<reading unit='fahrenheit'>20.5</reading>"),
+                   Err(UnexpectedAttribute("reading".to_string(), "unit".to_string())));
+        assert_eq!(validate(&schema, "<reading unit='celsius'>-300</reading>"),
+                   Err(InvalidText("reading".to_string())));
+        assert_eq!(validate(&schema, "<reading unit='celsius'>1.234</reading>"),
+                   Err(InvalidText("reading".to_string())));
+        assert_eq!(validate(&schema, "<reading unit='celsius'>warm</reading>"),
+                   Err(InvalidText("reading".to_string())));
+    }
+
+    #[test]
+    fn lists_and_exceptions() {
+        let schema = rnc(r#"
+            element point {
+              attribute tag { xsd:NCName - ( "none" | "null" ) },
+              list { xsd:integer, xsd:integer }
+            }
+        "#);
+
+        assert_eq!(validate(&schema, "<point tag='origin'> 0  0 </point>"), Ok(()));
+        assert_eq!(validate(&schema, "<point tag='origin'>1 2 3</point>"), Err(InvalidText("point".to_string())));
+        assert_eq!(validate(&schema, "<point tag='none'>1 2</point>"),
+                   Err(UnexpectedAttribute("point".to_string(), "tag".to_string())));
+    }
+
+    #[test]
+    fn recursive_definitions_and_combine() {
+        let schema = rng(r#"
+            <start><ref name="node"/></start>
+            <define name="node">
+              <element name="node">
+                <ref name="label"/>
+                <zeroOrMore><ref name="node"/></zeroOrMore>
+              </element>
+            </define>
+            <define name="label" combine="choice">
+              <attribute name="name"/>
+            </define>
+            <define name="label" combine="choice">
+              <attribute name="id"><data type="ID"/></attribute>
+            </define>
+            <a:documentation>Ignored</a:documentation>
+        "#);
+
+        assert_eq!(validate(&schema, "<node name='a'><node id='b'><node name='c'/></node><node id='d'/></node>"), Ok(()));
+        assert_eq!(validate(&schema, "<node name='a'><node/></node>"), Err(MissingAttribute("node".to_string())));
+        assert_eq!(validate(&schema, "<node name='a'><leaf/></node>"), Err(UnexpectedElement("leaf".to_string())));
+    }
+
+    #[test]
+    fn nested_grammar_with_parent_reference() {
+        let schema = rng(r#"
+            <start>
+              <element name="outer">
+                <grammar>
+                  <start><element name="inner"><parentRef name="content"/></element></start>
+                  <define name="content"><element name="wrong"><empty/></element></define>
+                </grammar>
+              </element>
+            </start>
+            <define name="content"><text/></define>
+        "#);
+
+        assert_eq!(validate(&schema, "<outer><inner>text</inner></outer>"), Ok(()));
+        assert_eq!(validate(&schema, "<outer><inner><wrong/></inner></outer>"), Err(UnexpectedElement("wrong".to_string())));
+    }
+
+    #[test]
+    fn name_classes() {
+        let schema = rnc(r#"
+            element config {
+              attribute * - (version | id) { text }*,
+              element (setting | option) { empty }*,
+              element * - (setting | option | ignored) { empty }?
+            }
+        "#);
+
+        assert_eq!(validate(&schema, "<config a='1' b='2'><setting/><option/><extra/></config>"), Ok(()));
+        assert_eq!(validate(&schema, "<config><ignored/></config>"), Err(UnexpectedElement("ignored".to_string())));
+        assert_eq!(validate(&schema, "<config version='1'/>"),
+                   Err(UnexpectedAttribute("config".to_string(), "version".to_string())));
+    }
+
+    #[test]
+    fn compact_grammar() {
+        let schema = rnc(r#"
+            # A library
+            default namespace = "http://example.com/library"
+            namespace a = "http://relaxng.org/ns/compatibility/annotations/1.0"
+            datatypes d = "http://www.w3.org/2001/XMLSchema-datatypes"
+
+            start = library
+            ## The books
+            library = element library { book* }
+            [ a:documentation [ "One book" ] ]
+            book = element book { attribute isbn { d:string { pattern = "\d{3}-\d+" } }, title }
+            title = element title { text }
+            book |= element magazine { title } >> a:note [ "Also allowed" ]
+        "#);
+
+        assert_eq!(validate(&schema, "<library><book isbn='978-1'><title>Rust</title></book><magazine><title>X</title></magazine></library>"), Ok(()));
+        assert_eq!(validate(&schema, "<library><book isbn='1'><title>Rust</title></book></library>"),
+                   Err(UnexpectedAttribute("book".to_string(), "isbn".to_string())));
+    }
+
+    #[test]
+    fn compact_syntax_errors_report_the_line() {
+        let r = Schema::parse_compact("start = element a {\n  text,\n  | empty\n}");
+        assert_eq!(r.err(), Some(CompactSyntaxError("".to_string(), 3)));
+
+        let r = Schema::parse_compact("element a { text ");
+        assert_eq!(r.err(), Some(CompactSyntaxError("".to_string(), 1)));
+    }
+
+    #[test]
+    fn schema_errors() {
+        assert_eq!(try_rng(r#"<start><ref name="missing"/></start>"#).err(),
+                   Some(UnresolvedReference("missing".to_string())));
+        assert_eq!(try_rng(r#"<define name="a"><empty/></define>"#).err(), Some(MissingStart));
+        assert_eq!(try_rng(r#"<start><ref name="a"/></start><define name="a"><ref name="a"/></define>"#).err(),
+                   Some(InvalidComponent("'a' refers to itself outside of an element".to_string())));
+        assert_eq!(try_rng(r#"<start><data type="float" datatypeLibrary="http://example.com/types"/></start>"#).err(),
+                   Some(UnknownDatatype("http://example.com/types#float".to_string())));
+        assert_eq!(try_rng(r#"<start><data type="string"><param name="pattern">[a-</param></data></start>"#).err(),
+                   Some(InvalidPattern("[a-".to_string())));
+        assert_eq!(try_rng(r#"<start><element><nsName ns="urn:a"/><empty/></element></start>"#).err(),
+                   Some(UnsupportedNamespaces("nsName".to_string())));
+        assert_eq!(Schema::parse_compact("namespace p = \"urn:a\"\nelement p:* { empty }").err(),
+                   Some(UnsupportedNamespaces("nsName".to_string())));
+    }
+
+    #[test]
+    fn validating_parser_events() {
+        let schema = rnc("element a { element b { xsd:boolean }+ }");
+
+        let mut validator = schema.validator();
+        Parser::new().parse_events("<a><b>true</b><b>0</b></a>", &mut validator).unwrap();
+        assert_eq!(validator.finish(), Ok(()));
+
+        let mut validator = schema.validator();
+        Parser::new().parse_events("<a><b>yes</b></a>", &mut validator).unwrap();
+        assert_eq!(validator.finish(), Err(InvalidText("b".to_string())));
+
+        assert_eq!(schema.validator().finish(), Err(IncompleteDocument));
+    }
+
+    #[test]
+    fn includes_and_external_references() {
+        let dir = TempDir::new("relaxng").unwrap();
+
+        let files = [
+            ("main.rng", r#"
+                <grammar xmlns="http://relaxng.org/ns/structure/1.0">
+                  <include href="common.rnc">
+                    <define name="id"><value>fixed</value></define>
+                  </include>
+                  <start><element name="doc"><ref name="id"/><externalRef href="body.rnc"/></element></start>
+                </grammar>"#),
+            ("common.rnc", r#"id = text"#),
+            ("body.rnc", r#"element body { text }"#),
+        ];
+        for &(name, contents) in files.iter() {
+            File::create(&dir.path().join(name)).write_str(contents).unwrap();
+        }
+
+        let schema = Schema::from_file(&dir.path().join("main.rng")).ok().expect("Invalid schema");
+
+        assert_eq!(validate(&schema, "<doc>fixed<body>text</body></doc>"), Ok(()));
+        assert_eq!(validate(&schema, "<doc>other<body>text</body></doc>"), Err(InvalidText("doc".to_string())));
+    }
+}
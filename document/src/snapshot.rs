@@ -0,0 +1,538 @@
+//! Saves packages in a compact binary form that keeps node IDs
+//!
+//! Every node is saved, including those that are not part of the
+//! document, so that a `NodeId` taken before saving finds the same
+//! node after loading. This allows IDs to be kept in external indexes
+//! or sent to other processes along with the snapshot.
+//!
+//! ### Example
+//! ```
+//! use std::io::{BufReader,MemWriter};
+//! use document::Package;
+//! use document::snapshot;
+//! use document::dom4::ElementNode;
+//!
+//! let package = Package::new();
+//! let hello = package.as_document().create_element("hello");
+//! let id = hello.id();
+//!
+//! let mut saved = MemWriter::new();
+//! snapshot::save(&package, &mut saved).ok().expect("unable to save");
+//!
+//! let mut reader = BufReader::new(saved.get_ref());
+//! let loaded = snapshot::load(&mut reader).ok().expect("unable to load");
+//! let doc = loaded.as_document();
+//!
+//! match doc.node_by_id(id) {
+//!     Some(ElementNode(e)) => assert_eq!(e.name(), "hello"),
+//!     _ => panic!("the element was not found"),
+//! }
+//! ```
+//!
+//! ### Format
+//!
+//! The bytes `SXDS` and a version number, followed by the number of
+//! nodes and then each node in the order of their IDs. A node is a
+//! tag byte followed by its strings and the IDs of the nodes it
+//! contains. Numbers are 64-bit big-endian; strings are UTF-8
+//! prefixed with their length.
+//!
+//! ### Known issues
+//!
+//! Recorded markup, user data, declared ID attributes and the name
+//! index are not saved.
+
+use std::io::{IoError,IoResult};
+use std::io::util::LimitReader;
+
+use super::Package;
+use super::raw;
+use super::raw::NodeId;
+
+static MAGIC: &'static [u8] = b"SXDS";
+static VERSION: u8 = 1;
+
+#[deriving(Show,Clone,PartialEq)]
+pub enum Error {
+    /// The snapshot could not be read
+    ReadFailure(IoError),
+    /// The input does not start like a snapshot
+    NotASnapshot,
+    /// The snapshot was saved by an incompatible version
+    UnsupportedVersion(u8),
+    /// The nodes do not form a valid package, such as when an
+    /// attribute is listed as a child
+    Corrupt,
+}
+
+fn write_string<W : Writer>(s: &str, writer: &mut W) -> IoResult<()> {
+    try!(writer.write_be_u64(s.len() as u64));
+    writer.write_str(s)
+}
+
+fn write_optional_string<W : Writer>(s: Option<&str>, writer: &mut W) -> IoResult<()> {
+    match s {
+        Some(s) => {
+            try!(writer.write_u8(1));
+            write_string(s, writer)
+        },
+        None => writer.write_u8(0),
+    }
+}
+
+fn write_ids<W : Writer>(ids: Vec<NodeId>, writer: &mut W) -> IoResult<()> {
+    try!(writer.write_be_u64(ids.len() as u64));
+    for &NodeId(id) in ids.iter() {
+        try!(writer.write_be_u64(id));
+    }
+    Ok(())
+}
+
+/// Writes every node of the package and how they are connected
+pub fn save<W : Writer>(package: &Package, writer: &mut W) -> IoResult<()> {
+    let connections = &package.connections;
+    let nodes = package.storage.nodes();
+
+    try!(writer.write(MAGIC));
+    try!(writer.write_u8(VERSION));
+    try!(writer.write_be_u64(nodes.len() as u64));
+
+    for node in nodes.iter() {
+        // This is safe because nodes are never deallocated, and the
+        // package cannot be changed while it is borrowed
+        unsafe {
+            match *node {
+                raw::RootNode(_) => {
+                    try!(writer.write_u8(0));
+                    let doctype = connections.root_doctype().map(|d| vec![(*d).id()]);
+                    try!(write_ids(doctype.unwrap_or(Vec::new()), writer));
+                    try!(write_ids(connections.root_children().iter().map(|c| c.id()).collect(), writer));
+                },
+                raw::DocumentTypeNode(n) => {
+                    let n = &*n;
+                    try!(writer.write_u8(1));
+                    try!(write_string(n.name(), writer));
+                    try!(write_optional_string(n.public_id(), writer));
+                    try!(write_optional_string(n.system_id(), writer));
+                    try!(write_optional_string(n.internal_subset(), writer));
+                    try!(write_optional_string(n.external_subset(), writer));
+                },
+                raw::ElementNode(n) => {
+                    try!(writer.write_u8(2));
+                    try!(write_string((*n).name(), writer));
+                    try!(write_ids(connections.attributes(n).iter().map(|a| (**a).id()).collect(), writer));
+                    try!(write_ids(connections.element_children(n).iter().map(|c| c.id()).collect(), writer));
+                },
+                raw::AttributeNode(n) => {
+                    try!(writer.write_u8(3));
+                    try!(write_string((*n).name(), writer));
+                    try!(write_string((*n).value(), writer));
+                },
+                raw::TextNode(n) => {
+                    try!(writer.write_u8(4));
+                    try!(write_string((*n).text(), writer));
+                },
+                raw::CommentNode(n) => {
+                    try!(writer.write_u8(5));
+                    try!(write_string((*n).text(), writer));
+                },
+                raw::ProcessingInstructionNode(n) => {
+                    try!(writer.write_u8(6));
+                    try!(write_string((*n).target(), writer));
+                    try!(write_optional_string((*n).value(), writer));
+                },
+            }
+        }
+    }
+
+    Ok(())
+}
+
+enum Record {
+    /// The document type declaration, if any, and the children
+    RootRecord(Vec<u64>, Vec<u64>),
+    DocumentTypeRecord(String, Option<String>, Option<String>, Option<String>, Option<String>),
+    /// The name, attributes and children
+    ElementRecord(String, Vec<u64>, Vec<u64>),
+    AttributeRecord(String, String),
+    TextRecord(String),
+    CommentRecord(String),
+    ProcessingInstructionRecord(String, Option<String>),
+}
+
+fn read_u8<R : Reader>(reader: &mut R) -> Result<u8, Error> {
+    reader.read_u8().map_err(ReadFailure)
+}
+
+fn read_u64<R : Reader>(reader: &mut R) -> Result<u64, Error> {
+    reader.read_be_u64().map_err(ReadFailure)
+}
+
+fn read_bytes<R : Reader>(reader: &mut R, length: uint) -> Result<Vec<u8>, Error> {
+    // Reading only what is there, so that a corrupt length cannot
+    // cause a huge allocation
+    let bytes = try!(LimitReader::new(reader.by_ref(), length).read_to_end().map_err(ReadFailure));
+    if bytes.len() != length { return Err(Corrupt) }
+    Ok(bytes)
+}
+
+fn read_string<R : Reader>(reader: &mut R) -> Result<String, Error> {
+    let length = try!(read_u64(reader));
+    let bytes = try!(read_bytes(reader, length as uint));
+    String::from_utf8(bytes).map_err(|_| Corrupt)
+}
+
+fn read_optional_string<R : Reader>(reader: &mut R) -> Result<Option<String>, Error> {
+    match try!(read_u8(reader)) {
+        0 => Ok(None),
+        1 => read_string(reader).map(Some),
+        _ => Err(Corrupt),
+    }
+}
+
+fn read_ids<R : Reader>(reader: &mut R) -> Result<Vec<u64>, Error> {
+    let count = try!(read_u64(reader));
+    let mut ids = Vec::new();
+    for _ in range(0, count) {
+        ids.push(try!(read_u64(reader)));
+    }
+    Ok(ids)
+}
+
+fn read_record<R : Reader>(reader: &mut R) -> Result<Record, Error> {
+    let record = match try!(read_u8(reader)) {
+        0 => RootRecord(try!(read_ids(reader)), try!(read_ids(reader))),
+        1 => DocumentTypeRecord(try!(read_string(reader)),
+                                try!(read_optional_string(reader)),
+                                try!(read_optional_string(reader)),
+                                try!(read_optional_string(reader)),
+                                try!(read_optional_string(reader))),
+        2 => ElementRecord(try!(read_string(reader)), try!(read_ids(reader)), try!(read_ids(reader))),
+        3 => AttributeRecord(try!(read_string(reader)), try!(read_string(reader))),
+        4 => TextRecord(try!(read_string(reader))),
+        5 => CommentRecord(try!(read_string(reader))),
+        6 => ProcessingInstructionRecord(try!(read_string(reader)), try!(read_optional_string(reader))),
+        _ => return Err(Corrupt),
+    };
+    Ok(record)
+}
+
+/// Makes the node a child or attribute of the parent, which it must
+/// not already be of any other node
+fn adopt(parents: &mut Vec<Option<uint>>, child: u64, parent: uint) -> Result<(), Error> {
+    let child = child as uint;
+    if parents[child].is_some() { return Err(Corrupt) }
+    *parents.get_mut(child) = Some(parent);
+    Ok(())
+}
+
+fn check_structure(records: &[Record]) -> Result<(), Error> {
+    let count = records.len();
+    let mut parents = Vec::from_elem(count, None);
+
+    let is_child = |id: u64, root: bool| -> bool {
+        match records.get(id as uint) {
+            Some(&ElementRecord(..))               => true,
+            Some(&CommentRecord(..))               => true,
+            Some(&ProcessingInstructionRecord(..)) => true,
+            Some(&TextRecord(..))                  => ! root,
+            _                                      => false,
+        }
+    };
+
+    for (index, record) in records.iter().enumerate() {
+        match *record {
+            RootRecord(ref doctype, ref children) => {
+                if index != 0 || doctype.len() > 1 { return Err(Corrupt) }
+                for &id in doctype.iter() {
+                    match records.get(id as uint) {
+                        Some(&DocumentTypeRecord(..)) => {},
+                        _ => return Err(Corrupt),
+                    }
+                }
+
+                let elements = children.iter().filter(|&&id| match records.get(id as uint) {
+                    Some(&ElementRecord(..)) => true,
+                    _ => false,
+                }).count();
+                if elements > 1 { return Err(Corrupt) }
+
+                for &id in children.iter() {
+                    if ! is_child(id, true) { return Err(Corrupt) }
+                    try!(adopt(&mut parents, id, index));
+                }
+            },
+            ElementRecord(_, ref attributes, ref children) => {
+                for &id in attributes.iter() {
+                    match records.get(id as uint) {
+                        Some(&AttributeRecord(..)) => {},
+                        _ => return Err(Corrupt),
+                    }
+                    try!(adopt(&mut parents, id, index));
+                }
+                for &id in children.iter() {
+                    if ! is_child(id, false) { return Err(Corrupt) }
+                    try!(adopt(&mut parents, id, index));
+                }
+            },
+            _ if index == 0 => return Err(Corrupt),
+            _ => {},
+        }
+    }
+
+    // An element must not contain itself
+    for start in range(0, count) {
+        let mut current = start;
+        let mut steps = 0u;
+        loop {
+            match parents[current] {
+                Some(parent) => current = parent,
+                None => break,
+            }
+            steps += 1;
+            if steps > count { return Err(Corrupt) }
+        }
+    }
+
+    Ok(())
+}
+
+fn as_str(s: &Option<String>) -> Option<&str> {
+    s.as_ref().map(|s| s.as_slice())
+}
+
+fn build(records: Vec<Record>) -> Package {
+    let package = Package::new();
+
+    {
+        let storage = &package.storage;
+        let connections = &package.connections;
+
+        // The nodes are created in order, so each is given the ID it
+        // was saved with. The new package already has its root.
+        for record in records.iter().skip(1) {
+            match *record {
+                RootRecord(..) => {},
+                DocumentTypeRecord(ref name, ref public_id, ref system_id, ref internal, ref external) => {
+                    let doctype = storage.create_document_type(name.as_slice(), as_str(public_id),
+                                                               as_str(system_id), as_str(internal));
+                    storage.document_type_set_external_subset(doctype, as_str(external));
+                },
+                ElementRecord(ref name, _, _) => { storage.create_element(name.as_slice()); },
+                AttributeRecord(ref name, ref value) => {
+                    storage.create_attribute(name.as_slice(), value.as_slice());
+                },
+                TextRecord(ref text) => { storage.create_text(text.as_slice()); },
+                CommentRecord(ref text) => { storage.create_comment(text.as_slice()); },
+                ProcessingInstructionRecord(ref target, ref value) => {
+                    storage.create_processing_instruction(target.as_slice(), as_str(value));
+                },
+            }
+        }
+
+        let nodes = storage.nodes();
+
+        for (record, node) in records.iter().zip(nodes.iter()) {
+            match (record, *node) {
+                (&RootRecord(ref doctype, ref children), _) => {
+                    for &id in doctype.iter() {
+                        if let raw::DocumentTypeNode(d) = nodes[id as uint] {
+                            connections.set_root_doctype(Some(d));
+                        }
+                    }
+                    for &id in children.iter() {
+                        match nodes[id as uint] {
+                            raw::ElementNode(n) => connections.append_root_child(raw::ElementCOR(n)),
+                            raw::CommentNode(n) => connections.append_root_child(raw::CommentCOR(n)),
+                            raw::ProcessingInstructionNode(n) => {
+                                connections.append_root_child(raw::ProcessingInstructionCOR(n))
+                            },
+                            _ => {},
+                        }
+                    }
+                },
+                (&ElementRecord(_, ref attributes, ref children), raw::ElementNode(element)) => {
+                    for &id in attributes.iter() {
+                        if let raw::AttributeNode(a) = nodes[id as uint] {
+                            connections.set_attribute(element, a);
+                        }
+                    }
+                    for &id in children.iter() {
+                        if let Some(child) = nodes[id as uint].to_child_of_element() {
+                            connections.append_element_child(element, child);
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    package
+}
+
+/// Reads a package saved by `save`. Its nodes have the same IDs as
+/// when they were saved.
+pub fn load<R : Reader>(reader: &mut R) -> Result<Package, Error> {
+    let magic = try!(LimitReader::new(reader.by_ref(), MAGIC.len()).read_to_end().map_err(ReadFailure));
+    if magic.as_slice() != MAGIC { return Err(NotASnapshot) }
+
+    let version = try!(read_u8(reader));
+    if version != VERSION { return Err(UnsupportedVersion(version)) }
+
+    let count = try!(read_u64(reader));
+    let mut records = Vec::new();
+    for _ in range(0, count) {
+        records.push(try!(read_record(reader)));
+    }
+
+    try!(check_structure(records.as_slice()));
+    Ok(build(records))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufReader,MemWriter};
+    use super::super::Package;
+    use super::super::dom4::{ElementNode,TextNode,AttributeNode};
+    use super::{save,load,Error,NotASnapshot,UnsupportedVersion,Corrupt};
+
+    fn round_trip(package: &Package) -> Package {
+        let mut saved = MemWriter::new();
+        save(package, &mut saved).ok().expect("unable to save");
+        let mut reader = BufReader::new(saved.get_ref());
+        load(&mut reader).ok().expect("unable to load")
+    }
+
+    fn load_bytes(bytes: &[u8]) -> Result<Package, Error> {
+        load(&mut BufReader::new(bytes))
+    }
+
+    #[test]
+    fn nodes_keep_their_ids() {
+        let package = Package::new();
+        let (element_id, text_id, attribute_id) = {
+            let doc = package.as_document();
+            let element = doc.create_element("element");
+            let text = doc.create_text("text");
+            let attribute = element.set_attribute_value("name", "value");
+            doc.root().append_child(element);
+            element.append_child(text);
+            (element.id(), text.id(), attribute.id())
+        };
+
+        let loaded = round_trip(&package);
+        let doc = loaded.as_document();
+
+        let element = match doc.node_by_id(element_id) {
+            Some(ElementNode(e)) => e,
+            n => panic!("expected an element, found {}", n),
+        };
+        assert_eq!(element.name(), "element");
+        assert_eq!(doc.root().children()[0].element(), Some(element));
+
+        match doc.node_by_id(text_id) {
+            Some(TextNode(t)) => assert_eq!(t.text(), "text"),
+            n => panic!("expected text, found {}", n),
+        }
+        match doc.node_by_id(attribute_id) {
+            Some(AttributeNode(a)) => assert_eq!(a.parent(), Some(element)),
+            n => panic!("expected an attribute, found {}", n),
+        }
+    }
+
+    #[test]
+    fn detached_nodes_are_kept() {
+        let package = Package::new();
+        let (parent_id, child_id) = {
+            let doc = package.as_document();
+            let parent = doc.create_element("parent");
+            let child = doc.create_element("child");
+            parent.append_child(child);
+            (parent.id(), child.id())
+        };
+
+        let loaded = round_trip(&package);
+        let doc = loaded.as_document();
+
+        assert!(doc.root().children().is_empty());
+        let parent = match doc.node_by_id(parent_id) {
+            Some(ElementNode(e)) => e,
+            n => panic!("expected an element, found {}", n),
+        };
+        assert_eq!(parent.parent(), None);
+        assert_eq!(parent.children()[0].element().map(|e| e.id()), Some(child_id));
+    }
+
+    #[test]
+    fn the_document_type_is_kept() {
+        let package = Package::new();
+        {
+            let doc = package.as_document();
+            let doctype = doc.create_document_type("alpha", Some("-//Alpha"), Some("alpha.dtd"), None);
+            doctype.set_external_subset(Some("<!ELEMENT alpha EMPTY>"));
+            doc.root().set_doctype(Some(doctype));
+        }
+
+        let loaded = round_trip(&package);
+        let doc = loaded.as_document();
+
+        let doctype = doc.root().doctype().unwrap();
+        assert_eq!(doctype.name(), "alpha");
+        assert_eq!(doctype.public_id(), Some("-//Alpha"));
+        assert_eq!(doctype.system_id(), Some("alpha.dtd"));
+        assert_eq!(doctype.internal_subset(), None);
+        assert_eq!(doctype.external_subset(), Some("<!ELEMENT alpha EMPTY>"));
+    }
+
+    #[test]
+    fn other_input_is_not_a_snapshot() {
+        assert_eq!(load_bytes(b"<xml/>").err(), Some(NotASnapshot));
+    }
+
+    #[test]
+    fn later_versions_are_not_supported() {
+        assert_eq!(load_bytes(b"SXDS\x09").err(), Some(UnsupportedVersion(9)));
+    }
+
+    #[test]
+    fn truncated_snapshots_fail() {
+        let package = Package::new();
+        {
+            let doc = package.as_document();
+            doc.root().append_child(doc.create_element("hello"));
+        }
+
+        let mut saved = MemWriter::new();
+        save(&package, &mut saved).ok().expect("unable to save");
+        let bytes = saved.get_ref();
+
+        assert!(load_bytes(bytes.slice_to(bytes.len() - 3)).is_err());
+    }
+
+    #[test]
+    fn children_must_have_one_parent() {
+        let bytes = [
+            b'S', b'X', b'D', b'S', 1,
+            0, 0, 0, 0, 0, 0, 0, 3,
+            // The root, whose child is the first element
+            0,
+            0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 1,
+            0, 0, 0, 0, 0, 0, 0, 1,
+            // An element named "a" with no attributes or children
+            2,
+            0, 0, 0, 0, 0, 0, 0, 1, b'a',
+            0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0,
+            // An element named "b" that also claims the first element
+            2,
+            0, 0, 0, 0, 0, 0, 0, 1, b'b',
+            0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 1,
+            0, 0, 0, 0, 0, 0, 0, 1,
+        ];
+
+        assert_eq!(load_bytes(bytes.as_slice()).err(), Some(Corrupt));
+    }
+}
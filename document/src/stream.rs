@@ -0,0 +1,609 @@
+//! Writes XML incrementally, without building a document first
+//!
+//! ### Example
+//! ```
+//! use std::io::MemWriter;
+//! use document::stream::StreamWriter;
+//!
+//! let mut xml = StreamWriter::new(MemWriter::new());
+//! xml.declaration().unwrap();
+//! xml.start_element("hello").unwrap();
+//! xml.attribute("planet", "Earth").unwrap();
+//! xml.text("Fish & chips").unwrap();
+//! xml.end_element("hello").unwrap();
+//!
+//! let output = xml.finish().unwrap().unwrap();
+//! assert_eq!(output.as_slice(), b"<?xml version='1.0'?><hello planet='Earth'>Fish &amp; chips</hello>");
+//! ```
+//!
+//! Output is written as soon as possible; only the names of the open
+//! elements and the namespaces in scope are kept. An element with no
+//! content is written as an empty-element tag.
+//!
+//! Text and attribute values are escaped. Misuse that would produce
+//! malformed XML, such as mismatched end tags, attributes after
+//! content, a second top-level element, or characters that XML does
+//! not allow, is reported as an `Error` and nothing is written.
+//!
+//! ### Namespaces
+//!
+//! Names are written as given, prefix included. `declare_namespace`
+//! adds a declaration to the current start tag, and every prefix used
+//! by an element or attribute must be declared by the time its start
+//! tag is closed. `start_element_ns` finds a prefix for a namespace
+//! name instead, declaring a new one when none is in scope.
+
+use std::ascii::AsciiExt;
+use std::io::IoError;
+
+use super::writer::{escape_text,escape_attribute_value};
+use super::xmlstr::{XmlChar,is_name,prefix_of};
+
+static XML_PREFIX: &'static str = "xml";
+static XML_NAMESPACE: &'static str = "http://www.w3.org/XML/1998/namespace";
+
+#[deriving(Show,Clone,PartialEq)]
+pub enum Error {
+    /// The underlying writer failed
+    WriteFailure(IoError),
+    /// The declaration can only be the very first thing written
+    MisplacedDeclaration,
+    InvalidName(String),
+    /// Attributes and namespace declarations must directly follow
+    /// their start tag
+    NotInStartTag,
+    DuplicateAttribute(String),
+    UndeclaredPrefix(String),
+    /// The end tag given and the name of the open element
+    MismatchedEndTag(String, String),
+    NoOpenElement,
+    /// Only comments, processing instructions and whitespace may
+    /// surround the document element
+    ContentOutsideRoot,
+    ExtraRootElement,
+    NoRootElement,
+    /// Comments cannot contain `--` or end with `-`
+    InvalidComment(String),
+    /// The target cannot be `xml`, and the value cannot contain `?>`
+    InvalidProcessingInstruction(String),
+    /// The character cannot appear anywhere in an XML document
+    InvalidCharacter(char),
+}
+
+struct Binding {
+    prefix: String,
+    namespace: String,
+    depth: uint,
+}
+
+pub struct StreamWriter<W> {
+    writer: W,
+    open: Vec<String>,
+    bindings: Vec<Binding>,
+    start_tag: Option<StartTag>,
+    written_anything: bool,
+    root_written: bool,
+    next_prefix: uint,
+}
+
+/// What is known about the start tag currently being written
+struct StartTag {
+    attributes: Vec<String>,
+    prefixes: Vec<String>,
+}
+
+fn check_chars(text: &str) -> Result<(), Error> {
+    match text.chars().find(|c| ! c.is_char()) {
+        Some(c) => Err(InvalidCharacter(c)),
+        None => Ok(()),
+    }
+}
+
+fn is_whitespace(text: &str) -> bool {
+    text.chars().all(|c| c == ' ' || c == '\t' || c == '\r' || c == '\n')
+}
+
+impl<W : Writer> StreamWriter<W> {
+    pub fn new(writer: W) -> StreamWriter<W> {
+        StreamWriter {
+            writer: writer,
+            open: Vec::new(),
+            bindings: Vec::new(),
+            start_tag: None,
+            written_anything: false,
+            root_written: false,
+            next_prefix: 1,
+        }
+    }
+
+    fn write(&mut self, s: &str) -> Result<(), Error> {
+        self.written_anything = true;
+        self.writer.write_str(s).map_err(|e| WriteFailure(e))
+    }
+
+    /// Finishes the current start tag, once every prefix it uses is
+    /// known to be declared
+    fn close_start_tag(&mut self) -> Result<(), Error> {
+        if self.start_tag.is_none() { return Ok(()) }
+
+        try!(self.check_prefixes());
+        self.start_tag = None;
+        self.write(">")
+    }
+
+    fn check_prefixes(&self) -> Result<(), Error> {
+        if let Some(ref tag) = self.start_tag {
+            for prefix in tag.prefixes.iter() {
+                if self.namespace_for(prefix.as_slice()).is_none() {
+                    return Err(UndeclaredPrefix(prefix.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The namespace bound to the prefix, where the empty prefix is
+    /// the default namespace
+    pub fn namespace_for(&self, prefix: &str) -> Option<&str> {
+        if prefix == XML_PREFIX { return Some(XML_NAMESPACE) }
+
+        self.bindings.iter().rev()
+            .find(|b| b.prefix.as_slice() == prefix)
+            .map(|b| b.namespace.as_slice())
+            .and_then(|n| if n.is_empty() { None } else { Some(n) })
+    }
+
+    /// A prefix in scope for the namespace, preferring the most
+    /// recently declared. The empty prefix is the default namespace.
+    pub fn prefix_for(&self, namespace: &str) -> Option<&str> {
+        if namespace == XML_NAMESPACE { return Some(XML_PREFIX) }
+
+        self.bindings.iter().rev()
+            .find(|b| {
+                b.namespace.as_slice() == namespace &&
+                    self.namespace_for(b.prefix.as_slice()) == Some(namespace)
+            })
+            .map(|b| b.prefix.as_slice())
+    }
+
+    fn check_name(&self, name: &str) -> Result<(), Error> {
+        if is_name(name) && name.split(':').count() <= 2 && ! name.starts_with(":") && ! name.ends_with(":") {
+            Ok(())
+        } else {
+            Err(InvalidName(name.to_string()))
+        }
+    }
+
+    fn before_content(&mut self) -> Result<(), Error> {
+        try!(self.close_start_tag());
+        if self.open.is_empty() { Err(ContentOutsideRoot) } else { Ok(()) }
+    }
+
+    /// Writes `<?xml version='1.0'?>`
+    pub fn declaration(&mut self) -> Result<(), Error> {
+        if self.written_anything { return Err(MisplacedDeclaration) }
+        self.write("<?xml version='1.0'?>")
+    }
+
+    pub fn start_element(&mut self, name: &str) -> Result<(), Error> {
+        try!(self.check_name(name));
+        try!(self.close_start_tag());
+
+        if self.open.is_empty() {
+            if self.root_written { return Err(ExtraRootElement) }
+            self.root_written = true;
+        }
+
+        try!(self.write("<"));
+        try!(self.write(name));
+
+        self.open.push(name.to_string());
+        self.start_tag = Some(StartTag {
+            attributes: Vec::new(),
+            prefixes: prefix_of(name).into_iter().map(|p| p.to_string()).collect(),
+        });
+
+        Ok(())
+    }
+
+    /// Starts an element in the namespace, using a prefix that is
+    /// already in scope or declaring a new one
+    pub fn start_element_ns(&mut self, namespace: &str, local_name: &str) -> Result<(), Error> {
+        let existing = self.prefix_for(namespace).map(|p| p.to_string());
+
+        let prefix = match existing {
+            Some(ref p) => p.clone(),
+            None => {
+                let p = format!("ns{}", self.next_prefix);
+                self.next_prefix += 1;
+                p
+            },
+        };
+
+        let name = if prefix.is_empty() {
+            local_name.to_string()
+        } else {
+            format!("{}:{}", prefix, local_name)
+        };
+
+        try!(self.start_element(name.as_slice()));
+
+        if existing.is_none() {
+            try!(self.declare_namespace(Some(prefix.as_slice()), namespace));
+        }
+
+        Ok(())
+    }
+
+    /// Declares the prefix, or the default namespace when the prefix
+    /// is `None`, on the current start tag
+    pub fn declare_namespace(&mut self, prefix: Option<&str>, namespace: &str) -> Result<(), Error> {
+        let name = match prefix {
+            Some(p) => format!("xmlns:{}", p),
+            None => "xmlns".to_string(),
+        };
+
+        try!(self.attribute(name.as_slice(), namespace));
+
+        self.bindings.push(Binding {
+            prefix: prefix.unwrap_or("").to_string(),
+            namespace: namespace.to_string(),
+            depth: self.open.len(),
+        });
+
+        Ok(())
+    }
+
+    pub fn attribute(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        try!(self.check_name(name));
+        try!(check_chars(value));
+
+        match self.start_tag {
+            None => return Err(NotInStartTag),
+            Some(ref mut tag) => {
+                if tag.attributes.iter().any(|a| a.as_slice() == name) {
+                    return Err(DuplicateAttribute(name.to_string()));
+                }
+                tag.attributes.push(name.to_string());
+
+                if let Some(prefix) = prefix_of(name) {
+                    if prefix != "xmlns" {
+                        tag.prefixes.push(prefix.to_string());
+                    }
+                }
+            },
+        }
+
+        try!(self.write(" "));
+        try!(self.write(name));
+        try!(self.write("='"));
+        try!(escape_attribute_value(value, '\'', &mut self.writer).map_err(|e| WriteFailure(e)));
+        self.write("'")
+    }
+
+    /// Writes escaped text. Outside of the document element, only
+    /// whitespace is allowed.
+    pub fn text(&mut self, text: &str) -> Result<(), Error> {
+        if text.is_empty() { return Ok(()) }
+        try!(check_chars(text));
+
+        if self.open.is_empty() && is_whitespace(text) {
+            return self.write(text);
+        }
+
+        try!(self.before_content());
+        self.written_anything = true;
+        escape_text(text, &mut self.writer).map_err(|e| WriteFailure(e))
+    }
+
+    pub fn comment(&mut self, text: &str) -> Result<(), Error> {
+        if text.contains("--") || text.ends_with("-") {
+            return Err(InvalidComment(text.to_string()));
+        }
+        try!(check_chars(text));
+
+        try!(self.close_start_tag());
+        try!(self.write("<!--"));
+        try!(self.write(text));
+        self.write("-->")
+    }
+
+    pub fn processing_instruction(&mut self, target: &str, value: Option<&str>) -> Result<(), Error> {
+        let invalid_target = ! is_name(target) || target.eq_ignore_ascii_case("xml");
+        let invalid_value = value.map_or(false, |v| v.contains("?>"));
+        if invalid_target || invalid_value {
+            return Err(InvalidProcessingInstruction(target.to_string()));
+        }
+        if let Some(value) = value { try!(check_chars(value)) }
+
+        try!(self.close_start_tag());
+        try!(self.write("<?"));
+        try!(self.write(target));
+        if let Some(value) = value {
+            try!(self.write(" "));
+            try!(self.write(value));
+        }
+        self.write("?>")
+    }
+
+    /// Closes the innermost open element, which must have this name
+    pub fn end_element(&mut self, name: &str) -> Result<(), Error> {
+        let open = match self.open.last() {
+            Some(open) => open.clone(),
+            None => return Err(NoOpenElement),
+        };
+
+        if open.as_slice() != name {
+            return Err(MismatchedEndTag(name.to_string(), open));
+        }
+
+        self.close_element()
+    }
+
+    fn close_element(&mut self) -> Result<(), Error> {
+        if self.start_tag.is_some() {
+            try!(self.check_prefixes());
+            self.start_tag = None;
+            try!(self.write("/>"));
+        } else {
+            let name = self.open.last().expect("No open element").clone();
+            try!(self.write("</"));
+            try!(self.write(name.as_slice()));
+            try!(self.write(">"));
+        }
+
+        let depth = self.open.len();
+        self.bindings.retain(|b| b.depth != depth);
+        self.open.pop();
+
+        Ok(())
+    }
+
+    /// The names of the open elements, outermost first
+    pub fn open_elements(&self) -> &[String] {
+        self.open.as_slice()
+    }
+
+    /// Closes any open elements and gives back the underlying writer
+    pub fn finish(mut self) -> Result<W, Error> {
+        if ! self.root_written { return Err(NoRootElement) }
+
+        while ! self.open.is_empty() {
+            try!(self.close_element());
+        }
+
+        try!(self.writer.flush().map_err(|e| WriteFailure(e)));
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::MemWriter;
+
+    use super::StreamWriter;
+    use super::{MisplacedDeclaration,NotInStartTag,DuplicateAttribute,UndeclaredPrefix};
+    use super::{MismatchedEndTag,NoOpenElement,ContentOutsideRoot,ExtraRootElement};
+    use super::{NoRootElement,InvalidComment,InvalidProcessingInstruction,InvalidName};
+    use super::InvalidCharacter;
+
+    macro_rules! assert_str_eq(
+        ($l:expr, $r:expr) => (assert_eq!($l.as_slice(), $r.as_slice()));
+    )
+
+    fn output(xml: StreamWriter<MemWriter>) -> String {
+        let w = xml.finish().ok().expect("Not finished");
+        String::from_utf8(w.unwrap()).ok().expect("Not a string")
+    }
+
+    fn writer() -> StreamWriter<MemWriter> {
+        StreamWriter::new(MemWriter::new())
+    }
+
+    #[test]
+    fn nested_elements_with_attributes_and_text() {
+        let mut xml = writer();
+        xml.declaration().unwrap();
+        xml.start_element("hello").unwrap();
+        xml.attribute("a", "b").unwrap();
+        xml.start_element("world").unwrap();
+        xml.text("text").unwrap();
+        xml.end_element("world").unwrap();
+        xml.end_element("hello").unwrap();
+
+        assert_str_eq!(output(xml), "<?xml version='1.0'?><hello a='b'><world>text</world></hello>");
+    }
+
+    #[test]
+    fn empty_elements_use_empty_element_tags() {
+        let mut xml = writer();
+        xml.start_element("hello").unwrap();
+        xml.attribute("a", "b").unwrap();
+        xml.end_element("hello").unwrap();
+
+        assert_str_eq!(output(xml), "<hello a='b'/>");
+    }
+
+    #[test]
+    fn text_and_attribute_values_are_escaped() {
+        let mut xml = writer();
+        xml.start_element("hello").unwrap();
+        xml.attribute("a", "it's <1> & more").unwrap();
+        xml.text("a < b && c > d").unwrap();
+        xml.end_element("hello").unwrap();
+
+        assert_str_eq!(output(xml), "<hello a='it&apos;s &lt;1> &amp; more'>a &lt; b &amp;&amp; c &gt; d</hello>");
+    }
+
+    #[test]
+    fn whitespace_that_would_be_normalized_is_escaped() {
+        let mut xml = writer();
+        xml.start_element("hello").unwrap();
+        xml.attribute("a", "1\t2\n3\r4\"5").unwrap();
+        xml.text("line\r\n").unwrap();
+        xml.end_element("hello").unwrap();
+
+        assert_str_eq!(output(xml), "<hello a='1&#9;2&#10;3&#13;4\"5'>line&#13;\n</hello>");
+    }
+
+    #[test]
+    fn characters_that_cannot_appear_in_xml_are_rejected() {
+        let mut xml = writer();
+        xml.start_element("hello").unwrap();
+
+        assert_eq!(xml.attribute("a", "\0"), Err(InvalidCharacter('\0')));
+        assert_eq!(xml.text("bell\x07"), Err(InvalidCharacter('\x07')));
+        assert_eq!(xml.comment("\x1b"), Err(InvalidCharacter('\x1b')));
+        assert_eq!(xml.processing_instruction("a", Some("\uFFFF")), Err(InvalidCharacter('\uFFFF')));
+
+        xml.end_element("hello").unwrap();
+        assert_str_eq!(output(xml), "<hello/>");
+    }
+
+    #[test]
+    fn comments_and_processing_instructions() {
+        let mut xml = writer();
+        xml.comment(" before ").unwrap();
+        xml.start_element("hello").unwrap();
+        xml.processing_instruction("display", Some("screen")).unwrap();
+        xml.end_element("hello").unwrap();
+        xml.processing_instruction("after", None).unwrap();
+
+        assert_str_eq!(output(xml), "<!-- before --><hello><?display screen?></hello><?after?>");
+    }
+
+    #[test]
+    fn finishing_closes_open_elements() {
+        let mut xml = writer();
+        xml.start_element("a").unwrap();
+        xml.start_element("b").unwrap();
+        xml.text("c").unwrap();
+        xml.start_element("d").unwrap();
+        assert_eq!(xml.open_elements().len(), 3);
+
+        assert_str_eq!(output(xml), "<a><b>c<d/></b></a>");
+    }
+
+    #[test]
+    fn end_tags_must_match() {
+        let mut xml = writer();
+        xml.start_element("a").unwrap();
+
+        assert_eq!(xml.end_element("b"), Err(MismatchedEndTag("b".to_string(), "a".to_string())));
+        xml.end_element("a").unwrap();
+        assert_eq!(xml.end_element("a"), Err(NoOpenElement));
+    }
+
+    #[test]
+    fn attributes_must_follow_the_start_tag() {
+        let mut xml = writer();
+        xml.start_element("a").unwrap();
+        xml.attribute("b", "1").unwrap();
+
+        assert_eq!(xml.attribute("b", "2"), Err(DuplicateAttribute("b".to_string())));
+        xml.text("text").unwrap();
+        assert_eq!(xml.attribute("c", "3"), Err(NotInStartTag));
+    }
+
+    #[test]
+    fn only_one_root_element() {
+        let mut xml = writer();
+        xml.start_element("a").unwrap();
+        xml.end_element("a").unwrap();
+
+        assert_eq!(xml.start_element("b"), Err(ExtraRootElement));
+    }
+
+    #[test]
+    fn only_whitespace_outside_the_root_element() {
+        let mut xml = writer();
+        xml.text("\n").unwrap();
+
+        assert_eq!(xml.text("hello"), Err(ContentOutsideRoot));
+    }
+
+    #[test]
+    fn a_root_element_is_required() {
+        let xml = writer();
+
+        assert_eq!(xml.finish().err(), Some(NoRootElement));
+    }
+
+    #[test]
+    fn the_declaration_must_come_first() {
+        let mut xml = writer();
+        xml.comment("hello").unwrap();
+
+        assert_eq!(xml.declaration(), Err(MisplacedDeclaration));
+    }
+
+    #[test]
+    fn names_must_be_valid() {
+        let mut xml = writer();
+
+        assert_eq!(xml.start_element("1a"), Err(InvalidName("1a".to_string())));
+        assert_eq!(xml.start_element("a:b:c"), Err(InvalidName("a:b:c".to_string())));
+    }
+
+    #[test]
+    fn comments_and_processing_instructions_must_be_valid() {
+        let mut xml = writer();
+
+        assert_eq!(xml.comment("a -- b"), Err(InvalidComment("a -- b".to_string())));
+        assert_eq!(xml.processing_instruction("xml", None), Err(InvalidProcessingInstruction("xml".to_string())));
+        assert_eq!(xml.processing_instruction("a", Some("?>")), Err(InvalidProcessingInstruction("a".to_string())));
+    }
+
+    #[test]
+    fn declared_prefixes_can_be_used() {
+        let mut xml = writer();
+        xml.start_element("x:a").unwrap();
+        xml.declare_namespace(Some("x"), "urn:x").unwrap();
+        xml.start_element("x:b").unwrap();
+        xml.attribute("x:c", "1").unwrap();
+        xml.attribute("xml:lang", "en").unwrap();
+
+        assert_str_eq!(output(xml), "<x:a xmlns:x='urn:x'><x:b x:c='1' xml:lang='en'/></x:a>");
+    }
+
+    #[test]
+    fn undeclared_prefixes_are_rejected() {
+        let mut xml = writer();
+        xml.start_element("a").unwrap();
+        xml.start_element("x:b").unwrap();
+        assert_eq!(xml.text("text"), Err(UndeclaredPrefix("x".to_string())));
+    }
+
+    #[test]
+    fn declarations_go_out_of_scope() {
+        let mut xml = writer();
+        xml.start_element("a").unwrap();
+        xml.start_element("b").unwrap();
+        xml.declare_namespace(Some("x"), "urn:x").unwrap();
+        xml.end_element("b").unwrap();
+
+        assert_eq!(xml.namespace_for("x"), None);
+        xml.start_element("x:c").unwrap();
+        assert_eq!(xml.end_element("x:c"), Err(UndeclaredPrefix("x".to_string())));
+    }
+
+    #[test]
+    fn namespaced_elements_reuse_prefixes_in_scope() {
+        let mut xml = writer();
+        xml.start_element_ns("urn:x", "a").unwrap();
+        xml.start_element_ns("urn:x", "b").unwrap();
+        xml.start_element_ns("urn:y", "c").unwrap();
+
+        assert_str_eq!(output(xml), "<ns1:a xmlns:ns1='urn:x'><ns1:b><ns2:c xmlns:ns2='urn:y'/></ns1:b></ns1:a>");
+    }
+
+    #[test]
+    fn namespaced_elements_use_the_default_namespace() {
+        let mut xml = writer();
+        xml.start_element("a").unwrap();
+        xml.declare_namespace(None, "urn:x").unwrap();
+        xml.start_element_ns("urn:x", "b").unwrap();
+
+        assert_str_eq!(output(xml), "<a xmlns='urn:x'><b/></a>");
+    }
+}
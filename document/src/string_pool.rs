@@ -77,6 +77,13 @@ impl InternedString {
             str::raw::from_utf8(bytes)
         }
     }
+
+    /// Whether both refer to the same interned copy. Strings interned
+    /// by the same pool are the same copy exactly when their contents
+    /// are equal, so this is a faster `==` for them.
+    pub fn is(&self, other: &InternedString) -> bool {
+        self.slice.data == other.slice.data && self.slice.len == other.slice.len
+    }
 }
 
 impl fmt::Show for InternedString {
@@ -132,6 +139,24 @@ impl StringPool {
         unsafe { mem::transmute(interned_str.as_slice()) }
     }
 
+    /// Like `intern`, but when the string has not been seen before
+    /// the input itself becomes the interned copy instead of being
+    /// copied into the pool.
+    ///
+    /// The caller must guarantee that the input outlives the pool.
+    pub unsafe fn intern_borrowed<'s>(&'s self, s: &str) -> &'s str {
+        if s == "" { return ""; }
+
+        let search_string = InternedString::from_str(s);
+
+        let interned_str = match self.index.borrow_mut().entry(search_string) {
+            Occupied(entry) => *entry.get(),
+            Vacant(entry) => *entry.set(search_string),
+        };
+
+        mem::transmute(interned_str.as_slice())
+    }
+
     fn do_intern(&self, s: &str) -> InternedString {
         self.ensure_capacity(s.len());
         self.store(s)
@@ -171,7 +196,7 @@ impl StringPool {
 
 #[cfg(test)]
 mod test {
-    use super::StringPool;
+    use super::{StringPool,InternedString};
 
     #[test]
     fn keeps_the_same_string() {
@@ -208,6 +233,45 @@ mod test {
         );
     }
 
+    #[test]
+    fn repeated_input_is_the_same_interned_string() {
+        let s = StringPool::new();
+
+        let interned1 = InternedString::from_str(s.intern("world"));
+        let interned2 = InternedString::from_str(s.intern("world"));
+        let other = InternedString::from_str(s.intern("word"));
+
+        assert!(interned1.is(&interned2));
+        assert!(! interned1.is(&other));
+        assert!(! interned1.is(&InternedString::from_str("world")));
+    }
+
+    #[test]
+    fn borrowed_interning_reuses_the_pointer_of_the_input() {
+        let s = StringPool::new();
+        let input = "hello";
+
+        let interned = unsafe { s.intern_borrowed(input) };
+
+        assert_eq!(
+            input.as_bytes().as_ptr(),
+            interned.as_bytes().as_ptr()
+        );
+    }
+
+    #[test]
+    fn borrowed_interning_prefers_an_existing_string() {
+        let s = StringPool::new();
+
+        let interned1 = s.intern("world");
+        let interned2 = unsafe { s.intern_borrowed("world") };
+
+        assert_eq!(
+            interned1.as_bytes().as_ptr(),
+            interned2.as_bytes().as_ptr()
+        );
+    }
+
     // #[test]
     // #[compile_failure]
     // fn string_cannot_outlive_the_pool() {
@@ -1,4 +1,6 @@
 use super::raw;
+use super::navigator;
+use super::navigator::{Navigator,NodeKind};
 use std::fmt;
 use std::kinds::marker::InvariantLifetime;
 
@@ -212,6 +214,10 @@ macro_rules! node(
                 self.node == other.node
             }
         }
+
+        impl<'d> Clone for $name<'d> {
+            fn clone(&self) -> $name<'d> { *self }
+        }
     )
 )
 
@@ -242,6 +248,12 @@ impl<'d> Attribute<'d> {
     pub fn value(&self) -> &str { self.node().value() }
 }
 
+impl<'d> fmt::Show for Attribute<'d> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Attribute {{ name: {}, value: {} }}", self.name(), self.value())
+    }
+}
+
 node!(Text, raw::Text)
 
 impl<'d> Text<'d> {
@@ -418,6 +430,104 @@ impl<'d> ToChildOfElement<'d> for ChildOfRoot<'d> {
     }
 }
 
+/// Any node of a document, for use with `navigator::Navigator`
+#[deriving(PartialEq,Show,Clone)]
+pub enum Node<'d> {
+    RootNode(Root<'d>),
+    ElementNode(Element<'d>),
+    AttributeNode(Attribute<'d>),
+    TextNode(Text<'d>),
+    CommentNode(Comment<'d>),
+    ProcessingInstructionNode(ProcessingInstruction<'d>),
+}
+
+impl<'d> ChildOfElement<'d> {
+    pub fn to_node(self) -> Node<'d> {
+        match self {
+            ElementCOE(n)               => ElementNode(n),
+            TextCOE(n)                  => TextNode(n),
+            CommentCOE(n)               => CommentNode(n),
+            ProcessingInstructionCOE(n) => ProcessingInstructionNode(n),
+        }
+    }
+}
+
+impl<'d> ParentOfChild<'d> {
+    pub fn to_node(self) -> Node<'d> {
+        match self {
+            RootPOC(n)    => RootNode(n),
+            ElementPOC(n) => ElementNode(n),
+        }
+    }
+}
+
+impl<'d> Navigator<Node<'d>> for Connections<'d> {
+    fn kind(&self, node: &Node<'d>) -> NodeKind {
+        match *node {
+            RootNode(..)                  => navigator::Root,
+            ElementNode(..)               => navigator::Element,
+            AttributeNode(..)             => navigator::Attribute,
+            TextNode(..)                  => navigator::Text,
+            CommentNode(..)               => navigator::Comment,
+            ProcessingInstructionNode(..) => navigator::ProcessingInstruction,
+        }
+    }
+
+    fn parent(&self, node: &Node<'d>) -> Option<Node<'d>> {
+        match *node {
+            RootNode(..)                 => None,
+            ElementNode(n)               => self.element_parent(n).map(|p| p.to_node()),
+            AttributeNode(n)             => self.attribute_parent(n).map(ElementNode),
+            TextNode(n)                  => self.text_parent(n).map(ElementNode),
+            CommentNode(n)               => self.comment_parent(n).map(|p| p.to_node()),
+            ProcessingInstructionNode(n) => self.processing_instruction_parent(n).map(|p| p.to_node()),
+        }
+    }
+
+    fn children(&self, node: &Node<'d>) -> Vec<Node<'d>> {
+        match *node {
+            RootNode(..)   => self.root_children().map(|c| c.to_child_of_element().to_node()).collect(),
+            ElementNode(n) => self.element_children(n).map(|c| c.to_node()).collect(),
+            _              => Vec::new(),
+        }
+    }
+
+    fn attributes(&self, node: &Node<'d>) -> Vec<Node<'d>> {
+        match *node {
+            // The inherent method, which takes the element itself
+            ElementNode(n) => self.attributes(n).map(AttributeNode).collect(),
+            _              => Vec::new(),
+        }
+    }
+
+    fn name<'a>(&'a self, node: &'a Node<'d>) -> Option<&'a str> {
+        match *node {
+            ElementNode(ref n)               => Some(n.name()),
+            AttributeNode(ref n)             => Some(n.name()),
+            ProcessingInstructionNode(ref n) => Some(n.target()),
+            _                                => None,
+        }
+    }
+
+    fn string_value(&self, node: &Node<'d>) -> String {
+        match *node {
+            RootNode(..) | ElementNode(..) => {
+                let mut value = String::new();
+                for n in self.descendants(node).iter() {
+                    if let TextNode(t) = *n {
+                        value.push_str(t.text());
+                    }
+                }
+                value
+            },
+            AttributeNode(n)             => n.value().to_string(),
+            TextNode(n)                  => n.text().to_string(),
+            CommentNode(n)               => n.text().to_string(),
+            ProcessingInstructionNode(n) => n.value().unwrap_or("").to_string(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::super::Package;
@@ -425,6 +535,9 @@ mod test {
     use super::{ChildOfElement,ElementCOE,TextCOE,CommentCOE,ProcessingInstructionCOE};
     use super::{RootPOC,ElementPOC};
     use super::Attribute;
+    use super::{RootNode,ElementNode,AttributeNode,TextNode};
+    use super::super::navigator;
+    use super::super::navigator::Navigator;
 
     #[test]
     fn root_can_have_element_children() {
@@ -776,6 +889,33 @@ mod test {
         assert_eq!(element.name(), "hello");
     }
 
+    // Connections has its own `attributes` method
+    fn attributes_of<N : Clone + PartialEq, T : Navigator<N>>(navigator: &T, node: &N) -> Vec<N> {
+        navigator.attributes(node)
+    }
+
+    #[test]
+    fn connections_navigate_nodes() {
+        let package = Package::new();
+        let (s, mut c) = package.as_thin_document();
+
+        let element = s.create_element("alpha");
+        let attribute = s.create_attribute("name", "value");
+        let text = s.create_text("hello");
+        c.append_root_child(element);
+        c.set_attribute(element, attribute);
+        c.append_element_child(element, text);
+
+        let root = RootNode(c.root());
+        assert_eq!(c.children(&root), vec![ElementNode(element)]);
+        assert_eq!(attributes_of(&c, &ElementNode(element)), vec![AttributeNode(attribute)]);
+        assert_eq!(c.parent(&AttributeNode(attribute)), Some(ElementNode(element)));
+        assert_eq!(c.parent(&TextNode(text)), Some(ElementNode(element)));
+        assert_eq!(c.name(&ElementNode(element)), Some("alpha"));
+        assert_eq!(c.kind(&TextNode(text)), navigator::Text);
+        assert_eq!(c.string_value(&root).as_slice(), "hello");
+    }
+
     // #[test]
     // #[compile_failure]
     // fn nodes_cannot_live_outside_of_the_document() {
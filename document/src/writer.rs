@@ -16,9 +16,48 @@
 //! format_document(&doc, &mut output).ok().expect("unable to output XML");
 //! ```
 //!
+//! ### Subtrees
+//!
+//! `format_node` writes a single node and everything beneath it, while
+//! `format_children` writes only what is inside an element. Neither
+//! writes an XML declaration. `node_to_string` and `children_to_string`
+//! do the same into a `String`:
+//!
+//! ```
+//! use document::Package;
+//! use document::writer::{node_to_string,children_to_string};
+//!
+//! let package = Package::new();
+//! let doc = package.as_document();
+//!
+//! let record = doc.create_element("record");
+//! record.append_child(doc.create_text("Earth"));
+//!
+//! assert_eq!(node_to_string(record).as_slice(), "<record>Earth</record>");
+//! assert_eq!(children_to_string(record).as_slice(), "Earth");
+//! ```
+//!
+//! ### Recorded markup
+//!
+//! Nodes parsed by `Parser::parse_lossless` are written exactly as
+//! they appeared in the input, as long as they have not been changed.
+//! This includes the XML declaration and whitespace outside of the
+//! document element. HTML output ignores recorded markup.
+//!
+//! ### HTML
+//!
+//! `format_html_document` writes HTML syntax instead:
+//!
+//! - Void elements, like `br`, have no end tag.
+//! - Other elements always have an end tag, even when empty.
+//! - The contents of `script` and `style` are written as-is.
+//! - Boolean attributes, like `checked`, are minimized.
+//! - Text and attribute values are escaped.
+//! - An optional doctype is written instead of the XML declaration.
+//!
 //! ### Known issues
 //!
-//! Output is not escaped in any way,
+//! XML output is not escaped in any way,
 //! it's very easy to create malformed XML!
 //!
 //! ### Potential options to support
@@ -27,100 +66,389 @@
 //! - Single vs double quotes
 //! - Fixed ordering of attributes
 
-use std::io::IoResult;
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::io::{IoResult,MemWriter};
 
 use super::dom4;
 use super::dom4::{ElementCOE,TextCOE,CommentCOE,ProcessingInstructionCOE};
 use super::dom4::{ElementCOR,CommentCOR,ProcessingInstructionCOR};
 
+#[deriving(PartialEq)]
+enum Method {
+    Xml,
+    Html,
+}
+
 enum Content<'d> {
     Element(dom4::Element<'d>),
     ElementEnd(dom4::Element<'d>),
     Text(dom4::Text<'d>),
+    RawText(dom4::Text<'d>),
     Comment(dom4::Comment<'d>),
     ProcessingInstruction(dom4::ProcessingInstruction<'d>),
 }
 
+static HTML_VOID_ELEMENTS: &'static [&'static str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input",
+    "keygen", "link", "meta", "param", "source", "track", "wbr",
+];
+
+static HTML_RAW_TEXT_ELEMENTS: &'static [&'static str] = &[
+    "script", "style",
+];
+
+static HTML_BOOLEAN_ATTRIBUTES: &'static [&'static str] = &[
+    "allowfullscreen", "async", "autofocus", "autoplay", "checked",
+    "compact", "controls", "declare", "default", "defer", "disabled",
+    "formnovalidate", "hidden", "ismap", "loop", "multiple", "muted",
+    "nohref", "noresize", "noshade", "novalidate", "nowrap", "open",
+    "readonly", "required", "reversed", "selected",
+];
+
+fn is_one_of(name: &str, names: &[&str]) -> bool {
+    names.iter().any(|n| n.eq_ignore_ascii_case(name))
+}
+
+fn to_content<'d>(child: dom4::ChildOfElement<'d>, raw_text: bool) -> Content<'d> {
+    match child {
+        ElementCOE(element)         => Element(element),
+        TextCOE(t) if raw_text      => RawText(t),
+        TextCOE(t)                  => Text(t),
+        CommentCOE(c)               => Comment(c),
+        ProcessingInstructionCOE(p) => ProcessingInstruction(p),
+    }
+}
+
+fn push_children<'d>(element: dom4::Element<'d>, todo: &mut Vec<Content<'d>>, raw_text: bool) {
+    let mut children = element.children();
+    children.reverse();
+    let x = children.into_iter().map(|c| to_content(c, raw_text));
+    todo.extend(x);
+}
+
+fn escape<W : Writer>(text: &str, writer: &mut W, replacement: |char| -> Option<&'static str>) -> IoResult<()> {
+    let mut start = 0;
+
+    for (i, c) in text.char_indices() {
+        if let Some(r) = replacement(c) {
+            try!(writer.write_str(text.slice(start, i)));
+            try!(writer.write_str(r));
+            // Only ASCII characters are replaced
+            start = i + 1;
+        }
+    }
+
+    writer.write_str(text.slice_from(start))
+}
+
+/// Writes text so that it can appear as element content. Carriage
+/// returns are written as references, as a parser would otherwise
+/// turn them into newlines.
+pub fn escape_text<W : Writer>(text: &str, writer: &mut W) -> IoResult<()> {
+    escape(text, writer, |c| match c {
+        '&'  => Some("&amp;"),
+        '<'  => Some("&lt;"),
+        '>'  => Some("&gt;"),
+        '\r' => Some("&#13;"),
+        _    => None,
+    })
+}
+
+/// Writes an attribute value so that it can appear between `quote`s.
+/// Whitespace other than spaces is written as references, as a parser
+/// would otherwise turn it into spaces.
+pub fn escape_attribute_value<W : Writer>(value: &str, quote: char, writer: &mut W) -> IoResult<()> {
+    escape(value, writer, |c| match c {
+        '&'                   => Some("&amp;"),
+        '<'                   => Some("&lt;"),
+        '\t'                  => Some("&#9;"),
+        '\n'                  => Some("&#10;"),
+        '\r'                  => Some("&#13;"),
+        '"'  if quote == '"'  => Some("&quot;"),
+        '\'' if quote == '\'' => Some("&apos;"),
+        _                     => None,
+    })
+}
+
+/// The markup recorded for the node when it was parsed, which is
+/// only used for XML
+fn recorded(method: Method, source: dom4::Source) -> Option<String> {
+    if method == Xml { source.markup } else { None }
+}
+
 fn format_element<'d, W : Writer>(element: dom4::Element<'d>, todo: &mut Vec<Content<'d>>, writer: &mut W) -> IoResult<()> {
     try!(write!(writer, "<{}", element.name()));
 
     for attr in element.attributes().iter() {
-        try!(write!(writer, " {}='{}'", attr.name(), attr.value()));
+        match attr.source().markup {
+            Some(markup) => try!(writer.write_str(markup.as_slice())),
+            None => try!(write!(writer, " {}='{}'", attr.name(), attr.value())),
+        }
     }
 
-    let mut children = element.children();
-    if children.is_empty() {
-        writer.write_str("/>")
-    } else {
-        try!(writer.write_str(">"));
+    let empty = element.children().is_empty();
+
+    match element.source().markup {
+        Some(ref tag_end) if empty => {
+            try!(writer.write_str(tag_end.as_slice()));
+            if tag_end.as_slice().ends_with("/>") {
+                return Ok(())
+            }
+            return format_end_tag(Xml, element, writer)
+        },
+        Some(ref tag_end) => {
+            // The element was empty when it was parsed
+            let tag_end = tag_end.as_slice();
+            let tag_end = if tag_end.ends_with("/>") { tag_end.slice_to(tag_end.len() - 2) } else { tag_end };
+            try!(writer.write_str(tag_end.trim_right_chars('>')));
+        },
+        None if empty => return writer.write_str("/>"),
+        None => {},
+    }
 
-        todo.push(ElementEnd(element));
-        children.reverse();
-        let x = children.into_iter().map(|c| match c {
-            ElementCOE(element) => Element(element),
-            TextCOE(t)          => Text(t),
-            CommentCOE(c)       => Comment(c),
-            ProcessingInstructionCOE(p)            => ProcessingInstruction(p),
-        });
-        todo.extend(x);
+    try!(writer.write_str(">"));
+
+    todo.push(ElementEnd(element));
+    push_children(element, todo, false);
+
+    Ok(())
+}
+
+fn format_end_tag<W : Writer>(method: Method, element: dom4::Element, writer: &mut W) -> IoResult<()> {
+    let end_tag = if method == Xml { element.source().after } else { None };
 
-        Ok(())
+    match end_tag {
+        Some(ref end_tag) if ! end_tag.is_empty() => writer.write_str(end_tag.as_slice()),
+        _ => write!(writer, "</{}>", element.name()),
     }
 }
 
-fn format_comment<W : Writer>(comment: dom4::Comment, writer: &mut W) -> IoResult<()> {
+fn format_html_element<'d, W : Writer>(element: dom4::Element<'d>, todo: &mut Vec<Content<'d>>, writer: &mut W) -> IoResult<()> {
+    try!(write!(writer, "<{}", element.name()));
+
+    for attr in element.attributes().iter() {
+        let name = attr.name();
+        let value = attr.value();
+
+        if is_one_of(name, HTML_BOOLEAN_ATTRIBUTES) &&
+            (value.is_empty() || value.eq_ignore_ascii_case(name))
+        {
+            try!(write!(writer, " {}", name));
+        } else {
+            try!(write!(writer, " {}=\"", name));
+            try!(escape_attribute_value(value, '"', writer));
+            try!(writer.write_str("\""));
+        }
+    }
+
+    try!(writer.write_str(">"));
+
+    if ! is_one_of(element.name(), HTML_VOID_ELEMENTS) {
+        todo.push(ElementEnd(element));
+        push_children(element, todo, is_one_of(element.name(), HTML_RAW_TEXT_ELEMENTS));
+    }
+
+    Ok(())
+}
+
+fn format_comment<W : Writer>(method: Method, comment: dom4::Comment, writer: &mut W) -> IoResult<()> {
+    if let Some(markup) = recorded(method, comment.source()) {
+        return writer.write_str(markup.as_slice());
+    }
+
     write!(writer, "<!--{}-->", comment.text())
 }
 
-fn format_processing_instruction<W : Writer>(pi: dom4::ProcessingInstruction, writer: &mut W) -> IoResult<()> {
+fn format_processing_instruction<W : Writer>(method: Method, pi: dom4::ProcessingInstruction, writer: &mut W) -> IoResult<()> {
+    if let Some(markup) = recorded(method, pi.source()) {
+        return writer.write_str(markup.as_slice());
+    }
+
     match pi.value() {
         None    => write!(writer, "<?{}?>", pi.target()),
         Some(v) => write!(writer, "<?{} {}?>", pi.target(), v),
     }
 }
 
-fn format_one<'d, W : Writer>(content: Content<'d>, todo: &mut Vec<Content<'d>>, writer: &mut W) -> IoResult<()> {
+fn format_system_literal<W : Writer>(literal: &str, writer: &mut W) -> IoResult<()> {
+    let quote = if literal.contains_char('\'') { '"' } else { '\'' };
+    write!(writer, " {}{}{}", quote, literal, quote)
+}
+
+fn format_document_type<W : Writer>(doctype: dom4::DocumentType, writer: &mut W) -> IoResult<()> {
+    let source = doctype.source();
+    if let Some(markup) = source.markup {
+        if let Some(before) = source.before {
+            try!(writer.write_str(before.as_slice()));
+        }
+        return writer.write_str(markup.as_slice());
+    }
+
+    try!(write!(writer, "<!DOCTYPE {}", doctype.name()));
+
+    match (doctype.public_id(), doctype.system_id()) {
+        (Some(public_id), Some(system_id)) => {
+            try!(writer.write_str(" PUBLIC"));
+            try!(format_system_literal(public_id, writer));
+            try!(format_system_literal(system_id, writer));
+        },
+        (_, Some(system_id)) => {
+            try!(writer.write_str(" SYSTEM"));
+            try!(format_system_literal(system_id, writer));
+        },
+        _ => {},
+    }
+
+    if let Some(subset) = doctype.internal_subset() {
+        try!(write!(writer, " [{}]", subset));
+    }
+
+    writer.write_str(">")
+}
+
+fn format_one<'d, W : Writer>(method: Method, content: Content<'d>, todo: &mut Vec<Content<'d>>, writer: &mut W) -> IoResult<()> {
     match content {
-        Element(e)               => format_element(e, todo, writer),
-        ElementEnd(e)            => write!(writer, "</{}>", e.name()),
-        Text(t)                  => writer.write_str(t.text().as_slice()),
-        Comment(c)               => format_comment(c, writer),
-        ProcessingInstruction(p) => format_processing_instruction(p, writer),
+        Element(e) if method == Html => format_html_element(e, todo, writer),
+        Element(e)                   => format_element(e, todo, writer),
+        ElementEnd(e)                => format_end_tag(method, e, writer),
+        Text(t) if method == Html    => escape_text(t.text(), writer),
+        Text(t)                      => match t.source().markup {
+            Some(markup) => writer.write_str(markup.as_slice()),
+            None => writer.write_str(t.text().as_slice()),
+        },
+        RawText(t)                   => writer.write_str(t.text().as_slice()),
+        Comment(c)                   => format_comment(method, c, writer),
+        ProcessingInstruction(p)     => format_processing_instruction(method, p, writer),
     }
 }
 
-fn format_body<W : Writer>(element: dom4::Element, writer: &mut W) -> IoResult<()> {
-    let mut todo = vec![Element(element)];
+fn format_body<W : Writer>(method: Method, element: dom4::Element, writer: &mut W) -> IoResult<()> {
+    format_todo(method, vec![Element(element)], writer)
+}
 
+fn format_todo<'d, W : Writer>(method: Method, mut todo: Vec<Content<'d>>, writer: &mut W) -> IoResult<()> {
     while ! todo.is_empty() {
-        try!(format_one(todo.pop().unwrap(), &mut todo, writer));
+        try!(format_one(method, todo.pop().unwrap(), &mut todo, writer));
     }
 
     Ok(())
 }
 
+/// Formats a node and everything beneath it into a Writer
+pub fn format_node<'d, N : dom4::ToChildOfElement<'d>, W : Writer>(node: N, writer: &mut W) -> IoResult<()> {
+    format_todo(Xml, vec![to_content(node.to_child_of_element(), false)], writer)
+}
+
+/// Formats the children of an element, but not the element itself,
+/// into a Writer
+pub fn format_children<'d, W : Writer>(element: dom4::Element<'d>, writer: &mut W) -> IoResult<()> {
+    let mut todo = Vec::new();
+    push_children(element, &mut todo, false);
+    format_todo(Xml, todo, writer)
+}
+
+fn format_to_string(f: |&mut MemWriter| -> IoResult<()>) -> String {
+    let mut w = MemWriter::new();
+    f(&mut w).ok().expect("Writing to memory failed");
+    String::from_utf8(w.unwrap()).ok().expect("Formatted XML is not UTF-8")
+}
+
+/// Formats a node and everything beneath it into a String
+pub fn node_to_string<'d, N : dom4::ToChildOfElement<'d>>(node: N) -> String {
+    let node = node.to_child_of_element();
+    format_to_string(|w| format_node(node, w))
+}
+
+/// Formats the children of an element, but not the element itself,
+/// into a String
+pub fn children_to_string<'d>(element: dom4::Element<'d>) -> String {
+    format_to_string(|w| format_children(element, w))
+}
+
 /// Formats a document into a Writer
 pub fn format_document<'d, W : Writer>(doc: &'d dom4::Document<'d>, writer: &mut W) -> IoResult<()> {
-    try!(writer.write_str("<?xml version='1.0'?>"));
+    let root = doc.root();
+    let source = root.source();
+
+    match source.markup {
+        Some(ref declaration) => try!(writer.write_str(declaration.as_slice())),
+        None => try!(writer.write_str("<?xml version='1.0'?>")),
+    }
+
+    let children = root.children();
+    let count = children.len();
+
+    // The declaration goes back where it was parsed from, but always
+    // before the document element
+    let doctype = root.doctype().map(|d| {
+        let element_at = children.iter().position(|c| c.element().is_some()).unwrap_or(count);
+        (d, cmp::min(d.source().position.unwrap_or(0), element_at))
+    });
+
+    for (i, child) in children.into_iter().enumerate() {
+        if let Some((d, at)) = doctype {
+            if at == i { try!(format_document_type(d, writer)) }
+        }
+        try!(format_root_child(Xml, child, writer));
+    }
+
+    if let Some((d, at)) = doctype {
+        if at == count { try!(format_document_type(d, writer)) }
+    }
+
+    match source.after {
+        Some(ref after) => writer.write_str(after.as_slice()),
+        None => Ok(()),
+    }
+}
+
+/// Formats a document into a Writer as HTML, optionally preceded by
+/// a doctype such as `Some("html")`
+pub fn format_html_document<'d, W : Writer>(doc: &'d dom4::Document<'d>, doctype: Option<&str>, writer: &mut W) -> IoResult<()> {
+    if let Some(doctype) = doctype {
+        try!(write!(writer, "<!DOCTYPE {}>", doctype));
+    }
 
+    format_root_children(Html, doc, writer)
+}
+
+fn format_root_children<'d, W : Writer>(method: Method, doc: &'d dom4::Document<'d>, writer: &mut W) -> IoResult<()> {
     for child in doc.root().children().into_iter() {
-        try!(match child {
-            ElementCOR(e) => format_body(e, writer),
-            CommentCOR(c) => format_comment(c, writer),
-            ProcessingInstructionCOR(p)      => format_processing_instruction(p, writer),
-        })
+        try!(format_root_child(method, child, writer));
     }
 
     Ok(())
 }
 
+fn format_root_child<'d, W : Writer>(method: Method, child: dom4::ChildOfRoot<'d>, writer: &mut W) -> IoResult<()> {
+    if method == Xml {
+        let before = match child {
+            ElementCOR(e)               => e.source().before,
+            CommentCOR(c)               => c.source().before,
+            ProcessingInstructionCOR(p) => p.source().before,
+        };
+        if let Some(before) = before {
+            try!(writer.write_str(before.as_slice()));
+        }
+    }
+
+    match child {
+        ElementCOR(e)               => format_body(method, e, writer),
+        CommentCOR(c)               => format_comment(method, c, writer),
+        ProcessingInstructionCOR(p) => format_processing_instruction(method, p, writer),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::io::MemWriter;
 
     use super::super::Package;
     use super::super::dom4;
-    use super::format_document;
+    use super::super::parser::Parser;
+    use super::{format_document,format_html_document};
+    use super::{format_node,node_to_string,children_to_string};
 
     macro_rules! assert_str_eq(
         ($l:expr, $r:expr) => (assert_eq!($l.as_slice(), $r.as_slice()));
@@ -132,6 +460,12 @@ mod test {
         String::from_utf8(w.unwrap()).ok().expect("Not a string")
     }
 
+    fn format_html<'d>(doc: &'d dom4::Document<'d>, doctype: Option<&str>) -> String {
+        let mut w = MemWriter::new();
+        format_html_document(doc, doctype, &mut w).ok().expect("Not formatted");
+        String::from_utf8(w.unwrap()).ok().expect("Not a string")
+    }
+
     #[test]
     fn top_element() {
         let p = Package::new();
@@ -241,4 +575,278 @@ mod test {
         let xml = format_xml(&d);
         assert_str_eq!(xml, "<?xml version='1.0'?><?display?>");
     }
+
+    #[test]
+    fn doctype() {
+        let p = Package::new();
+        let d = p.as_document();
+        let doctype = d.create_document_type("hello", None, Some("hello.dtd"), Some("<!ELEMENT hello EMPTY>"));
+        d.root().set_doctype(Some(doctype));
+        let e = d.create_element("hello");
+        d.root().append_child(e);
+
+        let xml = format_xml(&d);
+        assert_str_eq!(xml, "<?xml version='1.0'?><!DOCTYPE hello SYSTEM 'hello.dtd' [<!ELEMENT hello EMPTY>]><hello/>");
+    }
+
+    #[test]
+    fn public_doctype() {
+        let p = Package::new();
+        let d = p.as_document();
+        let doctype = d.create_document_type("hello", Some("-//Hello//EN"), Some("it's.dtd"), None);
+        d.root().set_doctype(Some(doctype));
+        let e = d.create_element("hello");
+        d.root().append_child(e);
+
+        let xml = format_xml(&d);
+        assert_str_eq!(xml, "<?xml version='1.0'?><!DOCTYPE hello PUBLIC '-//Hello//EN' \"it's.dtd\"><hello/>");
+    }
+
+    static LOSSLESS_INPUT: &'static str = r#"<?xml version="1.0" ?>
+<!-- head -->
+<!DOCTYPE root [<!ELEMENT root ANY>]>
+<root  a = "1" b='2' >
+  <empty/>
+  <pair ></pair >
+  &#169; &amp; <![CDATA[<raw>]]>
+  <?pi   spaced?>
+</root >
+<!-- tail -->
+"#;
+
+    fn lossless(xml: &str) -> Package {
+        Parser::new().parse_lossless(xml).ok().expect("Failed to parse")
+    }
+
+    fn root_element<'d>(d: &'d dom4::Document<'d>) -> dom4::Element<'d> {
+        d.root().children().iter().filter_map(|c| c.element()).next().unwrap()
+    }
+
+    fn child_named<'d>(e: dom4::Element<'d>, name: &str) -> dom4::Element<'d> {
+        e.children().iter().filter_map(|c| c.element()).find(|c| c.name() == name).unwrap()
+    }
+
+    #[test]
+    fn lossless_documents_are_reproduced_exactly() {
+        let p = lossless(LOSSLESS_INPUT);
+        let d = p.as_document();
+
+        assert_str_eq!(format_xml(&d), LOSSLESS_INPUT);
+    }
+
+    #[test]
+    fn lossless_documents_without_a_declaration() {
+        let p = lossless("<a/>");
+        let d = p.as_document();
+
+        assert_str_eq!(format_xml(&d), "<a/>");
+    }
+
+    #[test]
+    fn changed_attributes_keep_their_quotes_and_place() {
+        let p = lossless(LOSSLESS_INPUT);
+        let d = p.as_document();
+        let root = root_element(&d);
+
+        root.set_attribute_value("a", "x<y");
+        root.set_attribute_value("c", "3");
+
+        let xml = format_xml(&d);
+        assert!(xml.as_slice().contains(r#"<root  a = "x&lt;y" b='2' c='3' >"#));
+    }
+
+    #[test]
+    fn changed_text_loses_its_markup() {
+        let p = lossless("<a>&#169;</a>");
+        let d = p.as_document();
+        let text = root_element(&d).children()[0].text().unwrap();
+
+        text.set_text("(c)");
+
+        assert_str_eq!(format_xml(&d), "<a>(c)</a>");
+    }
+
+    #[test]
+    fn renamed_elements_keep_their_spacing() {
+        let p = lossless("<a><pair  x='1' ></pair ></a>");
+        let d = p.as_document();
+        let pair = child_named(root_element(&d), "pair");
+
+        pair.set_name("couple");
+
+        assert_str_eq!(format_xml(&d), "<a><couple  x='1' ></couple></a>");
+    }
+
+    #[test]
+    fn children_can_be_added_to_empty_element_tags() {
+        let p = lossless("<a><empty /></a>");
+        let d = p.as_document();
+        let empty = child_named(root_element(&d), "empty");
+
+        empty.append_child(d.create_element("new"));
+
+        assert_str_eq!(format_xml(&d), "<a><empty ><new/></empty></a>");
+    }
+
+    #[test]
+    fn removing_all_children_keeps_the_end_tag() {
+        let p = lossless("<a><b>text</b></a>");
+        let d = p.as_document();
+        let b = child_named(root_element(&d), "b");
+
+        b.set_text_content("");
+
+        assert_str_eq!(format_xml(&d), "<a><b></b></a>");
+    }
+
+    #[test]
+    fn document_type_stays_after_comments_by_default() {
+        let p = Parser::new().parse("<!-- c --><!DOCTYPE a><a/>").ok().expect("Failed to parse");
+        let d = p.as_document();
+
+        assert_str_eq!(format_xml(&d), "<?xml version='1.0'?><!-- c --><!DOCTYPE a><a/>");
+    }
+
+    #[test]
+    fn markup_is_not_recorded_by_default() {
+        let p = Parser::new().parse("<a  b=\"1\" >&#169;</a>\n").ok().expect("Failed to parse");
+        let d = p.as_document();
+
+        assert_str_eq!(format_xml(&d), "<?xml version='1.0'?><a b='1'>\u00a9</a>");
+    }
+
+    #[test]
+    fn a_nested_element_alone() {
+        let p = Package::new();
+        let d = p.as_document();
+        let records = d.create_element("records");
+        let record = d.create_element("record");
+        record.set_attribute_value("id", "1");
+        record.append_child(d.create_text("Earth"));
+        record.append_child(d.create_comment("home"));
+        records.append_child(record);
+        records.append_child(d.create_element("record"));
+        d.root().append_child(records);
+
+        let xml = node_to_string(record);
+        assert_str_eq!(xml, "<record id='1'>Earth<!--home--></record>");
+    }
+
+    #[test]
+    fn an_element_into_a_writer() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+
+        let mut w = MemWriter::new();
+        format_node(hello, &mut w).ok().expect("Not formatted");
+        assert_eq!(w.unwrap(), b"<hello/>".to_vec());
+    }
+
+    #[test]
+    fn text_comments_and_processing_instructions_alone() {
+        let p = Package::new();
+        let d = p.as_document();
+
+        assert_str_eq!(node_to_string(d.create_text("hello")), "hello");
+        assert_str_eq!(node_to_string(d.create_comment("hello")), "<!--hello-->");
+        assert_str_eq!(node_to_string(d.create_processing_instruction("hello", Some("world"))), "<?hello world?>");
+    }
+
+    #[test]
+    fn the_children_of_an_element() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+        hello.append_child(d.create_text("one"));
+        hello.append_child(d.create_element("two"));
+        hello.append_child(d.create_processing_instruction("three", None));
+
+        let xml = children_to_string(hello);
+        assert_str_eq!(xml, "one<two/><?three?>");
+    }
+
+    #[test]
+    fn the_children_of_an_empty_element() {
+        let p = Package::new();
+        let d = p.as_document();
+        let hello = d.create_element("hello");
+
+        assert_str_eq!(children_to_string(hello), "");
+    }
+
+    #[test]
+    fn html_void_elements_have_no_end_tag() {
+        let p = Package::new();
+        let d = p.as_document();
+        let p_elem = d.create_element("p");
+        let br = d.create_element("br");
+        p_elem.append_child(br);
+        d.root().append_child(p_elem);
+
+        let html = format_html(&d, None);
+        assert_str_eq!(html, "<p><br></p>");
+    }
+
+    #[test]
+    fn html_empty_elements_have_an_end_tag() {
+        let p = Package::new();
+        let d = p.as_document();
+        let script = d.create_element("script");
+        script.set_attribute_value("src", "app.js");
+        d.root().append_child(script);
+
+        let html = format_html(&d, None);
+        assert_str_eq!(html, "<script src=\"app.js\"></script>");
+    }
+
+    #[test]
+    fn html_script_content_is_not_escaped() {
+        let p = Package::new();
+        let d = p.as_document();
+        let script = d.create_element("script");
+        let text = d.create_text("if (a < b && c) {}");
+        script.append_child(text);
+        d.root().append_child(script);
+
+        let html = format_html(&d, None);
+        assert_str_eq!(html, "<script>if (a < b && c) {}</script>");
+    }
+
+    #[test]
+    fn html_text_is_escaped() {
+        let p = Package::new();
+        let d = p.as_document();
+        let para = d.create_element("p");
+        let text = d.create_text("Fish & chips < 5");
+        para.append_child(text);
+        para.set_attribute_value("title", "\"Fish\" & chips");
+        d.root().append_child(para);
+
+        let html = format_html(&d, None);
+        assert_str_eq!(html, "<p title=\"&quot;Fish&quot; &amp; chips\">Fish &amp; chips &lt; 5</p>");
+    }
+
+    #[test]
+    fn html_boolean_attributes_are_minimized() {
+        let p = Package::new();
+        let d = p.as_document();
+        let input = d.create_element("input");
+        input.set_attribute_value("checked", "checked");
+        d.root().append_child(input);
+
+        let html = format_html(&d, None);
+        assert_str_eq!(html, "<input checked>");
+    }
+
+    #[test]
+    fn html_doctype() {
+        let p = Package::new();
+        let d = p.as_document();
+        let root = d.create_element("html");
+        d.root().append_child(root);
+
+        let html = format_html(&d, Some("html"));
+        assert_str_eq!(html, "<!DOCTYPE html><html></html>");
+    }
 }
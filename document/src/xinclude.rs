@@ -0,0 +1,712 @@
+//! Processes XInclude 1.0 inclusions
+//!
+//! Each `xi:include` element is replaced by the resource its `href`
+//! refers to: the document element and its siblings when `parse` is
+//! `xml` (the default), or a single text node when `parse` is `text`.
+//! Included XML is processed for inclusions of its own before it is
+//! copied in.
+//!
+//! The `xpointer` attribute selects a single element from included
+//! XML. It may be a shorthand pointer, which is matched against `id`
+//! and `xml:id` attributes, or one or more `element()` schemes such as
+//! `element(intro)`, `element(/1/3)` or `element(intro/2)`, which are
+//! tried in order until one selects an element.
+//!
+//! When a resource cannot be read, parsed or pointed into, the
+//! children of the `xi:fallback` element are used instead. Without a
+//! fallback, the error is returned.
+//!
+//! ### Example
+//!
+//! ```no_run
+//! use document::xinclude;
+//!
+//! let package = xinclude::parse_file(&Path::new("book.xml")).ok().expect("Failed to include");
+//! let doc = package.as_document();
+//! ```
+//!
+//! Resources are found by a `Resolver`. `FileResolver` reads files
+//! relative to the including resource; other sources can be used by
+//! implementing the trait and calling `process`.
+//!
+//! ### Known issues
+//!
+//! - Same-document references, where `href` is empty, are not
+//!   supported.
+//! - `xml:base` and `xml:lang` are not fixed up on included elements.
+//! - The `encoding` attribute is ignored; text resources are whatever
+//!   the resolver returns.
+//! - IDs are only found through `id` and `xml:id` attributes, not
+//!   through attributes a DTD declares to be IDs.
+
+use std::io::{File,IoResult,IoError};
+
+use super::Package;
+use super::dom4;
+use super::dom4::{ElementCOR,CommentCOR,ProcessingInstructionCOR};
+use super::dom4::{ElementCOE,TextCOE,CommentCOE,ProcessingInstructionCOE};
+use super::dom4::{ElementPOC,ToChildOfElement};
+use super::parser::{Parser,ParseError};
+use super::xmlstr::{XmlChar,prefix_of,local_name,is_namespace_declaration};
+
+static NAMESPACE: &'static str = "http://www.w3.org/2001/XInclude";
+
+/// Why inclusion failed. The location is the `href` or resolved
+/// location of the resource involved.
+#[deriving(Show,Clone,PartialEq)]
+pub enum Error {
+    /// The resource could not be retrieved
+    ResourceError(String, IoError),
+    ParseFailure(String, ParseError),
+    /// The resource includes itself, directly or indirectly
+    InclusionLoop(String),
+    /// The `xpointer` selects nothing in the resource
+    UnresolvedPointer(String, String),
+    /// An `xi:include` element has missing or invalid attributes, or
+    /// its replacement cannot be placed where it is
+    InvalidInclude(String),
+}
+
+/// The contents of a resource and where it was found. The location
+/// is passed back to the resolver as the base of the resource's own
+/// inclusions, and is used to detect inclusion loops.
+pub struct Resource {
+    pub location: String,
+    pub content: String,
+}
+
+pub trait Resolver {
+    /// Retrieves the resource that `href` refers to from within the
+    /// resource at `base`
+    fn resolve(&mut self, base: &str, href: &str) -> IoResult<Resource>;
+}
+
+/// Reads resources from the filesystem, relative to the directory of
+/// the including resource
+pub struct FileResolver;
+
+impl Resolver for FileResolver {
+    fn resolve(&mut self, base: &str, href: &str) -> IoResult<Resource> {
+        let path = Path::new(base).dir_path().join(href);
+        let content = try!(File::open(&path).read_to_string());
+        Ok(Resource {
+            location: path.display().to_string(),
+            content: content,
+        })
+    }
+}
+
+/// Replaces every `xi:include` element in the document. The location
+/// is where the document came from; relative references are resolved
+/// against it.
+pub fn process<'d, R: Resolver>(doc: &'d dom4::Document<'d>, location: &str, resolver: &mut R)
+                                -> Result<(), Error>
+{
+    let mut stack = vec![location.to_string()];
+    process_document(doc, location, resolver, &mut stack)
+}
+
+/// Parses the file and processes its inclusions from the filesystem
+pub fn parse_file(path: &Path) -> Result<Package, Error> {
+    let location = path.display().to_string();
+
+    let xml = match File::open(path).read_to_string() {
+        Ok(xml) => xml,
+        Err(e) => return Err(ResourceError(location, e)),
+    };
+
+    let package = match Parser::new().parse(xml.as_slice()) {
+        Ok(package) => package,
+        Err(e) => return Err(ParseFailure(location, e)),
+    };
+
+    {
+        let doc = package.as_document();
+        try!(process(&doc, location.as_slice(), &mut FileResolver));
+    }
+
+    Ok(package)
+}
+
+fn process_document<'d, R: Resolver>(doc: &'d dom4::Document<'d>, base: &str,
+                                     resolver: &mut R, stack: &mut Vec<String>)
+                                     -> Result<(), Error>
+{
+    let root = doc.root();
+
+    for child in root.children().into_iter() {
+        let element = match child.element() {
+            Some(element) => element,
+            None => continue,
+        };
+
+        if ! is_xinclude(element, "include") {
+            try!(process_element(doc, element, base, resolver, stack));
+            continue;
+        }
+
+        let nodes = try!(resolve_include(doc, element, base, resolver, stack));
+        let nodes = try!(to_root_children(nodes));
+
+        let mut index = position(root.children(), child);
+        root.remove_child(element);
+        for node in nodes.into_iter() {
+            root.insert_child(index, node);
+            index += 1;
+        }
+    }
+
+    Ok(())
+}
+
+fn process_element<'d, R: Resolver>(doc: &'d dom4::Document<'d>, element: dom4::Element<'d>, base: &str,
+                                    resolver: &mut R, stack: &mut Vec<String>)
+                                    -> Result<(), Error>
+{
+    for child in element.children().into_iter() {
+        let child = match child.element() {
+            Some(child) => child,
+            None => continue,
+        };
+
+        if ! is_xinclude(child, "include") {
+            try!(process_element(doc, child, base, resolver, stack));
+            continue;
+        }
+
+        let nodes = try!(resolve_include(doc, child, base, resolver, stack));
+
+        let mut index = position(element.children(), ElementCOE(child));
+        element.remove_child(child);
+        for node in nodes.into_iter() {
+            element.insert_child(index, node);
+            index += 1;
+        }
+    }
+
+    Ok(())
+}
+
+fn position<T: PartialEq>(items: Vec<T>, item: T) -> uint {
+    items.iter().position(|i| *i == item).unwrap_or(items.len())
+}
+
+/// The document element may only be replaced by exactly one element,
+/// along with any comments and processing instructions
+fn to_root_children<'d>(nodes: Vec<dom4::ChildOfElement<'d>>) -> Result<Vec<dom4::ChildOfRoot<'d>>, Error> {
+    let mut children = Vec::new();
+    let mut elements = 0u;
+
+    for node in nodes.into_iter() {
+        let child = match node {
+            ElementCOE(n) => { elements += 1; ElementCOR(n) },
+            CommentCOE(n) => CommentCOR(n),
+            ProcessingInstructionCOE(n) => ProcessingInstructionCOR(n),
+            TextCOE(n) => {
+                if n.text().chars().all(|c| c.is_space_char()) { continue }
+                return Err(InvalidInclude("text cannot replace the document element".to_string()));
+            },
+        };
+        children.push(child);
+    }
+
+    if elements != 1 {
+        return Err(InvalidInclude("the document element must be replaced by exactly one element".to_string()));
+    }
+
+    Ok(children)
+}
+
+/// The nodes that replace the `xi:include` element, which are either
+/// copied from the resource or taken from the fallback
+fn resolve_include<'d, R: Resolver>(doc: &'d dom4::Document<'d>, include: dom4::Element<'d>, base: &str,
+                                    resolver: &mut R, stack: &mut Vec<String>)
+                                    -> Result<Vec<dom4::ChildOfElement<'d>>, Error>
+{
+    let href = include.attribute_value("href").unwrap_or("");
+    let xpointer = include.attribute_value("xpointer");
+    let parse = include.attribute_value("parse").unwrap_or("xml");
+
+    if href.is_empty() {
+        return Err(InvalidInclude("same-document references are not supported".to_string()));
+    }
+    if href.contains_char('#') {
+        return Err(InvalidInclude(format!("href {} has a fragment identifier", href)));
+    }
+
+    let is_xml = match parse {
+        "xml" => true,
+        "text" => false,
+        _ => return Err(InvalidInclude(format!("unknown parse value {}", parse))),
+    };
+    if ! is_xml && xpointer.is_some() {
+        return Err(InvalidInclude("xpointer cannot be used with parse=\"text\"".to_string()));
+    }
+
+    let resource = match resolver.resolve(base, href) {
+        Ok(resource) => resource,
+        Err(e) => return fallback(doc, include, ResourceError(href.to_string(), e), base, resolver, stack),
+    };
+    let location = resource.location;
+
+    if ! is_xml {
+        return Ok(vec![TextCOE(doc.create_text(resource.content.as_slice()))]);
+    }
+
+    if stack.contains(&location) {
+        return Err(InclusionLoop(location));
+    }
+
+    let package = match Parser::new().parse(resource.content.as_slice()) {
+        Ok(package) => package,
+        Err(e) => return fallback(doc, include, ParseFailure(location, e), base, resolver, stack),
+    };
+    let included = package.as_document();
+
+    stack.push(location.clone());
+    let result = process_document(&included, location.as_slice(), resolver, stack);
+    stack.pop();
+    try!(result);
+
+    match xpointer {
+        None => {
+            let children = included.root().children();
+            Ok(children.into_iter().map(|c| copy_child(doc, c.to_child_of_element())).collect())
+        },
+        Some(pointer) => match select(&included, pointer) {
+            Some(element) => {
+                let copy = copy_element(doc, element);
+                copy_namespaces(element, copy);
+                Ok(vec![ElementCOE(copy)])
+            },
+            None => {
+                let error = UnresolvedPointer(location, pointer.to_string());
+                fallback(doc, include, error, base, resolver, stack)
+            },
+        },
+    }
+}
+
+fn fallback<'d, R: Resolver>(doc: &'d dom4::Document<'d>, include: dom4::Element<'d>, error: Error, base: &str,
+                             resolver: &mut R, stack: &mut Vec<String>)
+                             -> Result<Vec<dom4::ChildOfElement<'d>>, Error>
+{
+    let fallback = include.children().into_iter()
+        .filter_map(|c| c.element())
+        .find(|e| is_xinclude(*e, "fallback"));
+
+    match fallback {
+        Some(fallback) => {
+            try!(process_element(doc, fallback, base, resolver, stack));
+            Ok(fallback.children())
+        },
+        None => Err(error),
+    }
+}
+
+fn is_xinclude(element: dom4::Element, local: &str) -> bool {
+    let name = element.name();
+    local_name(name) == local && namespace_uri(element, prefix_of(name)) == Some(NAMESPACE)
+}
+
+fn namespace_uri<'d>(element: dom4::Element<'d>, prefix: Option<&str>) -> Option<&'d str> {
+    let declaration = match prefix {
+        Some(prefix) => format!("xmlns:{}", prefix),
+        None => "xmlns".to_string(),
+    };
+
+    let mut current = element;
+    loop {
+        if let Some(uri) = current.attribute_value(declaration.as_slice()) {
+            return Some(uri);
+        }
+        match current.parent() {
+            Some(ElementPOC(parent)) => current = parent,
+            _ => return None,
+        }
+    }
+}
+
+/// Evaluates a shorthand pointer or a sequence of `element()` schemes
+fn select<'d>(doc: &'d dom4::Document<'d>, pointer: &str) -> Option<dom4::Element<'d>> {
+    let pointer = pointer.trim();
+
+    if ! pointer.contains_char('(') {
+        return find_by_id(doc, pointer);
+    }
+
+    let parts = match scheme_parts(pointer) {
+        Some(parts) => parts,
+        None => return None,
+    };
+
+    for &(ref scheme, ref data) in parts.iter() {
+        if scheme.as_slice() != "element" { continue }
+        if let Some(element) = select_element(doc, data.as_slice()) {
+            return Some(element);
+        }
+    }
+
+    None
+}
+
+/// Splits `a(x) b(y)` into its schemes and their unescaped data
+fn scheme_parts(pointer: &str) -> Option<Vec<(String, String)>> {
+    let mut parts = Vec::new();
+    let mut chars = pointer.chars().peekable();
+
+    loop {
+        while chars.peek().map_or(false, |c| c.is_space_char()) { chars.next(); }
+        if chars.peek().is_none() { break }
+
+        let mut scheme = String::new();
+        loop {
+            match chars.next() {
+                Some('(') => break,
+                Some(c) => scheme.push(c),
+                None => return None,
+            }
+        }
+
+        let mut data = String::new();
+        let mut depth = 0u;
+        loop {
+            match chars.next() {
+                Some('^') => match chars.next() {
+                    Some(c) if c == '(' || c == ')' || c == '^' => data.push(c),
+                    _ => return None,
+                },
+                Some('(') => { depth += 1; data.push('(') },
+                Some(')') if depth == 0 => break,
+                Some(')') => { depth -= 1; data.push(')') },
+                Some(c) => data.push(c),
+                None => return None,
+            }
+        }
+
+        parts.push((scheme.as_slice().trim().to_string(), data));
+    }
+
+    Some(parts)
+}
+
+/// Evaluates the data of an `element()` scheme: an optional ID
+/// followed by `/`-separated child element positions, counted from 1
+fn select_element<'d>(doc: &'d dom4::Document<'d>, data: &str) -> Option<dom4::Element<'d>> {
+    let mut steps = data.split('/');
+    let id = steps.next().unwrap_or("");
+
+    let mut current = if id.is_empty() {
+        None
+    } else {
+        match find_by_id(doc, id) {
+            Some(element) => Some(element),
+            None => return None,
+        }
+    };
+
+    for step in steps {
+        let index = match from_str::<uint>(step) {
+            Some(n) if n > 0 => n - 1,
+            _ => return None,
+        };
+
+        let next = match current {
+            Some(element) => element.children().into_iter().filter_map(|c| c.element()).nth(index),
+            None => doc.root().children().into_iter().filter_map(|c| c.element()).nth(index),
+        };
+        if next.is_none() { return None }
+        current = next;
+    }
+
+    current
+}
+
+fn find_by_id<'d>(doc: &'d dom4::Document<'d>, id: &str) -> Option<dom4::Element<'d>> {
+    for child in doc.root().children().into_iter() {
+        if let Some(element) = child.element() {
+            if let Some(found) = find_in_element(element, id) {
+                return Some(found);
+            }
+        }
+    }
+    None
+}
+
+fn find_in_element<'d>(element: dom4::Element<'d>, id: &str) -> Option<dom4::Element<'d>> {
+    if element.attribute_value("xml:id") == Some(id) || element.attribute_value("id") == Some(id) {
+        return Some(element);
+    }
+
+    for child in element.children().into_iter() {
+        if let Some(child) = child.element() {
+            if let Some(found) = find_in_element(child, id) {
+                return Some(found);
+            }
+        }
+    }
+    None
+}
+
+fn copy_child<'s, 'd>(doc: &'d dom4::Document<'d>, node: dom4::ChildOfElement<'s>) -> dom4::ChildOfElement<'d> {
+    match node {
+        ElementCOE(n) => ElementCOE(copy_element(doc, n)),
+        TextCOE(n) => TextCOE(doc.create_text(n.text())),
+        CommentCOE(n) => CommentCOE(doc.create_comment(n.text())),
+        ProcessingInstructionCOE(n) =>
+            ProcessingInstructionCOE(doc.create_processing_instruction(n.target(), n.value())),
+    }
+}
+
+fn copy_element<'s, 'd>(doc: &'d dom4::Document<'d>, element: dom4::Element<'s>) -> dom4::Element<'d> {
+    let copy = doc.create_element(element.name());
+
+    for attr in element.attributes().iter() {
+        copy.set_attribute_value(attr.name(), attr.value());
+    }
+    for child in element.children().into_iter() {
+        copy.append_child(copy_child(doc, child));
+    }
+
+    copy
+}
+
+/// Declares the namespaces that were in scope for an element taken
+/// from inside its document on the copy, so prefixes keep meaning
+fn copy_namespaces<'s, 'd>(element: dom4::Element<'s>, copy: dom4::Element<'d>) {
+    let mut parent = element.parent();
+
+    loop {
+        let ancestor = match parent {
+            Some(ElementPOC(ancestor)) => ancestor,
+            _ => break,
+        };
+
+        for attr in ancestor.attributes().iter() {
+            let name = attr.name();
+            if is_namespace_declaration(name) && copy.attribute_value(name).is_none() {
+                copy.set_attribute_value(name, attr.value());
+            }
+        }
+
+        parent = ancestor.parent();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::{File,TempDir,USER_RWX,IoResult,MemWriter,FileNotFound,standard_error};
+    use std::io::fs::mkdir;
+
+    use super::super::{Package,dom4,writer};
+    use super::super::parser::Parser;
+    use super::{Resolver,Resource,Error,process,parse_file};
+    use super::{ResourceError,InclusionLoop,UnresolvedPointer,InvalidInclude};
+
+    macro_rules! assert_str_eq(
+        ($l:expr, $r:expr) => (assert_eq!($l.as_slice(), $r.as_slice()));
+    )
+
+    struct MemoryResolver {
+        resources: HashMap<String, String>,
+    }
+
+    impl MemoryResolver {
+        fn new(resources: &[(&str, &str)]) -> MemoryResolver {
+            let mut map = HashMap::new();
+            for &(name, content) in resources.iter() {
+                map.insert(name.to_string(), content.to_string());
+            }
+            MemoryResolver { resources: map }
+        }
+    }
+
+    impl Resolver for MemoryResolver {
+        fn resolve(&mut self, _base: &str, href: &str) -> IoResult<Resource> {
+            match self.resources.get(href) {
+                Some(content) => Ok(Resource { location: href.to_string(), content: content.clone() }),
+                None => Err(standard_error(FileNotFound)),
+            }
+        }
+    }
+
+    fn format_xml<'d>(doc: &'d dom4::Document<'d>) -> String {
+        let mut w = MemWriter::new();
+        writer::format_document(doc, &mut w).ok().expect("Not formatted");
+        let xml = String::from_utf8(w.unwrap()).ok().expect("Not a string");
+        xml.as_slice().slice_from("<?xml version='1.0'?>".len()).to_string()
+    }
+
+    fn include(xml: &str, resources: &[(&str, &str)]) -> Result<String, Error> {
+        let package = Parser::new().parse(xml).ok().expect("Failed to parse");
+        let doc = package.as_document();
+        try!(process(&doc, "main.xml", &mut MemoryResolver::new(resources)));
+        Ok(format_xml(&doc))
+    }
+
+    static XI: &'static str = r#"xmlns:xi="http://www.w3.org/2001/XInclude""#;
+
+    #[test]
+    fn includes_xml() {
+        let xml = format!(r#"<book {}><xi:include href="ch1.xml"/></book>"#, XI);
+        let result = include(xml.as_slice(), [("ch1.xml", "<!--one--><chapter>One</chapter>")]);
+
+        assert_str_eq!(result.unwrap(), "<book xmlns:xi='http://www.w3.org/2001/XInclude'><!--one--><chapter>One</chapter></book>");
+    }
+
+    #[test]
+    fn includes_text() {
+        let xml = format!(r#"<pre {}>a<xi:include href="code.txt" parse="text"/>b</pre>"#, XI);
+        let result = include(xml.as_slice(), [("code.txt", "x < y")]);
+
+        assert_str_eq!(result.unwrap(), "<pre xmlns:xi='http://www.w3.org/2001/XInclude'>ax &lt; yb</pre>");
+    }
+
+    #[test]
+    fn ignores_elements_in_other_namespaces() {
+        let xml = r#"<book xmlns:xi="urn:other"><xi:include href="ch1.xml"/></book>"#;
+        let result = include(xml, []);
+
+        assert_str_eq!(result.unwrap(), "<book xmlns:xi='urn:other'><xi:include href='ch1.xml'/></book>");
+    }
+
+    #[test]
+    fn includes_are_processed_recursively() {
+        let xml = format!(r#"<book {}><xi:include href="part.xml"/></book>"#, XI);
+        let part = format!(r#"<part {}><xi:include href="ch1.xml"/></part>"#, XI);
+        let result = include(xml.as_slice(), [("part.xml", part.as_slice()), ("ch1.xml", "<chapter/>")]);
+
+        assert_str_eq!(result.unwrap(), "<book xmlns:xi='http://www.w3.org/2001/XInclude'><part xmlns:xi='http://www.w3.org/2001/XInclude'><chapter/></part></book>");
+    }
+
+    #[test]
+    fn document_element_can_be_replaced() {
+        let xml = format!(r#"<xi:include {} href="ch1.xml"/>"#, XI);
+        let result = include(xml.as_slice(), [("ch1.xml", "<chapter/>")]);
+
+        assert_str_eq!(result.unwrap(), "<chapter/>");
+    }
+
+    #[test]
+    fn document_element_cannot_be_replaced_by_text() {
+        let xml = format!(r#"<xi:include {} href="a.txt" parse="text"/>"#, XI);
+        match include(xml.as_slice(), [("a.txt", "words")]) {
+            Err(InvalidInclude(..)) => {},
+            other => panic!("Expected an invalid include, got {}", other),
+        }
+    }
+
+    #[test]
+    fn xpointer_selects_by_id() {
+        let xml = format!(r#"<book {}><xi:include href="ch.xml" xpointer="two"/></book>"#, XI);
+        let result = include(xml.as_slice(), [("ch.xml", r#"<all><ch id="one"/><ch xml:id="two"/></all>"#)]);
+
+        assert_str_eq!(result.unwrap(), "<book xmlns:xi='http://www.w3.org/2001/XInclude'><ch xml:id='two'/></book>");
+    }
+
+    #[test]
+    fn xpointer_element_scheme_selects_by_position() {
+        let chapters = r#"<all><ch n="1"/><sec id="s"><p n="1"/><p n="2"/></sec></all>"#;
+
+        let xml = format!(r#"<book {}><xi:include href="ch.xml" xpointer="element(/1/2/2)"/></book>"#, XI);
+        let result = include(xml.as_slice(), [("ch.xml", chapters)]);
+        assert_str_eq!(result.unwrap(), "<book xmlns:xi='http://www.w3.org/2001/XInclude'><p n='2'/></book>");
+
+        let xml = format!(r#"<book {}><xi:include href="ch.xml" xpointer="element(s/1)"/></book>"#, XI);
+        let result = include(xml.as_slice(), [("ch.xml", chapters)]);
+        assert_str_eq!(result.unwrap(), "<book xmlns:xi='http://www.w3.org/2001/XInclude'><p n='1'/></book>");
+    }
+
+    #[test]
+    fn xpointer_tries_each_scheme_in_turn() {
+        let xml = format!(r#"<book {}><xi:include href="ch.xml" xpointer="element(missing) element(/1)"/></book>"#, XI);
+        let result = include(xml.as_slice(), [("ch.xml", "<all/>")]);
+
+        assert_str_eq!(result.unwrap(), "<book xmlns:xi='http://www.w3.org/2001/XInclude'><all/></book>");
+    }
+
+    #[test]
+    fn selected_elements_keep_their_namespaces() {
+        let xml = format!(r#"<book {}><xi:include href="ch.xml" xpointer="element(/1/1)"/></book>"#, XI);
+        let result = include(xml.as_slice(), [("ch.xml", r#"<all xmlns:m="urn:m"><m:ch/></all>"#)]);
+
+        assert_str_eq!(result.unwrap(), "<book xmlns:xi='http://www.w3.org/2001/XInclude'><m:ch xmlns:m='urn:m'/></book>");
+    }
+
+    #[test]
+    fn unresolved_pointer_is_an_error() {
+        let xml = format!(r#"<book {}><xi:include href="ch.xml" xpointer="nope"/></book>"#, XI);
+        match include(xml.as_slice(), [("ch.xml", "<all/>")]) {
+            Err(UnresolvedPointer(location, pointer)) => {
+                assert_str_eq!(location, "ch.xml");
+                assert_str_eq!(pointer, "nope");
+            },
+            other => panic!("Expected an unresolved pointer, got {}", other),
+        }
+    }
+
+    #[test]
+    fn fallback_is_used_when_the_resource_is_missing() {
+        let xml = format!(r#"<book {}><xi:include href="missing.xml"><xi:fallback><p>Gone</p></xi:fallback></xi:include></book>"#, XI);
+        let result = include(xml.as_slice(), []);
+
+        assert_str_eq!(result.unwrap(), "<book xmlns:xi='http://www.w3.org/2001/XInclude'><p>Gone</p></book>");
+    }
+
+    #[test]
+    fn fallback_can_include() {
+        let xml = format!(r#"<book {}><xi:include href="missing.xml"><xi:fallback><xi:include href="b.txt" parse="text"/></xi:fallback></xi:include></book>"#, XI);
+        let result = include(xml.as_slice(), [("b.txt", "backup")]);
+
+        assert_str_eq!(result.unwrap(), "<book xmlns:xi='http://www.w3.org/2001/XInclude'>backup</book>");
+    }
+
+    #[test]
+    fn missing_resource_without_fallback_is_an_error() {
+        let xml = format!(r#"<book {}><xi:include href="missing.xml"/></book>"#, XI);
+        match include(xml.as_slice(), []) {
+            Err(ResourceError(href, _)) => assert_str_eq!(href, "missing.xml"),
+            other => panic!("Expected a resource error, got {}", other),
+        }
+    }
+
+    #[test]
+    fn inclusion_loops_are_detected() {
+        let a = format!(r#"<a {}><xi:include href="b.xml"/></a>"#, XI);
+        let b = format!(r#"<b {}><xi:include href="a.xml"/></b>"#, XI);
+        let xml = format!(r#"<book {}><xi:include href="a.xml"/></book>"#, XI);
+
+        match include(xml.as_slice(), [("a.xml", a.as_slice()), ("b.xml", b.as_slice())]) {
+            Err(InclusionLoop(location)) => assert_str_eq!(location, "a.xml"),
+            other => panic!("Expected an inclusion loop, got {}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_parse_value_is_an_error() {
+        let xml = format!(r#"<book {}><xi:include href="a.xml" parse="json"/></book>"#, XI);
+        match include(xml.as_slice(), [("a.xml", "<a/>")]) {
+            Err(InvalidInclude(..)) => {},
+            other => panic!("Expected an invalid include, got {}", other),
+        }
+    }
+
+    #[test]
+    fn files_are_included_relative_to_the_including_file() {
+        let dir = TempDir::new("xinclude").unwrap();
+        let sub = dir.path().join("sub");
+        mkdir(&sub, USER_RWX).unwrap();
+
+        let main = format!(r#"<book {}><xi:include href="sub/part.xml"/></book>"#, XI);
+        let part = format!(r#"<part {}><xi:include href="ch.txt" parse="text"/></part>"#, XI);
+        File::create(&dir.path().join("main.xml")).write_str(main.as_slice()).unwrap();
+        File::create(&sub.join("part.xml")).write_str(part.as_slice()).unwrap();
+        File::create(&sub.join("ch.txt")).write_str("words").unwrap();
+
+        let package: Package = parse_file(&dir.path().join("main.xml")).ok().expect("Failed to include");
+        let doc = package.as_document();
+
+        assert_str_eq!(format_xml(&doc), "<book xmlns:xi='http://www.w3.org/2001/XInclude'><part xmlns:xi='http://www.w3.org/2001/XInclude'>words</part></book>");
+    }
+}
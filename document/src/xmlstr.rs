@@ -12,6 +12,8 @@ pub trait XmlStr {
     fn end_of_name(&self) -> Option<uint>;
     fn end_of_space(&self) -> Option<uint>;
     fn end_of_start_tag(&self) -> Option<uint>;
+    fn end_of_system_literal(&self, quote: &str) -> Option<uint>;
+    fn end_of_internal_subset(&self) -> Option<uint>;
 }
 
 impl<'a> XmlStr for &'a str {
@@ -174,9 +176,84 @@ impl<'a> XmlStr for &'a str {
             None => Some(self.len()),
         }
     }
+
+    fn end_of_system_literal(&self, quote: &str) -> Option<uint> {
+        self.find_str(quote)
+    }
+
+    fn end_of_internal_subset(&self) -> Option<uint> {
+        let mut rest = *self;
+        let mut offset = 0;
+
+        loop {
+            // Skip over anything that may legitimately contain a `]`
+            let skip = if rest.starts_with("<!--") {
+                rest.find_str("-->").map(|e| e + "-->".len())
+            } else if rest.starts_with("<?") {
+                rest.find_str("?>").map(|e| e + "?>".len())
+            } else if rest.starts_with("\"") || rest.starts_with("'") {
+                rest.slice_from(1).find(rest.char_at(0)).map(|e| e + 2)
+            } else if rest.starts_with("\x5d") {
+                return Some(offset);
+            } else {
+                match rest.char_indices().nth(1) {
+                    Some((next, _)) => Some(next),
+                    None => return None,
+                }
+            };
+
+            match skip {
+                Some(len) => {
+                    rest = rest.slice_from(len);
+                    offset += len;
+                },
+                None => return None,
+            }
+        }
+    }
+}
+
+/// The replacement text of the entities every document may refer to
+pub fn predefined_entity(name: &str) -> Option<&'static str> {
+    match name {
+        "amp"  => Some("&"),
+        "lt"   => Some("<"),
+        "gt"   => Some(">"),
+        "apos" => Some("'"),
+        "quot" => Some("\""),
+        _      => None,
+    }
+}
+
+pub fn is_name(s: &str) -> bool {
+    s.end_of_name() == Some(s.len())
+}
+
+pub fn is_nmtoken(s: &str) -> bool {
+    s.end_of_start_rest(|c| c.is_name_char(), |c| c.is_name_char()) == Some(s.len())
+}
+
+/// The part of a qualified name before the colon, if there is one
+pub fn prefix_of(name: &str) -> Option<&str> {
+    name.find(':').map(|i| name.slice_to(i))
+}
+
+/// The part of a qualified name after the colon, or the whole name
+pub fn local_name(name: &str) -> &str {
+    match name.find(':') {
+        Some(i) => name.slice_from(i + 1),
+        None => name,
+    }
 }
 
-trait XmlChar {
+/// Whether an attribute with this name declares a namespace
+pub fn is_namespace_declaration(name: &str) -> bool {
+    name == "xmlns" || prefix_of(name) == Some("xmlns")
+}
+
+pub trait XmlChar {
+    /// Any character that may appear in an XML document
+    fn is_char(&self) -> bool;
     fn is_name_start_char(&self) -> bool;
     fn is_name_char(&self) -> bool;
     fn is_space_char(&self) -> bool;
@@ -185,6 +262,18 @@ trait XmlChar {
 }
 
 impl XmlChar for char {
+    fn is_char(&self) -> bool {
+        match *self {
+            '\x09'                      |
+            '\x0A'                      |
+            '\x0D'                      |
+            '\U00000020'...'\U0000D7FF' |
+            '\U0000E000'...'\U0000FFFD' |
+            '\U00010000'...'\U0010FFFF' => true,
+            _ => false,
+        }
+    }
+
     fn is_name_start_char(&self) -> bool {
         match *self {
             ':'                         |
@@ -251,6 +340,7 @@ impl XmlChar for char {
 mod test {
 
 use super::XmlStr;
+use super::{prefix_of,local_name,is_namespace_declaration};
 
 #[test]
 fn end_of_char_data_leading_ampersand() {
@@ -287,4 +377,36 @@ fn end_of_char_data_includes_right_square() {
     assert_eq!("hello]world".end_of_char_data(), Some("hello]world".len()));
 }
 
+#[test]
+fn end_of_internal_subset_stops_at_right_square() {
+    assert_eq!("<!ELEMENT a ANY>]>".end_of_internal_subset(), Some("<!ELEMENT a ANY>".len()));
+}
+
+#[test]
+fn end_of_internal_subset_skips_quoted_right_square() {
+    let subset = "<!ENTITY a ']'><!-- ] --><?pi ]?>";
+    assert_eq!(format!("{}]>", subset).as_slice().end_of_internal_subset(), Some(subset.len()));
+}
+
+#[test]
+fn end_of_internal_subset_requires_right_square() {
+    assert_eq!("<!ELEMENT a ANY>".end_of_internal_subset(), None);
+}
+
+#[test]
+fn qualified_names_are_split_at_the_colon() {
+    assert_eq!(prefix_of("a:b"), Some("a"));
+    assert_eq!(local_name("a:b"), "b");
+    assert_eq!(prefix_of("b"), None);
+    assert_eq!(local_name("b"), "b");
+}
+
+#[test]
+fn namespace_declarations_are_recognized() {
+    assert!(is_namespace_declaration("xmlns"));
+    assert!(is_namespace_declaration("xmlns:a"));
+    assert!(! is_namespace_declaration("xmlnsa"));
+    assert!(! is_namespace_declaration("a:xmlns"));
+}
+
 }
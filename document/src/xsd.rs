@@ -0,0 +1,2091 @@
+//! Validates documents against an XML Schema (XSD 1.0)
+//!
+//! ### Example
+//!
+//! ```
+//! use document::parser::Parser;
+//! use document::xsd::Schema;
+//!
+//! let xsd = r#"<xs:schema xmlns:xs="http://www.w3.org/2001/XMLSchema">
+//!   <xs:element name="age" type="xs:nonNegativeInteger"/>
+//! </xs:schema>"#;
+//!
+//! let schema = Schema::parse(xsd).ok().expect("Invalid schema");
+//!
+//! let package = Parser::new().parse("<age>42</age>").ok().expect("Failed to parse");
+//! let doc = package.as_document();
+//!
+//! assert!(schema.validate(&doc).is_empty());
+//! ```
+//!
+//! Schemas that use `xs:include` or `xs:import` should be loaded with
+//! `Schema::from_file`, so that the referenced files are found
+//! relative to the file that refers to them.
+//!
+//! ### Known issues
+//!
+//! - Namespaces are not supported. Elements, attributes and schema
+//!   components are matched by their local names, and the
+//!   `targetNamespace` of each schema document is ignored.
+//! - Patterns are compiled by the `regex` crate, which does not know
+//!   the XSD-only `\i` and `\c` escapes.
+//! - Substitution groups, `xsi:type` and the overriding parts of
+//!   `xs:redefine` are not supported.
+//! - Range facets compare numbers numerically and everything else,
+//!   including dates, as strings.
+
+use std::collections::{HashMap,HashSet};
+use std::io::{File,IoError};
+
+use super::datatypes::{WhiteSpace,Preserve,Collapse,Facets,Builtins};
+use super::datatypes::{UnknownFacet,InvalidFacetValue,InvalidFacetPattern};
+use super::datatypes::{is_builtin,is_ncname,is_qname,normalize,builtin_white_space,is_builtin_list};
+use super::dom4;
+use super::dom4::{ElementCOE,TextCOE};
+use super::parser::{Parser,ParseError};
+use super::xmlstr::{XmlChar,prefix_of,local_name,is_namespace_declaration};
+
+/// Why a schema could not be loaded. The location is the path of the
+/// schema document, or empty for a schema given to `Schema::parse`.
+#[deriving(Show,Clone,PartialEq)]
+pub enum SchemaError {
+    ReadFailure(String, IoError),
+    ParseFailure(String, ParseError),
+    /// The document element is not `xs:schema`
+    NotASchema(String),
+    /// A schema component is missing a required part or has a value
+    /// that cannot be understood
+    InvalidComponent(String),
+    /// A `pattern` facet is not a regular expression
+    InvalidPattern(String),
+    /// A type, element, group, attribute or key is referred to but
+    /// never defined
+    UnresolvedReference(String),
+    /// A type is derived from itself, or a group or attribute group
+    /// contains itself
+    CircularDefinition(String),
+}
+
+/// The constraint that a value does not meet
+#[deriving(Show,Clone,PartialEq)]
+pub enum Facet {
+    /// The value is not in the lexical space of this built-in type
+    DatatypeFacet(String),
+    LengthFacet(uint),
+    MinLengthFacet(uint),
+    MaxLengthFacet(uint),
+    /// The value matches none of the patterns, which are joined by `|`
+    PatternFacet(String),
+    EnumerationFacet,
+    MinInclusiveFacet(String),
+    MaxInclusiveFacet(String),
+    MinExclusiveFacet(String),
+    MaxExclusiveFacet(String),
+    TotalDigitsFacet(uint),
+    FractionDigitsFacet(uint),
+    /// The value differs from the declaration's `fixed` value
+    FixedFacet(String),
+    /// The value is valid for none of the union's member types
+    UnionFacet,
+}
+
+/// A way in which a document does not match its schema. Each variant
+/// carries the element where the problem was found and, for
+/// attribute problems, the attribute name.
+#[deriving(Show,PartialEq)]
+pub enum ValidationError<'d> {
+    /// There is no global declaration for the document element, or for
+    /// an element matched by a strict wildcard
+    UndeclaredElement(dom4::Element<'d>),
+    /// The element's children do not match its content model
+    InvalidContent(dom4::Element<'d>),
+    /// The element contains text, but its type only allows elements
+    UnexpectedText(dom4::Element<'d>),
+    UndeclaredAttribute(dom4::Element<'d>, String),
+    MissingRequiredAttribute(dom4::Element<'d>, String),
+    InvalidElementValue(dom4::Element<'d>, Facet),
+    InvalidAttributeValue(dom4::Element<'d>, String, Facet),
+    /// The element has the same field values as an earlier one
+    /// selected by this `key` or `unique` constraint
+    DuplicateKey(dom4::Element<'d>, String),
+    /// A field of this `key` constraint selects no value, or more
+    /// than one
+    IncompleteKey(dom4::Element<'d>, String),
+    /// The field values of this `keyref` constraint match no key
+    UnknownKeyReference(dom4::Element<'d>, String),
+}
+
+#[deriving(Show,Clone,PartialEq)]
+enum ProcessContents {
+    Strict,
+    Lax,
+    Skip,
+}
+
+enum TypeReference {
+    /// A type from the XML Schema namespace
+    BuiltinType(String),
+    /// A type defined by the schema, or a built-in type when the
+    /// schema does not use a prefix for its own namespace
+    NamedType(String),
+    AnonymousType(Box<TypeDefinition>),
+}
+
+enum TypeDefinition {
+    SimpleTypeDefinition(SimpleType),
+    ComplexTypeDefinition(ComplexType),
+}
+
+enum Variety {
+    /// A restriction of the base type
+    AtomicVariety(TypeReference),
+    ListVariety(TypeReference),
+    UnionVariety(Vec<TypeReference>),
+}
+
+struct SimpleType {
+    variety: Variety,
+    facets: Facets,
+}
+
+struct ComplexType {
+    /// The type this one is derived from, if any
+    base: Option<TypeReference>,
+    /// Derived by extension rather than restriction
+    extension: bool,
+    mixed: bool,
+    /// The type of the text, for types with simple content
+    simple_content: Option<TypeReference>,
+    particle: Option<Particle>,
+    attributes: AttributeSet,
+}
+
+struct AttributeUse {
+    name: String,
+    /// Refers to a global attribute declaration with this name
+    reference: bool,
+    type_ref: Option<TypeReference>,
+    required: bool,
+    prohibited: bool,
+    fixed: Option<String>,
+}
+
+/// The attributes of a complex type or attribute group
+struct AttributeSet {
+    uses: Vec<AttributeUse>,
+    groups: Vec<String>,
+    any_attribute: bool,
+}
+
+struct ElementDeclaration {
+    name: String,
+    /// `None` allows any content
+    type_ref: Option<TypeReference>,
+    nillable: bool,
+    fixed: Option<String>,
+    constraints: Vec<String>,
+}
+
+enum Term {
+    ElementTerm(ElementDeclaration),
+    ElementReference(String),
+    GroupReference(String),
+    SequenceTerm(Vec<Particle>),
+    ChoiceTerm(Vec<Particle>),
+    AllTerm(Vec<Particle>),
+    WildcardTerm(ProcessContents),
+}
+
+struct Particle {
+    term: Term,
+    min_occurs: uint,
+    /// `None` when unbounded
+    max_occurs: Option<uint>,
+}
+
+#[deriving(Show,Clone,PartialEq)]
+enum ConstraintKind {
+    KeyConstraint,
+    UniqueConstraint,
+    /// Refers to the named `key` or `unique` constraint
+    KeyRefConstraint(String),
+}
+
+/// One alternative of the XPath subset allowed in identity
+/// constraints: `(.//)? step (/ step)*`, where only fields may end by
+/// selecting an attribute
+struct RestrictedPath {
+    descendants: bool,
+    steps: Vec<String>,
+    attribute: Option<String>,
+}
+
+struct IdentityConstraint {
+    name: String,
+    kind: ConstraintKind,
+    selector: Vec<RestrictedPath>,
+    fields: Vec<Vec<RestrictedPath>>,
+}
+
+/// How an element was matched against a content model
+#[deriving(Clone)]
+enum Match<'s> {
+    DeclaredMatch(&'s ElementDeclaration),
+    WildcardMatch(ProcessContents),
+}
+
+enum Resolved<'s> {
+    ResolvedBuiltin(&'s str),
+    ResolvedSimple(&'s SimpleType),
+    ResolvedComplex(&'s ComplexType),
+}
+
+/// Namespace declarations and `xsi:` attributes are not validated
+fn is_special_attribute(name: &str) -> bool {
+    is_namespace_declaration(name) || prefix_of(name) == Some("xsi")
+}
+
+fn push<'s>(mut state: Vec<Match<'s>>, m: Match<'s>) -> Vec<Match<'s>> {
+    state.push(m);
+    state
+}
+
+/// Adds the states that end at a position not already reached. The
+/// unique particle attribution rule means that states ending at the
+/// same position matched the elements the same way.
+fn add_states<'s>(states: &mut Vec<Vec<Match<'s>>>, new_states: Vec<Vec<Match<'s>>>) {
+    for state in new_states.into_iter() {
+        if ! states.iter().any(|s| s.len() == state.len()) {
+            states.push(state);
+        }
+    }
+}
+
+/// The element children, skipping annotations
+fn child_elements<'d>(element: dom4::Element<'d>) -> Vec<dom4::Element<'d>> {
+    element.children().into_iter()
+        .filter_map(|c| c.element())
+        .filter(|e| local_name(e.name()) != "annotation")
+        .collect()
+}
+
+fn descendants_or_self<'d>(element: dom4::Element<'d>) -> Vec<dom4::Element<'d>> {
+    let mut found = Vec::new();
+    let mut todo = vec![element];
+
+    while ! todo.is_empty() {
+        let element = todo.pop().unwrap();
+        found.push(element);
+
+        let mut children: Vec<dom4::Element<'d>> = element.children().into_iter().filter_map(|c| c.element()).collect();
+        children.reverse();
+        todo.extend(children.into_iter());
+    }
+
+    found
+}
+
+/// The concatenated text children of an element
+fn text_of(element: dom4::Element) -> String {
+    let mut text = String::new();
+    for child in element.children().into_iter() {
+        if let TextCOE(t) = child {
+            text.push_str(t.text());
+        }
+    }
+    text
+}
+
+fn required<'d>(element: dom4::Element<'d>, attribute: &str) -> Result<&'d str, SchemaError> {
+    match element.attribute_value(attribute) {
+        Some(value) => Ok(value),
+        None => Err(InvalidComponent(format!("{} requires a {} attribute", element.name(), attribute))),
+    }
+}
+
+fn number(value: &str) -> Result<uint, SchemaError> {
+    match from_str::<uint>(value.trim()) {
+        Some(n) => Ok(n),
+        None => Err(InvalidComponent(format!("'{}' is not a number", value))),
+    }
+}
+
+fn occurrences(element: dom4::Element) -> Result<(uint, Option<uint>), SchemaError> {
+    let min = match element.attribute_value("minOccurs") {
+        Some(value) => try!(number(value)),
+        None => 1,
+    };
+    let max = match element.attribute_value("maxOccurs") {
+        Some(value) if value.trim() == "unbounded" => None,
+        Some(value) => Some(try!(number(value))),
+        None => Some(1),
+    };
+    Ok((min, max))
+}
+
+fn process_contents(element: dom4::Element) -> Result<ProcessContents, SchemaError> {
+    match element.attribute_value("processContents") {
+        None | Some("strict") => Ok(Strict),
+        Some("lax") => Ok(Lax),
+        Some("skip") => Ok(Skip),
+        Some(other) => Err(InvalidComponent(format!("unknown processContents '{}'", other))),
+    }
+}
+
+fn is_name_test(s: &str) -> bool {
+    s == "*" || is_qname(s) || (s.ends_with(":*") && is_ncname(s.slice_to(s.len() - 2)))
+}
+
+fn restricted_paths(xpath: &str, field: bool) -> Result<Vec<RestrictedPath>, SchemaError> {
+    let unsupported = || InvalidComponent(format!("unsupported XPath '{}'", xpath));
+    let mut paths = Vec::new();
+
+    for alternative in xpath.split('|') {
+        let mut alternative = alternative.trim();
+        let descendants = alternative.starts_with(".//");
+        if descendants { alternative = alternative.slice_from(3) }
+
+        let steps: Vec<&str> = alternative.split('/').map(|s| s.trim()).collect();
+        let mut path = RestrictedPath { descendants: descendants, steps: Vec::new(), attribute: None };
+
+        for (i, &step) in steps.iter().enumerate() {
+            let step = if step.starts_with("child::") { step.slice_from(7) } else { step };
+
+            let attribute = if step.starts_with("@") {
+                Some(step.slice_from(1))
+            } else if step.starts_with("attribute::") {
+                Some(step.slice_from(11))
+            } else {
+                None
+            };
+
+            match attribute {
+                Some(name) => {
+                    if ! field || i + 1 != steps.len() || ! is_name_test(name) {
+                        return Err(unsupported());
+                    }
+                    path.attribute = Some(local_name(name).to_string());
+                },
+                None if step == "." => {},
+                None if is_name_test(step) => path.steps.push(local_name(step).to_string()),
+                None => return Err(unsupported()),
+            }
+        }
+
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+fn identity_constraint(element: dom4::Element) -> Result<IdentityConstraint, SchemaError> {
+    let name = local_name(try!(required(element, "name"))).to_string();
+    let kind = match local_name(element.name()) {
+        "key"    => KeyConstraint,
+        "unique" => UniqueConstraint,
+        _        => KeyRefConstraint(local_name(try!(required(element, "refer"))).to_string()),
+    };
+
+    let mut selector = None;
+    let mut fields = Vec::new();
+
+    for child in child_elements(element).into_iter() {
+        let xpath = try!(required(child, "xpath"));
+        match local_name(child.name()) {
+            "selector" => selector = Some(try!(restricted_paths(xpath, false))),
+            "field"    => fields.push(try!(restricted_paths(xpath, true))),
+            _          => {},
+        }
+    }
+
+    match selector {
+        Some(selector) => Ok(IdentityConstraint { name: name, kind: kind, selector: selector, fields: fields }),
+        None => Err(InvalidComponent(format!("{} '{}' has no selector", element.name(), name))),
+    }
+}
+
+/// The elements reached by any of the paths
+fn select<'d>(context: dom4::Element<'d>, paths: &[RestrictedPath]) -> Vec<dom4::Element<'d>> {
+    let mut selected = Vec::new();
+
+    for path in paths.iter() {
+        let mut current = if path.descendants { descendants_or_self(context) } else { vec![context] };
+
+        for step in path.steps.iter() {
+            let mut next = Vec::new();
+            for element in current.iter() {
+                for child in element.children().into_iter().filter_map(|c| c.element()) {
+                    if step.as_slice() == "*" || local_name(child.name()) == step.as_slice() {
+                        next.push(child);
+                    }
+                }
+            }
+            current = next;
+        }
+
+        for element in current.into_iter() {
+            if ! selected.contains(&element) { selected.push(element) }
+        }
+    }
+
+    selected
+}
+
+/// The whitespace-collapsed values that a field selects
+fn field_values(context: dom4::Element, paths: &[RestrictedPath]) -> Vec<String> {
+    let mut values = Vec::new();
+
+    for path in paths.iter() {
+        let without_attribute = RestrictedPath { descendants: path.descendants, steps: path.steps.clone(), attribute: None };
+
+        for element in select(context, &[without_attribute]).into_iter() {
+            match path.attribute {
+                Some(ref name) => {
+                    for attr in element.attributes().iter() {
+                        if name.as_slice() == "*" || local_name(attr.name()) == name.as_slice() {
+                            values.push(normalize(attr.value(), Collapse));
+                        }
+                    }
+                },
+                None => values.push(normalize(text_of(element).as_slice(), Collapse)),
+            }
+        }
+    }
+
+    values
+}
+
+/// Reads schema components from the children of `xs:schema`
+struct ComponentReader {
+    /// The prefix the schema document uses for the XML Schema namespace
+    prefix: String,
+    constraints: Vec<IdentityConstraint>,
+}
+
+impl ComponentReader {
+    fn type_reference(&self, qname: &str) -> TypeReference {
+        let local = local_name(qname).to_string();
+
+        if ! self.prefix.is_empty() && prefix_of(qname) == Some(self.prefix.as_slice()) {
+            BuiltinType(local)
+        } else {
+            NamedType(local)
+        }
+    }
+
+    /// The type named by the attribute, or else defined by a child
+    fn type_of(&mut self, element: dom4::Element, attribute: &str) -> Result<Option<TypeReference>, SchemaError> {
+        if let Some(name) = element.attribute_value(attribute) {
+            return Ok(Some(self.type_reference(name)));
+        }
+
+        for child in child_elements(element).into_iter() {
+            match local_name(child.name()) {
+                "simpleType" => {
+                    let definition = SimpleTypeDefinition(try!(self.simple_type(child)));
+                    return Ok(Some(AnonymousType(box definition)));
+                },
+                "complexType" => {
+                    let definition = ComplexTypeDefinition(try!(self.complex_type(child)));
+                    return Ok(Some(AnonymousType(box definition)));
+                },
+                _ => {},
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn required_type(&mut self, element: dom4::Element, attribute: &str) -> Result<TypeReference, SchemaError> {
+        match try!(self.type_of(element, attribute)) {
+            Some(type_ref) => Ok(type_ref),
+            None => Err(InvalidComponent(format!("{} requires a {} attribute or an anonymous type",
+                                                 element.name(), attribute))),
+        }
+    }
+
+    fn element_declaration(&mut self, element: dom4::Element) -> Result<ElementDeclaration, SchemaError> {
+        let name = local_name(try!(required(element, "name"))).to_string();
+        let type_ref = try!(self.type_of(element, "type"));
+        let mut constraints = Vec::new();
+
+        for child in child_elements(element).into_iter() {
+            match local_name(child.name()) {
+                "key" | "unique" | "keyref" => {
+                    let constraint = try!(identity_constraint(child));
+                    constraints.push(constraint.name.clone());
+                    self.constraints.push(constraint);
+                },
+                _ => {},
+            }
+        }
+
+        Ok(ElementDeclaration {
+            name: name,
+            type_ref: type_ref,
+            nillable: element.attribute_value("nillable") == Some("true"),
+            fixed: element.attribute_value("fixed").map(|v| v.to_string()),
+            constraints: constraints,
+        })
+    }
+
+    fn particle(&mut self, element: dom4::Element) -> Result<Option<Particle>, SchemaError> {
+        let term = match local_name(element.name()) {
+            "element" => match element.attribute_value("ref") {
+                Some(name) => ElementReference(local_name(name).to_string()),
+                None => ElementTerm(try!(self.element_declaration(element))),
+            },
+            "group"    => GroupReference(local_name(try!(required(element, "ref"))).to_string()),
+            "sequence" => SequenceTerm(try!(self.particles(element))),
+            "choice"   => ChoiceTerm(try!(self.particles(element))),
+            "all"      => AllTerm(try!(self.particles(element))),
+            "any"      => WildcardTerm(try!(process_contents(element))),
+            _          => return Ok(None),
+        };
+
+        let (min_occurs, max_occurs) = try!(occurrences(element));
+        Ok(Some(Particle { term: term, min_occurs: min_occurs, max_occurs: max_occurs }))
+    }
+
+    fn particles(&mut self, element: dom4::Element) -> Result<Vec<Particle>, SchemaError> {
+        let mut particles = Vec::new();
+        for child in child_elements(element).into_iter() {
+            if let Some(particle) = try!(self.particle(child)) {
+                particles.push(particle);
+            }
+        }
+        Ok(particles)
+    }
+
+    /// The first particle among the children
+    fn content_particle(&mut self, element: dom4::Element) -> Result<Option<Particle>, SchemaError> {
+        for child in child_elements(element).into_iter() {
+            if let Some(particle) = try!(self.particle(child)) {
+                return Ok(Some(particle));
+            }
+        }
+        Ok(None)
+    }
+
+    fn attribute_use(&mut self, element: dom4::Element) -> Result<AttributeUse, SchemaError> {
+        let (name, reference) = match element.attribute_value("ref") {
+            Some(name) => (name, true),
+            None => (try!(required(element, "name")), false),
+        };
+        let usage = element.attribute_value("use").unwrap_or("optional");
+
+        Ok(AttributeUse {
+            name: local_name(name).to_string(),
+            reference: reference,
+            type_ref: try!(self.type_of(element, "type")),
+            required: usage == "required",
+            prohibited: usage == "prohibited",
+            fixed: element.attribute_value("fixed").map(|v| v.to_string()),
+        })
+    }
+
+    fn attribute_set(&mut self, element: dom4::Element, set: &mut AttributeSet) -> Result<(), SchemaError> {
+        for child in child_elements(element).into_iter() {
+            match local_name(child.name()) {
+                "attribute"      => set.uses.push(try!(self.attribute_use(child))),
+                "attributeGroup" => set.groups.push(local_name(try!(required(child, "ref"))).to_string()),
+                "anyAttribute"   => set.any_attribute = true,
+                _                => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn complex_type(&mut self, element: dom4::Element) -> Result<ComplexType, SchemaError> {
+        let mut complex = ComplexType {
+            base: None,
+            extension: false,
+            mixed: element.attribute_value("mixed") == Some("true"),
+            simple_content: None,
+            particle: None,
+            attributes: AttributeSet::new(),
+        };
+
+        for child in child_elements(element).into_iter() {
+            let simple = match local_name(child.name()) {
+                "simpleContent"  => true,
+                "complexContent" => false,
+                _                => continue,
+            };
+
+            if child.attribute_value("mixed") == Some("true") { complex.mixed = true }
+
+            let derivation = match child_elements(child).into_iter().next() {
+                Some(derivation) => derivation,
+                None => return Err(InvalidComponent(format!("{} has no extension or restriction", child.name()))),
+            };
+            let base = try!(required(derivation, "base"));
+
+            complex.extension = match local_name(derivation.name()) {
+                "extension"   => true,
+                "restriction" => false,
+                _ => return Err(InvalidComponent(format!("{} has no extension or restriction", child.name()))),
+            };
+            complex.base = Some(self.type_reference(base));
+
+            if simple {
+                complex.simple_content = Some(if complex.extension {
+                    self.type_reference(base)
+                } else {
+                    let restriction = SimpleType {
+                        variety: AtomicVariety(self.type_reference(base)),
+                        facets: try!(self.facets(derivation)),
+                    };
+                    AnonymousType(box SimpleTypeDefinition(restriction))
+                });
+            } else {
+                complex.particle = try!(self.content_particle(derivation));
+            }
+
+            try!(self.attribute_set(derivation, &mut complex.attributes));
+        }
+
+        if complex.base.is_none() {
+            complex.particle = try!(self.content_particle(element));
+            try!(self.attribute_set(element, &mut complex.attributes));
+        }
+
+        Ok(complex)
+    }
+
+    fn simple_type(&mut self, element: dom4::Element) -> Result<SimpleType, SchemaError> {
+        for child in child_elements(element).into_iter() {
+            let variety = match local_name(child.name()) {
+                "restriction" => AtomicVariety(try!(self.required_type(child, "base"))),
+                "list"        => ListVariety(try!(self.required_type(child, "itemType"))),
+                "union"       => {
+                    let names = child.attribute_value("memberTypes").unwrap_or("");
+                    let mut members: Vec<TypeReference> = names.words().map(|n| self.type_reference(n)).collect();
+                    for member in child_elements(child).into_iter() {
+                        members.push(AnonymousType(box SimpleTypeDefinition(try!(self.simple_type(member)))));
+                    }
+                    UnionVariety(members)
+                },
+                _ => continue,
+            };
+
+            return Ok(SimpleType { variety: variety, facets: try!(self.facets(child)) });
+        }
+
+        Err(InvalidComponent(format!("{} has no restriction, list or union", element.name())))
+    }
+
+    fn facets(&self, element: dom4::Element) -> Result<Facets, SchemaError> {
+        let mut facets = Facets::new();
+
+        for child in child_elements(element).into_iter() {
+            let name = local_name(child.name());
+            match name {
+                "simpleType" | "attribute" | "attributeGroup" | "anyAttribute" => continue,
+                _ => {},
+            }
+
+            let value = try!(required(child, "value"));
+
+            match facets.add(name, value) {
+                Ok(()) => {},
+                Err(InvalidFacetPattern) => return Err(InvalidPattern(value.to_string())),
+                Err(InvalidFacetValue) => {
+                    return Err(InvalidComponent(format!("invalid value '{}' for {}", value, child.name())))
+                },
+                Err(UnknownFacet) => return Err(InvalidComponent(format!("unknown facet {}", child.name()))),
+            }
+        }
+
+        Ok(facets)
+    }
+}
+
+impl AttributeSet {
+    fn new() -> AttributeSet {
+        AttributeSet { uses: Vec::new(), groups: Vec::new(), any_attribute: false }
+    }
+}
+
+fn define<V>(map: &mut HashMap<String, V>, name: String, value: V) {
+    if ! map.contains_key(&name) {
+        map.insert(name, value);
+    }
+}
+
+fn resolved(found: bool, name: &str) -> Result<(), SchemaError> {
+    if found { Ok(()) } else { Err(UnresolvedReference(name.to_string())) }
+}
+
+/// The components of one kind that have been followed while looking
+/// for circular definitions
+struct Visited {
+    /// The components being followed, outermost first
+    path: Vec<String>,
+    done: HashSet<String>,
+}
+
+impl Visited {
+    fn new() -> Visited {
+        Visited { path: Vec::new(), done: HashSet::new() }
+    }
+
+    /// Whether the component still needs to be followed. Reaching one
+    /// that is already being followed is an error.
+    fn enter(&mut self, name: &str) -> Result<bool, SchemaError> {
+        if self.path.iter().any(|n| n.as_slice() == name) {
+            return Err(CircularDefinition(name.to_string()));
+        }
+        if self.done.contains(name) { return Ok(false) }
+
+        self.path.push(name.to_string());
+        Ok(true)
+    }
+
+    fn leave(&mut self) {
+        let name = self.path.pop().expect("No component to leave");
+        self.done.insert(name);
+    }
+}
+
+/// The global components of one or more schema documents. When a
+/// component is defined more than once, the first definition wins.
+pub struct Schema {
+    elements: HashMap<String, ElementDeclaration>,
+    attributes: HashMap<String, AttributeUse>,
+    types: HashMap<String, TypeDefinition>,
+    groups: HashMap<String, Particle>,
+    attribute_groups: HashMap<String, AttributeSet>,
+    constraints: HashMap<String, IdentityConstraint>,
+    builtins: Builtins,
+}
+
+impl Schema {
+    fn new() -> Schema {
+        Schema {
+            elements: HashMap::new(),
+            attributes: HashMap::new(),
+            types: HashMap::new(),
+            groups: HashMap::new(),
+            attribute_groups: HashMap::new(),
+            constraints: HashMap::new(),
+            builtins: Builtins::new(),
+        }
+    }
+
+    /// Reads a schema from a string. Included and imported schemas
+    /// are found relative to the current directory.
+    pub fn parse(xsd: &str) -> Result<Schema, SchemaError> {
+        let mut schema = Schema::new();
+        try!(schema.load_str(xsd, "", &Path::new("."), &mut HashSet::new()));
+        try!(schema.check_references());
+        Ok(schema)
+    }
+
+    /// Reads a schema from a file, along with every schema it includes
+    /// or imports
+    pub fn from_file(path: &Path) -> Result<Schema, SchemaError> {
+        let mut schema = Schema::new();
+        try!(schema.load_file(path, &mut HashSet::new()));
+        try!(schema.check_references());
+        Ok(schema)
+    }
+
+    fn load_file(&mut self, path: &Path, loaded: &mut HashSet<String>) -> Result<(), SchemaError> {
+        let location = path.display().to_string();
+        if ! loaded.insert(location.clone()) { return Ok(()) }
+
+        let xsd = match File::open(path).read_to_string() {
+            Ok(xsd) => xsd,
+            Err(e) => return Err(ReadFailure(location, e)),
+        };
+
+        self.load_str(xsd.as_slice(), location.as_slice(), &path.dir_path(), loaded)
+    }
+
+    fn load_str(&mut self, xsd: &str, location: &str, directory: &Path, loaded: &mut HashSet<String>)
+                -> Result<(), SchemaError>
+    {
+        let package = match Parser::new().parse(xsd) {
+            Ok(package) => package,
+            Err(e) => return Err(ParseFailure(location.to_string(), e)),
+        };
+        let doc = package.as_document();
+
+        self.load_document(&doc, location, directory, loaded)
+    }
+
+    fn load_document<'d>(&mut self,
+                         doc: &'d dom4::Document<'d>,
+                         location: &str,
+                         directory: &Path,
+                         loaded: &mut HashSet<String>)
+                         -> Result<(), SchemaError>
+    {
+        let root = match doc.root().children().into_iter().filter_map(|c| c.element()).next() {
+            Some(root) if local_name(root.name()) == "schema" => root,
+            _ => return Err(NotASchema(location.to_string())),
+        };
+
+        let mut reader = ComponentReader { prefix: prefix_of(root.name()).unwrap_or("").to_string(), constraints: Vec::new() };
+
+        for child in child_elements(root).into_iter() {
+            match local_name(child.name()) {
+                "include" | "import" | "redefine" => {
+                    if let Some(schema_location) = child.attribute_value("schemaLocation") {
+                        try!(self.load_file(&directory.join(schema_location), loaded));
+                    }
+                },
+                "element" => {
+                    let decl = try!(reader.element_declaration(child));
+                    define(&mut self.elements, decl.name.clone(), decl);
+                },
+                "attribute" => {
+                    let attribute = try!(reader.attribute_use(child));
+                    define(&mut self.attributes, attribute.name.clone(), attribute);
+                },
+                "simpleType" => {
+                    let name = local_name(try!(required(child, "name"))).to_string();
+                    let simple = try!(reader.simple_type(child));
+                    define(&mut self.types, name, SimpleTypeDefinition(simple));
+                },
+                "complexType" => {
+                    let name = local_name(try!(required(child, "name"))).to_string();
+                    let complex = try!(reader.complex_type(child));
+                    define(&mut self.types, name, ComplexTypeDefinition(complex));
+                },
+                "group" => {
+                    let name = local_name(try!(required(child, "name"))).to_string();
+                    match try!(reader.content_particle(child)) {
+                        Some(particle) => define(&mut self.groups, name, particle),
+                        None => return Err(InvalidComponent(format!("group '{}' is empty", name))),
+                    }
+                },
+                "attributeGroup" => {
+                    let name = local_name(try!(required(child, "name"))).to_string();
+                    let mut set = AttributeSet::new();
+                    try!(reader.attribute_set(child, &mut set));
+                    define(&mut self.attribute_groups, name, set);
+                },
+                _ => {},
+            }
+        }
+
+        for constraint in reader.constraints.into_iter() {
+            define(&mut self.constraints, constraint.name.clone(), constraint);
+        }
+
+        Ok(())
+    }
+
+    fn check_references(&self) -> Result<(), SchemaError> {
+        for decl in self.elements.values() { try!(self.check_element(decl)) }
+        for attribute in self.attributes.values() { try!(self.check_attribute(attribute)) }
+        for definition in self.types.values() { try!(self.check_definition(definition)) }
+        for particle in self.groups.values() { try!(self.check_particle(particle)) }
+        for set in self.attribute_groups.values() { try!(self.check_attribute_set(set)) }
+
+        for constraint in self.constraints.values() {
+            if let KeyRefConstraint(ref refer) = constraint.kind {
+                try!(resolved(self.constraints.contains_key(refer), refer.as_slice()));
+            }
+        }
+
+        self.check_cycles()
+    }
+
+    /// Types, groups and attribute groups are followed through each
+    /// other when validating, which must not go on forever
+    fn check_cycles(&self) -> Result<(), SchemaError> {
+        let mut visited = Visited::new();
+        for name in self.types.keys() {
+            try!(self.type_cycles(name.as_slice(), &mut visited));
+        }
+
+        let mut visited = Visited::new();
+        for name in self.groups.keys() {
+            try!(self.group_cycles(name.as_slice(), &mut visited));
+        }
+
+        let mut visited = Visited::new();
+        for name in self.attribute_groups.keys() {
+            try!(self.attribute_group_cycles(name.as_slice(), &mut visited));
+        }
+
+        Ok(())
+    }
+
+    fn type_cycles(&self, name: &str, visited: &mut Visited) -> Result<(), SchemaError> {
+        let definition = match self.types.get(name) {
+            Some(definition) => definition,
+            None => return Ok(()),
+        };
+
+        if try!(visited.enter(name)) {
+            try!(self.definition_cycles(definition, visited));
+            visited.leave();
+        }
+        Ok(())
+    }
+
+    /// Follows the types a definition is derived from. Element
+    /// declarations are not followed, as elements may contain
+    /// themselves.
+    fn definition_cycles(&self, definition: &TypeDefinition, visited: &mut Visited) -> Result<(), SchemaError> {
+        let mut bases = Vec::new();
+        match *definition {
+            SimpleTypeDefinition(ref simple) => match simple.variety {
+                AtomicVariety(ref base) => bases.push(base),
+                ListVariety(ref item) => bases.push(item),
+                UnionVariety(ref members) => bases.extend(members.iter()),
+            },
+            ComplexTypeDefinition(ref complex) => {
+                bases.extend(complex.base.iter());
+                bases.extend(complex.simple_content.iter());
+            },
+        }
+
+        for base in bases.into_iter() {
+            match *base {
+                BuiltinType(..) => {},
+                NamedType(ref name) => try!(self.type_cycles(name.as_slice(), visited)),
+                AnonymousType(ref definition) => try!(self.definition_cycles(&**definition, visited)),
+            }
+        }
+        Ok(())
+    }
+
+    fn group_cycles(&self, name: &str, visited: &mut Visited) -> Result<(), SchemaError> {
+        let particle = match self.groups.get(name) {
+            Some(particle) => particle,
+            None => return Ok(()),
+        };
+
+        if try!(visited.enter(name)) {
+            try!(self.particle_cycles(particle, visited));
+            visited.leave();
+        }
+        Ok(())
+    }
+
+    fn particle_cycles(&self, particle: &Particle, visited: &mut Visited) -> Result<(), SchemaError> {
+        match particle.term {
+            GroupReference(ref name) => self.group_cycles(name.as_slice(), visited),
+            SequenceTerm(ref particles) | ChoiceTerm(ref particles) | AllTerm(ref particles) => {
+                for particle in particles.iter() { try!(self.particle_cycles(particle, visited)) }
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn attribute_group_cycles(&self, name: &str, visited: &mut Visited) -> Result<(), SchemaError> {
+        let set = match self.attribute_groups.get(name) {
+            Some(set) => set,
+            None => return Ok(()),
+        };
+
+        if try!(visited.enter(name)) {
+            for group in set.groups.iter() {
+                try!(self.attribute_group_cycles(group.as_slice(), visited));
+            }
+            visited.leave();
+        }
+        Ok(())
+    }
+
+    fn check_type(&self, type_ref: &TypeReference) -> Result<(), SchemaError> {
+        match *type_ref {
+            BuiltinType(ref name) => resolved(is_builtin(name.as_slice()), name.as_slice()),
+            NamedType(ref name) => {
+                resolved(self.types.contains_key(name) || is_builtin(name.as_slice()), name.as_slice())
+            },
+            AnonymousType(ref definition) => self.check_definition(&**definition),
+        }
+    }
+
+    fn check_definition(&self, definition: &TypeDefinition) -> Result<(), SchemaError> {
+        match *definition {
+            SimpleTypeDefinition(ref simple) => match simple.variety {
+                AtomicVariety(ref base) => self.check_type(base),
+                ListVariety(ref item) => self.check_type(item),
+                UnionVariety(ref members) => {
+                    for member in members.iter() { try!(self.check_type(member)) }
+                    Ok(())
+                },
+            },
+            ComplexTypeDefinition(ref complex) => {
+                for type_ref in complex.base.iter().chain(complex.simple_content.iter()) {
+                    try!(self.check_type(type_ref));
+                }
+                for particle in complex.particle.iter() {
+                    try!(self.check_particle(particle));
+                }
+                self.check_attribute_set(&complex.attributes)
+            },
+        }
+    }
+
+    fn check_particle(&self, particle: &Particle) -> Result<(), SchemaError> {
+        match particle.term {
+            ElementTerm(ref decl) => self.check_element(decl),
+            ElementReference(ref name) => resolved(self.elements.contains_key(name), name.as_slice()),
+            GroupReference(ref name) => resolved(self.groups.contains_key(name), name.as_slice()),
+            SequenceTerm(ref particles) | ChoiceTerm(ref particles) | AllTerm(ref particles) => {
+                for particle in particles.iter() { try!(self.check_particle(particle)) }
+                Ok(())
+            },
+            WildcardTerm(..) => Ok(()),
+        }
+    }
+
+    fn check_element(&self, decl: &ElementDeclaration) -> Result<(), SchemaError> {
+        match decl.type_ref {
+            Some(ref type_ref) => self.check_type(type_ref),
+            None => Ok(()),
+        }
+    }
+
+    fn check_attribute(&self, attribute: &AttributeUse) -> Result<(), SchemaError> {
+        if attribute.reference {
+            return resolved(self.attributes.contains_key(&attribute.name), attribute.name.as_slice());
+        }
+        match attribute.type_ref {
+            Some(ref type_ref) => self.check_type(type_ref),
+            None => Ok(()),
+        }
+    }
+
+    fn check_attribute_set(&self, set: &AttributeSet) -> Result<(), SchemaError> {
+        for attribute in set.uses.iter() { try!(self.check_attribute(attribute)) }
+        for name in set.groups.iter() {
+            try!(resolved(self.attribute_groups.contains_key(name), name.as_slice()));
+        }
+        Ok(())
+    }
+
+    fn resolve<'s>(&'s self, type_ref: &'s TypeReference) -> Resolved<'s> {
+        let definition = match *type_ref {
+            BuiltinType(ref name) => return ResolvedBuiltin(name.as_slice()),
+            NamedType(ref name) => match self.types.get(name.as_slice()) {
+                Some(definition) => definition,
+                None => return ResolvedBuiltin(name.as_slice()),
+            },
+            AnonymousType(ref definition) => &**definition,
+        };
+
+        match *definition {
+            SimpleTypeDefinition(ref simple) => ResolvedSimple(simple),
+            ComplexTypeDefinition(ref complex) => ResolvedComplex(complex),
+        }
+    }
+
+    fn white_space(&self, type_ref: &TypeReference) -> WhiteSpace {
+        match self.resolve(type_ref) {
+            ResolvedBuiltin(name) => builtin_white_space(name),
+            ResolvedSimple(simple) => match (simple.facets.white_space, &simple.variety) {
+                (Some(white_space), _) => white_space,
+                (None, &AtomicVariety(ref base)) => self.white_space(base),
+                (None, _) => Collapse,
+            },
+            ResolvedComplex(complex) => match complex.simple_content {
+                Some(ref content) => self.white_space(content),
+                None => Preserve,
+            },
+        }
+    }
+
+    /// The length of a value for the length facets: the number of
+    /// items for list types and of characters otherwise
+    fn length_of(&self, type_ref: &TypeReference, value: &str) -> uint {
+        match self.resolve(type_ref) {
+            ResolvedBuiltin(name) if is_builtin_list(name) => value.words().count(),
+            ResolvedSimple(simple) => match simple.variety {
+                AtomicVariety(ref base) => self.length_of(base, value),
+                ListVariety(..) => value.words().count(),
+                UnionVariety(..) => value.chars().count(),
+            },
+            _ => value.chars().count(),
+        }
+    }
+
+    /// Checks a value against a simple type, or against the text type
+    /// of a complex type with simple content
+    fn check_value(&self, type_ref: &TypeReference, value: &str) -> Result<(), Facet> {
+        let value = normalize(value, self.white_space(type_ref));
+        let value = value.as_slice();
+
+        match self.resolve(type_ref) {
+            ResolvedBuiltin(name) => {
+                if self.builtins.check(name, value) { Ok(()) } else { Err(DatatypeFacet(name.to_string())) }
+            },
+            ResolvedSimple(simple) => match simple.variety {
+                AtomicVariety(ref base) => {
+                    try!(self.check_value(base, value));
+                    simple.facets.check(value, self.length_of(base, value))
+                },
+                ListVariety(ref item) => {
+                    let items: Vec<&str> = value.words().collect();
+                    for item_value in items.iter() {
+                        try!(self.check_value(item, *item_value));
+                    }
+                    simple.facets.check(value, items.len())
+                },
+                UnionVariety(ref members) => {
+                    if ! members.iter().any(|m| self.check_value(m, value).is_ok()) {
+                        return Err(UnionFacet);
+                    }
+                    simple.facets.check(value, value.chars().count())
+                },
+            },
+            ResolvedComplex(complex) => match complex.simple_content {
+                Some(ref content) => self.check_value(content, value),
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Checks a value against an optional type and `fixed` value
+    fn check_typed_value(&self, type_ref: Option<&TypeReference>, fixed: Option<&String>, value: &str)
+                         -> Result<(), Facet>
+    {
+        let white_space = match type_ref {
+            Some(type_ref) => {
+                try!(self.check_value(type_ref, value));
+                self.white_space(type_ref)
+            },
+            None => Preserve,
+        };
+
+        match fixed {
+            Some(fixed) if normalize(value, white_space) != normalize(fixed.as_slice(), white_space) => {
+                Err(FixedFacet(fixed.clone()))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the document against the schema, returning every problem
+    /// found
+    pub fn validate<'d>(&self, doc: &'d dom4::Document<'d>) -> Vec<ValidationError<'d>> {
+        let mut errors = Vec::new();
+
+        for element in doc.root().children().into_iter().filter_map(|c| c.element()) {
+            match self.elements.get(local_name(element.name())) {
+                Some(decl) => self.validate_element(element, decl, &mut errors),
+                None => errors.push(UndeclaredElement(element)),
+            }
+        }
+
+        errors
+    }
+
+    fn validate_element<'d>(&self,
+                            element: dom4::Element<'d>,
+                            decl: &ElementDeclaration,
+                            errors: &mut Vec<ValidationError<'d>>)
+    {
+        let nil = decl.nillable && element.attributes().iter().any(|a| {
+            prefix_of(a.name()) == Some("xsi") && local_name(a.name()) == "nil" && a.value().trim() == "true"
+        });
+
+        if nil && ! element.children().is_empty() {
+            errors.push(InvalidContent(element));
+        }
+
+        if let Some(ref type_ref) = decl.type_ref {
+            match self.resolve(type_ref) {
+                ResolvedBuiltin("anyType") => {},
+                ResolvedComplex(complex) => {
+                    self.validate_attributes(element, complex, errors);
+                    if ! nil { self.validate_complex_content(element, complex, decl.fixed.as_ref(), errors) }
+                },
+                _ => {
+                    for attr in element.attributes().iter() {
+                        if ! is_special_attribute(attr.name()) {
+                            errors.push(UndeclaredAttribute(element, attr.name().to_string()));
+                        }
+                    }
+                    if ! nil { self.validate_simple_content(element, type_ref, decl.fixed.as_ref(), errors) }
+                },
+            }
+        }
+
+        for name in decl.constraints.iter() {
+            if let Some(constraint) = self.constraints.get(name.as_slice()) {
+                self.check_constraint(element, constraint, errors);
+            }
+        }
+    }
+
+    fn validate_simple_content<'d>(&self,
+                                   element: dom4::Element<'d>,
+                                   type_ref: &TypeReference,
+                                   fixed: Option<&String>,
+                                   errors: &mut Vec<ValidationError<'d>>)
+    {
+        if element.children().into_iter().any(|c| c.element().is_some()) {
+            errors.push(InvalidContent(element));
+            return;
+        }
+
+        if let Err(facet) = self.check_typed_value(Some(type_ref), fixed, text_of(element).as_slice()) {
+            errors.push(InvalidElementValue(element, facet));
+        }
+    }
+
+    fn validate_complex_content<'d>(&self,
+                                    element: dom4::Element<'d>,
+                                    complex: &ComplexType,
+                                    fixed: Option<&String>,
+                                    errors: &mut Vec<ValidationError<'d>>)
+    {
+        if let Some(ref content) = complex.simple_content {
+            return self.validate_simple_content(element, content, fixed, errors);
+        }
+
+        let mut children = Vec::new();
+        let mut has_text = false;
+
+        for child in element.children().into_iter() {
+            match child {
+                ElementCOE(e) => children.push(e),
+                TextCOE(t) => {
+                    if ! t.text().chars().all(|c| c.is_space_char()) { has_text = true }
+                },
+                _ => {},
+            }
+        }
+
+        if has_text && ! self.is_mixed(complex) {
+            errors.push(UnexpectedText(element));
+        }
+
+        let mut particles = Vec::new();
+        self.content_particles(complex, &mut particles);
+
+        let names: Vec<&str> = children.iter().map(|e| local_name(e.name())).collect();
+        let states = self.match_sequence(particles, names.as_slice(), Vec::new());
+
+        let matches = match states.into_iter().find(|s| s.len() == names.len()) {
+            Some(matches) => matches,
+            None => {
+                errors.push(InvalidContent(element));
+                return;
+            },
+        };
+
+        for (&child, m) in children.iter().zip(matches.iter()) {
+            match *m {
+                DeclaredMatch(decl) => self.validate_element(child, decl, errors),
+                WildcardMatch(Skip) => {},
+                WildcardMatch(process) => match self.elements.get(local_name(child.name())) {
+                    Some(decl) => self.validate_element(child, decl, errors),
+                    None => if process == Strict { errors.push(UndeclaredElement(child)) },
+                },
+            }
+        }
+    }
+
+    fn is_mixed(&self, complex: &ComplexType) -> bool {
+        if complex.mixed { return true }
+
+        match complex.base {
+            Some(ref base) if complex.extension => match self.resolve(base) {
+                ResolvedComplex(base) => self.is_mixed(base),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// The particles of a complex type, preceded by those of the types
+    /// it extends
+    fn content_particles<'s>(&'s self, complex: &'s ComplexType, particles: &mut Vec<&'s Particle>) {
+        if let Some(ref base) = complex.base {
+            if complex.extension {
+                if let ResolvedComplex(base) = self.resolve(base) {
+                    self.content_particles(base, particles);
+                }
+            }
+        }
+
+        if let Some(ref particle) = complex.particle {
+            particles.push(particle);
+        }
+    }
+
+    fn validate_attributes<'d>(&self,
+                               element: dom4::Element<'d>,
+                               complex: &ComplexType,
+                               errors: &mut Vec<ValidationError<'d>>)
+    {
+        let mut uses = Vec::new();
+        let any_attribute = self.attribute_uses(complex, &mut uses);
+        let uses: Vec<&AttributeUse> = uses.into_iter().filter(|u| ! u.prohibited).collect();
+
+        for attr in element.attributes().iter() {
+            if is_special_attribute(attr.name()) { continue }
+
+            match uses.iter().find(|u| u.name.as_slice() == local_name(attr.name())) {
+                Some(attribute) => {
+                    if let Err(facet) = self.check_attribute_value(*attribute, attr.value()) {
+                        errors.push(InvalidAttributeValue(element, attr.name().to_string(), facet));
+                    }
+                },
+                None => {
+                    if ! any_attribute {
+                        errors.push(UndeclaredAttribute(element, attr.name().to_string()));
+                    }
+                },
+            }
+        }
+
+        for attribute in uses.iter() {
+            let present = element.attributes().iter().any(|a| local_name(a.name()) == attribute.name.as_slice());
+            if attribute.required && ! present {
+                errors.push(MissingRequiredAttribute(element, attribute.name.clone()));
+            }
+        }
+    }
+
+    fn check_attribute_value(&self, attribute: &AttributeUse, value: &str) -> Result<(), Facet> {
+        let declaration = if attribute.reference {
+            match self.attributes.get(attribute.name.as_slice()) {
+                Some(declaration) => declaration,
+                None => return Ok(()),
+            }
+        } else {
+            attribute
+        };
+        let fixed = attribute.fixed.as_ref().or(declaration.fixed.as_ref());
+
+        self.check_typed_value(declaration.type_ref.as_ref(), fixed, value)
+    }
+
+    /// Collects the attribute uses of a complex type, including those
+    /// of its base types and attribute groups. Later uses replace
+    /// earlier ones with the same name. Returns whether other
+    /// attributes are allowed.
+    fn attribute_uses<'s>(&'s self, complex: &'s ComplexType, uses: &mut Vec<&'s AttributeUse>) -> bool {
+        let mut any_attribute = false;
+
+        if let Some(ref base) = complex.base {
+            if let ResolvedComplex(base) = self.resolve(base) {
+                any_attribute = self.attribute_uses(base, uses);
+            }
+        }
+
+        self.collect_attribute_set(&complex.attributes, uses) || any_attribute
+    }
+
+    fn collect_attribute_set<'s>(&'s self, set: &'s AttributeSet, uses: &mut Vec<&'s AttributeUse>) -> bool {
+        let mut any_attribute = set.any_attribute;
+
+        for name in set.groups.iter() {
+            if let Some(group) = self.attribute_groups.get(name.as_slice()) {
+                any_attribute = self.collect_attribute_set(group, uses) || any_attribute;
+            }
+        }
+
+        for attribute in set.uses.iter() {
+            uses.retain(|other| other.name != attribute.name);
+            uses.push(attribute);
+        }
+
+        any_attribute
+    }
+
+    fn declaration<'s>(&'s self, term: &'s Term) -> Option<&'s ElementDeclaration> {
+        match *term {
+            ElementTerm(ref decl) => Some(decl),
+            ElementReference(ref name) => self.elements.get(name.as_slice()),
+            _ => None,
+        }
+    }
+
+    fn match_sequence<'s>(&'s self, particles: Vec<&'s Particle>, names: &[&str], state: Vec<Match<'s>>)
+                          -> Vec<Vec<Match<'s>>>
+    {
+        let mut states = vec![state];
+
+        for particle in particles.into_iter() {
+            let mut next = Vec::new();
+            for state in states.into_iter() {
+                add_states(&mut next, self.match_particle(particle, names, state));
+            }
+            states = next;
+        }
+
+        states
+    }
+
+    /// Every way that the particle could match the names following
+    /// those already matched by `state`
+    fn match_particle<'s>(&'s self, particle: &'s Particle, names: &[&str], state: Vec<Match<'s>>)
+                          -> Vec<Vec<Match<'s>>>
+    {
+        let mut results = Vec::new();
+        let mut frontier = vec![state];
+        let mut reached = Vec::new();
+        let mut count = 0u;
+
+        loop {
+            if count >= particle.min_occurs {
+                add_states(&mut results, frontier.clone());
+                reached.extend(frontier.iter().map(|s| s.len()));
+            }
+
+            if particle.max_occurs == Some(count) || frontier.is_empty() { break }
+
+            let mut next = Vec::new();
+            for state in frontier.into_iter() {
+                let matched = self.match_term(&particle.term, names, state);
+                // Once the minimum is met, repeating only helps if it
+                // reaches somewhere new
+                let fresh = matched.into_iter().filter(|s| ! reached.contains(&s.len())).collect();
+                add_states(&mut next, fresh);
+            }
+
+            frontier = next;
+            count += 1;
+        }
+
+        results
+    }
+
+    fn match_term<'s>(&'s self, term: &'s Term, names: &[&str], state: Vec<Match<'s>>) -> Vec<Vec<Match<'s>>> {
+        let position = state.len();
+
+        if let Some(decl) = self.declaration(term) {
+            return if position < names.len() && names[position] == decl.name.as_slice() {
+                vec![push(state, DeclaredMatch(decl))]
+            } else {
+                vec![]
+            };
+        }
+
+        match *term {
+            GroupReference(ref name) => match self.groups.get(name.as_slice()) {
+                Some(particle) => self.match_particle(particle, names, state),
+                None => vec![],
+            },
+            SequenceTerm(ref particles) => self.match_sequence(particles.iter().collect(), names, state),
+            ChoiceTerm(ref particles) => {
+                let mut states = Vec::new();
+                for particle in particles.iter() {
+                    add_states(&mut states, self.match_particle(particle, names, state.clone()));
+                }
+                states
+            },
+            AllTerm(ref particles) => self.match_all(particles.as_slice(), names, state),
+            WildcardTerm(process) => {
+                if position < names.len() { vec![push(state, WildcardMatch(process))] } else { vec![] }
+            },
+            ElementTerm(..) | ElementReference(..) => vec![],
+        }
+    }
+
+    /// Matches the elements of an `xs:all` group, in any order
+    fn match_all<'s>(&'s self, particles: &'s [Particle], names: &[&str], mut state: Vec<Match<'s>>)
+                     -> Vec<Vec<Match<'s>>>
+    {
+        let mut used = Vec::new();
+
+        while state.len() < names.len() {
+            let name = names[state.len()];
+            let found = particles.iter().enumerate().filter(|&(i, _)| ! used.contains(&i)).filter_map(|(i, p)| {
+                match self.declaration(&p.term) {
+                    Some(decl) if decl.name.as_slice() == name => Some((i, decl)),
+                    _ => None,
+                }
+            }).next();
+
+            match found {
+                Some((i, decl)) => {
+                    used.push(i);
+                    state.push(DeclaredMatch(decl));
+                },
+                None => break,
+            }
+        }
+
+        let complete = particles.iter().enumerate().all(|(i, p)| p.min_occurs == 0 || used.contains(&i));
+        if complete { vec![state] } else { vec![] }
+    }
+
+    /// The elements selected by a constraint, each with its field
+    /// values, or `None` when a field does not select exactly one value
+    fn key_rows<'d>(&self, element: dom4::Element<'d>, constraint: &IdentityConstraint)
+                    -> Vec<(dom4::Element<'d>, Option<Vec<String>>)>
+    {
+        select(element, constraint.selector.as_slice()).into_iter().map(|selected| {
+            let mut values = Vec::new();
+            for field in constraint.fields.iter() {
+                let mut found = field_values(selected, field.as_slice());
+                if found.len() != 1 { return (selected, None) }
+                values.push(found.pop().unwrap());
+            }
+            (selected, Some(values))
+        }).collect()
+    }
+
+    fn check_constraint<'d>(&self,
+                            element: dom4::Element<'d>,
+                            constraint: &IdentityConstraint,
+                            errors: &mut Vec<ValidationError<'d>>)
+    {
+        let rows = self.key_rows(element, constraint);
+
+        match constraint.kind {
+            KeyRefConstraint(ref refer) => {
+                let keys = match self.constraints.get(refer.as_slice()) {
+                    Some(key) => self.key_rows(element, key),
+                    None => return,
+                };
+
+                for &(selected, ref values) in rows.iter() {
+                    if let Some(ref values) = *values {
+                        if ! keys.iter().any(|&(_, ref key)| key.as_ref() == Some(values)) {
+                            errors.push(UnknownKeyReference(selected, constraint.name.clone()));
+                        }
+                    }
+                }
+            },
+            KeyConstraint | UniqueConstraint => {
+                let mut seen = HashSet::new();
+
+                for &(selected, ref values) in rows.iter() {
+                    match *values {
+                        Some(ref values) => {
+                            if ! seen.insert(values.clone()) {
+                                errors.push(DuplicateKey(selected, constraint.name.clone()));
+                            }
+                        },
+                        None => {
+                            if constraint.kind == KeyConstraint {
+                                errors.push(IncompleteKey(selected, constraint.name.clone()));
+                            }
+                        },
+                    }
+                }
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::{File,TempDir,USER_RWX};
+    use std::io::fs::mkdir;
+
+    use super::super::Package;
+    use super::super::dom4;
+    use super::super::parser::Parser;
+    use super::{Schema,SchemaError,ReadFailure,NotASchema,InvalidPattern,UnresolvedReference};
+    use super::CircularDefinition;
+    use super::{UndeclaredElement,InvalidContent,UnexpectedText,UndeclaredAttribute};
+    use super::{MissingRequiredAttribute,InvalidElementValue,InvalidAttributeValue};
+    use super::{DuplicateKey,IncompleteKey,UnknownKeyReference};
+    use super::{DatatypeFacet,LengthFacet,MaxLengthFacet,PatternFacet,EnumerationFacet};
+    use super::{MinInclusiveFacet,MaxInclusiveFacet,MaxExclusiveFacet,TotalDigitsFacet};
+    use super::{FixedFacet,UnionFacet};
+
+    fn parse(xml: &str) -> Package {
+        Parser::new().parse(xml).ok().expect("Failed to parse the XML string")
+    }
+
+    fn try_schema(body: &str) -> Result<Schema, SchemaError> {
+        let xsd = format!(r#"<xs:schema xmlns:xs="http://www.w3.org/2001/XMLSchema">{}</xs:schema>"#, body);
+        Schema::parse(xsd.as_slice())
+    }
+
+    fn schema(body: &str) -> Schema {
+        try_schema(body).ok().expect("Invalid schema")
+    }
+
+    fn document_element<'d>(doc: &'d dom4::Document<'d>) -> dom4::Element<'d> {
+        doc.root().children()[0].element().unwrap()
+    }
+
+    fn child_elements<'d>(element: dom4::Element<'d>) -> Vec<dom4::Element<'d>> {
+        element.children().into_iter().filter_map(|c| c.element()).collect()
+    }
+
+    #[test]
+    fn simple_type_facets() {
+        let schema = schema(r#"
+            <xs:simpleType name="code">
+              <xs:restriction base="xs:string">
+                <xs:maxLength value="5"/>
+                <xs:pattern value="[a-z]+"/>
+              </xs:restriction>
+            </xs:simpleType>
+            <xs:element name="codes">
+              <xs:complexType>
+                <xs:sequence>
+                  <xs:element name="code" type="code" maxOccurs="unbounded"/>
+                </xs:sequence>
+              </xs:complexType>
+            </xs:element>
+        "#);
+
+        let package = parse("<codes><code>abc</code><code>abcdef</code><code>AB</code></codes>");
+        let doc = package.as_document();
+        let codes = child_elements(document_element(&doc));
+
+        assert_eq!(schema.validate(&doc), vec![
+            InvalidElementValue(codes[1], MaxLengthFacet(5)),
+            InvalidElementValue(codes[2], PatternFacet("[a-z]+".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn built_in_types() {
+        let schema = schema(r#"
+            <xs:element name="values">
+              <xs:complexType>
+                <xs:sequence>
+                  <xs:element name="int" type="xs:int" maxOccurs="unbounded"/>
+                  <xs:element name="date" type="xs:date" maxOccurs="unbounded"/>
+                </xs:sequence>
+              </xs:complexType>
+            </xs:element>
+        "#);
+
+        let package = parse(r#"<values>
+            <int> -42 </int><int>99999999999</int>
+            <date>2014-10-18Z</date><date>18/10/2014</date>
+        </values>"#);
+        let doc = package.as_document();
+        let values = child_elements(document_element(&doc));
+
+        assert_eq!(schema.validate(&doc), vec![
+            InvalidElementValue(values[1], DatatypeFacet("int".to_string())),
+            InvalidElementValue(values[3], DatatypeFacet("date".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn numeric_facets() {
+        let schema = schema(r#"
+            <xs:element name="prices">
+              <xs:complexType>
+                <xs:sequence>
+                  <xs:element name="price" maxOccurs="unbounded">
+                    <xs:simpleType>
+                      <xs:restriction base="xs:decimal">
+                        <xs:minInclusive value="1"/>
+                        <xs:maxExclusive value="100"/>
+                        <xs:totalDigits value="3"/>
+                      </xs:restriction>
+                    </xs:simpleType>
+                  </xs:element>
+                </xs:sequence>
+              </xs:complexType>
+            </xs:element>
+        "#);
+
+        let package = parse(r#"<prices>
+            <price>12.50</price><price>0.5</price><price>100</price><price>1.234</price>
+        </prices>"#);
+        let doc = package.as_document();
+        let prices = child_elements(document_element(&doc));
+
+        assert_eq!(schema.validate(&doc), vec![
+            InvalidElementValue(prices[1], MinInclusiveFacet("1".to_string())),
+            InvalidElementValue(prices[2], MaxExclusiveFacet("100".to_string())),
+            InvalidElementValue(prices[3], TotalDigitsFacet(3)),
+        ]);
+    }
+
+    #[test]
+    fn enumerations_lists_and_unions() {
+        let schema = schema(r#"
+            <xs:simpleType name="size">
+              <xs:restriction base="xs:token">
+                <xs:enumeration value="small"/>
+                <xs:enumeration value="large"/>
+              </xs:restriction>
+            </xs:simpleType>
+            <xs:simpleType name="pair">
+              <xs:restriction>
+                <xs:simpleType><xs:list itemType="size"/></xs:simpleType>
+                <xs:length value="2"/>
+              </xs:restriction>
+            </xs:simpleType>
+            <xs:simpleType name="sizeOrNumber">
+              <xs:union memberTypes="size xs:integer"/>
+            </xs:simpleType>
+            <xs:element name="r">
+              <xs:complexType>
+                <xs:attribute name="pair" type="pair"/>
+                <xs:attribute name="one" type="sizeOrNumber"/>
+              </xs:complexType>
+            </xs:element>
+        "#);
+
+        let package = parse(r#"<r pair=" small  large " one="7"/>"#);
+        let doc = package.as_document();
+        assert!(schema.validate(&doc).is_empty());
+
+        let package = parse(r#"<r pair="small" one="medium"/>"#);
+        let doc = package.as_document();
+        let r = document_element(&doc);
+        assert_eq!(schema.validate(&doc), vec![
+            InvalidAttributeValue(r, "pair".to_string(), LengthFacet(2)),
+            InvalidAttributeValue(r, "one".to_string(), UnionFacet),
+        ]);
+
+        let package = parse(r#"<r pair="small medium"/>"#);
+        let doc = package.as_document();
+        let r = document_element(&doc);
+        assert_eq!(schema.validate(&doc), vec![
+            InvalidAttributeValue(r, "pair".to_string(), EnumerationFacet),
+        ]);
+    }
+
+    #[test]
+    fn sequences_and_occurrences() {
+        let schema = schema(r#"
+            <xs:element name="r">
+              <xs:complexType>
+                <xs:sequence>
+                  <xs:element name="a" maxOccurs="2"/>
+                  <xs:element name="b" minOccurs="0"/>
+                  <xs:element name="c" minOccurs="2" maxOccurs="unbounded"/>
+                </xs:sequence>
+              </xs:complexType>
+            </xs:element>
+        "#);
+
+        for xml in ["<r><a/><a/><b/><c/><c/><c/></r>", "<r><a/><c/><c/></r>"].iter() {
+            let package = parse(*xml);
+            let doc = package.as_document();
+            assert!(schema.validate(&doc).is_empty());
+        }
+
+        for xml in ["<r><a/><a/><a/><c/><c/></r>", "<r><a/><c/></r>", "<r><b/><c/><c/></r>"].iter() {
+            let package = parse(*xml);
+            let doc = package.as_document();
+            let r = document_element(&doc);
+            assert_eq!(schema.validate(&doc), vec![InvalidContent(r)]);
+        }
+    }
+
+    #[test]
+    fn choices_groups_and_all() {
+        let schema = schema(r#"
+            <xs:group name="ab">
+              <xs:choice>
+                <xs:element name="a"/>
+                <xs:element name="b"/>
+              </xs:choice>
+            </xs:group>
+            <xs:element name="r">
+              <xs:complexType>
+                <xs:sequence>
+                  <xs:group ref="ab" maxOccurs="unbounded"/>
+                  <xs:element ref="s"/>
+                </xs:sequence>
+              </xs:complexType>
+            </xs:element>
+            <xs:element name="s">
+              <xs:complexType>
+                <xs:all>
+                  <xs:element name="x"/>
+                  <xs:element name="y" minOccurs="0"/>
+                </xs:all>
+              </xs:complexType>
+            </xs:element>
+        "#);
+
+        let package = parse("<r><b/><a/><b/><s><y/><x/></s></r>");
+        let doc = package.as_document();
+        assert!(schema.validate(&doc).is_empty());
+
+        let package = parse("<r><s><x/></s></r>");
+        let doc = package.as_document();
+        let r = document_element(&doc);
+        assert_eq!(schema.validate(&doc), vec![InvalidContent(r)]);
+
+        let package = parse("<r><a/><s><y/><y/></s></r>");
+        let doc = package.as_document();
+        let s = child_elements(document_element(&doc))[1];
+        assert_eq!(schema.validate(&doc), vec![InvalidContent(s)]);
+    }
+
+    #[test]
+    fn attributes() {
+        let schema = schema(r#"
+            <xs:attributeGroup name="common">
+              <xs:attribute name="id" type="xs:ID" use="required"/>
+            </xs:attributeGroup>
+            <xs:element name="r">
+              <xs:complexType>
+                <xs:attribute name="count" type="xs:positiveInteger"/>
+                <xs:attribute name="version" type="xs:decimal" fixed="1.0"/>
+                <xs:attributeGroup ref="common"/>
+              </xs:complexType>
+            </xs:element>
+        "#);
+
+        let package = parse(r#"<r xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" id="r1" count="3" version=" 1.0 "/>"#);
+        let doc = package.as_document();
+        assert!(schema.validate(&doc).is_empty());
+
+        let package = parse(r#"<r count="0" version="2" extra="x"/>"#);
+        let doc = package.as_document();
+        let r = document_element(&doc);
+        assert_eq!(schema.validate(&doc), vec![
+            InvalidAttributeValue(r, "count".to_string(), DatatypeFacet("positiveInteger".to_string())),
+            InvalidAttributeValue(r, "version".to_string(), FixedFacet("1.0".to_string())),
+            UndeclaredAttribute(r, "extra".to_string()),
+            MissingRequiredAttribute(r, "id".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn complex_types_can_be_extended() {
+        let schema = schema(r#"
+            <xs:complexType name="named">
+              <xs:sequence>
+                <xs:element name="name" type="xs:string"/>
+              </xs:sequence>
+              <xs:attribute name="id" type="xs:NCName"/>
+            </xs:complexType>
+            <xs:complexType name="person">
+              <xs:complexContent>
+                <xs:extension base="named">
+                  <xs:sequence>
+                    <xs:element name="age" type="xs:int"/>
+                  </xs:sequence>
+                </xs:extension>
+              </xs:complexContent>
+            </xs:complexType>
+            <xs:element name="person" type="person"/>
+        "#);
+
+        let package = parse(r#"<person id="p1"><name>Ann</name><age>30</age></person>"#);
+        let doc = package.as_document();
+        assert!(schema.validate(&doc).is_empty());
+
+        let package = parse(r#"<person id="1"><age>30</age><name>Ann</name></person>"#);
+        let doc = package.as_document();
+        let person = document_element(&doc);
+        assert_eq!(schema.validate(&doc), vec![
+            InvalidAttributeValue(person, "id".to_string(), DatatypeFacet("NCName".to_string())),
+            InvalidContent(person),
+        ]);
+    }
+
+    #[test]
+    fn simple_and_mixed_content() {
+        let schema = schema(r#"
+            <xs:element name="r">
+              <xs:complexType>
+                <xs:sequence>
+                  <xs:element name="price">
+                    <xs:complexType>
+                      <xs:simpleContent>
+                        <xs:extension base="xs:decimal">
+                          <xs:attribute name="currency" type="xs:string" use="required"/>
+                        </xs:extension>
+                      </xs:simpleContent>
+                    </xs:complexType>
+                  </xs:element>
+                  <xs:element name="note">
+                    <xs:complexType mixed="true">
+                      <xs:sequence>
+                        <xs:element name="b" minOccurs="0"/>
+                      </xs:sequence>
+                    </xs:complexType>
+                  </xs:element>
+                </xs:sequence>
+              </xs:complexType>
+            </xs:element>
+        "#);
+
+        let package = parse(r#"<r><price currency="EUR">9.99</price><note>very <b>good</b></note></r>"#);
+        let doc = package.as_document();
+        assert!(schema.validate(&doc).is_empty());
+
+        let package = parse(r#"<r>text<price>cheap</price><note/></r>"#);
+        let doc = package.as_document();
+        let r = document_element(&doc);
+        let price = child_elements(r)[0];
+        assert_eq!(schema.validate(&doc), vec![
+            UnexpectedText(r),
+            MissingRequiredAttribute(price, "currency".to_string()),
+            InvalidElementValue(price, DatatypeFacet("decimal".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn wildcards_and_undeclared_elements() {
+        let schema = schema(r#"
+            <xs:element name="r">
+              <xs:complexType>
+                <xs:sequence>
+                  <xs:any maxOccurs="unbounded"/>
+                </xs:sequence>
+              </xs:complexType>
+            </xs:element>
+            <xs:element name="n" type="xs:int"/>
+        "#);
+
+        let package = parse("<r><n>x</n><other/></r>");
+        let doc = package.as_document();
+        let children = child_elements(document_element(&doc));
+        assert_eq!(schema.validate(&doc), vec![
+            InvalidElementValue(children[0], DatatypeFacet("int".to_string())),
+            UndeclaredElement(children[1]),
+        ]);
+
+        let package = parse("<other/>");
+        let doc = package.as_document();
+        let other = document_element(&doc);
+        assert_eq!(schema.validate(&doc), vec![UndeclaredElement(other)]);
+    }
+
+    #[test]
+    fn keys_and_key_references() {
+        let schema = schema(r#"
+            <xs:element name="library">
+              <xs:complexType>
+                <xs:sequence>
+                  <xs:element name="book" maxOccurs="unbounded">
+                    <xs:complexType>
+                      <xs:attribute name="id" type="xs:string"/>
+                    </xs:complexType>
+                  </xs:element>
+                  <xs:element name="loan" minOccurs="0" maxOccurs="unbounded">
+                    <xs:complexType>
+                      <xs:sequence>
+                        <xs:element name="book" type="xs:string"/>
+                      </xs:sequence>
+                    </xs:complexType>
+                  </xs:element>
+                </xs:sequence>
+              </xs:complexType>
+              <xs:key name="bookId">
+                <xs:selector xpath="book"/>
+                <xs:field xpath="@id"/>
+              </xs:key>
+              <xs:keyref name="loanedBook" refer="bookId">
+                <xs:selector xpath=".//loan"/>
+                <xs:field xpath="book"/>
+              </xs:keyref>
+            </xs:element>
+        "#);
+
+        let package = parse(r#"<library>
+            <book id="a"/><book id="a"/><book/>
+            <loan><book> a </book></loan><loan><book>b</book></loan>
+        </library>"#);
+        let doc = package.as_document();
+        let children = child_elements(document_element(&doc));
+
+        assert_eq!(schema.validate(&doc), vec![
+            DuplicateKey(children[1], "bookId".to_string()),
+            IncompleteKey(children[2], "bookId".to_string()),
+            UnknownKeyReference(children[4], "loanedBook".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn schema_errors() {
+        assert_eq!(Schema::parse("<notASchema/>").err(), Some(NotASchema("".to_string())));
+
+        assert_eq!(try_schema(r#"<xs:element name="a" type="missing"/>"#).err(),
+                   Some(UnresolvedReference("missing".to_string())));
+
+        assert_eq!(try_schema(r#"<xs:element name="a" type="xs:missing"/>"#).err(),
+                   Some(UnresolvedReference("missing".to_string())));
+
+        let invalid_pattern = r#"
+            <xs:simpleType name="t">
+              <xs:restriction base="xs:string"><xs:pattern value="[a-"/></xs:restriction>
+            </xs:simpleType>
+        "#;
+        assert_eq!(try_schema(invalid_pattern).err(), Some(InvalidPattern("[a-".to_string())));
+    }
+
+    #[test]
+    fn circular_definitions_are_rejected() {
+        let derived_from_itself = r#"
+            <xs:simpleType name="a"><xs:restriction base="b"/></xs:simpleType>
+            <xs:simpleType name="b"><xs:list itemType="a"/></xs:simpleType>
+        "#;
+        assert!(match try_schema(derived_from_itself).err() {
+            Some(CircularDefinition(ref name)) => name.as_slice() == "a" || name.as_slice() == "b",
+            _ => false,
+        });
+
+        let group_in_itself = r#"
+            <xs:group name="g"><xs:sequence><xs:group ref="g"/></xs:sequence></xs:group>
+        "#;
+        assert_eq!(try_schema(group_in_itself).err(), Some(CircularDefinition("g".to_string())));
+
+        let attribute_group_in_itself = r#"
+            <xs:attributeGroup name="g"><xs:attributeGroup ref="g"/></xs:attributeGroup>
+        "#;
+        assert_eq!(try_schema(attribute_group_in_itself).err(), Some(CircularDefinition("g".to_string())));
+
+        let element_in_itself = r#"
+            <xs:complexType name="tree">
+              <xs:sequence><xs:element name="tree" type="tree" minOccurs="0"/></xs:sequence>
+            </xs:complexType>
+        "#;
+        assert!(try_schema(element_in_itself).is_ok());
+    }
+
+    #[test]
+    fn schemas_can_include_and_import_files() {
+        let dir = TempDir::new("xsd").unwrap();
+        mkdir(&dir.path().join("sub"), USER_RWX).unwrap();
+
+        let files = [
+            ("main.xsd", r#"<xs:schema xmlns:xs="http://www.w3.org/2001/XMLSchema">
+                <xs:include schemaLocation="types.xsd"/>
+                <xs:import namespace="urn:other" schemaLocation="sub/other.xsd"/>
+                <xs:element name="r">
+                  <xs:complexType>
+                    <xs:sequence>
+                      <xs:element name="v" type="digit"/>
+                      <xs:element ref="o"/>
+                    </xs:sequence>
+                  </xs:complexType>
+                </xs:element>
+              </xs:schema>"#),
+            ("types.xsd", r#"<xs:schema xmlns:xs="http://www.w3.org/2001/XMLSchema">
+                <xs:include schemaLocation="main.xsd"/>
+                <xs:simpleType name="digit">
+                  <xs:restriction base="xs:int"><xs:maxInclusive value="9"/></xs:restriction>
+                </xs:simpleType>
+              </xs:schema>"#),
+            ("sub/other.xsd", r#"<xsd:schema xmlns:xsd="http://www.w3.org/2001/XMLSchema" targetNamespace="urn:other">
+                <xsd:element name="o" type="xsd:boolean"/>
+              </xsd:schema>"#),
+        ];
+
+        for &(name, contents) in files.iter() {
+            File::create(&dir.path().join(name)).write_str(contents).unwrap();
+        }
+
+        let schema = Schema::from_file(&dir.path().join("main.xsd")).ok().expect("Invalid schema");
+
+        let package = parse("<r><v>10</v><o>true</o></r>");
+        let doc = package.as_document();
+        let v = child_elements(document_element(&doc))[0];
+        assert_eq!(schema.validate(&doc), vec![
+            InvalidElementValue(v, MaxInclusiveFacet("9".to_string())),
+        ]);
+
+        match Schema::from_file(&dir.path().join("missing.xsd")) {
+            Err(ReadFailure(..)) => {},
+            _ => panic!("Missing files should fail to load"),
+        }
+    }
+}
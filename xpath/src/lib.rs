@@ -1,3 +1,20 @@
+//! Evaluates XPath expressions against documents
+//!
+//! ### Known issues
+//!
+//! - The crate is written against an earlier version of the document
+//!   API (`Nodeset`, `Any` and the `*Any` node wrappers) and does not
+//!   build until it is ported.
+//! - There is no `id()` function. Once ported, it should find elements
+//!   with `Document::element_by_id`.
+//! - Descendant steps visit every node. `//name` and
+//!   `descendant::name` should use `Document::elements_named` and
+//!   `Element::descendants_named`, which use the name index once it is
+//!   enabled.
+//! - Axes and node tests work on a single node type. They should be
+//!   generic over `document::navigator::Navigator`, so that any tree
+//!   can be queried.
+
 #![crate_name = "xpath"]
 #![feature(macro_rules)]
 